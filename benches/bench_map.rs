@@ -108,7 +108,7 @@ fn bench_typical(num_threads: usize) {
                 map_clone.get_clone(&(i % 1000));
             }
             for i in 0..200 / num_threads{
-                map_clone.remove(&i, &i);
+                map_clone.compare_and_remove(&i, &i);
             }
         }));
     }
@@ -126,7 +126,7 @@ fn bench_typical(num_threads: usize) {
                 map_clone.get_clone(&((i % 1000) + 1000));
             }
             for i in 1000..1200 / num_threads{
-                map_clone.remove(&i, &i);
+                map_clone.compare_and_remove(&i, &i);
             }
         }));
     }
@@ -243,10 +243,10 @@ fn bench_with_updates(num_threads: usize) {
                 map_clone.get_clone(&(i % 1000));
             }
             for i in 0..200 / num_threads{
-                map_clone.remove(&i, &i);
+                map_clone.compare_and_remove(&i, &i);
             }
             for i in 200..400 / num_threads{
-                map_clone.update(&i, &i, i + 1);
+                let _ = map_clone.update(&i, &i, i + 1);
             }
         }));
     }
@@ -264,10 +264,10 @@ fn bench_with_updates(num_threads: usize) {
                 map_clone.get_clone(&((i % 1000) + 1000));
             }
             for i in 1000..1200 / num_threads{
-                map_clone.remove(&i, &i);
+                map_clone.compare_and_remove(&i, &i);
             }
             for i in 1200..1400 / num_threads{
-                map_clone.update(&i, &i, i + 1);
+                let _ = map_clone.update(&i, &i, i + 1);
             }
         }));
     }
@@ -396,10 +396,10 @@ fn bench_heavy_insert(num_threads: usize) {
                 map_clone.get_clone(&(i % 1000));
             }
             for i in 0..200 / num_threads{
-                map_clone.remove(&i, &i);
+                map_clone.compare_and_remove(&i, &i);
             }
             for i in 200..400 / num_threads{
-                map_clone.update(&i, &i, i + 1);
+                let _ = map_clone.update(&i, &i, i + 1);
             }
         }));
     }
@@ -417,10 +417,10 @@ fn bench_heavy_insert(num_threads: usize) {
                 map_clone.get_clone(&((i % 1000) + 1000));
             }
             for i in 10000..10200 / num_threads{
-                map_clone.remove(&i, &i);
+                map_clone.compare_and_remove(&i, &i);
             }
             for i in 10200..10400 / num_threads{
-                map_clone.update(&i, &i, i + 1);
+                let _ = map_clone.update(&i, &i, i + 1);
             }
         }));
     }
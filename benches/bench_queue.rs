@@ -108,6 +108,39 @@ fn bench_equal_crossbeam(num_threads: usize) {
     }
 }
 
+fn bench_pools(pool_size: usize) {
+    let queue = Arc::new(Queue::new());
+    let mut wait_vec: Vec<JoinHandle<()>> = Vec::new();
+
+    // Every thread moves the same number of elements however big the pools are, so the time taken
+    // shows how well enqueuers and dequeuers keep out of each other's way
+    for _ in 0..pool_size {
+        let queue_clone = queue.clone();
+        wait_vec.push(thread::spawn(move || {
+            for i in 0..1000 {
+                queue_clone.enqueue(i);
+            }
+        }));
+    }
+
+    for _ in 0..pool_size {
+        let queue_clone = queue.clone();
+        wait_vec.push(thread::spawn(move || {
+            let mut dequeued = 0;
+            while dequeued < 1000 {
+                match queue_clone.dequeue() {
+                    Some(_) => dequeued += 1,
+                    None => thread::yield_now()
+                }
+            }
+        }))
+    }
+
+    for handle in wait_vec {
+        handle.join().unwrap();
+    }
+}
+
 fn bench_mp_sc_lock(num_threads: usize) {
     let queue = Arc::new(Mutex::new(VecDeque::new()));
     let mut wait_vec = Vec::new();
@@ -306,6 +339,44 @@ fn bench_sp_mc_crossbeam(num_threads: usize) {
     }
 }
 
+fn bench_batch_loop(num_threads: usize) {
+    let queue = Arc::new(Queue::new());
+    let mut wait_vec = Vec::new();
+
+    for _ in 0..num_threads {
+        let q = queue.clone();
+        wait_vec.push(thread::spawn(move || {
+            for batch in 0..(10000 / num_threads) / 100 {
+                for i in 0..100 {
+                    q.enqueue(batch * 100 + i);
+                }
+            }
+        }))
+    }
+
+    for handle in wait_vec {
+        handle.join().unwrap();
+    }
+}
+
+fn bench_batch(num_threads: usize) {
+    let queue = Arc::new(Queue::new());
+    let mut wait_vec = Vec::new();
+
+    for _ in 0..num_threads {
+        let q = queue.clone();
+        wait_vec.push(thread::spawn(move || {
+            for batch in 0..(10000 / num_threads) / 100 {
+                q.enqueue_all(batch * 100..(batch + 1) * 100);
+            }
+        }))
+    }
+
+    for handle in wait_vec {
+        handle.join().unwrap();
+    }
+}
+
 fn bench_queue_equal_lock(c: &mut Criterion) {
     c.bench_function_over_inputs("queue_equal", |b: &mut Bencher, num_threads: &usize| b.iter(|| bench_equal_lock(*num_threads)), (2..42).filter(|num| num % 2 == 0).collect::<Vec<usize>>());
 }
@@ -314,6 +385,10 @@ fn bench_queue_equal(c: &mut Criterion) {
     c.bench_function_over_inputs("queue_equal", |b: &mut Bencher, num_threads: &usize| b.iter(|| bench_equal(*num_threads)), (2..42).filter(|num| num % 2 == 0).collect::<Vec<usize>>());
 }
 
+fn bench_queue_pools(c: &mut Criterion) {
+    c.bench_function_over_inputs("queue_producer_consumer_pools", |b: &mut Bencher, pool_size: &usize| b.iter(|| bench_pools(*pool_size)), vec![1, 2, 4, 8, 16]);
+}
+
 fn bench_queue_mp_sc_lock(c: &mut Criterion) {
     c.bench_function_over_inputs("queue_mp_sc", |b: &mut Bencher, num_threads: &usize| b.iter(|| bench_mp_sc_lock(*num_threads)), (2..42).filter(|num| num % 2 == 0).collect::<Vec<usize>>());
 }
@@ -330,6 +405,14 @@ fn bench_queue_sp_mc(c: &mut Criterion) {
     c.bench_function_over_inputs("queue_sp_mc", |b: &mut Bencher, num_threads: &usize| b.iter(|| bench_sp_mc(*num_threads)), (2..42).filter(|num| num % 2 == 0).collect::<Vec<usize>>());
 }
 
+fn bench_queue_batch_loop(c: &mut Criterion) {
+    c.bench_function_over_inputs("queue_batch", |b: &mut Bencher, num_threads: &usize| b.iter(|| bench_batch_loop(*num_threads)), (2..42).filter(|num| num % 2 == 0).collect::<Vec<usize>>());
+}
+
+fn bench_queue_batch(c: &mut Criterion) {
+    c.bench_function_over_inputs("queue_batch_enqueue_all", |b: &mut Bencher, num_threads: &usize| b.iter(|| bench_batch(*num_threads)), (2..42).filter(|num| num % 2 == 0).collect::<Vec<usize>>());
+}
+
 fn crossbeam_bench_equal(c: &mut Criterion) {
     c.bench_function_over_inputs("crossbeam_queue_equal", |b: &mut Bencher, num_threads: &usize| b.iter(|| bench_equal_crossbeam(*num_threads)), (2..42).filter(|num| num % 2 == 0).collect::<Vec<usize>>());
 }
@@ -342,5 +425,6 @@ fn crossbeam_bench_sp_mc(c: &mut Criterion) {
     c.bench_function_over_inputs("crossbeam_queue_sp_mc", |b: &mut Bencher, num_threads: &usize| b.iter(|| bench_sp_mc_crossbeam(*num_threads)), (2..42).filter(|num| num % 2 == 0).collect::<Vec<usize>>());
 }
 
-criterion_group!(benches, bench_queue_equal_lock, bench_queue_equal, bench_queue_mp_sc_lock, bench_queue_mp_sc, bench_queue_sp_mc_lock, bench_queue_sp_mc);
+criterion_group!(benches, bench_queue_equal_lock, bench_queue_equal, bench_queue_pools, bench_queue_mp_sc_lock, bench_queue_mp_sc, bench_queue_sp_mc_lock, bench_queue_sp_mc,
+                 bench_queue_batch_loop, bench_queue_batch);
 criterion_main!(benches);
\ No newline at end of file
@@ -206,6 +206,17 @@ fn bench_sp_mc(num_threads: usize) {
     }
 }
 
+fn bench_small_k(k: usize) {
+    let queue = SegQueue::new(k);
+    for i in 0..10000 {
+        queue.enqueue(i);
+        if i % 2 == 0 {
+            queue.dequeue();
+        }
+    }
+    while queue.dequeue().is_some() {}
+}
+
 fn bench_seg_equal_lock(c: &mut Criterion) {
     c.bench_function_over_inputs("seg_equal", |b: &mut Bencher, num_threads: &usize| b.iter(|| bench_equal_lock(*num_threads)),
                                  (2..42).filter(|num| num % 2 == 0).collect::<Vec<usize>>());
@@ -236,6 +247,11 @@ fn bench_seg_sp_mc(c: &mut Criterion) {
                                  (2..42).filter(|num| num % 2 == 0).collect::<Vec<usize>>());
 }
 
+fn bench_seg_small_k(c: &mut Criterion) {
+    c.bench_function_over_inputs("queue_small_k_segment", |b: &mut Bencher, k: &usize| b.iter(|| bench_small_k(*k)),
+                                 vec![1, 2, 4, 8]);
+}
+
 criterion_group!(benches, bench_seg_equal_lock, bench_seg_equal, bench_seg_mp_sc_lock,
-                          bench_seg_mp_sc, bench_seg_sp_mc_lock, bench_seg_sp_mc);
+                          bench_seg_mp_sc, bench_seg_sp_mc_lock, bench_seg_sp_mc, bench_seg_small_k);
 criterion_main!(benches);
\ No newline at end of file
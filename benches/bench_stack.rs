@@ -5,9 +5,11 @@ extern crate crossbeam;
 
 use criterion::{Bencher, Criterion};
 use rustcurrent::structures::Stack;
+use rustcurrent::util::Backoff;
 use crossbeam::sync::TreiberStack;
 use std::thread;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Barrier, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread::JoinHandle;
 
 fn bench_rustcurrent_stack(num_threads: usize, elim: bool) {
@@ -295,6 +297,61 @@ fn bench_sp_mc_lock(num_threads: usize) {
     }
 }
 
+// Every thread increments the same counter with the retry loop the Stack uses for push and pop, backing off
+// after each lost CAS if asked to, and the number of lost CASes is returned
+fn contended_cas(num_threads: usize, backoff: bool) -> usize {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let retries = Arc::new(AtomicUsize::new(0));
+    // Start every thread at once, otherwise the first ones finish before the last ones are spawned
+    let barrier = Arc::new(Barrier::new(num_threads));
+    let mut wait_vec: Vec<JoinHandle<()>> = Vec::new();
+
+    for _ in 0..num_threads {
+        let counter = counter.clone();
+        let retries = retries.clone();
+        let barrier = barrier.clone();
+        wait_vec.push(thread::spawn(move || {
+            let mut lost = 0;
+            barrier.wait();
+            for _ in 0..100000 / num_threads {
+                let mut waiter = Backoff::new();
+                loop {
+                    let current = counter.load(Ordering::Acquire);
+                    if counter.compare_exchange_weak(current, current + 1, Ordering::Release, Ordering::Relaxed).is_ok() {
+                        break;
+                    }
+                    lost += 1;
+                    if backoff {
+                        waiter.snooze();
+                    }
+                }
+            }
+            retries.fetch_add(lost, Ordering::Relaxed);
+        }));
+    }
+
+    for handle in wait_vec {
+        handle.join().unwrap();
+    }
+    retries.load(Ordering::Relaxed)
+}
+
+fn bench_cas_backoff(c: &mut Criterion) {
+    c.bench_function_over_inputs("cas_backoff", |b: &mut Bencher, num_threads: &usize| b.iter(|| contended_cas(*num_threads, true)), (2..42).filter(|num| num % 8 == 0).collect::<Vec<usize>>());
+}
+
+fn bench_cas_no_backoff(c: &mut Criterion) {
+    c.bench_function_over_inputs("cas_no_backoff", |b: &mut Bencher, num_threads: &usize| b.iter(|| contended_cas(*num_threads, false)), (2..42).filter(|num| num % 8 == 0).collect::<Vec<usize>>());
+}
+
+// Criterion only reports times, so the lost CASes are printed as well
+fn report_cas_retries(_c: &mut Criterion) {
+    for num_threads in (2..42).filter(|num| num % 8 == 0) {
+        println!("{} threads: {} lost CASes with backoff, {} without", num_threads,
+                 contended_cas(num_threads, true), contended_cas(num_threads, false));
+    }
+}
+
 fn bench_elim_equal(c: &mut Criterion) {
     c.bench_function_over_inputs("stack_equal_elimination", |b: &mut Bencher, num_threads: &usize| b.iter(|| bench_rustcurrent_stack(*num_threads, true)), (2..42).filter(|num| num % 2 == 0).collect::<Vec<usize>>());
 }
@@ -344,5 +401,6 @@ fn bench_crossbeam_sp_mc(c: &mut Criterion) {
 }
 
 criterion_group!(benches, bench_lock_equal, bench_elim_equal, bench_no_elim_equal, bench_lock_mp_sc, bench_elim_mp_sc, bench_no_elim_mp_sc,
-                          bench_lock_sp_mc, bench_elim_sp_mc, bench_no_elim_sp_mc);
+                          bench_lock_sp_mc, bench_elim_sp_mc, bench_no_elim_sp_mc, bench_cas_backoff, bench_cas_no_backoff,
+                          report_cas_retries);
 criterion_main!(benches);
\ No newline at end of file
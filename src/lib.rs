@@ -1,17 +1,52 @@
 #![allow(dead_code)]
+#![cfg_attr(not(feature = "std"), no_std)]
 //! A lock-free concurrency library for Rust.
 //!
 //! This crate provides both some lock-free data structures and lock-free memory management.
 //! The structures can be used in a variety of scenarios. Of particular interest is the wait-free
 //! HashMap, which guarantees that progress is made by every thread in a bounded amount of time.
+//!
+//! Everything is available with the default `std` feature. Without it the crate only needs `core` and
+//! `alloc`, but the HPBRManager relies on thread-local storage, so only the structures which can be
+//! given another ReclamationStrategy are left: `Stack` and `Queue`, created with `with_manager`, and
+//! `BoundedQueue`, `AtomicMin` and `AtomicMax`, which do not need one. The Stack's elimination layer is not available.
 
+#[cfg(feature = "std")]
 extern crate time;
+#[cfg(feature = "std")]
 extern crate rand;
+#[cfg(feature = "std")]
 extern crate thread_local;
+#[cfg(feature = "std")]
 extern crate rayon;
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "loom")]
+extern crate loom;
+
+// Without std, paths starting with `std::` resolve to this module instead, so the structures which
+// only need core and alloc can be written the same way either way.
+#[cfg(not(feature = "std"))]
+mod std {
+    pub use core::*;
+    pub use alloc::{boxed, vec};
+}
+
+// The atomics used by the Stack, the Queue and the ReclamationStrategy interface. With the `loom` feature they
+// come from loom instead, so that its model checker can explore the orders their operations can happen in.
+mod sync {
+    #[cfg(not(feature = "loom"))]
+    pub use std::sync::atomic;
+    #[cfg(feature = "loom")]
+    pub use loom::sync::atomic;
+}
 
 pub mod structures;
 pub mod memory;
+pub mod util;
+#[cfg(feature = "std")]
 pub mod testing;
 
 mod tests {
@@ -1,12 +1,19 @@
 #![allow(unused_assignments)]
-use std::sync::atomic::{AtomicPtr, Ordering, AtomicBool};
+use std::sync::atomic::{AtomicPtr, Ordering, AtomicBool, AtomicUsize};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use std::fmt::Debug;
 use thread_local::CachedThreadLocal;
 use std::collections::{VecDeque, HashSet};
-use std::cell::UnsafeCell;
+use std::cell::{UnsafeCell, RefCell};
 use std::fmt;
 use std::ptr;
 use std::mem;
+use super::{ReclamationStrategy, HazardGuard};
+
+/// How many times bigger than its maximum a free list has to grow before the manager reports reclaim pressure.
+const PRESSURE_FACTOR: usize = 2;
 
 /// A Hazard Pointer based memory manager for use in lock-free data structures.
 ///
@@ -45,11 +52,48 @@ use std::mem;
 ///
 /// Records are freed by reclaiming `Box` ownership, so the manager should be used with raw pointers
 /// created through the `Box::into_raw()` function.
+///
+/// A manager created with `with_background_reclaim` does not scan on the threads that retire records.
+/// Instead, once a thread's free list is full it is handed over to a background thread, which periodically
+/// scans the hazard pointers and frees everything it can. The background thread is stopped when the
+/// manager is dropped.
+///
+/// When a thread exits, any records left in its free list are moved onto a global list, along with the
+/// free lists handed over by other exited threads. The next scan on any other thread (or the background
+/// thread, if there is one) will free them once they are no longer protected. The exited thread's hazard
+/// pointers are cleared at the same time, and given back so that threads which start later can take them
+/// over instead of allocating new ones.
+///
+/// If a free list ever grows past `PRESSURE_FACTOR` times the maximum, because the records in it are still
+/// protected when it is scanned, the manager remembers that reclamation has fallen behind. This can be
+/// checked with `has_reclaim_pressure`.
+///
+/// A manager created with `with_recycling` keeps the records its scans find safe to free, rather than
+/// freeing them, so that structures can reuse their memory through `allocate`.
 pub struct HPBRManager<T: Send> {
     thread_info: CachedThreadLocal<UnsafeCell<ThreadLocalInfo<T>>>,
+    shared: Arc<SharedState<T>>,
+    num_hp_per_thread: usize,
+    retire_capacity: usize,
+    recycle: bool,
+    reclaim_thread: Option<thread::JoinHandle<()>>,
+    scan_count: AtomicUsize
+}
+
+/// The parts of the manager which the background reclamation thread needs access to.
+struct SharedState<T: Send> {
     head: AtomicPtr<HazardPointer<T>>,
-    max_retired: usize,
-    num_hp_per_thread: usize
+    retired_batches: AtomicPtr<RetiredBatch<T>>,
+    shutdown: AtomicBool,
+    reclaim_pressure: AtomicBool,
+    max_retired: AtomicUsize
+}
+
+/// A free list handed over by a thread, either to the background thread because it was full or to
+/// the other threads because its owner exited.
+struct RetiredBatch<T: Send> {
+    records: VecDeque<*mut T>,
+    next: *mut RetiredBatch<T>
 }
 
 impl<'a, T: Send + Debug + 'a> Debug for HPBRManager<T> {
@@ -66,7 +110,7 @@ impl<'a, T: Send + Debug + 'a> Debug for HPBRManager<T> {
             }
         };
 
-        write!(f, "HPBRManager(\n\tthread_info: {:?}, \n\thead: {:?}, \n\tmax_retired: {:?}", &thread_info_string, self.head, self.max_retired)
+        write!(f, "HPBRManager(\n\tthread_info: {:?}, \n\thead: {:?}, \n\tmax_retired: {:?}", &thread_info_string, self.shared.head, self.shared.max_retired)
     }
 }
 
@@ -80,14 +124,74 @@ impl<'a, T: Send> HPBRManager<T> {
     pub fn new(max_retired: usize, num_hp_per_thread: usize) -> Self {
         HPBRManager {
             thread_info: CachedThreadLocal::new(),
-            head: AtomicPtr::default(),
-            max_retired,
-            num_hp_per_thread
+            shared: Arc::new(SharedState::new(max_retired)),
+            num_hp_per_thread,
+            retire_capacity: 0,
+            recycle: false,
+            reclaim_thread: None,
+            scan_count: AtomicUsize::new(0)
+        }
+    }
+
+    /// Create a new HPBRManager where each thread's free list has room for `retire_capacity` records
+    /// from the start, so that retiring up to that many records never has to grow the list. The free
+    /// list keeps its capacity when it is scanned.
+    /// # Examples
+    /// ```
+    /// let manager: HPBRManager<u8> = HPBRManager::with_retire_capacity(100, 1, 128);
+    /// ```
+    pub fn with_retire_capacity(max_retired: usize, num_hp_per_thread: usize, retire_capacity: usize) -> Self {
+        let mut manager = HPBRManager::new(max_retired, num_hp_per_thread);
+        manager.retire_capacity = retire_capacity;
+        manager
+    }
+
+    /// Create a new HPBRManager which recycles records instead of freeing them. When a scan finds a
+    /// record which is no longer protected, it is kept in a list of spare records for the thread which
+    /// scanned it, to be handed out again by `allocate`. Each thread keeps at most the scan threshold's
+    /// worth of spares, and frees any more as usual.
+    /// # Examples
+    /// ```
+    /// let manager: HPBRManager<u8> = HPBRManager::with_recycling(100, 1);
+    /// ```
+    pub fn with_recycling(max_retired: usize, num_hp_per_thread: usize) -> Self {
+        let mut manager = HPBRManager::new(max_retired, num_hp_per_thread);
+        manager.recycle = true;
+        manager
+    }
+
+    /// Take one of this thread's spare records, if the manager recycles records and has one. The box still
+    /// holds the retired record, which should be overwritten with the new one. Returns None if there are
+    /// no spares, in which case the caller should allocate a new box as usual.
+    /// # Examples
+    /// ```
+    /// let manager: HPBRManager<u8> = HPBRManager::with_recycling(100, 1);
+    /// let record = match manager.allocate() {
+    ///     Some(mut spare) => { *spare = 8; spare },
+    ///     None => Box::new(8)
+    /// };
+    /// ```
+    pub fn allocate(&self) -> Option<Box<T>> {
+        if !self.recycle {
+            return None
+        }
+        unsafe {
+            self.get_mut_thread_info().recycled.pop().map(|record| Box::from_raw(record))
         }
     }
 
-    fn allocate(&self, data: T) -> AtomicPtr<T> {
-        AtomicPtr::new(Box::into_raw(Box::new(data)))
+    /// Take over a hazard pointer given back by an exited thread, or allocate a new one if there are none.
+    fn acquire_hp(&self) -> *mut HazardPointer<T> {
+        let mut current = self.shared.head.load(Ordering::Acquire);
+        while !current.is_null() {
+            unsafe {
+                if (*current).activate() {
+                    return current
+                }
+                current = (*current).next.load(Ordering::Acquire);
+            }
+        }
+        self.allocate_hp()
     }
 
     fn allocate_hp(&self) -> *mut HazardPointer<T> {
@@ -95,13 +199,13 @@ impl<'a, T: Send> HPBRManager<T> {
         let new_hp_ptr =  Box::into_raw(Box::new(new_hp));
 
         // CAS push the new hazard pointer onto the global list
-        // We do not need to worry about freeing as we will not be deleting hazard pointers
+        // Hazard pointers are never removed from the list, and are only freed along with the shared state
         loop {            
-            let old_head = self.head.load(Ordering::Acquire);
+            let old_head = self.shared.head.load(Ordering::Acquire);
             unsafe {
                 (*new_hp_ptr).next.store(old_head, Ordering::Release);
             }
-            if self.head.compare_and_swap(old_head, new_hp_ptr, Ordering::AcqRel) == old_head {
+            if self.shared.head.compare_exchange(old_head, new_hp_ptr, Ordering::AcqRel, Ordering::Acquire).is_ok() {
                 break;
             }
         }
@@ -111,7 +215,7 @@ impl<'a, T: Send> HPBRManager<T> {
 
     /// Retire the given record, which was protected inside the given hazard pointer. If the 
     /// number of records in the free list is bigger than the maximum allowed, this call will trigger
-    /// garbage collection for this thread.
+    /// garbage collection for this thread, or hand the free list to the background thread if there is one.
     /// # Unsafe
     /// Make sure the record pointer is a valid address that has not already been freed.
     /// # Examples
@@ -128,13 +232,68 @@ impl<'a, T: Send> HPBRManager<T> {
             thread_info_mut.get_mut_hazard_pointer(hazard_num).unprotect();
             thread_info_mut.retired_list.push_back(record);
             thread_info_mut.retired_number += 1;
+            let max_retired = self.scan_threshold();
+            if thread_info_mut.retired_number > max_retired * PRESSURE_FACTOR {
+                self.shared.reclaim_pressure.store(true, Ordering::Relaxed);
+            }
 
-            if thread_info_mut.retired_number > self.max_retired {
-                self.scan();
+            if thread_info_mut.retired_number > max_retired {
+                if self.reclaim_thread.is_some() {
+                    let records = mem::replace(&mut thread_info_mut.retired_list, Box::new(VecDeque::with_capacity(self.retire_capacity)));
+                    thread_info_mut.retired_number = 0;
+                    self.shared.push_batch(*records);
+                } else {
+                    self.scan();
+                }
             }
         }
     }
 
+    /// Free every retired record which is not currently protected by a hazard pointer, without waiting
+    /// for the free list to reach the scan threshold. This includes this thread's free list and any
+    /// records handed over by exited threads or waiting for the background thread. Useful at shutdown,
+    /// or to give memory back on demand.
+    /// # Examples
+    /// ```
+    /// let manager: HPBRManager<u8> = HPBRManager::new(100, 1);
+    /// let ptr = Box::into_raw(Box::new(8u8));
+    /// manager.retire(ptr, 0);
+    /// manager.force_scan(); // ptr has now been freed
+    /// ```
+    pub fn force_scan(&self) {
+        self.scan();
+    }
+
+    /// Change the maximum number of records a thread keeps in its free list before it scans the hazard
+    /// pointers to free them, or hands them to the background thread. A smaller threshold frees memory
+    /// sooner, while a larger one does the work of scanning less often, in bigger batches. The new
+    /// threshold applies from each thread's next retire.
+    /// # Examples
+    /// ```
+    /// let manager: HPBRManager<u8> = HPBRManager::new(100, 1);
+    /// manager.set_scan_threshold(1000);
+    /// assert_eq!(manager.scan_threshold(), 1000);
+    /// ```
+    pub fn set_scan_threshold(&self, max_retired: usize) {
+        self.shared.max_retired.store(max_retired, Ordering::Relaxed);
+    }
+
+    /// The maximum number of records a thread keeps in its free list before it scans them.
+    pub fn scan_threshold(&self) -> usize {
+        self.shared.max_retired.load(Ordering::Relaxed)
+    }
+
+    /// Check whether any free list has ever grown past its high watermark, meaning that records were
+    /// being retired faster than they could be freed. Once set, this stays true for the life of the manager.
+    /// # Examples
+    /// ```
+    /// let manager: HPBRManager<u8> = HPBRManager::new(100, 1);
+    /// assert!(!manager.has_reclaim_pressure());
+    /// ```
+    pub fn has_reclaim_pressure(&self) -> bool {
+        self.shared.reclaim_pressure.load(Ordering::Relaxed)
+    }
+
     /// Protect the given record with in the given hazard pointer. The caller should always check after protection
     /// that the proteced record has not changed before operating on it, to make sure the protected record has not
     /// already been removed and possibly freed.
@@ -177,6 +336,27 @@ impl<'a, T: Send> HPBRManager<T> {
         }
     }
     
+    /// Take charge of one of this thread's hazard pointers until the returned HazardGuard goes out of scope,
+    /// at which point the hazard pointer is cleared. This saves having to unprotect on every path out of
+    /// an operation.
+    /// # Panics
+    /// Panics if `hazard_num` is not one of the hazard pointers the manager was created with.
+    /// # Examples
+    /// ```
+    /// let manager: HPBRManager<u8> = HPBRManager::new(100, 1);
+    /// let head = AtomicPtr::new(Box::into_raw(Box::new(8u8)));
+    /// let guard = manager.guard(0);
+    /// let protected = guard.protect(&head);
+    /// // Operate on protected...
+    /// // The guard goes out of scope here and unprotects the pointer
+    /// ```
+    pub fn guard(&'a self, hazard_num: usize) -> HazardGuard<'a, T> {
+        if hazard_num >= self.num_hp_per_thread {
+            panic!("The manager only has {} hazard pointers per thread!", self.num_hp_per_thread)
+        }
+        HazardGuard::new(self, hazard_num)
+    }
+
     /// Protect a record inside a non-default allocated hazard pointer. If none are found, a new
     /// hazard pointer is allocated in a lock-free manner. Returns a HPHandle, representing the lifetime
     /// of the protection.
@@ -191,13 +371,15 @@ impl<'a, T: Send> HPBRManager<T> {
             let thread_info_mut = self.get_mut_thread_info();
             for i in thread_info_mut.starting_hazards_num..thread_info_mut.local_hazards.len() {
                 let hp = thread_info_mut.get_mut_hazard_pointer(i);
-                if !hp.protected.load(Ordering::Relaxed).is_null() {
+                // Only reuse hazard pointers which are not protecting anything
+                if hp.protected.load(Ordering::Relaxed).is_null() {
                     hp.protect(record);
                     return HPHandle::new(i, self)
                 }
             }
-            let new_hp = self.allocate_hp();
+            let new_hp = self.acquire_hp();
             let new_hp_index = thread_info_mut.add_dynamic_hazard_pointer(new_hp);
+            thread_info_mut.get_mut_hazard_pointer(new_hp_index).protect(record);
             HPHandle::new(new_hp_index, self)
         }
     }
@@ -249,40 +431,42 @@ impl<'a, T: Send> HPBRManager<T> {
             let info = &*local.get();
             if info.retired_list.contains(&record) {return true}
         }
+        let mut batch = self.shared.retired_batches.load(Ordering::Acquire);
+        while !batch.is_null() {
+            if (*batch).records.contains(&record) {return true}
+            batch = (*batch).next;
+        }
         false
     }
 
     /// Where the main deletion aspect of the HBPRManager takes place
     /// Deletes any retired nodes of this thread which are not protected by hazard pointers
     fn scan(&self) {
-        let mut hazard_set: HashSet<*mut T> = HashSet::new();
-        let mut current = self.head.load(Ordering::Relaxed);
-
-        // Loop through the hazard list and add all non-nulls to the hazard list
-        while !ptr::eq(current, ptr::null()) {
-            unsafe {
-                let hazard_pointer = &*current;
-                let ptr = hazard_pointer.protected.load(Ordering::Acquire);
-                if !ptr.is_null() {
-                    hazard_set.insert(ptr);
-                }
-                current = hazard_pointer.next.load(Ordering::Relaxed);
-            }
-        }
+        self.scan_count.fetch_add(1, Ordering::Relaxed);
 
-        // This will store the nodes that cannot yet be deleted
-        let mut new_retired_list: VecDeque<*mut T> = VecDeque::new();
         unsafe {
             let thread_info = self.get_mut_thread_info();
-            for ptr in thread_info.retired_list.drain(..) {
+            // Adopt the free lists of any threads which have exited. This must happen before the hazard
+            // pointers are read, since an adopted record may have been protected after an earlier read
+            // but before its thread retired it
+            self.shared.take_batches(&mut thread_info.retired_list);
+            let hazard_set = self.shared.protected_records();
+            let recycled = &mut thread_info.recycled;
+            let max_recycled = if self.recycle { self.scan_threshold() } else { 0 };
+            // Keep the nodes that cannot yet be deleted, without giving up the list's capacity
+            thread_info.retired_list.retain(|&ptr| {
                 if hazard_set.contains(&ptr) {
-                    new_retired_list.push_back(ptr);
+                    true
                 } else {
-                    Self::free(ptr);
+                    if recycled.len() < max_recycled {
+                        recycled.push(ptr);
+                    } else {
+                        Self::free(ptr);
+                    }
+                    false
                 }
-            }
-            thread_info.retired_number = new_retired_list.len();
-            thread_info.retired_list = Box::new(new_retired_list);
+            });
+            thread_info.retired_number = thread_info.retired_list.len();
         }
     }
 
@@ -290,7 +474,7 @@ impl<'a, T: Send> HPBRManager<T> {
         // Letting this box go out of scope should call Drop on the garbage
         //println!("Deleting: {:?}", garbage);
         unsafe {
-            Box::from_raw(garbage);
+            drop(Box::from_raw(garbage));
         }
     }
 
@@ -303,13 +487,200 @@ impl<'a, T: Send> HPBRManager<T> {
         let thread_info_ptr = self.thread_info.get_or(|| {
             let mut starting_hp: Vec<*mut HazardPointer<T>> = Vec::new();
             for _ in 0..self.num_hp_per_thread {
-                let hp = self.allocate_hp();
+                let hp = self.acquire_hp();
                 starting_hp.push(hp);
             }
-            Box::new(UnsafeCell::new(ThreadLocalInfo::new(starting_hp)))
+            Box::new(UnsafeCell::new(ThreadLocalInfo::new(starting_hp, self.retire_capacity)))
         }).get();
 
-        &mut *thread_info_ptr
+        // The thread local info may have been left behind by an exited thread with the same id,
+        // so check for a registered exit hook every time rather than only on creation
+        let thread_info = &mut *thread_info_ptr;
+        if thread_info.exit_state.load(Ordering::Acquire) == EXIT_UNREGISTERED {
+            // The exited thread gave back its hazard pointers, so take over some of our own
+            if thread_info.local_hazards.is_empty() {
+                for _ in 0..self.num_hp_per_thread {
+                    let hp = self.acquire_hp();
+                    thread_info.local_hazards.push(hp);
+                }
+            }
+            thread_info.exit_state.store(EXIT_REGISTERED, Ordering::Release);
+            register_exit_hook(thread_info, self.shared.clone());
+        }
+        thread_info
+    }
+}
+
+impl<T: Send + 'static> HPBRManager<T> {
+    /// Create a new HPBRManager which frees retired records on a background thread. Threads hand over
+    /// their free lists once they hold more than `max_retired` records, and the background thread
+    /// tries to free the records it has been given every `interval`.
+    /// # Examples
+    /// ```
+    /// let manager: HPBRManager<u8> = HPBRManager::with_background_reclaim(100, 1, Duration::from_millis(10));
+    /// ```
+    pub fn with_background_reclaim(max_retired: usize, num_hp_per_thread: usize, interval: Duration) -> Self {
+        let shared: Arc<SharedState<T>> = Arc::new(SharedState::new(max_retired));
+        let thread_shared = shared.clone();
+        let reclaim_thread = thread::spawn(move || {
+            let mut survivors: VecDeque<*mut T> = VecDeque::new();
+            while !thread_shared.shutdown.load(Ordering::Acquire) {
+                thread_shared.reclaim(&mut survivors);
+                if survivors.len() > thread_shared.max_retired.load(Ordering::Relaxed) * PRESSURE_FACTOR {
+                    thread_shared.reclaim_pressure.store(true, Ordering::Relaxed);
+                }
+                thread::park_timeout(interval);
+            }
+            // The manager is being dropped, so nothing can be protected any more
+            thread_shared.take_batches(&mut survivors);
+            for garbage in survivors.drain(..) {
+                HPBRManager::free(garbage);
+            }
+        });
+
+        HPBRManager {
+            thread_info: CachedThreadLocal::new(),
+            shared,
+            num_hp_per_thread,
+            retire_capacity: 0,
+            recycle: false,
+            reclaim_thread: Some(reclaim_thread),
+            scan_count: AtomicUsize::new(0)
+        }
+    }
+}
+
+impl<T: Send> ReclamationStrategy<T> for HPBRManager<T> {
+    fn protect(&self, record: *mut T, hazard_num: usize) {
+        HPBRManager::protect(self, record, hazard_num);
+    }
+
+    fn unprotect(&self, hazard_num: usize) {
+        HPBRManager::unprotect(self, hazard_num);
+    }
+
+    fn retire(&self, record: *mut T, hazard_num: usize) {
+        HPBRManager::retire(self, record, hazard_num);
+    }
+
+    fn guard(&self, hazard_num: usize) -> HazardGuard<'_, T> {
+        HPBRManager::guard(self, hazard_num)
+    }
+
+    fn allocate(&self) -> Option<Box<T>> {
+        HPBRManager::allocate(self)
+    }
+
+    fn has_reclaim_pressure(&self) -> bool {
+        HPBRManager::has_reclaim_pressure(self)
+    }
+}
+
+impl<T: Send> Drop for HPBRManager<T> {
+    fn drop(&mut self) {
+        // Stop exiting threads from handing over any more free lists
+        for local in self.thread_info.iter_mut() {
+            unsafe {
+                (*local.get()).detach();
+            }
+        }
+        if let Some(handle) = self.reclaim_thread.take() {
+            self.shared.shutdown.store(true, Ordering::Release);
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+        // Free anything left behind by exited threads
+        let mut orphans: VecDeque<*mut T> = VecDeque::new();
+        self.shared.take_batches(&mut orphans);
+        for garbage in orphans.drain(..) {
+            HPBRManager::free(garbage);
+        }
+    }
+}
+
+impl<T: Send> SharedState<T> {
+    fn new(max_retired: usize) -> Self {
+        SharedState {
+            head: AtomicPtr::default(),
+            retired_batches: AtomicPtr::default(),
+            shutdown: AtomicBool::new(false),
+            reclaim_pressure: AtomicBool::new(false),
+            max_retired: AtomicUsize::new(max_retired)
+        }
+    }
+
+    /// Collect every record currently protected by a hazard pointer.
+    fn protected_records(&self) -> HashSet<*mut T> {
+        let mut hazard_set: HashSet<*mut T> = HashSet::new();
+        let mut current = self.head.load(Ordering::Relaxed);
+
+        // Loop through the hazard list and add all non-nulls to the hazard list
+        while !ptr::eq(current, ptr::null()) {
+            unsafe {
+                let hazard_pointer = &*current;
+                let ptr = hazard_pointer.protected.load(Ordering::Acquire);
+                if !ptr.is_null() {
+                    hazard_set.insert(ptr);
+                }
+                current = hazard_pointer.next.load(Ordering::Relaxed);
+            }
+        }
+        hazard_set
+    }
+
+    /// CAS push a full free list onto the list of batches waiting for the background thread.
+    fn push_batch(&self, records: VecDeque<*mut T>) {
+        let batch_ptr = Box::into_raw(Box::new(RetiredBatch { records, next: ptr::null_mut() }));
+        loop {
+            let old_head = self.retired_batches.load(Ordering::Acquire);
+            unsafe {
+                (*batch_ptr).next = old_head;
+            }
+            if self.retired_batches.compare_exchange(old_head, batch_ptr, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+                break;
+            }
+        }
+    }
+
+    /// Take every waiting batch and move its records onto the end of the given list.
+    fn take_batches(&self, records: &mut VecDeque<*mut T>) {
+        let mut batch_ptr = self.retired_batches.swap(ptr::null_mut(), Ordering::AcqRel);
+        while !batch_ptr.is_null() {
+            let mut batch = unsafe { Box::from_raw(batch_ptr) };
+            records.append(&mut batch.records);
+            batch_ptr = batch.next;
+        }
+    }
+
+    /// Free every record handed to the background thread which is not protected by a hazard pointer.
+    /// Records which are still protected are left in `survivors` for the next attempt.
+    fn reclaim(&self, survivors: &mut VecDeque<*mut T>) {
+        self.take_batches(survivors);
+        if survivors.is_empty() {
+            return
+        }
+        let hazard_set = self.protected_records();
+        let mut new_survivors: VecDeque<*mut T> = VecDeque::new();
+        for ptr in survivors.drain(..) {
+            if hazard_set.contains(&ptr) {
+                new_survivors.push_back(ptr);
+            } else {
+                HPBRManager::free(ptr);
+            }
+        }
+        *survivors = new_survivors;
+    }
+}
+
+impl<T: Send> Drop for SharedState<T> {
+    fn drop(&mut self) {
+        let mut current = *self.head.get_mut();
+        while !current.is_null() {
+            unsafe {
+                let hazard_pointer = Box::from_raw(current);
+                current = hazard_pointer.next.load(Ordering::Relaxed);
+            }
+        }
     }
 }
 
@@ -353,7 +724,8 @@ impl<T: Send> HazardPointer<T> {
         HazardPointer {
             protected: AtomicPtr::default(),
             next: AtomicPtr::default(),
-            active: AtomicBool::new(false)
+            // Owned by the thread which allocated it
+            active: AtomicBool::new(true)
         }
     }
 
@@ -365,8 +737,15 @@ impl<T: Send> HazardPointer<T> {
         self.protected.store(ptr::null_mut(), Ordering::Release);
     }
 
+    /// Try to take over a hazard pointer which no thread owns, returning whether it succeeded.
     fn activate(&self) -> bool {
-        self.active.compare_and_swap(false, true, Ordering::AcqRel)
+        self.active.compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+    }
+
+    /// Clear the hazard pointer and give it back, so that another thread can take it over.
+    fn release(&mut self) {
+        self.unprotect();
+        self.active.store(false, Ordering::Release);
     }
 }
 
@@ -393,17 +772,38 @@ struct ThreadLocalInfo<T: Send> {
     local_hazards: Vec<*mut HazardPointer<T>>,
     retired_list: Box<VecDeque<*mut T>>,
     retired_number: usize,
-    starting_hazards_num: usize
+    recycled: Vec<*mut T>,
+    starting_hazards_num: usize,
+    exit_state: Arc<AtomicUsize>
 }
 
 impl<T: Send> ThreadLocalInfo<T> {
-    fn new(starting_hazards: Vec<*mut HazardPointer<T>>) -> Self {
+    fn new(starting_hazards: Vec<*mut HazardPointer<T>>, retire_capacity: usize) -> Self {
         let starting_hazards_num = starting_hazards.len(); 
         ThreadLocalInfo {
             local_hazards: starting_hazards,
-            retired_list: Box::new(VecDeque::new()),
+            retired_list: Box::new(VecDeque::with_capacity(retire_capacity)),
             retired_number: 0,
-            starting_hazards_num
+            recycled: Vec::new(),
+            starting_hazards_num,
+            exit_state: Arc::new(AtomicUsize::new(EXIT_UNREGISTERED))
+        }
+    }
+
+    /// Stop this thread's exit hook from touching the info, waiting for it to finish if it is running.
+    fn detach(&self) {
+        loop {
+            let state = self.exit_state.load(Ordering::Acquire);
+            if state == EXIT_DETACHED {
+                return
+            }
+            if state == EXIT_MIGRATING {
+                thread::yield_now();
+                continue
+            }
+            if self.exit_state.compare_exchange(state, EXIT_DETACHED, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                return
+            }
         }
     }
 
@@ -420,25 +820,113 @@ impl<T: Send> ThreadLocalInfo<T> {
 impl<T: Send> Drop for ThreadLocalInfo<T> {
     fn drop(&mut self) {
         // Free all nodes left over at program end
-        for garbage in self.retired_list.drain(..) {
+        for garbage in self.retired_list.drain(..).chain(self.recycled.drain(..)) {
             //println!("dropping {:?}", garbage);
             unsafe {
-                Box::from_raw(garbage);
+                drop(Box::from_raw(garbage));
             }
         }
-        // Need to replace the vector in the struct with an empty one to take possession of it
-        let hp_vec = mem::replace(&mut self.local_hazards, Vec::new());
-        for hp_ptr in hp_vec {
+        // The hazard pointers are freed with the shared state, since other threads may have taken them over
+    }
+}
+
+// The states of a thread's exit hook, stored in the thread local info
+const EXIT_UNREGISTERED: usize = 0;
+const EXIT_REGISTERED: usize = 1;
+const EXIT_MIGRATING: usize = 2;
+const EXIT_DETACHED: usize = 3;
+
+/// The data needed to hand over a thread's free list when it exits.
+struct OrphanHandover<T: Send> {
+    info: *mut ThreadLocalInfo<T>,
+    shared: Arc<SharedState<T>>,
+    exit_state: Arc<AtomicUsize>
+}
+
+/// A type-erased OrphanHandover, so that hooks for every type of manager can be kept in one thread local.
+struct ExitHook {
+    data: *mut (),
+    run: unsafe fn(*mut ()),
+    exit_state: Arc<AtomicUsize>
+}
+
+/// The exit hooks registered by a thread, which are run when its thread locals are destroyed.
+struct ExitHooks {
+    hooks: RefCell<Vec<ExitHook>>
+}
+
+impl Drop for ExitHooks {
+    fn drop(&mut self) {
+        for hook in self.hooks.borrow_mut().drain(..) {
             unsafe {
-                Box::from_raw(hp_ptr);
+                (hook.run)(hook.data);
             }
         }
     }
 }
 
+thread_local! {
+    static EXIT_HOOKS: ExitHooks = const { ExitHooks { hooks: RefCell::new(Vec::new()) } };
+}
+
+fn register_exit_hook<T: Send>(info: &mut ThreadLocalInfo<T>, shared: Arc<SharedState<T>>) {
+    let handover = OrphanHandover {
+        info: info as *mut ThreadLocalInfo<T>,
+        shared,
+        exit_state: info.exit_state.clone()
+    };
+    let hook = ExitHook {
+        data: Box::into_raw(Box::new(handover)) as *mut (),
+        run: hand_over_orphans::<T>,
+        exit_state: info.exit_state.clone()
+    };
+    // If the thread is already exiting the hook can't be registered, and the free list will be freed with the manager
+    let _ = EXIT_HOOKS.try_with(|exit_hooks| {
+        let mut hooks = exit_hooks.hooks.borrow_mut();
+        // Clean up the hooks of managers which have been dropped so that they don't build up
+        hooks.retain(|old_hook| {
+            if old_hook.exit_state.load(Ordering::Acquire) == EXIT_DETACHED {
+                unsafe {
+                    (old_hook.run)(old_hook.data);
+                }
+                false
+            } else {
+                true
+            }
+        });
+        hooks.push(hook);
+    });
+}
+
+/// Run when a thread exits or its manager's hook is cleaned up. If the manager is still alive, the thread's
+/// hazard pointers are cleared and given back, and its free list is handed over for other threads to free.
+unsafe fn hand_over_orphans<T: Send>(data: *mut ()) {
+    let handover = Box::from_raw(data as *mut OrphanHandover<T>);
+    if handover.exit_state.compare_exchange(EXIT_REGISTERED, EXIT_MIGRATING, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        return
+    }
+    let info = &mut *handover.info;
+    for hp_ptr in info.local_hazards.drain(..) {
+        (*hp_ptr).release();
+    }
+    let mut records = mem::replace(&mut info.retired_list, Box::new(VecDeque::new()));
+    info.retired_number = 0;
+    // Spare records are safe to free, so they can go with the rest
+    records.extend(info.recycled.drain(..));
+    if !records.is_empty() {
+        handover.shared.push_batch(*records);
+    }
+    // Another thread which is given the same id will register a new hook when it first uses the manager
+    handover.exit_state.store(EXIT_UNREGISTERED, Ordering::Release);
+}
+
 mod tests {
     #![allow(unused_imports)]
     use super::HPBRManager;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
 
     #[derive(Debug)]
     struct Foo {
@@ -451,6 +939,24 @@ mod tests {
         }
     }
 
+    // Counts how many records have been dropped into a counter of the test's own, since the tests run
+    // at the same time. Not zero sized, so that every record has its own address.
+    struct Counted {
+        dropped: Arc<AtomicUsize>
+    }
+
+    impl Counted {
+        fn new(dropped: &Arc<AtomicUsize>) -> Self {
+            Counted { dropped: dropped.clone() }
+        }
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
     #[test]
      
     fn test_add_hp() {
@@ -467,4 +973,287 @@ mod tests {
         println!("{:?}", manager);
                 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_background_reclaim() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let manager: Arc<HPBRManager<Counted>> = Arc::new(HPBRManager::with_background_reclaim(9, 1, Duration::from_millis(1)));
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+        for _ in 0..4 {
+            let manager_clone = manager.clone();
+            let dropped_clone = dropped.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..100 {
+                    let ptr = Box::into_raw(Box::new(Counted::new(&dropped_clone)));
+                    manager_clone.protect(ptr, 0);
+                    manager_clone.retire(ptr, 0);
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
+        }
+
+        // Every free list was handed over in full batches, so all the records should be freed without scanning
+        for _ in 0..1000 {
+            if dropped.load(Ordering::Relaxed) == 400 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(dropped.load(Ordering::Relaxed), 400);
+        assert_eq!(manager.scan_count.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_orphans_freed_after_exit() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let manager: Arc<HPBRManager<Counted>> = Arc::new(HPBRManager::new(100, 1));
+        let ptr = Box::into_raw(Box::new(Counted::new(&dropped)));
+        manager.protect(ptr, 0);
+
+        let manager_clone = manager.clone();
+        let dropped_clone = dropped.clone();
+        let ptr_addr = ptr as usize;
+        thread::spawn(move || {
+            // Retire a record protected by the main thread, and another protected by this thread
+            manager_clone.retire(ptr_addr as *mut Counted, 0);
+            let own_ptr = Box::into_raw(Box::new(Counted::new(&dropped_clone)));
+            manager_clone.protect(own_ptr, 0);
+            manager_clone.retire(own_ptr, 0);
+            manager_clone.protect(own_ptr, 0);
+        }).join().unwrap();
+
+        // The exited thread's hazard pointer was cleared, so only its own record can be freed
+        manager.scan();
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+
+        manager.unprotect(0);
+        manager.scan();
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_reclaim_pressure() {
+        let manager: HPBRManager<Foo> = HPBRManager::new(10, 1);
+        for i in 0..100 {
+            let ptr = Box::into_raw(Box::new(Foo {data: i}));
+            manager.protect(ptr, 0);
+            manager.retire(ptr, 0);
+        }
+        assert!(!manager.has_reclaim_pressure());
+
+        // Keep every record protected so that scanning cannot shrink the free list
+        let mut handles = Vec::new();
+        for i in 0..(10 * super::PRESSURE_FACTOR) {
+            let ptr = Box::into_raw(Box::new(Foo {data: i as u8}));
+            handles.push(manager.protect_dynamic(ptr));
+            manager.retire(ptr, 0);
+        }
+        assert!(!manager.has_reclaim_pressure());
+        let ptr = Box::into_raw(Box::new(Foo {data: 0}));
+        handles.push(manager.protect_dynamic(ptr));
+        manager.retire(ptr, 0);
+        assert!(manager.has_reclaim_pressure());
+
+        // The signal is sticky, even once the records can be freed
+        handles.clear();
+        manager.scan();
+        assert!(manager.has_reclaim_pressure());
+    }
+
+    #[test]
+    fn test_retire_capacity() {
+        let manager: HPBRManager<Foo> = HPBRManager::with_retire_capacity(200, 1, 200);
+        let mut handles = Vec::new();
+        let first = Box::into_raw(Box::new(Foo {data: 0}));
+        handles.push(manager.protect_dynamic(first));
+        manager.retire(first, 0);
+        let capacity = unsafe { manager.get_mut_thread_info().retired_list.capacity() };
+        assert!(capacity >= 200);
+
+        for i in 1..200 {
+            let ptr = Box::into_raw(Box::new(Foo {data: i as u8}));
+            handles.push(manager.protect_dynamic(ptr));
+            manager.retire(ptr, 0);
+        }
+        unsafe {
+            assert_eq!(manager.get_mut_thread_info().retired_list.len(), 200);
+            assert_eq!(manager.get_mut_thread_info().retired_list.capacity(), capacity);
+        }
+
+        // Scanning frees what it can but keeps the space for the next burst
+        handles.clear();
+        manager.scan();
+        unsafe {
+            assert_eq!(manager.get_mut_thread_info().retired_list.len(), 0);
+            assert_eq!(manager.get_mut_thread_info().retired_list.capacity(), capacity);
+        }
+    }
+
+    #[test]
+    fn test_scan_threshold() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let retire = |manager: &HPBRManager<Counted>, count: usize| {
+            for _ in 0..count {
+                let ptr = Box::into_raw(Box::new(Counted::new(&dropped)));
+                manager.protect(ptr, 0);
+                manager.retire(ptr, 0);
+            }
+        };
+
+        let manager: HPBRManager<Counted> = HPBRManager::new(100, 1);
+        retire(&manager, 10);
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+
+        // A lower threshold frees the records sooner
+        manager.set_scan_threshold(5);
+        retire(&manager, 1);
+        assert_eq!(dropped.load(Ordering::Relaxed), 11);
+        retire(&manager, 6);
+        assert_eq!(dropped.load(Ordering::Relaxed), 17);
+
+        // A higher one frees them in bigger batches
+        manager.set_scan_threshold(50);
+        assert_eq!(manager.scan_threshold(), 50);
+        retire(&manager, 50);
+        assert_eq!(dropped.load(Ordering::Relaxed), 17);
+        retire(&manager, 1);
+        assert_eq!(dropped.load(Ordering::Relaxed), 68);
+    }
+
+    #[test]
+    #[cfg(not(feature = "loom"))]
+    fn test_guard_cleared_on_early_return() {
+        fn read_if_small(manager: &HPBRManager<Foo>, source: &AtomicPtr<Foo>) -> Option<u8> {
+            let guard = manager.guard(1);
+            let record = guard.protect(source);
+            let data = unsafe { (*record).data };
+            if data > 10 {
+                return None
+            }
+            Some(data)
+        }
+
+        let manager: HPBRManager<Foo> = HPBRManager::new(100, 2);
+        let record = Box::into_raw(Box::new(Foo {data: 32}));
+        let source = AtomicPtr::new(record);
+        assert_eq!(read_if_small(&manager, &source), None);
+        unsafe {
+            assert!(manager.get_mut_thread_info().get_mut_hazard_pointer(1).protected.load(Ordering::Relaxed).is_null());
+        }
+
+        // Nothing is left protecting the record, so it is freed by the next scan
+        manager.retire(record, 0);
+        manager.scan();
+        unsafe {
+            assert_eq!(manager.get_mut_thread_info().retired_list.len(), 0);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_guard_out_of_range() {
+        let manager: HPBRManager<Foo> = HPBRManager::new(100, 2);
+        manager.guard(2);
+    }
+
+    #[test]
+    fn test_force_scan() {
+        let manager: HPBRManager<Foo> = HPBRManager::new(100, 1);
+        let mut handles = Vec::new();
+        let mut protected = Vec::new();
+        for i in 0..20 {
+            let ptr = Box::into_raw(Box::new(Foo {data: i}));
+            if i % 4 == 0 {
+                handles.push(manager.protect_dynamic(ptr));
+                protected.push(ptr);
+            }
+            manager.retire(ptr, 0);
+        }
+        unsafe { assert_eq!(manager.get_mut_thread_info().retired_list.len(), 20) };
+
+        // Well under the threshold, but only the protected records are kept
+        manager.force_scan();
+        unsafe {
+            let retired: Vec<*mut Foo> = manager.get_mut_thread_info().retired_list.iter().cloned().collect();
+            assert_eq!(retired, protected);
+        }
+
+        handles.clear();
+        manager.force_scan();
+        unsafe { assert!(manager.get_mut_thread_info().retired_list.is_empty()) };
+    }
+
+    fn count_hazard_pointers(manager: &HPBRManager<Foo>) -> usize {
+        let mut count = 0;
+        let mut current = manager.shared.head.load(Ordering::Acquire);
+        while !current.is_null() {
+            count += 1;
+            current = unsafe { (*current).next.load(Ordering::Acquire) };
+        }
+        count
+    }
+
+    #[test]
+    fn test_exited_thread_hazards_reused() {
+        let manager: Arc<HPBRManager<Foo>> = Arc::new(HPBRManager::new(100, 2));
+        let ptr = Box::into_raw(Box::new(Foo {data: 0}));
+        manager.protect(ptr, 0);
+        assert_eq!(count_hazard_pointers(&manager), 2);
+
+        // The thread dies while still protecting the record
+        let ptr_addr = ptr as usize;
+        let manager_clone = manager.clone();
+        thread::spawn(move || {
+            manager_clone.protect(ptr_addr as *mut Foo, 0);
+            manager_clone.protect(ptr_addr as *mut Foo, 1);
+        }).join().unwrap();
+        assert_eq!(count_hazard_pointers(&manager), 4);
+
+        // Its protection is gone, so the record can be freed
+        manager.retire(ptr, 0);
+        manager.force_scan();
+        unsafe { assert!(manager.get_mut_thread_info().retired_list.is_empty()) };
+
+        // Later threads take over its hazard pointers rather than allocating more
+        for _ in 0..4 {
+            let manager_clone = manager.clone();
+            thread::spawn(move || {
+                let ptr = Box::into_raw(Box::new(Foo {data: 1}));
+                manager_clone.protect(ptr, 1);
+                manager_clone.retire(ptr, 1);
+            }).join().unwrap();
+        }
+        assert_eq!(count_hazard_pointers(&manager), 4);
+    }
+
+    #[test]
+    fn test_recycling() {
+        // Stands in for a stack's push and pop, counting the boxes it has to allocate
+        let push_pop = |manager: &HPBRManager<Foo>| -> usize {
+            let mut allocations = 0;
+            for i in 0..10000 {
+                let record = match manager.allocate() {
+                    Some(mut spare) => {
+                        *spare = Foo {data: (i % 256) as u8};
+                        spare
+                    },
+                    None => {
+                        allocations += 1;
+                        Box::new(Foo {data: (i % 256) as u8})
+                    }
+                };
+                let ptr = Box::into_raw(record);
+                manager.protect(ptr, 0);
+                manager.retire(ptr, 0);
+            }
+            allocations
+        };
+
+        assert_eq!(push_pop(&HPBRManager::new(100, 1)), 10000);
+        let allocations = push_pop(&HPBRManager::with_recycling(100, 1));
+        // Only the first free list, and one record for each later scan, have to be allocated
+        assert!(allocations < 250, "{} allocations", allocations);
+    }
+}
@@ -5,6 +5,12 @@
 //! that no pieces of data are freed while other thread can still access them, and
 //! prevent the [ABA problem](https://en.wikipedia.org/wiki/ABA_problem).
 
+#[cfg(feature = "std")]
 pub use self::hazardpointers::HPBRManager;
+#[cfg(feature = "std")]
 pub use self::hazardpointers::HPHandle;
-mod hazardpointers;
\ No newline at end of file
+pub use self::reclamation::ReclamationStrategy;
+pub use self::reclamation::HazardGuard;
+#[cfg(feature = "std")]
+mod hazardpointers;
+mod reclamation;
\ No newline at end of file
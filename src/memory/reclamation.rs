@@ -0,0 +1,95 @@
+use sync::atomic::{AtomicPtr, Ordering};
+use std::marker::PhantomData;
+use std::ptr;
+use std::boxed::Box;
+#[cfg(feature = "std")]
+use super::HPBRManager;
+
+/// The operations a data structure needs from its memory manager to make sure that no record is freed
+/// while another thread may still be reading it.
+///
+/// The structures in this crate use an HPBRManager by default, but are generic over this trait so that
+/// another scheme can be swapped in. A strategy which never frees anything, for example, is useful for
+/// measuring how much of a benchmark is spent on reclamation.
+///
+/// Records are protected in numbered slots, in the style of hazard pointers. A thread only ever uses
+/// the slots the structure was written for, so a strategy can keep a fixed number of them per thread.
+pub trait ReclamationStrategy<T: Send> {
+    /// Protect the given record in the given slot, so that it is not freed until the slot is cleared or
+    /// reused. The caller should check that the record is still reachable after protecting it.
+    fn protect(&self, record: *mut T, hazard_num: usize);
+
+    /// Clear the given slot, so that the record it protected may be freed.
+    fn unprotect(&self, hazard_num: usize);
+
+    /// Hand over a record which has been removed from the structure, to be freed once no thread is
+    /// protecting it, and clear the given slot.
+    fn retire(&self, record: *mut T, hazard_num: usize);
+
+    /// Take charge of the given slot until the returned HazardGuard goes out of scope, at which point
+    /// the slot is cleared.
+    fn guard(&self, hazard_num: usize) -> HazardGuard<'_, T, Self>
+    where Self: Sized
+    {
+        HazardGuard::new(self, hazard_num)
+    }
+
+    /// Take a spare record to reuse instead of allocating a new one, if the strategy keeps any. The box
+    /// still holds the retired record, which should be overwritten. Strategies which do not recycle
+    /// records always return None.
+    fn allocate(&self) -> Option<Box<T>> {
+        None
+    }
+
+    /// Check whether records have ever been retired faster than they could be freed. Strategies which
+    /// do not track this always return false.
+    fn has_reclaim_pressure(&self) -> bool {
+        false
+    }
+}
+
+/// One of a thread's hazard pointers, obtained with `ReclamationStrategy::guard`. When it goes out of
+/// scope, the hazard pointer is cleared.
+pub struct HazardGuard<'a, T: 'a + Send,
+                       #[cfg(feature = "std")] M: 'a + ReclamationStrategy<T> = HPBRManager<T>,
+                       #[cfg(not(feature = "std"))] M: 'a + ReclamationStrategy<T>> {
+    index: usize,
+    manager: &'a M,
+    // A hazard pointer belongs to the thread which took it
+    _marker: PhantomData<*mut T>
+}
+
+impl<'a, T: Send, M: ReclamationStrategy<T>> HazardGuard<'a, T, M> {
+    /// Take charge of the given hazard pointer of the manager. Prefer `ReclamationStrategy::guard`,
+    /// which can check that the hazard pointer exists.
+    pub fn new(manager: &'a M, hazard_num: usize) -> Self {
+        HazardGuard {
+            index: hazard_num,
+            manager,
+            _marker: PhantomData
+        }
+    }
+
+    /// Load the record from the given pointer and protect it, trying again until the pointer still holds
+    /// the same record after protection, so the protected record cannot already have been freed.
+    pub fn protect(&self, source: &AtomicPtr<T>) -> *mut T {
+        loop {
+            let record = source.load(Ordering::Acquire);
+            self.manager.protect(record, self.index);
+            if ptr::eq(record, source.load(Ordering::Acquire)) {
+                return record
+            }
+        }
+    }
+
+    /// Retire the given record, which this guard protected. See `ReclamationStrategy::retire`.
+    pub fn retire(self, record: *mut T) {
+        self.manager.retire(record, self.index);
+    }
+}
+
+impl<'a, T: Send, M: ReclamationStrategy<T>> Drop for HazardGuard<'a, T, M> {
+    fn drop(&mut self) {
+        self.manager.unprotect(self.index);
+    }
+}
@@ -0,0 +1,168 @@
+use super::stack::Stack;
+use super::utils::cache_padded::CachePadded;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+const DEFAULT_SUBLISTS: usize = 16;
+
+// Hands out sublist indices to threads in turn, so threads are spread evenly over the sublists
+static NEXT_THREAD_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static THREAD_INDEX: usize = NEXT_THREAD_INDEX.fetch_add(1, Relaxed);
+}
+
+/// A lock-free unordered collection, which gives back the items added to it in no particular order.
+///
+/// The items are kept in a number of sublists, each a lock-free Stack on its own cache line. Every thread
+/// is given a sublist the first time it uses any Bag, and adds its items there. Removing an item takes one
+/// from the caller's own sublist if it can, and only steals from the other sublists once its own is empty.
+/// Threads which add and remove their own items therefore rarely contend with each other, which makes a
+/// Bag faster than a Queue when the order of the items does not matter.
+/// # Examples
+/// ```
+/// let bag: Bag<u8> = Bag::new();
+/// bag.add(8);
+/// assert_eq!(bag.try_remove(), Some(8));
+/// assert_eq!(bag.try_remove(), None);
+/// ```
+pub struct Bag<T: Send> {
+    sublists: Vec<CachePadded<Stack<T>>>
+}
+
+impl<T: Send> Bag<T> {
+    /// Create a new, empty Bag.
+    /// # Examples
+    /// ```
+    /// let bag: Bag<u8> = Bag::new();
+    /// ```
+    pub fn new() -> Self {
+        Bag::with_sublists(DEFAULT_SUBLISTS)
+    }
+
+    /// Create a new, empty Bag which spreads its items over the given number of sublists.
+    /// The number of sublists must not be zero.
+    /// # Examples
+    /// ```
+    /// let bag: Bag<u8> = Bag::with_sublists(64);
+    /// ```
+    pub fn with_sublists(sublists: usize) -> Self {
+        if sublists == 0 {
+            panic!("sublists must be non-zero!")
+        }
+        Bag {
+            sublists: (0..sublists).map(|_| CachePadded::new(Stack::new(false))).collect()
+        }
+    }
+
+    /// Add an item to the bag.
+    /// # Examples
+    /// ```
+    /// let bag: Bag<u8> = Bag::new();
+    /// bag.add(8);
+    /// ```
+    pub fn add(&self, val: T) {
+        self.sublists[self.own_index()].push(val);
+    }
+
+    /// Remove an item from the bag, or return None if every sublist was empty when it was looked at.
+    /// Items added while the other sublists are being searched may be missed.
+    /// # Examples
+    /// ```
+    /// let bag: Bag<u8> = Bag::new();
+    /// bag.add(8);
+    /// assert_eq!(bag.try_remove(), Some(8));
+    /// ```
+    pub fn try_remove(&self) -> Option<T> {
+        let own = self.own_index();
+        // Start with our own sublist, then try the others in turn
+        for offset in 0..self.sublists.len() {
+            let index = (own + offset) % self.sublists.len();
+            if let Some(val) = self.sublists[index].pop() {
+                return Some(val)
+            }
+        }
+        None
+    }
+
+    /// The number of items in the bag. While other threads are adding or removing items it is only
+    /// approximate, but it is exact once they stop.
+    pub fn len(&self) -> usize {
+        self.sublists.iter().map(|sublist| sublist.len()).sum()
+    }
+
+    /// Check whether the bag has no items, with the same caveats as `len`.
+    pub fn is_empty(&self) -> bool {
+        self.sublists.iter().all(|sublist| sublist.is_empty())
+    }
+
+    fn own_index(&self) -> usize {
+        THREAD_INDEX.with(|index| *index) % self.sublists.len()
+    }
+}
+
+impl<T: Send> Default for Bag<T> {
+    fn default() -> Self {
+        Bag::new()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::Bag;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_add_and_remove() {
+        let bag: Bag<u32> = Bag::with_sublists(4);
+        assert!(bag.is_empty());
+        for i in 0..10 {
+            bag.add(i);
+        }
+        assert_eq!(bag.len(), 10);
+        let mut removed: Vec<u32> = Vec::new();
+        while let Some(val) = bag.try_remove() {
+            removed.push(val);
+        }
+        removed.sort();
+        assert_eq!(removed, (0..10).collect::<Vec<u32>>());
+        assert!(bag.is_empty());
+    }
+
+    #[test]
+    fn test_multiset_preserved() {
+        let bag: Arc<Bag<u32>> = Arc::new(Bag::with_sublists(4));
+        let mut wait_vec: Vec<thread::JoinHandle<Vec<u32>>> = Vec::new();
+        for i in 0..8 {
+            let bag_clone = bag.clone();
+            wait_vec.push(thread::spawn(move || {
+                let mut removed = Vec::new();
+                for j in 0..2000 {
+                    // Every value is added twice, so the bag has to keep duplicates
+                    bag_clone.add(i * 1000 + j % 1000);
+                    // Only the even threads remove, so they have to steal from the odd ones
+                    if i % 2 == 0 {
+                        if let Some(val) = bag_clone.try_remove() {
+                            removed.push(val);
+                        }
+                    }
+                }
+                removed
+            }));
+        }
+
+        let mut removed: Vec<u32> = Vec::new();
+        for handle in wait_vec {
+            removed.extend(handle.join().unwrap());
+        }
+        while let Some(val) = bag.try_remove() {
+            removed.push(val);
+        }
+
+        removed.sort();
+        let mut added: Vec<u32> = (0..8000).flat_map(|val| vec![val, val]).collect();
+        added.sort();
+        assert_eq!(removed, added);
+    }
+}
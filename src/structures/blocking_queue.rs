@@ -0,0 +1,158 @@
+use super::queue::Queue;
+use std::sync::{Mutex, Condvar};
+use std::sync::atomic::{AtomicUsize, fence};
+use std::sync::atomic::Ordering::SeqCst;
+
+/// A Queue which consumers can wait on until an element is available.
+///
+/// The elements are kept in a lock-free Queue, so pushing, and popping from a queue which is not empty,
+/// never take a lock. The lock and condition variable are only used by consumers which find the queue
+/// empty and go to sleep, and by producers which find that some consumers are asleep and wake one up.
+pub struct BlockingQueue<T: Send> {
+    queue: Queue<T>,
+    lock: Mutex<()>,
+    not_empty: Condvar,
+    waiters: AtomicUsize
+}
+
+impl<T: Send> BlockingQueue<T> {
+    /// Create a new, empty BlockingQueue.
+    /// # Examples
+    /// ```
+    /// let queue: BlockingQueue<String> = BlockingQueue::new();
+    /// ```
+    pub fn new() -> Self {
+        BlockingQueue {
+            queue: Queue::new(),
+            lock: Mutex::new(()),
+            not_empty: Condvar::new(),
+            waiters: AtomicUsize::new(0)
+        }
+    }
+
+    /// Add a new element to the back of the queue, waking up one consumer if any are waiting.
+    /// # Examples
+    /// ```
+    /// let queue: BlockingQueue<String> = BlockingQueue::new();
+    /// queue.push("hello".to_owned());
+    /// ```
+    pub fn push(&self, val: T) {
+        self.queue.enqueue(val);
+        // Pairs with the fence in pop, so either the waiter sees the element or we see the waiter
+        fence(SeqCst);
+        if self.waiters.load(SeqCst) > 0 {
+            // Taking the lock means the waiter is either still before its last check or already asleep
+            let _guard = self.lock.lock().unwrap();
+            self.not_empty.notify_one();
+        }
+    }
+
+    /// Take an element from the front of the queue, waiting until one is pushed if the queue is empty.
+    /// # Examples
+    /// ```
+    /// let queue: BlockingQueue<String> = BlockingQueue::new();
+    /// queue.push("hello".to_owned());
+    /// assert_eq!(queue.pop(), "hello".to_owned());
+    /// ```
+    pub fn pop(&self) -> T {
+        if let Some(val) = self.queue.dequeue() {
+            return val
+        }
+
+        let mut guard = self.lock.lock().unwrap();
+        self.waiters.fetch_add(1, SeqCst);
+        fence(SeqCst);
+        loop {
+            if let Some(val) = self.queue.dequeue() {
+                self.waiters.fetch_sub(1, SeqCst);
+                return val
+            }
+            guard = self.not_empty.wait(guard).unwrap();
+        }
+    }
+
+    /// Take an element from the front of the queue without waiting, or return None if the queue is empty.
+    /// # Examples
+    /// ```
+    /// let queue: BlockingQueue<String> = BlockingQueue::new();
+    /// assert_eq!(queue.try_pop(), None);
+    /// ```
+    pub fn try_pop(&self) -> Option<T> {
+        self.queue.dequeue()
+    }
+
+    /// The number of elements in the queue. See `Queue::len`.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Check whether the queue has no elements. See `Queue::is_empty`.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+impl<T: Send> Default for BlockingQueue<T> {
+    fn default() -> Self {
+        BlockingQueue::new()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::BlockingQueue;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pop_waits_for_push() {
+        let queue: Arc<BlockingQueue<u32>> = Arc::new(BlockingQueue::new());
+        let queue_copy = queue.clone();
+        let consumer = thread::spawn(move || queue_copy.pop());
+
+        // Wait for the consumer to find the queue empty and go to sleep
+        while queue.waiters.load(Ordering::SeqCst) == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(100));
+        queue.push(8);
+        assert_eq!(consumer.join().unwrap(), 8);
+        assert_eq!(queue.waiters.load(Ordering::SeqCst), 0);
+        assert_eq!(queue.try_pop(), None);
+    }
+
+    #[test]
+    fn test_many_consumers() {
+        let queue: Arc<BlockingQueue<u32>> = Arc::new(BlockingQueue::new());
+        let mut consumers: Vec<thread::JoinHandle<Vec<u32>>> = Vec::new();
+        for _ in 0..8 {
+            let queue_copy = queue.clone();
+            consumers.push(thread::spawn(move || {
+                (0..1000).map(|_| queue_copy.pop()).collect()
+            }));
+        }
+
+        let mut producers: Vec<thread::JoinHandle<()>> = Vec::new();
+        for id in 0..4 {
+            let queue_copy = queue.clone();
+            producers.push(thread::spawn(move || {
+                for i in 0..2000 {
+                    queue_copy.push(id * 2000 + i);
+                }
+            }));
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        let mut popped: Vec<u32> = Vec::new();
+        for consumer in consumers {
+            popped.append(&mut consumer.join().unwrap());
+        }
+        popped.sort();
+        assert_eq!(popped, (0..8000).collect::<Vec<u32>>());
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
+use std::cell::UnsafeCell;
+use std::vec::Vec;
+use super::utils::cache_padded::CachePadded;
+
+/// A lock-free bounded multi-producer multi-consumer queue.
+///
+/// This queue is an implementation of Dmitry Vyukov's [Bounded MPMC queue]
+/// (http://www.1024cores.net/home/lock-free-algorithms/queues/bounded-mpmc-queue). The elements are kept
+/// in an array of slots which is allocated up front, so no memory is allocated or freed per element and
+/// the HPBRManager is not needed.
+///
+/// Each slot has a sequence number which says what the slot is ready for. A slot at position `pos` is
+/// ready to be written to when its sequence number is `pos`, and ready to be read from when it is `pos + 1`.
+/// Reading from the slot sets it to `pos + capacity`, which is the position it will be at when the
+/// producers next wrap around to it.
+pub struct BoundedQueue<T: Send> {
+    slots: Vec<Slot<T>>,
+    mask: usize,
+    // Producers only move the enqueue position and consumers the dequeue position
+    enqueue_pos: CachePadded<AtomicUsize>,
+    dequeue_pos: CachePadded<AtomicUsize>
+}
+
+unsafe impl<T: Send> Sync for BoundedQueue<T> {}
+
+struct Slot<T: Send> {
+    sequence: AtomicUsize,
+    value: UnsafeCell<Option<T>>
+}
+
+impl<T: Send> BoundedQueue<T> {
+    /// Create a new BoundedQueue which can hold at least `capacity` elements. The capacity is rounded
+    /// up to the next power of 2, and must not be zero.
+    /// # Examples
+    /// ```
+    /// let queue: BoundedQueue<u8> = BoundedQueue::new(6);
+    /// assert_eq!(queue.capacity(), 8);
+    /// ```
+    pub fn new(capacity: usize) -> Self {
+        if capacity == 0 {
+            panic!("capacity must be non-zero!")
+        }
+        let capacity = capacity.next_power_of_two();
+        let slots = (0..capacity).map(|pos| Slot {
+            sequence: AtomicUsize::new(pos),
+            value: UnsafeCell::new(None)
+        }).collect();
+        BoundedQueue {
+            slots,
+            mask: capacity - 1,
+            enqueue_pos: CachePadded::new(AtomicUsize::new(0)),
+            dequeue_pos: CachePadded::new(AtomicUsize::new(0))
+        }
+    }
+
+    /// The number of elements the queue can hold.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Add a new element to the back of the queue.
+    /// # Errors
+    /// If the queue is full, the element is returned in an Err.
+    /// # Examples
+    /// ```
+    /// let queue: BoundedQueue<u8> = BoundedQueue::new(1);
+    /// assert_eq!(queue.try_enqueue(8), Ok(()));
+    /// assert_eq!(queue.try_enqueue(9), Err(9));
+    /// ```
+    pub fn try_enqueue(&self, val: T) -> Result<(), T> {
+        let mut pos = self.enqueue_pos.load(Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let sequence = slot.sequence.load(Acquire);
+            let diff = sequence as isize - pos as isize;
+            if diff == 0 {
+                // The slot is free, so try to claim it
+                match self.enqueue_pos.compare_exchange_weak(pos, pos + 1, Relaxed, Relaxed) {
+                    Ok(_) => {
+                        unsafe { *slot.value.get() = Some(val) };
+                        slot.sequence.store(pos + 1, Release);
+                        return Ok(())
+                    },
+                    Err(current) => pos = current
+                }
+            } else if diff < 0 {
+                // The slot still holds the element from the last time around
+                return Err(val)
+            } else {
+                // Another producer claimed the slot first
+                pos = self.enqueue_pos.load(Relaxed);
+            }
+        }
+    }
+
+    /// Take an element from the front of the queue, or return None if the queue is empty.
+    /// # Examples
+    /// ```
+    /// let queue: BoundedQueue<u8> = BoundedQueue::new(8);
+    /// queue.try_enqueue(8);
+    /// assert_eq!(queue.try_dequeue(), Some(8));
+    /// assert_eq!(queue.try_dequeue(), None);
+    /// ```
+    pub fn try_dequeue(&self) -> Option<T> {
+        let mut pos = self.dequeue_pos.load(Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let sequence = slot.sequence.load(Acquire);
+            let diff = sequence as isize - (pos + 1) as isize;
+            if diff == 0 {
+                // The slot has been written to, so try to claim it
+                match self.dequeue_pos.compare_exchange_weak(pos, pos + 1, Relaxed, Relaxed) {
+                    Ok(_) => {
+                        let val = unsafe { (*slot.value.get()).take() };
+                        slot.sequence.store(pos + self.mask + 1, Release);
+                        return val
+                    },
+                    Err(current) => pos = current
+                }
+            } else if diff < 0 {
+                // Nothing has been written to the slot yet
+                return None
+            } else {
+                // Another consumer claimed the slot first
+                pos = self.dequeue_pos.load(Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+mod tests {
+    #![allow(unused_imports)]
+    use super::BoundedQueue;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_fill_and_drain() {
+        let queue: BoundedQueue<u32> = BoundedQueue::new(100);
+        assert_eq!(queue.capacity(), 128);
+        assert_eq!(queue.try_dequeue(), None);
+
+        // Go round the ring a few times
+        for round in 0..3 {
+            for i in 0..128 {
+                assert_eq!(queue.try_enqueue(round * 128 + i), Ok(()));
+            }
+            assert_eq!(queue.try_enqueue(1000), Err(1000));
+            for i in 0..128 {
+                assert_eq!(queue.try_dequeue(), Some(round * 128 + i));
+            }
+            assert_eq!(queue.try_dequeue(), None);
+        }
+    }
+
+    #[test]
+    fn test_multithreaded() {
+        let queue: Arc<BoundedQueue<usize>> = Arc::new(BoundedQueue::new(64));
+        let mut producers: Vec<thread::JoinHandle<()>> = Vec::new();
+        let mut consumers: Vec<thread::JoinHandle<Vec<usize>>> = Vec::new();
+
+        for id in 0..4 {
+            let queue_copy = queue.clone();
+            producers.push(thread::spawn(move || {
+                for i in 0..10000 {
+                    let mut val = id * 10000 + i;
+                    while let Err(returned) = queue_copy.try_enqueue(val) {
+                        val = returned;
+                        thread::yield_now();
+                    }
+                }
+            }));
+            let queue_copy = queue.clone();
+            consumers.push(thread::spawn(move || {
+                let mut dequeued = Vec::new();
+                while dequeued.len() < 10000 {
+                    match queue_copy.try_dequeue() {
+                        Some(val) => dequeued.push(val),
+                        None => thread::yield_now()
+                    }
+                }
+                dequeued
+            }));
+        }
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let mut dequeued: Vec<usize> = Vec::new();
+        for consumer in consumers {
+            dequeued.append(&mut consumer.join().unwrap());
+        }
+        dequeued.sort();
+        assert_eq!(dequeued, (0..40000).collect::<Vec<usize>>());
+        assert_eq!(queue.try_dequeue(), None);
+    }
+}
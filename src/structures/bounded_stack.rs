@@ -0,0 +1,150 @@
+use super::stack::Stack;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
+
+/// A lock-free Stack which holds at most a fixed number of items.
+///
+/// The items are kept in a lock-free Stack, alongside a count of the items it holds. A push reserves
+/// room in the count before it links its node onto the stack, and gives up if the stack is full, while a
+/// pop only gives its room back once it has unlinked its node. The count is never more than the capacity,
+/// so neither is the number of items on the stack.
+pub struct BoundedStack<T: Send> {
+    stack: Stack<T>,
+    capacity: usize,
+    size: AtomicUsize
+}
+
+impl<T: Send> BoundedStack<T> {
+    /// Create a new BoundedStack which can hold `capacity` items. The capacity must not be zero.
+    /// # Examples
+    /// ```
+    /// let stack: BoundedStack<u8> = BoundedStack::new(4);
+    /// assert_eq!(stack.capacity(), 4);
+    /// ```
+    pub fn new(capacity: usize) -> Self {
+        if capacity == 0 {
+            panic!("capacity must be non-zero!")
+        }
+        BoundedStack {
+            stack: Stack::new(false),
+            capacity,
+            size: AtomicUsize::new(0)
+        }
+    }
+
+    /// The number of items the stack can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Push a piece of data onto the stack.
+    /// # Errors
+    /// If the stack is full, the data is returned in an Err.
+    /// # Examples
+    /// ```
+    /// let stack: BoundedStack<u8> = BoundedStack::new(1);
+    /// assert_eq!(stack.try_push(8), Ok(()));
+    /// assert_eq!(stack.try_push(9), Err(9));
+    /// ```
+    pub fn try_push(&self, val: T) -> Result<(), T> {
+        let mut size = self.size.load(Relaxed);
+        loop {
+            if size >= self.capacity {
+                return Err(val)
+            }
+            // Pairs with the release in pop, so the room we take has really been given back
+            match self.size.compare_exchange_weak(size, size + 1, Acquire, Relaxed) {
+                Ok(_) => { break; },
+                Err(current) => size = current
+            }
+        }
+        self.stack.push(val);
+        Ok(())
+    }
+
+    /// Pop a piece of data from the top of the stack, or return None if the stack is empty.
+    /// # Examples
+    /// ```
+    /// let stack: BoundedStack<u8> = BoundedStack::new(1);
+    /// stack.try_push(8);
+    /// assert_eq!(stack.pop(), Some(8));
+    /// assert_eq!(stack.pop(), None);
+    /// ```
+    pub fn pop(&self) -> Option<T> {
+        let val = self.stack.pop();
+        if val.is_some() {
+            self.size.fetch_sub(1, Release);
+        }
+        val
+    }
+
+    /// The number of items on the stack, including any which are still being pushed. While other threads
+    /// are pushing or popping it is only approximate, but it is exact once they stop.
+    pub fn len(&self) -> usize {
+        self.size.load(Relaxed)
+    }
+
+    /// Check whether the stack has no items, with the same caveats as `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::BoundedStack;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_fill_to_capacity() {
+        let stack: BoundedStack<u32> = BoundedStack::new(4);
+        for i in 0..4 {
+            assert_eq!(stack.try_push(i), Ok(()));
+        }
+        assert_eq!(stack.len(), 4);
+        assert_eq!(stack.try_push(4), Err(4));
+
+        assert_eq!(stack.pop(), Some(3));
+        assert_eq!(stack.try_push(5), Ok(()));
+        assert_eq!(stack.try_push(6), Err(6));
+
+        for i in [5, 2, 1, 0].iter() {
+            assert_eq!(stack.pop(), Some(*i));
+        }
+        assert_eq!(stack.pop(), None);
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn test_multithreaded() {
+        let stack: Arc<BoundedStack<u32>> = Arc::new(BoundedStack::new(8));
+        let mut wait_vec: Vec<thread::JoinHandle<isize>> = Vec::new();
+        for i in 0..8 {
+            let stack_clone = stack.clone();
+            wait_vec.push(thread::spawn(move || {
+                let mut pushed: isize = 0;
+                for j in 0..1000 {
+                    if stack_clone.try_push(i * 1000 + j).is_ok() {
+                        pushed += 1;
+                    }
+                    assert!(stack_clone.len() <= 8);
+                    if j % 2 == 0 && stack_clone.pop().is_some() {
+                        pushed -= 1;
+                    }
+                }
+                pushed
+            }));
+        }
+        let mut left: isize = 0;
+        for handle in wait_vec {
+            left += handle.join().unwrap();
+        }
+        let mut popped = 0;
+        while stack.pop().is_some() {
+            popped += 1;
+        }
+        assert!(popped <= 8);
+        assert_eq!(left, popped);
+    }
+}
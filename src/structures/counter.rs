@@ -0,0 +1,134 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use super::utils::cache_padded::CachePadded;
+
+const DEFAULT_CELLS: usize = 16;
+
+// Hands out cell indices to threads in turn, so threads are spread evenly over the cells
+static NEXT_THREAD_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static THREAD_INDEX: usize = NEXT_THREAD_INDEX.fetch_add(1, Relaxed);
+}
+
+/// A counter which many threads can update at once without contending on a single atomic.
+///
+/// The count is split over a number of cells, each on its own cache line. Every thread is given a
+/// cell the first time it uses any Counter, and only updates that cell, so threads only contend
+/// when there are more of them than cells. Reading the count adds up every cell, so it is slower
+/// than updating it, and while other threads are updating the counter it is only approximate.
+pub struct Counter {
+    cells: Vec<CachePadded<AtomicUsize>>
+}
+
+impl Counter {
+    /// Create a new Counter, starting at zero.
+    /// # Examples
+    /// ```
+    /// let counter = Counter::new();
+    /// assert_eq!(counter.sum(), 0);
+    /// ```
+    pub fn new() -> Self {
+        Counter::with_cells(DEFAULT_CELLS)
+    }
+
+    /// Create a new Counter, starting at zero, which spreads the count over the given number of cells.
+    /// The number of cells must not be zero.
+    /// # Examples
+    /// ```
+    /// let counter = Counter::with_cells(64);
+    /// ```
+    pub fn with_cells(cells: usize) -> Self {
+        if cells == 0 {
+            panic!("cells must be non-zero!")
+        }
+        Counter {
+            cells: (0..cells).map(|_| CachePadded::new(AtomicUsize::new(0))).collect()
+        }
+    }
+
+    /// Add one to the counter.
+    /// # Examples
+    /// ```
+    /// let counter = Counter::new();
+    /// counter.increment();
+    /// assert_eq!(counter.sum(), 1);
+    /// ```
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    /// Take one away from the counter.
+    /// # Examples
+    /// ```
+    /// let counter = Counter::new();
+    /// counter.increment();
+    /// counter.decrement();
+    /// assert_eq!(counter.sum(), 0);
+    /// ```
+    pub fn decrement(&self) {
+        // A single cell can wrap around, but the cells still add up to the right count
+        self.cell().fetch_sub(1, Relaxed);
+    }
+
+    /// Add the given amount to the counter.
+    pub fn add(&self, amount: usize) {
+        self.cell().fetch_add(amount, Relaxed);
+    }
+
+    /// Add up every cell to get the count.
+    pub fn sum(&self) -> usize {
+        self.cells.iter().fold(0, |sum, cell| sum.wrapping_add(cell.load(Relaxed)))
+    }
+
+    fn cell(&self) -> &AtomicUsize {
+        let index = THREAD_INDEX.with(|index| *index);
+        &self.cells[index % self.cells.len()]
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Counter::new()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::Counter;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_many_threads() {
+        let counter = Arc::new(Counter::new());
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+        for _ in 0..32 {
+            let counter_clone = counter.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..100000 {
+                    counter_clone.increment();
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
+        }
+        assert_eq!(counter.sum(), 3200000);
+    }
+
+    #[test]
+    fn test_decrement_on_another_thread() {
+        let counter = Arc::new(Counter::with_cells(4));
+        counter.add(10);
+        let counter_clone = counter.clone();
+        thread::spawn(move || {
+            for _ in 0..15 {
+                counter_clone.decrement();
+            }
+            counter_clone.increment();
+        }).join().unwrap();
+        counter.add(5);
+        assert_eq!(counter.sum(), 1);
+    }
+}
@@ -0,0 +1,136 @@
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering::{Acquire, AcqRel, Release, Relaxed};
+use std::ptr;
+use std::time::{Duration, Instant};
+use super::utils::atomic_markable::{is_marked, is_marked_second, mark, mark_second, unmark, unmark_second};
+use super::utils::backoff::Backoff;
+
+/// A meeting point where pairs of threads swap values.
+///
+/// A thread which arrives at an empty exchanger leaves its value in the exchanger's slot and waits for
+/// a partner. The next thread to arrive takes that value and leaves its own in return, and each thread
+/// goes away with the other's value. A thread which waits longer than its timeout withdraws its offer
+/// and gets its own value back. This is the lock-free exchanger described by Herlihy and Shavit in
+/// The Art of Multiprocessor Programming, and is the handoff at the heart of elimination backoff.
+///
+/// The slot is a single pointer to the offered value, whose two lowest bits hold the slot's state.
+/// # Examples
+/// ```
+/// let exchanger: Arc<Exchanger<u8>> = Arc::new(Exchanger::new());
+/// let exchanger_clone = exchanger.clone();
+/// let handle = thread::spawn(move || exchanger_clone.exchange(1, Duration::from_secs(10)));
+/// assert_eq!(exchanger.exchange(2, Duration::from_secs(10)), Ok(1));
+/// assert_eq!(handle.join().unwrap(), Ok(2));
+/// ```
+#[derive(Debug)]
+pub struct Exchanger<T: Send> {
+    // Null while empty, marked while holding a waiting thread's offer, and marked second while
+    // holding the value its partner left in return
+    slot: AtomicPtr<Offer<T>>
+}
+
+// Offers are aligned so that their pointers always have the two lowest bits free for the state
+#[derive(Debug)]
+#[repr(align(4))]
+struct Offer<T> {
+    value: T
+}
+
+impl<T: Send> Exchanger<T> {
+    /// Create a new, empty Exchanger.
+    pub fn new() -> Self {
+        Exchanger {
+            slot: AtomicPtr::default()
+        }
+    }
+
+    /// Swap the given value with another thread's, waiting up to the given timeout for another thread
+    /// to arrive. Returns the other thread's value, or gives back the given value as an Err if no
+    /// other thread arrived in time.
+    /// # Examples
+    /// ```
+    /// let exchanger: Exchanger<u8> = Exchanger::new();
+    /// assert_eq!(exchanger.exchange(1, Duration::from_millis(1)), Err(1));
+    /// ```
+    pub fn exchange(&self, value: T, timeout: Duration) -> Result<T, T> {
+        let deadline = Instant::now() + timeout;
+        let mine = Box::into_raw(Box::new(Offer { value }));
+        let mut backoff = Backoff::new();
+        loop {
+            let current = self.slot.load(Acquire);
+            if current.is_null() {
+                if self.slot.compare_exchange(current, mark(mine), Release, Relaxed).is_ok() {
+                    return self.await_partner(mine, deadline)
+                }
+            } else if is_marked(current) {
+                // Take the waiting thread's offer, and leave ours in return
+                if self.slot.compare_exchange(current, mark_second(mine), AcqRel, Relaxed).is_ok() {
+                    let theirs = unsafe { Box::from_raw(unmark(current)) };
+                    return Ok(theirs.value)
+                }
+            }
+            // Otherwise another pair of threads is finishing its exchange
+            if Instant::now() >= deadline {
+                let mine = unsafe { Box::from_raw(mine) };
+                return Err(mine.value)
+            }
+            backoff.snooze();
+        }
+    }
+
+    // Wait for a partner to swap its value for our offer, which is in the slot. Only we can empty the
+    // slot once a partner has taken our offer, so nobody else can touch the slot until we return.
+    fn await_partner(&self, mine: *mut Offer<T>, deadline: Instant) -> Result<T, T> {
+        let mut backoff = Backoff::new();
+        loop {
+            let current = self.slot.load(Acquire);
+            if is_marked_second(current) {
+                self.slot.store(ptr::null_mut(), Release);
+                let theirs = unsafe { Box::from_raw(unmark_second(current)) };
+                return Ok(theirs.value)
+            }
+            if Instant::now() >= deadline {
+                // Withdraw the offer, unless a partner took it in the meantime
+                if self.slot.compare_exchange(mark(mine), ptr::null_mut(), Relaxed, Relaxed).is_ok() {
+                    let mine = unsafe { Box::from_raw(mine) };
+                    return Err(mine.value)
+                }
+                continue;
+            }
+            backoff.snooze();
+        }
+    }
+}
+
+impl<T: Send> Default for Exchanger<T> {
+    fn default() -> Self {
+        Exchanger::new()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::Exchanger;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_two_threads_exchange() {
+        let exchanger: Arc<Exchanger<String>> = Arc::new(Exchanger::new());
+        let exchanger_clone = exchanger.clone();
+        let handle = thread::spawn(move || {
+            exchanger_clone.exchange("from the spawned thread".to_owned(), Duration::from_secs(10))
+        });
+        let received = exchanger.exchange("from the main thread".to_owned(), Duration::from_secs(10));
+        assert_eq!(received, Ok("from the spawned thread".to_owned()));
+        assert_eq!(handle.join().unwrap(), Ok("from the main thread".to_owned()));
+    }
+
+    #[test]
+    fn test_timeout_returns_value() {
+        let exchanger: Exchanger<u8> = Exchanger::new();
+        assert_eq!(exchanger.exchange(5, Duration::from_millis(10)), Err(5));
+        assert_eq!(exchanger.exchange(6, Duration::from_millis(0)), Err(6));
+    }
+}
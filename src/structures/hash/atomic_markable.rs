@@ -1,6 +1,7 @@
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
 use std::marker::PhantomData;
+use std::ptr;
 
 pub fn is_marked<T>(ptr: *mut T) -> bool {
     let ptr_usize = ptr as usize;
@@ -38,6 +39,23 @@ pub fn mark_second<T>(ptr: *mut T) -> *mut T {
     (ptr_usize | 0x2) as *mut T
 }
 
+/// A frozen slot belongs to an ArrayNode which HashMap::shrink is removing from the tree. The node types
+/// are `#[repr(align(8))]`, so the third bit is free, and an empty slot freezes to 0x4.
+pub fn is_frozen<T>(ptr: *mut T) -> bool {
+    let ptr_usize = ptr as usize;
+    ptr_usize & 0x4 != 0
+}
+
+pub fn unfreeze<T>(ptr: *mut T) -> *mut T {
+    let ptr_usize = ptr as usize;
+    (ptr_usize & !(0x4)) as *mut T
+}
+
+pub fn freeze<T>(ptr: *mut T) -> *mut T {
+    let ptr_usize = ptr as usize;
+    (ptr_usize | 0x4) as *mut T
+}
+
 #[derive(Debug)]
 pub struct AtomicMarkablePtr<T: Send> {
     ptr: AtomicUsize,
@@ -73,6 +91,21 @@ impl <T: Send> AtomicMarkablePtr<T> {
         }
     }
 
+    /// Clear the frozen bit, keeping a mark which was set while the slot was frozen unless the slot was empty.
+    pub fn unfreeze(&self) {
+        let mut current = self.ptr.load(Acquire);
+        loop {
+            let mut thawed = unfreeze(current as *mut T);
+            if unmark(thawed).is_null() {
+                thawed = ptr::null_mut();
+            }
+            match self.ptr.compare_exchange_weak(current, thawed as usize, Release, Acquire) {
+                Ok(_) => return,
+                Err(actual) => current = actual
+            }
+        }
+    }
+
     pub fn store(&self, val: *mut T) {
         self.ptr.store(val as usize, Release);
     }
@@ -91,10 +124,14 @@ impl<T: Send> Drop for AtomicMarkablePtr<T>
 {
     fn drop(&mut self) {
         let mut ptr = self.ptr.load(Relaxed) as *mut T;
+        // A frozen slot of a collapsed ArrayNode holds a copy of the node which was moved up to its parent
+        if is_frozen(ptr) {
+            return
+        }
         ptr = unmark(unmark_second(ptr));
         if !ptr.is_null() {
             unsafe {
-                Box::from_raw(ptr);
+                drop(Box::from_raw(ptr));
             }
         }
     }
@@ -1,18 +1,31 @@
-use std::hash::{Hash, Hasher, BuildHasher};
+use std::hash::{Hash, BuildHasher};
 use std::fmt::Debug;
 use std::fmt;
 use std::ptr;
+use std::ops::{Shr, ShrAssign, BitAnd};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::borrow::Borrow;
-use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::collections::hash_map::RandomState;
-use memory::HPBRManager;
+use std::marker::PhantomData;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+#[cfg(feature = "serde")]
+use serde::ser::SerializeMap;
+#[cfg(feature = "serde")]
+use serde::de::{Visitor, MapAccess};
+use memory::{HPBRManager, HPHandle};
 use super::atomic_markable::AtomicMarkablePtr;
 use super::atomic_markable;
 use super::data_guard::DataGuard;
 
 const HEAD_SIZE: usize = 256;
 const CHILD_SIZE: usize = 16;
+// log2(CHILD_SIZE), the number of bits of the hash used below the head
+const CHILD_BITS: usize = 4;
 const KEY_SIZE: usize = 64;
+const WIDE_KEY_SIZE: usize = 128;
 const MAX_FAILURES: u64 = 10;
 
 /// A wait-free HashMap based on a tree structure.
@@ -22,37 +35,292 @@ const MAX_FAILURES: u64 = 10;
 /// is unchanged, and follows the tree structure laid out in the paper.
 ///
 /// The head of the hashmap is an array of HEAD_SIZE elements, each one can either point to a node 
-/// containing data, or a node containing an array of CHILD_SIZE elements, where CHILD_SIZE is smaller
-/// than HEAD_SIZE. By default, this implementation uses a HEAD_SIZE of 256 and a CHILD_SIZE of 16.
-/// Once a slot contains an array node, it can never be changed, which allows for a number of memory
+/// containing data, or a node containing an array of CHILD_SIZE elements. By default, this implementation
+/// uses a HEAD_SIZE of 256 and a CHILD_SIZE of 16. A different HEAD_SIZE can be chosen with `with_capacity`,
+/// as long as it is a power of two.
+/// Once a slot contains an array node, it can only be changed by `shrink`, which allows for a number of memory
 /// management guarantees.
 ///
-/// Keys are not currently stored in the hashmap, only values and the corresponding hash. 
-/// This can easily be changed if needed. Finding a value in the map follows this process:
+/// Keys are stored alongside their values and hashes. The hash is used to find the slot for a key, and
+/// the key itself is compared once the slot is found. Finding a value in the map follows this process:
 ///
-/// * The hash is computed from the key. This hash will always be a 64-bit integer, and needs to be unique. 
-/// If two keys hash to the same value, only one can be inserted. This should not be a problem in most cases.
+/// * The hash is computed from the key. This hash is a 64-bit integer, or 128-bit with `with_wide_hashes`. If two different
+///   keys hash to the same value, they are kept together in a collision node, which holds a data node for each of them.
 /// * The first `n` bits of the key are used to index into the head array through bitwise AND. 
-/// Here, `n` is defined as `log2(HEAD_SIZE)`.
+///   Here, `n` is defined as `log2(HEAD_SIZE)`.
 /// * If we find a data node, we have found the value, if we find an array node, then we 
-/// shift the hash 'r' bits to the right, where r is `log2(CHILD_SIZE)`. We can use 
-/// this to index into the new array, and continue.
+///   shift the used bits off the hash: `n` bits after the head, and 'r' bits after any other array,
+///   where r is `log2(CHILD_SIZE)`. We can use this to index into the new array, and continue.
 /// * If we reach a null spot at any point, then the element is not in the array.
 /// * Once we reach the bottom, the full key will have been used, ensuring correct hashing given unique hashing.
 ///
 /// The tree structure is bounded by HEAD_SIZE and CHILD_SIZE, such that 
 /// `max_depth = (hash_size - log2(HEAD_SIZE)) / log2(CHILD_SIZE)`. In this case, 
-/// that means the maximum depth is 14. This is used to justify the implementation of 
-/// recursive destructors: they should not be able to overflow the stack.
-pub struct HashMap<K, V> 
+/// that means the maximum depth is 14 for 64-bit hashes, and 30 for 128-bit ones. This is used to justify the
+/// implementation of recursive destructors: they should not be able to overflow the stack.
+///
+/// By default the hash is consumed starting from its least significant bits. If the keys' hashes
+/// vary mostly in their most significant bits, a map created with `BitOrder::HighFirst` will
+/// spread them out closer to the head, giving a shallower tree.
+///
+/// Keys are hashed with `RandomState` unless another `BuildHasher` is given to `with_hasher`, for example
+/// a faster hasher, or one with a fixed seed so that the layout of the tree is the same on every run.
+pub struct HashMap<K, V, S = RandomState, H = u64>
 where K: Send,
-      V: Send
+      V: Send,
+      H: HashBits
+{
+    head: Vec<AtomicMarkablePtr<Node<K, V, H>>>,
+    hasher: S,
+    // Hashes the upper 64 bits of a 128-bit hash, if the map uses them
+    wide_hasher: Option<S>,
+    head_size: usize,
+    bit_order: BitOrder,
+    max_steps: Option<usize>,
+    max_failures: u64,
+    len: AtomicUsize,
+    // Set while shrink is collapsing an ArrayNode
+    shrinking: AtomicBool,
+    // Held by shrink, so that only one thread collapses ArrayNodes at a time
+    shrink_lock: Mutex<()>,
+    manager: HPBRManager<Node<K, V, H>>,
+    // How many more inserts into an occupied slot should fail, so that tests can act out contention
+    #[cfg(test)]
+    forced_failures: AtomicUsize
+}
+
+/// The end of the hash which a HashMap consumes first when travelling down the tree.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum BitOrder {
+    /// Index the head with the least significant bits of the hash, then move towards the most significant.
+    LowFirst,
+    /// Index the head with the most significant bits of the hash, then move towards the least significant.
+    HighFirst
+}
+
+/// The error returned by `insert_bounded`. Both variants give back the key and value which could not be inserted.
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum InsertError<K, V> {
+    /// The key is already in the map.
+    Exists(K, V),
+    /// The insertion took more steps than the map's step budget allows.
+    StepBudgetExceeded(K, V)
+}
+
+/// The error returned by bounded operations which take more steps than the map's step budget allows.
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct StepBudgetExceeded;
+
+/// A summary of the shape of a HashMap's tree, returned by `depth_stats`. Depths are counted from the head,
+/// which is depth 0.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct DepthStats {
+    /// The depth of the deepest data node, or 0 if the map is empty.
+    pub max_depth: usize,
+    /// The mean depth of the data nodes, or 0 if the map is empty.
+    pub average_depth: f64,
+    /// The number of ArrayNodes below the head.
+    pub array_nodes: usize
+}
+
+/// The integer a HashMap stores each key's hash in. Maps use `u64` unless they are built with a wide hasher,
+/// which makes them `u128` maps, so only the maps which use the upper 64 bits pay for them.
+pub trait HashBits: Copy + Eq + Default + Debug + Send + Sync + 'static
+    + Shr<usize, Output = Self> + ShrAssign<usize> + BitAnd<Output = Self>
 {
-    head: Vec<AtomicMarkablePtr<Node<K, V>>>,
-    hasher: RandomState,
+    /// The number of bits in the hash, which bounds the depth of the tree.
+    const BITS: usize;
+
+    /// Combine the map's hash of a key with its wide hash, if the map has a wide hasher.
+    fn from_halves(low: u64, high: Option<u64>) -> Self;
+
+    /// The lowest bits of the hash, for indexing into a bucket.
+    fn low_bits(self) -> usize;
+
+    /// Reverse the order of the bits, for maps which consume the hash from the high end.
+    fn reverse_bits(self) -> Self;
+
+    /// A mask of the lowest `bits` bits, which may be all of them.
+    fn low_mask(bits: usize) -> Self;
+}
+
+impl HashBits for u64 {
+    const BITS: usize = KEY_SIZE;
+
+    fn from_halves(low: u64, _high: Option<u64>) -> Self {
+        low
+    }
+
+    fn low_bits(self) -> usize {
+        self as usize
+    }
+
+    fn reverse_bits(self) -> Self {
+        u64::reverse_bits(self)
+    }
+
+    fn low_mask(bits: usize) -> Self {
+        if bits == KEY_SIZE { !0 } else { (1 << bits) - 1 }
+    }
+}
+
+impl HashBits for u128 {
+    const BITS: usize = WIDE_KEY_SIZE;
+
+    fn from_halves(low: u64, high: Option<u64>) -> Self {
+        low as u128 | (high.unwrap_or(0) as u128) << KEY_SIZE
+    }
+
+    fn low_bits(self) -> usize {
+        self as usize
+    }
+
+    fn reverse_bits(self) -> Self {
+        u128::reverse_bits(self)
+    }
+
+    fn low_mask(bits: usize) -> Self {
+        if bits == WIDE_KEY_SIZE { !0 } else { (1 << bits) - 1 }
+    }
+}
+
+/// The settings for a new HashMap, for when more than one of them should differ from the defaults. Each
+/// `with_*` constructor of HashMap is a shortcut for a builder with one setting changed, and the settings
+/// are described there.
+/// # Examples
+/// ```
+/// let map: HashMap<String, u8> = HashMapBuilder::new()
+///     .capacity(64)
+///     .bit_order(BitOrder::HighFirst)
+///     .max_failures(2)
+///     .build();
+/// ```
+pub struct HashMapBuilder<S = RandomState, H = u64> {
     head_size: usize,
-    shift_step: usize,
-    manager: HPBRManager<Node<K, V>>
+    bit_order: BitOrder,
+    hasher: S,
+    wide_hasher: Option<S>,
+    max_steps: Option<usize>,
+    max_failures: u64,
+    retire_capacity: usize,
+    hash_bits: PhantomData<H>
+}
+
+impl HashMapBuilder<RandomState> {
+    /// Start from the default settings, hashing keys with `RandomState`.
+    pub fn new() -> Self {
+        HashMapBuilder::with_hasher(RandomState::new())
+    }
+
+    /// Give each key a 128-bit hash, with a second `RandomState` for the upper 64 bits. See `HashMap::with_wide_hashes`.
+    pub fn wide_hashes(self) -> HashMapBuilder<RandomState, u128> {
+        self.wide_hasher(RandomState::new())
+    }
+}
+
+impl<S: BuildHasher> HashMapBuilder<S> {
+    /// Start from the default settings, hashing keys with the given BuildHasher. See `HashMap::with_hasher`.
+    pub fn with_hasher(hasher: S) -> Self {
+        HashMapBuilder {
+            head_size: HEAD_SIZE,
+            bit_order: BitOrder::LowFirst,
+            hasher,
+            wide_hasher: None,
+            max_steps: None,
+            max_failures: MAX_FAILURES,
+            retire_capacity: 0,
+            hash_bits: PhantomData
+        }
+    }
+
+    /// Give each key a 128-bit hash, with the upper 64 bits hashed by `wide_hasher`. See `HashMap::with_wide_hasher`.
+    pub fn wide_hasher(self, wide_hasher: S) -> HashMapBuilder<S, u128> {
+        HashMapBuilder {
+            head_size: self.head_size,
+            bit_order: self.bit_order,
+            hasher: self.hasher,
+            wide_hasher: Some(wide_hasher),
+            max_steps: self.max_steps,
+            max_failures: self.max_failures,
+            retire_capacity: self.retire_capacity,
+            hash_bits: PhantomData
+        }
+    }
+}
+
+impl<S: BuildHasher, H: HashBits> HashMapBuilder<S, H> {
+
+    /// Use a head array of `head_size` elements. See `HashMap::with_capacity`.
+    /// # Panics
+    /// The head size must be a non-zero power of two.
+    pub fn capacity(mut self, head_size: usize) -> Self {
+        if head_size == 0 || (head_size & (head_size - 1)) != 0 {
+            panic!("head_size must be a non-zero power of 2, got {}!", head_size)
+        }
+        self.head_size = head_size;
+        self
+    }
+
+    /// Consume the hash in the given order. See `HashMap::with_bit_order`.
+    pub fn bit_order(mut self, bit_order: BitOrder) -> Self {
+        self.bit_order = bit_order;
+        self
+    }
+
+    /// Give up on bounded operations after `max_steps` steps. See `HashMap::with_max_steps`.
+    pub fn max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// Expand a slot after `max_failures` failed attempts to change it. See `HashMap::with_max_failures`.
+    pub fn max_failures(mut self, max_failures: u64) -> Self {
+        self.max_failures = max_failures;
+        self
+    }
+
+    /// Make room for `retire_capacity` removed nodes per thread from the start. See `HashMap::with_retire_capacity`.
+    pub fn retire_capacity(mut self, retire_capacity: usize) -> Self {
+        self.retire_capacity = retire_capacity;
+        self
+    }
+
+    /// Create an empty HashMap with these settings.
+    pub fn build<K: Hash + Send, V: Send>(self) -> HashMap<K, V, S, H> {
+        let mut head: Vec<AtomicMarkablePtr<Node<K, V, H>>> = Vec::with_capacity(self.head_size);
+        for _ in 0..self.head_size {
+            head.push(AtomicMarkablePtr::default());
+        }
+
+        HashMap {
+            head,
+            hasher: self.hasher,
+            wide_hasher: self.wide_hasher,
+            head_size: self.head_size,
+            bit_order: self.bit_order,
+            max_steps: self.max_steps,
+            max_failures: self.max_failures,
+            len: AtomicUsize::new(0),
+            shrinking: AtomicBool::new(false),
+            shrink_lock: Mutex::new(()),
+            manager: HPBRManager::with_retire_capacity(100, 1, self.retire_capacity),
+            #[cfg(test)]
+            forced_failures: AtomicUsize::new(0)
+        }
+    }
+}
+
+impl Default for HashMapBuilder<RandomState> {
+    fn default() -> Self {
+        HashMapBuilder::new()
+    }
 }
 
 impl<K: Hash + Send, V: Send> HashMap<K, V> {
@@ -62,37 +330,209 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
     /// let map: HashMap<String, u8> = HashMap::new(); // Creates a new map of String to u8
     /// ```
     pub fn new() -> Self {
-        let mut head: Vec<AtomicMarkablePtr<Node<K, V>>> = Vec::with_capacity(HEAD_SIZE);
-        for _ in 0..HEAD_SIZE {
-            head.push(AtomicMarkablePtr::default());
-        }
+        HashMapBuilder::new().build()
+    }
 
-        Self {
-            head,
-            hasher: RandomState::new(),
-            head_size: HEAD_SIZE,
-            shift_step: f64::floor((CHILD_SIZE as f64).log2()) as usize,
-            manager: HPBRManager::new(100, 1)
-        }   
+    /// Create a new Wait-Free HashMap which consumes the hash in the given order.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::with_bit_order(BitOrder::HighFirst);
+    /// ```
+    pub fn with_bit_order(bit_order: BitOrder) -> Self {
+        HashMapBuilder::new().bit_order(bit_order).build()
+    }
+
+    /// Create a new Wait-Free HashMap with a head array of `head_size` elements. Every key in the map
+    /// shares the head, so a bigger head means a shallower tree at the cost of more memory up front.
+    /// # Panics
+    /// The head size must be a non-zero power of two.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::with_capacity(64);
+    /// ```
+    pub fn with_capacity(head_size: usize) -> Self {
+        HashMapBuilder::new().capacity(head_size).build()
+    }
+
+    /// Create a new Wait-Free HashMap where the bounded operations give up after `max_steps` steps. A step is 
+    /// one attempt at a level of the tree, or one attempt at expanding a slot. The unbounded operations 
+    /// are not affected.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::with_max_steps(8);
+    /// match map.insert_bounded("hello".to_owned(), 8) {
+    ///     Err(InsertError::StepBudgetExceeded(key, value)) => { /* Try again later */ },
+    ///     _ => {}
+    /// }
+    /// ```
+    pub fn with_max_steps(max_steps: usize) -> Self {
+        HashMapBuilder::new().max_steps(max_steps).build()
+    }
+
+    /// Create a new Wait-Free HashMap which expands a slot after `max_failures` failed attempts to change it,
+    /// instead of the default 10. Lower values spread contended keys out sooner at the cost of a deeper
+    /// tree, while higher values spend longer retrying before expanding.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::with_max_failures(2);
+    /// ```
+    pub fn with_max_failures(max_failures: u64) -> Self {
+        HashMapBuilder::new().max_failures(max_failures).build()
+    }
+
+    /// Create a new Wait-Free HashMap where each thread's list of removed nodes waiting to be freed
+    /// has room for `retire_capacity` nodes from the start. See `HPBRManager::with_retire_capacity`.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::with_retire_capacity(128);
+    /// ```
+    pub fn with_retire_capacity(retire_capacity: usize) -> Self {
+        HashMapBuilder::new().retire_capacity(retire_capacity).build()
+    }
+}
+
+impl<K: Hash + Send, V: Send> HashMap<K, V, RandomState, u128> {
+    /// Create a new Wait-Free HashMap which gives each key a 128-bit hash, built from two independently
+    /// seeded hashers. Two keys then only end up in the same collision node if both halves of their hashes
+    /// match, at the cost of a deeper tree in the worst case and a larger node for every key.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8, RandomState, u128> = HashMap::with_wide_hashes();
+    /// ```
+    pub fn with_wide_hashes() -> Self {
+        HashMapBuilder::new().wide_hashes().build()
+    }
+}
+
+impl<K: Hash + Send, V: Send, S: BuildHasher> HashMap<K, V, S> {
+    /// Create a new Wait-Free HashMap which hashes its keys with the given BuildHasher.
+    /// # Examples
+    /// ```
+    /// use std::collections::hash_map::DefaultHasher;
+    /// use std::hash::BuildHasherDefault;
+    /// // DefaultHasher::new() always starts from the same state, so every run hashes the keys the same way
+    /// let map: HashMap<String, u8, BuildHasherDefault<DefaultHasher>> = HashMap::with_hasher(BuildHasherDefault::default());
+    /// ```
+    pub fn with_hasher(hasher: S) -> Self {
+        HashMapBuilder::with_hasher(hasher).build()
+    }
+}
+
+impl<K: Hash + Send, V: Send, S: BuildHasher> HashMap<K, V, S, u128> {
+    /// Create a new Wait-Free HashMap which gives each key a 128-bit hash. The lower 64 bits are hashed with
+    /// `hasher`, and the upper 64 bits with `wide_hasher`, which should hash keys differently to it.
+    /// # Examples
+    /// ```
+    /// use std::collections::hash_map::RandomState;
+    /// let map: HashMap<String, u8, RandomState, u128> = HashMap::with_wide_hasher(RandomState::new(), RandomState::new());
+    /// ```
+    pub fn with_wide_hasher(hasher: S, wide_hasher: S) -> Self {
+        HashMapBuilder::with_hasher(hasher).wide_hasher(wide_hasher).build()
+    }
+}
+
+impl<K: Hash + Send, V: Send, S: BuildHasher, H: HashBits> HashMap<K, V, S, H> {
+    /// Hash a single element with the map's BuildHasher, and its wide hasher if it has one.
+    fn hash<Q>(&self, key: &Q) -> H
+    where K: Borrow<Q>,
+          Q: ?Sized + Hash + Send
+    {
+        let wide_hash = self.wide_hasher.as_ref().map(|wide_hasher| wide_hasher.hash_one(key));
+        self.order_hash(H::from_halves(self.hasher.hash_one(key), wide_hash))
     }
 
-    /// Hash a single element with the default Rust hasher initialised to a random state.
-    fn hash<Q: ?Sized>(&self, key: &Q) -> u64 
+    /// Arrange the bits of the hash so that the tree can always consume them from the low end.
+    fn order_hash(&self, hash: H) -> H {
+        match self.bit_order {
+            BitOrder::LowFirst => hash,
+            BitOrder::HighFirst => hash.reverse_bits()
+        }
+    }
+
+    /// Find the data node holding the given key in the data or collision node taken from `slot`, which should
+    /// already be protected by hazard pointer 0. A data node found inside a collision node is protected by the
+    /// returned handle. Returns Err if the slot changed while the collision node was being searched, in which
+    /// case the caller should start again.
+    fn find_entry<Q>(&self, slot: &AtomicMarkablePtr<Node<K, V, H>>, node_ptr: *mut Node<K, V, H>, key: &Q)
+        -> Result<Option<FoundEntry<'_, K, V, H>>, ()>
     where K: Borrow<Q>,
-          Q: Hash + Send 
+          Q: ?Sized + PartialEq
+    {
+        self.find_entry_where(slot, node_ptr, |data_node| data_node.has_key(key))
+    }
+
+    /// Find the first data node for which the predicate returns true in the data or collision node taken
+    /// from `slot`, with the same protection and errors as `find_entry`.
+    fn find_entry_where<F>(&self, slot: &AtomicMarkablePtr<Node<K, V, H>>, node_ptr: *mut Node<K, V, H>, predicate: F)
+        -> Result<Option<FoundEntry<'_, K, V, H>>, ()>
+    where F: Fn(&DataNode<K, V, H>) -> bool
     {
-        let mut hasher = self.hasher.build_hasher();
-        key.hash(&mut hasher);
-        hasher.finish()
+        unsafe {
+            match *atomic_markable::unmark(node_ptr) {
+                Node::Data(ref data_node) => {
+                    if predicate(data_node) {
+                        Ok(Some((atomic_markable::unmark(node_ptr), None)))
+                    } else {
+                        Ok(None)
+                    }
+                },
+                Node::Collision(ref collision_node) => {
+                    for &entry_ptr in &collision_node.entries {
+                        let handle = self.manager.protect_dynamic(entry_ptr);
+                        // The entry cannot have been retired if the collision node is still in the slot
+                        if slot.get_ptr() != Some(node_ptr) {
+                            return Err(())
+                        }
+                        if predicate(get_data_node(entry_ptr)) {
+                            return Ok(Some((entry_ptr, Some(handle))))
+                        }
+                    }
+                    Ok(None)
+                },
+                Node::Array(_) => panic!("Unexpected array node!")
+            }
+        }
+    }
+
+    /// Retire a data or collision node which has been replaced in the map, along with the entry which was
+    /// removed or replaced. The other entries of a collision node are still in the map.
+    fn retire_replaced(&self, node_ptr: *mut Node<K, V, H>, entry_ptr: *mut Node<K, V, H>) {
+        let node_ptr = atomic_markable::unmark(node_ptr);
+        if !ptr::eq(node_ptr, entry_ptr) {
+            self.manager.retire(node_ptr, 0);
+        }
+        self.manager.retire(entry_ptr, 0);
+    }
+
+    /// Take the value out of an entry which has just been removed from the map, and retire it.
+    fn take_removed(&self, node_ptr: *mut Node<K, V, H>, entry_ptr: *mut Node<K, V, H>) -> Option<V> {
+        let data = self.take_replaced(node_ptr, entry_ptr);
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        data
+    }
+
+    /// Take the value out of a data node which has just been swapped for a new one, and retire it along with
+    /// the data or collision node which held it.
+    fn take_replaced(&self, node_ptr: *mut Node<K, V, H>, entry_ptr: *mut Node<K, V, H>) -> Option<V> {
+        unsafe {
+            // The key is left in place for any threads still reading the node
+            let data = match *entry_ptr {
+                Node::Data(ref mut node) => node.value.take(),
+                _ => panic!("Expected a data node!")
+            };
+            self.retire_replaced(node_ptr, entry_ptr);
+            data
+        }
     }
 
     /// Attempt to set the current MarkablePtr to point to an ArrayNode. This function adds the old DataNode
     /// at this position to the new ArrayNode.
-    fn expand_map(&self, bucket: &Vec<AtomicMarkablePtr<Node<K, V>>>, pos: usize, shift_amount: usize) -> *mut Node<K, V> {
+    fn expand_map(&self, bucket: &Vec<AtomicMarkablePtr<Node<K, V, H>>>, pos: usize, shift_amount: usize) -> *mut Node<K, V, H> {
         // We know this node must exist
         let node = bucket[pos].get_ptr().unwrap();
         self.manager.protect(atomic_markable::unmark(node), 0);
-        if atomic_markable::is_marked_second(node) {
+        // A frozen slot cannot be expanded, and the caller has to wait for shrink and start again
+        if atomic_markable::is_marked_second(node) || atomic_markable::is_frozen(node) {
             //println!("already expanded: {:b}", node as usize);
             return node
         }
@@ -102,13 +542,10 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
             return node2
         }
 
-        let array_node: ArrayNode<K, V> = ArrayNode::new(CHILD_SIZE);
+        let array_node: ArrayNode<K, V, H> = ArrayNode::new(CHILD_SIZE);
         unsafe {
-            let hash = match &*atomic_markable::unmark(node) {
-                &Node::Data(ref data_node) => data_node.hash,
-                &Node::Array(_) => {panic!("Unexpected array node!")}
-            };
-            let new_pos = (hash >> (shift_amount + self.shift_step)) as usize & (CHILD_SIZE - 1);
+            let hash = get_node_hash(node);
+            let new_pos = (hash >> (shift_amount + slot_bits(bucket))).low_bits() & (CHILD_SIZE - 1);
             array_node.array[new_pos].store(atomic_markable::unmark(node));
 
             let array_node_ptr = Box::into_raw(Box::new(Node::Array(array_node)));
@@ -123,14 +560,70 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                     // Need to remove the pointer to the old element or this will delete a valid node
                     let vec = get_bucket(array_node_ptr);
                     vec[new_pos].store(ptr::null_mut()); 
-                    Box::from_raw(array_node_ptr);
+                    drop(Box::from_raw(array_node_ptr));
                     current
                 }
             }
         }
     }
 
-    /// Attempt to insert the given value with the given key into the HashMap.
+    /// Protect the ArrayNode `array_ptr` read from `slot`, in place of the ArrayNode protected by `guard`,
+    /// and return its array. Shrink retires the ArrayNodes it collapses, so the slot is read again once the
+    /// ArrayNode is protected. If the slot has changed, or did not hold an ArrayNode, this waits for shrink
+    /// and returns None, and the caller has to start again from the head.
+    fn enter_bucket<'a>(&'a self, slot: &AtomicMarkablePtr<Node<K, V, H>>, array_ptr: *mut Node<K, V, H>,
+                        guard: &mut Option<HPHandle<'a, Node<K, V, H>>>) -> Option<&'a Bucket<K, V, H>> {
+        if atomic_markable::is_marked_second(array_ptr) && !atomic_markable::is_frozen(array_ptr) {
+            let handle = self.manager.protect_dynamic(atomic_markable::unmark_second(array_ptr));
+            if slot.get_ptr() == Some(array_ptr) {
+                *guard = Some(handle);
+                return Some(get_bucket(array_ptr))
+            }
+        }
+        self.wait_for_shrink();
+        None
+    }
+
+    /// Start a walk over every slot of the tree.
+    fn walk(&self) -> Walk<'_, K, V, H> {
+        Walk::new(&self.head, &self.manager, &self.shrinking)
+    }
+
+    /// Insert the given value with the given key into the HashMap, replacing the value already there if
+    /// the key is in the map. Returns the replaced value, or None if the key was not in the map.
+    /// # Panics
+    /// If the internal structure of the map becomes inconsistent, this will panic.
+    /// # Examples:
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// assert_eq!(map.insert("hello".to_owned(), 8), None);
+    /// assert_eq!(map.insert("hello".to_owned(), 9), Some(8));
+    /// ```
+    pub fn insert(&self, key: K, value: V) -> Option<V>
+    where K: PartialEq
+    {
+        let hash = self.hash(&key);
+        let mut key = key;
+        let mut value = value;
+        loop {
+            match self.insert_hashed(hash, key, value, None) {
+                Ok(()) => return None,
+                Err(InsertError::Exists(old_key, old_value)) | Err(InsertError::StepBudgetExceeded(old_key, old_value)) => {
+                    // If the key has been removed again in the meantime, go back to inserting it
+                    match self.try_replace(hash, old_key, old_value) {
+                        Ok(replaced) => return Some(replaced),
+                        Err((old_key, old_value)) => {
+                            key = old_key;
+                            value = old_value;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Attempt to insert the given value with the given key into the HashMap, leaving the map
+    /// unchanged if the key is already in it.
     /// # Panics
     /// If the internal structure of the map becomes inconsistent, this will panic.
     /// # Errors
@@ -140,48 +633,136 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
     /// # Examples:
     /// ```
     /// let map: HashMap<String, u8> = HashMap::new();
-    /// map.insert("hello".to_owned(), 8);
+    /// assert!(map.insert_if_absent("hello".to_owned(), 8).is_ok());
+    /// assert!(map.insert_if_absent("hello".to_owned(), 9).is_err());
+    /// ```
+    pub fn insert_if_absent(&self, key: K, value: V) -> Result<(), (K, V)>
+    where K: PartialEq
+    {
+        let hash = self.hash(&key);
+        match self.insert_hashed(hash, key, value, None) {
+            Ok(()) => Ok(()),
+            Err(InsertError::Exists(key, value)) | Err(InsertError::StepBudgetExceeded(key, value)) => Err((key, value))
+        }
+    }
+
+    /// Retrieve a clone of the value for the given key, or insert the value computed by `f` if the key is
+    /// not in the map. If another thread inserts the key first, its value is returned and the computed
+    /// value is dropped. The function is called at most once.
+    /// # Panics
+    /// If the internal structure of the map becomes inconsistent, this will panic.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// assert_eq!(map.get_or_insert_with("hello".to_owned(), || 8), 8);
+    /// assert_eq!(map.get_or_insert_with("hello".to_owned(), || 9), 8);
     /// ```
-    pub fn insert(&self, key: K, mut value: V) -> Result<(), (K, V)> {
+    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> V
+    where K: PartialEq,
+          V: Clone,
+          F: FnOnce() -> V
+    {
+        if let Some(value) = self.get_clone(&key) {
+            return value
+        }
+        let mut key = key;
+        let mut value = f();
+        loop {
+            let result = value.clone();
+            match self.insert_if_absent(key, value) {
+                Ok(()) => return result,
+                Err((old_key, old_value)) => {
+                    if let Some(winner) = self.get_clone(&old_key) {
+                        return winner
+                    }
+                    // The winner has already been removed again, so try to insert the computed value once more
+                    key = old_key;
+                    value = old_value;
+                }
+            }
+        }
+    }
+
+    /// Attempt to insert the given value with the given key into the HashMap, giving up if the insertion
+    /// takes more steps than the map's step budget. This allows the caller to back off and retry later
+    /// instead of spinning under heavy contention.
+    /// # Panics
+    /// If the internal structure of the map becomes inconsistent, this will panic.
+    /// # Errors
+    /// The key and value are returned in an `InsertError::Exists` if the key is already in the map, or 
+    /// in an `InsertError::StepBudgetExceeded` if the step budget ran out.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::with_max_steps(8);
+    /// map.insert_bounded("hello".to_owned(), 8);
+    /// ```
+    pub fn insert_bounded(&self, key: K, value: V) -> Result<(), InsertError<K, V>>
+    where K: PartialEq
+    {
         let hash = self.hash(&key);
+        self.insert_hashed(hash, key, value, self.max_steps)
+    }
+
+    fn insert_hashed(&self, hash: H, mut key: K, mut value: V, max_steps: Option<usize>) -> Result<(), InsertError<K, V>>
+    where K: PartialEq
+    {
         let mut mut_hash = hash;
         let mut bucket = &self.head;
+        let mut array_guard = None;
         let mut r = 0usize;
-        while r < (KEY_SIZE - self.shift_step) {
-            let pos = mut_hash as usize & (bucket.len() - 1);
-            mut_hash = mut_hash >> self.shift_step;
+        let mut steps = 0;
+        while r < (H::BITS - CHILD_BITS) {
+            let pos = mut_hash.low_bits() & (bucket.len() - 1);
+            let level_bits = slot_bits(bucket);
+            mut_hash >>= level_bits;
             let mut fail_count = 0;
             let mut node = bucket[pos].get_ptr();
 
             loop {
-                if fail_count > MAX_FAILURES {
+                steps += 1;
+                if over_budget(steps, max_steps) {
+                    return Err(InsertError::StepBudgetExceeded(key, value))
+                }
+                if fail_count > self.max_failures {
                     bucket[pos].mark();
                     node = bucket[pos].get_ptr();
                 }
+                if node.is_some_and(atomic_markable::is_frozen) {
+                    self.wait_for_shrink();
+                    return self.insert_hashed(hash, key, value, remaining_budget(steps, max_steps))
+                }
                 match node {
                     None => {
-                        value = match self.try_insert(&bucket[pos], ptr::null_mut(), hash, value) {
+                        match self.try_insert(&bucket[pos], ptr::null_mut(), hash, key, value) {
                             Ok(_) => { return Ok(()) },
-                            Err(old) => {
+                            Err((old_key, old_value)) => {
                                 node = bucket[pos].get_ptr();
                                 fail_count += 1;
-                                old
+                                key = old_key;
+                                value = old_value;
                             } 
                         }
                     },
                     Some(mut node_ptr) => {
                         if atomic_markable::is_marked(node_ptr) {
                             // Check that doing this never breaks, ie expand_map returns a data node
+                            steps += 1;
                             let new_bucket_ptr = self.expand_map(bucket, pos, r);
                             if atomic_markable::is_marked_second(new_bucket_ptr) {
-                                bucket = get_bucket(new_bucket_ptr);
+                                bucket = match self.enter_bucket(&bucket[pos], new_bucket_ptr, &mut array_guard) {
+                                    Some(next) => next,
+                                    None => return self.insert_hashed(hash, key, value, remaining_budget(steps, max_steps))
+                                };
                                 break;
                             } else {
                                 node_ptr = new_bucket_ptr;
                             }
                         }
                         if atomic_markable::is_marked_second(node_ptr) {
-                            bucket = get_bucket(node_ptr);
+                            bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                                Some(next) => next,
+                                None => return self.insert_hashed(hash, key, value, remaining_budget(steps, max_steps))
+                            };
                             break;
                         } else {
                             self.manager.protect(node_ptr, 0);
@@ -192,15 +773,34 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                                 continue;
                             } else {
                                 // Hazard pointer should be safe
-                                let data_node = get_data_node(node_ptr);
-                                if data_node.hash == hash {
-                                    return Err((key, value))
+                                if get_node_hash(node_ptr) == hash {
+                                    // Only the same key counts as a duplicate, other keys share the slot
+                                    match self.find_entry(&bucket[pos], node_ptr, &key) {
+                                        Ok(Some(_)) => return Err(InsertError::Exists(key, value)),
+                                        Ok(None) => {
+                                            match self.try_insert_colliding(&bucket[pos], node_ptr, hash, key, value) {
+                                                Ok(()) => return Ok(()),
+                                                Err((old_key, old_value)) => {
+                                                    key = old_key;
+                                                    value = old_value;
+                                                }
+                                            }
+                                        },
+                                        Err(()) => {}
+                                    }
+                                    node = bucket[pos].get_ptr();
+                                    fail_count += 1;
+                                    continue;
                                 }
                                 match bucket[pos].compare_and_mark(node_ptr) {
                                     Ok(_) => {
+                                        steps += 1;
                                         let new_ptr = self.expand_map(bucket, pos, r);
                                         if atomic_markable::is_marked_second(new_ptr) {
-                                            bucket = get_bucket(new_ptr);
+                                            bucket = match self.enter_bucket(&bucket[pos], new_ptr, &mut array_guard) {
+                                                Some(next) => next,
+                                                None => return self.insert_hashed(hash, key, value, remaining_budget(steps, max_steps))
+                                            };
                                             break;
                                         } else {
                                             fail_count += 1;
@@ -208,7 +808,10 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                                     },
                                     Err(current) => {
                                         if atomic_markable::is_marked_second(current) {
-                                            bucket = get_bucket(current);
+                                            bucket = match self.enter_bucket(&bucket[pos], current, &mut array_guard) {
+                                                Some(next) => next,
+                                                None => return self.insert_hashed(hash, key, value, remaining_budget(steps, max_steps))
+                                            };
                                             break;
                                         } else {
                                             fail_count += 1;   
@@ -221,19 +824,39 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                 }
             }
 
-            r += self.shift_step;
+            r += level_bits;
         }
-        let pos = mut_hash as usize & (CHILD_SIZE - 1);
-        let node = bucket[pos].get_ptr();
-        return match node {
-            None => {
-                match self.try_insert(&bucket[pos], ptr::null_mut(), hash, value) {
-                    Err(val) => Err((key, val)),
-                    Ok(_) => Ok(())
+        let pos = mut_hash.low_bits() & (CHILD_SIZE - 1);
+        loop {
+            steps += 1;
+            if over_budget(steps, max_steps) {
+                return Err(InsertError::StepBudgetExceeded(key, value))
+            }
+            let result = match bucket[pos].get_ptr() {
+                None => self.try_insert(&bucket[pos], ptr::null_mut(), hash, key, value),
+                Some(node_ptr) if atomic_markable::is_frozen(node_ptr) => {
+                    self.wait_for_shrink();
+                    return self.insert_hashed(hash, key, value, remaining_budget(steps, max_steps))
+                },
+                Some(node_ptr) => {
+                    // Every key which reaches the bottom of the tree here shares the slot
+                    self.manager.protect(node_ptr, 0);
+                    if bucket[pos].get_ptr() != Some(node_ptr) {
+                        continue;
+                    }
+                    match self.find_entry(&bucket[pos], node_ptr, &key) {
+                        Ok(Some(_)) => return Err(InsertError::Exists(key, value)),
+                        Ok(None) => self.try_insert_colliding(&bucket[pos], node_ptr, hash, key, value),
+                        Err(()) => Err((key, value))
+                    }
+                }
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err((old_key, old_value)) => {
+                    key = old_key;
+                    value = old_value;
                 }
-            },
-            Some(_) => {
-                Err((key, value))
             }
         }
     }
@@ -250,7 +873,7 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
     /// map.insert("hello".to_owned(), 8);
     /// assert_eq!(map.get("hello"), Some(&8));
     /// ``` 
-    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<DataGuard<V, Node<K, V>>>
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<DataGuard<'_, V, Node<K, V, H>>>
     where K: Borrow<Q>,
           Q: PartialEq + Hash + Send  
     {
@@ -258,22 +881,31 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
         let mut mut_hash = hash;
         let mut r = 0usize;
         let mut bucket = &self.head;
+        let mut array_guard = None;
 
-        while r < (KEY_SIZE - self.shift_step) {
-            let pos = mut_hash as usize & (bucket.len() - 1);
-            mut_hash >>= self.shift_step;
+        while r < (H::BITS - CHILD_BITS) {
+            let pos = mut_hash.low_bits() & (bucket.len() - 1);
+            let level_bits = slot_bits(bucket);
+            mut_hash >>= level_bits;
             let mut node = bucket[pos].get_ptr();
 
             match node {
                 None => { return None; }
+                Some(node_ptr) if atomic_markable::is_frozen(node_ptr) => {
+                    self.wait_for_shrink();
+                    return self.get(key)
+                },
                 Some(mut node_ptr) => {
                     if atomic_markable::is_marked(node_ptr) {
                         let new_bucket_ptr = self.expand_map(bucket, pos, r);
                         node_ptr = new_bucket_ptr;
                     }
                     if atomic_markable::is_marked_second(node_ptr) {
-                        bucket = get_bucket(node_ptr);
-                        r += self.shift_step;
+                        bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                            Some(next) => next,
+                            None => return self.get(key)
+                        };
+                        r += level_bits;
                         continue;
                     } else {
                         self.manager.protect(atomic_markable::unmark(node_ptr), 0);
@@ -284,15 +916,26 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                                 node = bucket[pos].get_ptr();
                                 match node {
                                     None => { return None },
+                                    Some(new_ptr) if atomic_markable::is_frozen(new_ptr) => {
+                                        self.wait_for_shrink();
+                                        return self.get(key)
+                                    },
                                     Some(new_ptr) => {
                                         self.manager.protect(atomic_markable::unmark(atomic_markable::unmark_second(new_ptr)), 0);
                                         fail_count += 1;
-                                        if fail_count > MAX_FAILURES {
+                                        if fail_count > self.max_failures {
                                             bucket[pos].mark();
                                             // Force a bucket update
                                             //println!("hello");
                                             node_ptr = self.expand_map(bucket, pos, r);
-                                            bucket = get_bucket(node_ptr);
+                                            if atomic_markable::is_frozen(node_ptr) {
+                                                self.wait_for_shrink();
+                                                return self.get(key)
+                                            }
+                                            bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                                                Some(next) => next,
+                                                None => return self.get(key)
+                                            };
                                             //println!("fart");
                                             break;
                                         }
@@ -302,82 +945,238 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                             }
                             // Hazard pointer should be fine now
                             if atomic_markable::is_marked(node_ptr) {
-                                bucket = get_bucket(self.expand_map(bucket, pos, r));
-                                r += self.shift_step;
+                                bucket = match self.enter_bucket(&bucket[pos], self.expand_map(bucket, pos, r), &mut array_guard) {
+                                    Some(next) => next,
+                                    None => return self.get(key)
+                                };
+                                r += level_bits;
                                 continue;
                             } else if atomic_markable::is_marked_second(node_ptr) {
-                                bucket = get_bucket(node_ptr);
-                                r += self.shift_step;
+                                bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                                    Some(next) => next,
+                                    None => return self.get(key)
+                                };
+                                r += level_bits;
                                 continue;
                             }
                         }
-                        let data_node = get_data_node(node_ptr);
-                        if data_node.hash == hash {
-                            let hp_handle = self.manager.protect_dynamic(atomic_markable::unmark(node_ptr));
-                            self.manager.unprotect(0);
-                            return match data_node.value {
-                                None => None, // The node has already been deleted
-                                Some(ref value) => return Some(DataGuard::new(value, hp_handle))
-                            }
-                            //return Some(DataGuard::new(data_node.value.as_ref().unwrap(), hp_handle));
-                        } else {
-                            return None
-                        }
+                        return self.guard_entry(&bucket[pos], node_ptr, key)
                     }
                 }
             }
         }
         // We should only be here if we got to the bottom
-        let pos = mut_hash as usize & (CHILD_SIZE - 1);
-        if let Some(node_ptr) = bucket[pos].get_ptr() {
-            unsafe {
-                match &*node_ptr {
-                    &Node::Array(_) => panic!("Unexpected array node!"),
-                    &Node::Data(ref data_node) => {
-                        let hp_handle = self.manager.protect_dynamic(atomic_markable::unmark(node_ptr));
-                        self.manager.unprotect(0);
-                        return match data_node.value {
-                            None => None, // The node has already been removed
-                            Some(ref value) => return Some(DataGuard::new(value, hp_handle))
-                        }
-                        //return Some(DataGuard::new(data_node.value.as_ref().unwrap(), hp_handle));
-                    }
+        let pos = mut_hash.low_bits() & (CHILD_SIZE - 1);
+        match bucket[pos].get_ptr() {
+            None => None,
+            Some(node_ptr) if atomic_markable::is_frozen(node_ptr) => {
+                self.wait_for_shrink();
+                self.get(key)
+            },
+            Some(node_ptr) => {
+                self.manager.protect(node_ptr, 0);
+                if bucket[pos].get_ptr() != Some(node_ptr) {
+                    return self.get(key)
                 }
+                self.guard_entry(&bucket[pos], node_ptr, key)
             }
-        } else {
-            return None
         }
     }
 
-    fn try_insert(&self, position: &AtomicMarkablePtr<Node<K, V>>, old: *mut Node<K, V>, hash: u64, value: V) -> Result<(), V> {
-        let data_node: DataNode<K, V> = DataNode::new(value, hash);
-        let data_node_ptr = Box::into_raw(Box::new(Node::Data(data_node)));
-
-        return match position.compare_exchange(old, data_node_ptr) {
-            Ok(_) => Ok(()),
-            Err(_) => {
-                unsafe {
-                    let node = ptr::replace(data_node_ptr, Node::Data(DataNode::default()));
-                    if let Node::Data(data_node) = node {
-                        let data = data_node.value.unwrap();
-                        Box::from_raw(data_node_ptr);
-                        Err(data)
-                    } else {
-                        panic!("Unexpected array node!");
-                    }
+    /// Protect the value of the given key in the data or collision node taken from `slot` with a DataGuard.
+    fn guard_entry<Q>(&self, slot: &AtomicMarkablePtr<Node<K, V, H>>, node_ptr: *mut Node<K, V, H>, key: &Q) -> Option<DataGuard<'_, V, Node<K, V, H>>>
+    where K: Borrow<Q>,
+          Q: ?Sized + PartialEq + Hash + Send
+    {
+        match self.find_entry(slot, node_ptr, key) {
+            Err(()) => self.get(key),
+            Ok(None) => None,
+            Ok(Some((entry_ptr, handle))) => {
+                let hp_handle = match handle {
+                    Some(handle) => handle,
+                    None => self.manager.protect_dynamic(entry_ptr)
+                };
+                self.manager.unprotect(0);
+                match get_data_node(entry_ptr).value {
+                    None => None, // The node has already been deleted
+                    Some(ref value) => Some(DataGuard::new(value, hp_handle))
                 }
             }
         }
     }
 
-    /// Attempt to update a value in the map with the given key and expected value. The 
-    /// expected value is needed so that a newer element cannot be overwrittn with an old one
-    /// by another thread.
-    /// # Panics
-    /// This method will panic if the internal state of the HashMap becomes inconsistent.
-    /// # Errors
-    /// This method returns Err containing the attempted insertion value on the following conditions:
-    /// * The CAS fails.
+    /// Find the slot holding the data or collision node for the given key, and the key's data node within it.
+    /// The data or collision node is protected by hazard pointer 0, and a data node inside a collision node
+    /// is protected by the returned handle. Returns None if the key is not in the map.
+    fn locate<Q>(&self, key: &Q, hash: H) -> Option<LocatedEntry<'_, K, V, H>>
+    where K: Borrow<Q>,
+          Q: ?Sized + PartialEq
+    {
+        let mut mut_hash = hash;
+        let mut bucket = &self.head;
+        let mut array_guard = None;
+        let mut r = 0usize;
+        while r < (H::BITS - CHILD_BITS) {
+            let pos = mut_hash.low_bits() & (bucket.len() - 1);
+            let level_bits = slot_bits(bucket);
+            mut_hash >>= level_bits;
+            let mut fail_count = 0;
+            loop {
+                let node_ptr = bucket[pos].get_ptr()?;
+                if atomic_markable::is_frozen(node_ptr) {
+                    self.wait_for_shrink();
+                    return self.locate(key, hash)
+                }
+                if atomic_markable::is_marked_second(node_ptr) {
+                    bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                        Some(next) => next,
+                        None => return self.locate(key, hash)
+                    };
+                    break;
+                }
+                if atomic_markable::is_marked(node_ptr) || fail_count > self.max_failures {
+                    // The slot is being expanded, or keeps changing, so finish expanding it and go down a level
+                    bucket[pos].mark();
+                    let expanded = self.expand_map(bucket, pos, r);
+                    if atomic_markable::is_frozen(expanded) {
+                        self.wait_for_shrink();
+                        return self.locate(key, hash)
+                    }
+                    bucket = match self.enter_bucket(&bucket[pos], expanded, &mut array_guard) {
+                        Some(next) => next,
+                        None => return self.locate(key, hash)
+                    };
+                    break;
+                }
+                self.manager.protect(node_ptr, 0);
+                if bucket[pos].get_ptr() != Some(node_ptr) {
+                    fail_count += 1;
+                    continue;
+                }
+                match self.find_entry(&bucket[pos], node_ptr, key) {
+                    Ok(Some((entry_ptr, handle))) => return Some((&bucket[pos], node_ptr, entry_ptr, handle)),
+                    Ok(None) => return None,
+                    Err(()) => fail_count += 1
+                }
+            }
+            r += level_bits;
+        }
+
+        let pos = mut_hash.low_bits() & (CHILD_SIZE - 1);
+        loop {
+            let node_ptr = bucket[pos].get_ptr()?;
+            if atomic_markable::is_frozen(node_ptr) {
+                self.wait_for_shrink();
+                return self.locate(key, hash)
+            }
+            self.manager.protect(node_ptr, 0);
+            if bucket[pos].get_ptr() != Some(node_ptr) {
+                continue;
+            }
+            match self.find_entry(&bucket[pos], node_ptr, key) {
+                Ok(Some((entry_ptr, handle))) => return Some((&bucket[pos], node_ptr, entry_ptr, handle)),
+                Ok(None) => return None,
+                Err(()) => {}
+            }
+        }
+    }
+
+    /// Attempt to replace the data node for the key with one holding the given value. Returns the old value,
+    /// or gives back the key and value if the key is not in the map or its slot changed first.
+    fn try_replace(&self, hash: H, key: K, value: V) -> Result<V, (K, V)>
+    where K: PartialEq
+    {
+        let (slot, node_ptr, entry_ptr, _handle) = match self.locate(&key, hash) {
+            Some(found) => found,
+            None => return Err((key, value))
+        };
+        let new_entry_ptr = Box::into_raw(Box::new(Node::Data(DataNode::new(key, value, hash))));
+        let replacement = replacement_node(node_ptr, entry_ptr, new_entry_ptr);
+        match slot.compare_exchange(node_ptr, replacement) {
+            Ok(_) => Ok(self.take_replaced(node_ptr, entry_ptr).expect("Replaced a data node with no value!")),
+            Err(_) => {
+                free_replacement(replacement);
+                unsafe {
+                    match *Box::from_raw(new_entry_ptr) {
+                        Node::Data(data_node) => Err(data_node.into_parts()),
+                        _ => panic!("Expected a data node!")
+                    }
+                }
+            }
+        }
+    }
+
+    fn try_insert(&self, position: &AtomicMarkablePtr<Node<K, V, H>>, old: *mut Node<K, V, H>, hash: H, key: K, value: V) -> Result<(), (K, V)> {
+        let data_node: DataNode<K, V, H> = DataNode::new(key, value, hash);
+        let data_node_ptr = Box::into_raw(Box::new(Node::Data(data_node)));
+
+        return match position.compare_exchange(old, data_node_ptr) {
+            Ok(_) => {
+                self.len.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            Err(_) => {
+                unsafe {
+                    let node = ptr::replace(data_node_ptr, Node::Data(DataNode::default()));
+                    if let Node::Data(data_node) = node {
+                        let entry = data_node.into_parts();
+                        drop(Box::from_raw(data_node_ptr));
+                        Err(entry)
+                    } else {
+                        panic!("Unexpected array node!");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Attempt to add a data node for a key alongside the data or collision node in the given position,
+    /// whose keys have the same hash but are different. Returns the key and value if the position has changed.
+    fn try_insert_colliding(&self, position: &AtomicMarkablePtr<Node<K, V, H>>, old: *mut Node<K, V, H>, hash: H, key: K, value: V) -> Result<(), (K, V)> {
+        let entry_ptr = Box::into_raw(Box::new(Node::Data(DataNode::new(key, value, hash))));
+        let replacement = replacement_node(old, ptr::null_mut(), entry_ptr);
+
+        let swapped = if self.take_forced_failure() { Err(old) } else { position.compare_exchange(old, replacement) };
+        match swapped {
+            Ok(_) => {
+                // The old entries are now held by the replacement, so only a collision node can be retired
+                if let &Node::Collision(_) = unsafe { &*old } {
+                    self.manager.retire(old, 0);
+                }
+                self.len.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            Err(_) => {
+                free_replacement(replacement);
+                unsafe {
+                    match *Box::from_raw(entry_ptr) {
+                        Node::Data(data_node) => Err(data_node.into_parts()),
+                        _ => panic!("Expected a data node!")
+                    }
+                }
+            }
+        }
+    }
+
+    /// Use up one of the failures a test has asked for, as if another thread had changed the slot first.
+    #[cfg(test)]
+    fn take_forced_failure(&self) -> bool {
+        self.forced_failures.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |failures| failures.checked_sub(1)).is_ok()
+    }
+
+    #[cfg(not(test))]
+    fn take_forced_failure(&self) -> bool {
+        false
+    }
+
+    /// Attempt to update a value in the map with the given key and expected value. The 
+    /// expected value is needed so that a newer element cannot be overwrittn with an old one
+    /// by another thread.
+    /// # Panics
+    /// This method will panic if the internal state of the HashMap becomes inconsistent.
+    /// # Errors
+    /// This method returns Err containing the attempted insertion value on the following conditions:
+    /// * The CAS fails.
     /// * The expected value does not match the actual one.
     /// * The key is not in the map.
     /// # Examples
@@ -398,22 +1197,31 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
         let mut mut_hash = hash;
         let mut r = 0usize;
         let mut bucket = &self.head;
+        let mut array_guard = None;
 
-        while r < (KEY_SIZE - self.shift_step) {
-            let pos = mut_hash as usize & (bucket.len() - 1);
-            mut_hash >>= self.shift_step;
+        while r < (H::BITS - CHILD_BITS) {
+            let pos = mut_hash.low_bits() & (bucket.len() - 1);
+            let level_bits = slot_bits(bucket);
+            mut_hash >>= level_bits;
             let mut node = bucket[pos].get_ptr();
 
             match node {
                 None => { return Err(new) },
+                Some(node_ptr) if atomic_markable::is_frozen(node_ptr) => {
+                    self.wait_for_shrink();
+                    return self.update(key, expected, new)
+                },
                 Some(mut node_ptr) => {
                     if atomic_markable::is_marked(node_ptr) {
                         let new_bucket_ptr = self.expand_map(bucket, pos, r);
                         node_ptr = new_bucket_ptr;
                     }
                     if atomic_markable::is_marked_second(node_ptr) {
-                        bucket = get_bucket(node_ptr);
-                        r += self.shift_step;
+                        bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                            Some(next) => next,
+                            None => return self.update(key, expected, new)
+                        };
+                        r += level_bits;
                         continue;
                     } else {
                         self.manager.protect(atomic_markable::unmark(node_ptr), 0);
@@ -423,13 +1231,25 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                                 node = bucket[pos].get_ptr();
                                 match node {
                                     None => { return Err(new); },
+                                    Some(new_ptr) if atomic_markable::is_frozen(new_ptr) => {
+                                        self.wait_for_shrink();
+                                        return self.update(key, expected, new)
+                                    },
                                     Some(new_ptr) => {
                                         self.manager.protect(atomic_markable::unmark(atomic_markable::unmark_second(new_ptr)), 0);
                                         fail_count += 1;
-                                        if fail_count > MAX_FAILURES {
+                                        if fail_count > self.max_failures {
                                             bucket[pos].mark();
                                             // Force a bucket update
-                                            bucket = get_bucket(self.expand_map(bucket, pos, r));
+                                            let expanded = self.expand_map(bucket, pos, r);
+                                            if atomic_markable::is_frozen(expanded) {
+                                                self.wait_for_shrink();
+                                                return self.update(key, expected, new)
+                                            }
+                                            bucket = match self.enter_bucket(&bucket[pos], expanded, &mut array_guard) {
+                                                Some(next) => next,
+                                                None => return self.update(key, expected, new)
+                                            };
                                             break;
                                         }
                                         node_ptr = new_ptr;
@@ -437,63 +1257,96 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                                 }
                             }
                             if atomic_markable::is_marked_second(node_ptr) {
-                                bucket = get_bucket(node_ptr);
-                                r += self.shift_step;
+                                bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                                    Some(next) => next,
+                                    None => return self.update(key, expected, new)
+                                };
+                                r += level_bits;
                                 continue;
                             } else if atomic_markable::is_marked(node_ptr) {
-                                bucket = get_bucket(self.expand_map(bucket, pos, r));
-                                r += self.shift_step;
+                                bucket = match self.enter_bucket(&bucket[pos], self.expand_map(bucket, pos, r), &mut array_guard) {
+                                    Some(next) => next,
+                                    None => return self.update(key, expected, new)
+                                };
+                                r += level_bits;
                                 continue;
                             }
                         }
                         // Hazard pointer is safe now, so we can access the node
-                        let data_node = get_data_node(node_ptr);
-                        if data_node.hash == hash {
-                            if data_node.value.as_ref() != Some(expected) {
-                                return Err(new)
-                            }
-                            new = match self.try_update(&bucket[pos], node_ptr, hash, new) {
-                                Ok(()) => { 
-                                    self.manager.retire(node_ptr, 0);
-                                    return Ok(()) 
-                                },
-                                Err((value, current_ptr)) => {
-                                    if atomic_markable::is_marked_second(current_ptr) {
-                                        bucket = get_bucket(current_ptr);
-                                        value
-                                    } else if atomic_markable::is_marked(current_ptr) &&
-                                              ptr::eq(node_ptr, atomic_markable::unmark(current_ptr)) 
-                                    {
-                                        bucket = get_bucket(self.expand_map(bucket, pos, r));
-                                        value
-                                    } else {
-                                        return Err(value);
-                                    }
+                        let (entry_ptr, _handle) = match self.find_entry(&bucket[pos], node_ptr, key) {
+                            Ok(Some(entry)) => entry,
+                            Ok(None) => return Err(new),
+                            Err(()) => return self.update(key, expected, new)
+                        };
+                        let data_node = get_data_node(entry_ptr);
+                        if data_node.value.as_ref() != Some(expected) {
+                            return Err(new)
+                        }
+                        new = match self.try_update(&bucket[pos], node_ptr, entry_ptr, data_node.shared_key(), hash, new) {
+                            Ok(()) => { 
+                                self.retire_replaced(node_ptr, entry_ptr);
+                                return Ok(()) 
+                            },
+                            Err((value, current_ptr)) => {
+                                if atomic_markable::is_frozen(current_ptr) {
+                                    self.wait_for_shrink();
+                                    return self.update(key, expected, value)
+                                } else if atomic_markable::is_marked_second(current_ptr) {
+                                    bucket = match self.enter_bucket(&bucket[pos], current_ptr, &mut array_guard) {
+                                        Some(next) => next,
+                                        None => return self.update(key, expected, value)
+                                    };
+                                    value
+                                } else if atomic_markable::is_marked(current_ptr) &&
+                                          ptr::eq(node_ptr, atomic_markable::unmark(current_ptr)) 
+                                {
+                                    bucket = match self.enter_bucket(&bucket[pos], self.expand_map(bucket, pos, r), &mut array_guard) {
+                                        Some(next) => next,
+                                        None => return self.update(key, expected, value)
+                                    };
+                                    value
+                                } else {
+                                    return Err(value);
                                 }
                             }
-                        } else {
-                            return Err(new)
                         }
                     }
                 }
             }
-            r += self.shift_step;
+            r += level_bits;
         }
         
         // Since we are at the bottom of the tree, we can only have data nodes here
-        let pos = mut_hash as usize & (CHILD_SIZE - 1);
+        let pos = mut_hash.low_bits() & (CHILD_SIZE - 1);
         let node = bucket[pos].get_ptr();
         match node {
             None => { Err(new) },
+            Some(node_ptr) if atomic_markable::is_frozen(node_ptr) => {
+                self.wait_for_shrink();
+                self.update(key, expected, new)
+            },
             Some(node_ptr) => {
-                let data_node = get_data_node(node_ptr);
+                self.manager.protect(node_ptr, 0);
+                if bucket[pos].get_ptr() != Some(node_ptr) {
+                    return self.update(key, expected, new)
+                }
+                let (entry_ptr, _handle) = match self.find_entry(&bucket[pos], node_ptr, key) {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => return Err(new),
+                    Err(()) => return self.update(key, expected, new)
+                };
+                let data_node = get_data_node(entry_ptr);
                 if data_node.value.as_ref() == Some(expected) {
-                    match self.try_update(&bucket[pos], node_ptr, hash, new) {
+                    match self.try_update(&bucket[pos], node_ptr, entry_ptr, data_node.shared_key(), hash, new) {
                         Ok(()) => {
-                            self.manager.retire(node_ptr, 0);
+                            self.retire_replaced(node_ptr, entry_ptr);
                             Ok(())
                         },
-                        Err((value, _)) => {
+                        Err((value, current_ptr)) => {
+                            if atomic_markable::is_frozen(current_ptr) {
+                                self.wait_for_shrink();
+                                return self.update(key, expected, value)
+                            }
                             Err(value)
                         }
                     }
@@ -504,17 +1357,116 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
         }
     }
 
-    fn try_update(&self, position: &AtomicMarkablePtr<Node<K, V>>, old: *mut Node<K, V>, hash: u64, value: V) -> Result<(), (V, *mut Node<K, V>)> {
-        let new_data_node: DataNode<K, V> = DataNode::new(value, hash);
+    /// Replace the value for the given key with `f` applied to it, retrying against the latest value until
+    /// the replacement succeeds, so each call applies `f` exactly once. `f` may be called more than once
+    /// if other threads change the value at the same time. Returns the value which was replaced, or None
+    /// if the key is not in the map.
+    /// # Panics
+    /// This method will panic if the internal state of the HashMap becomes inconsistent.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// assert_eq!(map.update_with("hello", |count| count + 1), Some(8));
+    /// assert_eq!(map.get_clone("hello"), Some(9));
+    /// assert_eq!(map.update_with("rust", |count| count + 1), None);
+    /// ```
+    pub fn update_with<Q, F>(&self, key: &Q, f: F) -> Option<V>
+    where K: Borrow<Q>,
+          Q: ?Sized + PartialEq + Hash + Send,
+          F: Fn(&V) -> V
+    {
+        let hash = self.hash(key);
+        loop {
+            let (slot, node_ptr, entry_ptr, _handle) = self.locate(key, hash)?;
+            let data_node = get_data_node(entry_ptr);
+            let new_value = match data_node.value.as_ref() {
+                Some(value) => f(value),
+                // The value has already been taken by a thread which replaced the node
+                None => continue
+            };
+            if self.try_update(slot, node_ptr, entry_ptr, data_node.shared_key(), hash, new_value).is_ok() {
+                return self.take_replaced(node_ptr, entry_ptr)
+            }
+        }
+    }
+
+    /// Insert the given value with the given key if the key is not in the map, or otherwise replace the value
+    /// already there with `f` applied to it and the given value. Like `update_with`, the replacement is retried
+    /// against the latest value until it succeeds, so `f` may be called more than once if other threads change
+    /// the value at the same time, but only one result is stored.
+    /// # Panics
+    /// This method will panic if the internal state of the HashMap becomes inconsistent.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.merge("hello".to_owned(), 1, |count, new| count + new);
+    /// map.merge("hello".to_owned(), 1, |count, new| count + new);
+    /// assert_eq!(map.get_clone("hello"), Some(2));
+    /// ```
+    pub fn merge<F>(&self, key: K, value: V, f: F)
+    where K: PartialEq,
+          F: Fn(&V, &V) -> V
+    {
+        let hash = self.hash(&key);
+        let mut key = key;
+        let mut value = value;
+        loop {
+            match self.insert_hashed(hash, key, value, None) {
+                Ok(()) => return,
+                Err(InsertError::Exists(old_key, old_value)) | Err(InsertError::StepBudgetExceeded(old_key, old_value)) => {
+                    key = old_key;
+                    value = old_value;
+                }
+            }
+            // If the key has been removed again in the meantime, go back to inserting it
+            if let Some((slot, node_ptr, entry_ptr, _handle)) = self.locate(&key, hash) {
+                let data_node = get_data_node(entry_ptr);
+                let new_value = match data_node.value.as_ref() {
+                    Some(existing) => f(existing, &value),
+                    // The value has already been taken by a thread which replaced the node
+                    None => continue
+                };
+                if self.try_update(slot, node_ptr, entry_ptr, data_node.shared_key(), hash, new_value).is_ok() {
+                    self.take_replaced(node_ptr, entry_ptr);
+                    return
+                }
+            }
+        }
+    }
+
+    /// Get the entry for the given key, which records whether the key was in the map at the time. The entry
+    /// is not a lock: other threads can still insert, update and remove the key while it is held, so each of
+    /// its methods works against the map as it is when the method is called.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// assert_eq!(map.entry("hello".to_owned()).or_insert(8), 8);
+    /// assert_eq!(map.entry("hello".to_owned()).and_modify(|count| count + 1).or_insert(8), 9);
+    /// ```
+    pub fn entry(&self, key: K) -> Entry<'_, K, V, S, H>
+    where K: PartialEq
+    {
+        if self.contains_key(&key) {
+            Entry::Occupied(OccupiedEntry { map: self, key })
+        } else {
+            Entry::Vacant(VacantEntry { map: self, key })
+        }
+    }
+
+    fn try_update(&self, position: &AtomicMarkablePtr<Node<K, V, H>>, old: *mut Node<K, V, H>, entry: *mut Node<K, V, H>, key: SharedKey<K>, hash: H, value: V) -> Result<(), (V, *mut Node<K, V, H>)> {
+        let new_data_node: DataNode<K, V, H> = DataNode::sharing_key(key, value, hash);
         let data_node_ptr = Box::into_raw(Box::new(Node::Data(new_data_node)));
+        let replacement = replacement_node(old, entry, data_node_ptr);
 
-        match position.compare_exchange(old, data_node_ptr) {
+        match position.compare_exchange(old, replacement) {
             Ok(_) => Ok(()),
             Err(current) => {
+                free_replacement(replacement);
                 unsafe {
                     if let Node::Data(node) = ptr::replace(data_node_ptr, Node::Data(DataNode::default())) {
                         let data = node.value.unwrap();
-                        Box::from_raw(data_node_ptr);
+                        drop(Box::from_raw(data_node_ptr));
                         Err((data, current))
                     } else {
                         panic!("Unexpected array node!")
@@ -524,6 +1476,25 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
         }
     }
 
+    /// Attempt to remove the element with the given key from the HashMap, whatever its value.
+    /// Returns the removed value on success, and None if the key is not in the map. If several threads
+    /// remove the same key at once, only one of them will get the value.
+    /// # Panics
+    /// This method panics if the internal state of the HashMap becomes inconsistent.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// assert_eq!(map.remove("hello"), Some(8));
+    /// assert_eq!(map.remove("hello"), None);
+    /// ```
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where K: Borrow<Q>,
+          Q: ?Sized + PartialEq + Hash + Send
+    {
+        self.remove_matching(key, |_| true)
+    }
+
     /// Attempt to remove the element with the given key and expected value from the HashMap.
     /// Returns the removed value on success, and None on failure.
     /// # Panics
@@ -533,31 +1504,52 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
     /// let map: HashMap<String, u8> = HashMap::new();
     /// map.insert("hello".to_owned(), 8);
     /// assert_eq!(map.get("hello"), Some(&8));
-    /// assert_eq!(map.remove("hello", &8), Some(8));
+    /// assert_eq!(map.compare_and_remove("hello", &8), Some(8));
     /// assert_eq!(map.get("hello"), None);
     /// ```
-    pub fn remove<Q: ?Sized>(&self, key: &Q, expected: &V) -> Option<V>
+    pub fn compare_and_remove<Q>(&self, key: &Q, expected: &V) -> Option<V>
     where K: Borrow<Q>,
-          Q: PartialEq + Hash + Send,
+          Q: ?Sized + PartialEq + Hash + Send,
           V: PartialEq   
+    {
+        self.remove_matching(key, |value| value == expected)
+    }
+
+    /// Remove the element with the given key if its value satisfies the predicate.
+    fn remove_matching<Q, F>(&self, key: &Q, matches: F) -> Option<V>
+    where K: Borrow<Q>,
+          Q: ?Sized + PartialEq + Hash + Send,
+          F: Fn(&V) -> bool
     {
         let hash = self.hash(key);
         let mut mut_hash = hash;
         let mut r = 0usize;
         let mut bucket = &self.head;
+        let mut array_guard = None;
 
-        while r < (KEY_SIZE - self.shift_step) {
-            let pos = mut_hash as usize & (bucket.len() - 1);
-            mut_hash >>= self.shift_step;
+        while r < (H::BITS - CHILD_BITS) {
+            let pos = mut_hash.low_bits() & (bucket.len() - 1);
+            let level_bits = slot_bits(bucket);
+            mut_hash >>= level_bits;
             let mut node = bucket[pos].get_ptr();
 
             match node {
                 None => { return None; },
+                Some(node_ptr) if atomic_markable::is_frozen(node_ptr) => {
+                    self.wait_for_shrink();
+                    return self.remove_matching(key, matches)
+                },
                 Some(mut node_ptr) => {
                     if atomic_markable::is_marked_second(node_ptr) {
-                        bucket = get_bucket(node_ptr);
+                        bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                            Some(next) => next,
+                            None => return self.remove_matching(key, matches)
+                        };
                     } else if atomic_markable::is_marked(node_ptr) {
-                        bucket = get_bucket(self.expand_map(bucket, pos, r));
+                        bucket = match self.enter_bucket(&bucket[pos], self.expand_map(bucket, pos, r), &mut array_guard) {
+                            Some(next) => next,
+                            None => return self.remove_matching(key, matches)
+                        };
                     } else {
                         self.manager.protect(atomic_markable::unmark(node_ptr), 0);
                         if node != bucket[pos].get_ptr() {
@@ -566,13 +1558,25 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                                 node = bucket[pos].get_ptr();
                                 match node {
                                     None => { return None; },
+                                    Some(new_ptr) if atomic_markable::is_frozen(new_ptr) => {
+                                        self.wait_for_shrink();
+                                        return self.remove_matching(key, matches)
+                                    },
                                     Some(new_ptr) => {
                                         self.manager.protect(atomic_markable::unmark(atomic_markable::unmark_second(new_ptr)), 0);
                                         fail_count += 1;
-                                        if fail_count > MAX_FAILURES {
+                                        if fail_count > self.max_failures {
                                             bucket[pos].mark();
                                             // Force a bucket update
-                                            bucket = get_bucket(self.expand_map(bucket, pos, r));
+                                            let expanded = self.expand_map(bucket, pos, r);
+                                            if atomic_markable::is_frozen(expanded) {
+                                                self.wait_for_shrink();
+                                                return self.remove_matching(key, matches)
+                                            }
+                                            bucket = match self.enter_bucket(&bucket[pos], expanded, &mut array_guard) {
+                                                Some(next) => next,
+                                                None => return self.remove_matching(key, matches)
+                                            };
                                             continue;
                                         }
                                         node_ptr = new_ptr;
@@ -581,77 +1585,86 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                             }
                             // Hazard pointer is safe here
                             if atomic_markable::is_marked_second(node_ptr) {
-                                bucket = get_bucket(node_ptr);
-                                r += self.shift_step;
+                                bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                                    Some(next) => next,
+                                    None => return self.remove_matching(key, matches)
+                                };
+                                r += level_bits;
                                 continue;
                             } else if atomic_markable::is_marked(node_ptr) {
-                                bucket = get_bucket(self.expand_map(bucket, pos, r));
-                                r += self.shift_step;
+                                bucket = match self.enter_bucket(&bucket[pos], self.expand_map(bucket, pos, r), &mut array_guard) {
+                                    Some(next) => next,
+                                    None => return self.remove_matching(key, matches)
+                                };
+                                r += level_bits;
                                 continue;
                             }
                         }
-                        let data_node = get_data_node(node_ptr);
-                        if data_node.hash == hash {
-                            if data_node.value.as_ref() != Some(expected) {
-                                return None
-                            }
-                            match self.try_remove(&bucket[pos], node_ptr) {
-                                Ok(()) => {
-                                    // Get the value out of the node_ptr, return it, retire it?
-                                    unsafe {
-                                        //println!("removed: {:b}", node_ptr as usize);
-                                        let owned_node = ptr::replace(node_ptr, Node::Data(DataNode::default()));
-                                        if let Node::Data(node) = owned_node {
-                                            let data = node.value;
-                                            self.manager.retire(node_ptr, 0);
-                                            return data;
-                                        } else {
-                                            panic!("Unexpected array node!");
-                                        }
-                                    }
-                                },
-                                Err(current) => {
-                                    if atomic_markable::is_marked_second(current) {
-                                        bucket = get_bucket(current);
-                                    } else if atomic_markable::is_marked(current)
-                                        && ptr::eq(atomic_markable::unmark(current), node_ptr) 
-                                    {
-                                        bucket = get_bucket(self.expand_map(bucket, pos, r));
-                                    } else {
-                                        return None
-                                    }
+                        let (entry_ptr, _handle) = match self.find_entry(&bucket[pos], node_ptr, key) {
+                            Ok(Some(entry)) => entry,
+                            Ok(None) => return None,
+                            Err(()) => return self.remove_matching(key, matches)
+                        };
+                        if !get_data_node(entry_ptr).value.as_ref().is_some_and(&matches) {
+                            return None
+                        }
+                        match self.try_remove(&bucket[pos], node_ptr, entry_ptr) {
+                            Ok(()) => {
+                                return self.take_removed(node_ptr, entry_ptr);
+                            },
+                            Err(current) => {
+                                if atomic_markable::is_frozen(current) {
+                                    self.wait_for_shrink();
+                                    return self.remove_matching(key, matches)
+                                } else if atomic_markable::is_marked_second(current) {
+                                    bucket = match self.enter_bucket(&bucket[pos], current, &mut array_guard) {
+                                        Some(next) => next,
+                                        None => return self.remove_matching(key, matches)
+                                    };
+                                } else if atomic_markable::is_marked(current)
+                                    && ptr::eq(atomic_markable::unmark(current), node_ptr) 
+                                {
+                                    bucket = match self.enter_bucket(&bucket[pos], self.expand_map(bucket, pos, r), &mut array_guard) {
+                                        Some(next) => next,
+                                        None => return self.remove_matching(key, matches)
+                                    };
+                                } else {
+                                    return None
                                 }
                             }
-                        } else {
-                            return None
                         }
                     }
                 }
             }
-            r += self.shift_step;
+            r += level_bits;
         }
-        let pos = mut_hash as usize & (bucket.len() - 1);
+        let pos = mut_hash.low_bits() & (bucket.len() - 1);
         let node = bucket[pos].get_ptr();
         match node {
             None => None,
+            Some(node_ptr) if atomic_markable::is_frozen(node_ptr) => {
+                self.wait_for_shrink();
+                self.remove_matching(key, matches)
+            },
             Some(node_ptr) => {
                 //println!("nodeptr: {:b}", node_ptr as usize);
-                let data_node = get_data_node(node_ptr);
-                if data_node.value.as_ref() == Some(expected) {
-                    match self.try_remove(&bucket[pos], node_ptr) {
+                self.manager.protect(node_ptr, 0);
+                if bucket[pos].get_ptr() != Some(node_ptr) {
+                    return self.remove_matching(key, matches)
+                }
+                let (entry_ptr, _handle) = match self.find_entry(&bucket[pos], node_ptr, key) {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => return None,
+                    Err(()) => return self.remove_matching(key, matches)
+                };
+                if get_data_node(entry_ptr).value.as_ref().is_some_and(&matches) {
+                    match self.try_remove(&bucket[pos], node_ptr, entry_ptr) {
+                        Err(current) if atomic_markable::is_frozen(current) => {
+                            self.wait_for_shrink();
+                            self.remove_matching(key, matches)
+                        },
                         Err(_) => None,
-                        Ok(()) => {
-                            unsafe {
-                                let owned_node = ptr::replace(node_ptr, Node::Data(DataNode::default()));
-                                if let Node::Data(node) = owned_node {
-                                    let data = node.value;
-                                    self.manager.retire(node_ptr, 0);
-                                    data
-                                } else {
-                                    panic!("Unexpected array node!");
-                                }
-                            }
-                        }
+                        Ok(()) => self.take_removed(node_ptr, entry_ptr)
                     }
                 } else {
                     None
@@ -674,26 +1687,115 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
     where K: Borrow<Q>,
           Q: PartialEq + Hash + Send,
           V: Clone
+    {
+        self.find_and_apply(key, None, |data_node| data_node.value.clone()).unwrap_or(None)
+    }
+
+    /// Retrieves a clone of the element with the given key, giving up if the search takes more steps 
+    /// than the map's step budget.
+    /// # Panics
+    /// This method will panic if the internal state of the HashMap becomes inconsistent.
+    /// # Errors
+    /// Returns `StepBudgetExceeded` if the step budget ran out before the key was found.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::with_max_steps(8);
+    /// map.insert("hello".to_owned(), 8);
+    /// assert_eq!(map.get_bounded("hello"), Ok(Some(8)));
+    /// ``` 
+    pub fn get_bounded<Q>(&self, key: &Q) -> Result<Option<V>, StepBudgetExceeded>
+    where K: Borrow<Q>,
+          Q: ?Sized + PartialEq + Hash + Send,
+          V: Clone
+    {
+        self.find_and_apply(key, self.max_steps, |data_node| data_node.value.clone())
+    }
+
+    /// Retrieves clones of both the stored key and the element for the given key. The stored key 
+    /// may differ from the one used for the lookup if the key type's equality is looser than identity.
+    /// This method is guaranteed to be wait-free.
+    /// # Panics
+    /// This method will panic if the internal state of the HashMap becomes inconsistent.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// assert_eq!(map.get_entry("hello"), Some(("hello".to_owned(), 8)));
+    /// ``` 
+    pub fn get_entry<Q>(&self, key: &Q) -> Option<(K, V)>
+    where K: Borrow<Q> + Clone,
+          Q: ?Sized + PartialEq + Hash + Send,
+          V: Clone
+    {
+        self.find_and_apply(key, None, |data_node| {
+            match (data_node.key(), data_node.value.as_ref()) {
+                (Some(key), Some(value)) => Some((key.clone(), value.clone())),
+                _ => None
+            }
+        }).unwrap_or(None)
+    }
+
+    /// Check whether there is an element with the given key, without cloning or guarding its value.
+    /// An entry which is in the middle of being removed counts as absent. This method is guaranteed to be wait-free.
+    /// # Panics
+    /// This method will panic if the internal state of the HashMap becomes inconsistent.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// assert!(map.contains_key("hello"));
+    /// assert!(!map.contains_key("world"));
+    /// ``` 
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where K: Borrow<Q>,
+          Q: ?Sized + PartialEq + Hash + Send
+    {
+        self.find_and_apply(key, None, |data_node| {
+            if data_node.value.is_some() { Some(()) } else { None }
+        }).unwrap_or(None).is_some()
+    }
+
+    /// Find the DataNode for the given key, and return the result of applying the function to it.
+    /// Returns None if there is no DataNode for the key, or an error if it takes more than `max_steps` to look.
+    fn find_and_apply<Q, F, R>(&self, key: &Q, max_steps: Option<usize>, func: F) -> Result<Option<R>, StepBudgetExceeded>
+    where K: Borrow<Q>,
+          Q: ?Sized + PartialEq + Hash + Send,
+          F: FnOnce(&DataNode<K, V, H>) -> Option<R>
     {
         let hash = self.hash(key);
         let mut mut_hash = hash;
         let mut r = 0usize;
         let mut bucket = &self.head;
+        let mut array_guard = None;
+        let mut steps = 0;
 
-        while r < (KEY_SIZE - self.shift_step) {
-            let pos = mut_hash as usize & (bucket.len() - 1);
-            mut_hash >>= self.shift_step;
+        while r < (H::BITS - CHILD_BITS) {
+            steps += 1;
+            if over_budget(steps, max_steps) {
+                return Err(StepBudgetExceeded)
+            }
+            let pos = mut_hash.low_bits() & (bucket.len() - 1);
+            let level_bits = slot_bits(bucket);
+            mut_hash >>= level_bits;
             let mut node = bucket[pos].get_ptr();
 
             match node {
-                None => { return None; }
+                None => { return Ok(None); }
+                Some(node_ptr) if atomic_markable::is_frozen(node_ptr) => {
+                    self.wait_for_shrink();
+                    return self.find_and_apply(key, remaining_budget(steps, max_steps), func)
+                },
                 Some(mut node_ptr) => {
                     if atomic_markable::is_marked(node_ptr) {
+                        steps += 1;
                         let new_bucket_ptr = self.expand_map(bucket, pos, r);
                         node_ptr = new_bucket_ptr;
                         /* if atomic_markable::is_marked_second(new_bucket_ptr) {
                             //println!("hello 1: {:b}", new_bucket_ptr as usize);
-                            bucket = get_bucket(new_bucket_ptr);
+                            bucket = match self.enter_bucket(&bucket[pos], new_bucket_ptr, &mut array_guard) {
+                                Some(next) => next,
+                                None => return self.find_and_apply(key, remaining_budget(steps, max_steps), func)
+                            };
                             //println!("fart");
                         } else {
                             //println!("hello 2");
@@ -702,8 +1804,11 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                         } */
                     }
                     if atomic_markable::is_marked_second(node_ptr) {
-                        bucket = get_bucket(node_ptr);
-                        r += self.shift_step;
+                        bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                            Some(next) => next,
+                            None => return self.find_and_apply(key, remaining_budget(steps, max_steps), func)
+                        };
+                        r += level_bits;
                         continue;
                     } else {
                         self.manager.protect(atomic_markable::unmark(node_ptr), 0);
@@ -711,18 +1816,33 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                         if node != bucket[pos].get_ptr() {
                             let mut fail_count = 0;
                             while node != bucket[pos].get_ptr() {
+                                steps += 1;
+                                if over_budget(steps, max_steps) {
+                                    return Err(StepBudgetExceeded)
+                                }
                                 node = bucket[pos].get_ptr();
                                 match node {
-                                    None => { return None },
+                                    None => { return Ok(None) },
+                                    Some(new_ptr) if atomic_markable::is_frozen(new_ptr) => {
+                                        self.wait_for_shrink();
+                                        return self.find_and_apply(key, remaining_budget(steps, max_steps), func)
+                                    },
                                     Some(new_ptr) => {
                                         self.manager.protect(atomic_markable::unmark(atomic_markable::unmark_second(new_ptr)), 0);
                                         fail_count += 1;
-                                        if fail_count > MAX_FAILURES {
+                                        if fail_count > self.max_failures {
                                             bucket[pos].mark();
                                             // Force a bucket update
                                             //println!("hello");
                                             node_ptr = self.expand_map(bucket, pos, r);
-                                            bucket = get_bucket(node_ptr);
+                                            if atomic_markable::is_frozen(node_ptr) {
+                                                self.wait_for_shrink();
+                                                return self.find_and_apply(key, remaining_budget(steps, max_steps), func)
+                                            }
+                                            bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                                                Some(next) => next,
+                                                None => return self.find_and_apply(key, remaining_budget(steps, max_steps), func)
+                                            };
                                             //println!("fart");
                                             break;
                                         }
@@ -732,635 +1852,2750 @@ impl<K: Hash + Send, V: Send> HashMap<K, V> {
                             }
                             // Hazard pointer should be fine now
                             if atomic_markable::is_marked(node_ptr) {
-                                bucket = get_bucket(self.expand_map(bucket, pos, r));
-                                r += self.shift_step;
+                                bucket = match self.enter_bucket(&bucket[pos], self.expand_map(bucket, pos, r), &mut array_guard) {
+                                    Some(next) => next,
+                                    None => return self.find_and_apply(key, remaining_budget(steps, max_steps), func)
+                                };
+                                r += level_bits;
                                 continue;
                             } else if atomic_markable::is_marked_second(node_ptr) {
-                                bucket = get_bucket(node_ptr);
-                                r += self.shift_step;
+                                bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                                    Some(next) => next,
+                                    None => return self.find_and_apply(key, remaining_budget(steps, max_steps), func)
+                                };
+                                r += level_bits;
                                 continue;
                             }
                         }
-                        let data_node = get_data_node(node_ptr);
-                        if data_node.hash == hash {
-                            return data_node.value.clone();
-                        } else {
-                            return None
-                        }
+                        return self.apply_to_entry(&bucket[pos], node_ptr, key, remaining_budget(steps, max_steps), func)
                     }
                 }
             }
         }
         // We should only be here if we got to the bottom
-        let pos = mut_hash as usize & (CHILD_SIZE - 1);
-        if let Some(node_ptr) = bucket[pos].get_ptr() {
-            unsafe {
-                match &*node_ptr {
-                    &Node::Array(_) => panic!("Unexpected array node!"),
-                    &Node::Data(ref data_node) => {
-                        return data_node.value.clone()
-                    }
+        let pos = mut_hash.low_bits() & (CHILD_SIZE - 1);
+        match bucket[pos].get_ptr() {
+            None => Ok(None),
+            Some(node_ptr) if atomic_markable::is_frozen(node_ptr) => {
+                self.wait_for_shrink();
+                self.find_and_apply(key, remaining_budget(steps, max_steps), func)
+            },
+            Some(node_ptr) => {
+                self.manager.protect(node_ptr, 0);
+                // The retry only gets what is left of the budget
+                if bucket[pos].get_ptr() != Some(node_ptr) {
+                    return self.find_and_apply(key, remaining_budget(steps, max_steps), func)
                 }
+                self.apply_to_entry(&bucket[pos], node_ptr, key, remaining_budget(steps, max_steps), func)
             }
-        } else {
-            return None
         }
     }
 
-    fn try_remove(&self, position: &AtomicMarkablePtr<Node<K, V>>, old: *mut Node<K, V>) -> Result<(), *mut Node<K, V>> {
-        match position.compare_exchange(old, ptr::null_mut()) {
-            Ok(_) => Ok(()),
-            Err(current) => Err(current)
+    /// Apply the function to the data node for the given key in the data or collision node taken from `slot`.
+    /// `max_steps` is what is left of the budget of the search which found the node.
+    fn apply_to_entry<Q, F, R>(&self, slot: &AtomicMarkablePtr<Node<K, V, H>>, node_ptr: *mut Node<K, V, H>, key: &Q, max_steps: Option<usize>, func: F) -> Result<Option<R>, StepBudgetExceeded>
+    where K: Borrow<Q>,
+          Q: ?Sized + PartialEq + Hash + Send,
+          F: FnOnce(&DataNode<K, V, H>) -> Option<R>
+    {
+        match self.find_entry(slot, node_ptr, key) {
+            Err(()) => self.find_and_apply(key, max_steps, func),
+            Ok(None) => Ok(None),
+            Ok(Some((entry_ptr, _handle))) => Ok(func(get_data_node(entry_ptr)))
         }
     }
 
-    /// Obtain an iterator over values in the HashMap. No ordering is guaranteed.
-    pub fn iter(&self) -> Iter<K, V> {
-        Iter::new(&self.head, &self.manager)
-    }
-}
-
-fn get_bucket<'a, K: Send, V: Send>(node_ptr: *mut Node<K, V>) -> &'a Vec<AtomicMarkablePtr<Node<K, V>>> {
-    unsafe {
-        match &*(atomic_markable::unmark_second(node_ptr)) {
-            &Node::Data(_) => panic!("Unexpected data node!: {:b}", node_ptr as usize),
-            &Node::Array(ref array_node) => &array_node.array
+    /// Attempt to remove an entry from the data or collision node in the given position. The position
+    /// is left empty if there are no other entries.
+    fn try_remove(&self, position: &AtomicMarkablePtr<Node<K, V, H>>, old: *mut Node<K, V, H>, entry: *mut Node<K, V, H>) -> Result<(), *mut Node<K, V, H>> {
+        let replacement = replacement_node(old, entry, ptr::null_mut());
+        match position.compare_exchange(old, replacement) {
+            Ok(_) => Ok(()),
+            Err(current) => {
+                free_replacement(replacement);
+                Err(current)
+            }
         }
     }
-}
 
-fn get_data_node<'a, K: Send, V: Send>(node_ptr: *mut Node<K, V>) -> &'a DataNode<K, V> {
-    unsafe {
-        match &*(atomic_markable::unmark(node_ptr)) {
-            &Node::Data(ref data_node) => data_node,
-            &Node::Array(_) => panic!("Unexpected array node!: {:b}", node_ptr as usize)
-        }
-    }
-}
+    /// Force the slot which the given key hashes to into an ArrayNode, if it currently holds a DataNode.
+    /// This can be used to spread out keys which are known to be contended before the contention happens.
+    /// Returns true if the slot now holds an ArrayNode, or false if the slot is empty or is at the bottom
+    /// of the tree, where it cannot be expanded.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// assert!(map.force_expand("hello"));
+    /// assert_eq!(map.get_clone("hello"), Some(8));
+    /// ```
+    pub fn force_expand<Q>(&self, key: &Q) -> bool
+    where K: Borrow<Q>,
+          Q: ?Sized + Hash + Send
+    {
+        let hash = self.hash(key);
+        let mut mut_hash = hash;
+        let mut r = 0usize;
+        let mut bucket = &self.head;
+        let mut array_guard = None;
 
-impl<K, V> Debug for HashMap<K, V> 
-where K: PartialEq + Hash + Send + Debug,
-      V: Send + Debug
-{
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // Depth first printing, tab on each depth level
-        let mut string = "".to_owned();
-        let mut none_count = 0;
-        for node in &self.head {
-            if let Some(mut node_ptr) = node.get_ptr() {
-                string.push_str("\n");
-                if none_count > 0 {
-                    string.push_str(&format!("None x {}\n", none_count));
-                    none_count = 0;
-                }
-                node_ptr = atomic_markable::unmark_second(atomic_markable::unmark(node_ptr));
-                unsafe {
-                    match &*node_ptr {
-                        &Node::Array(ref array_node) => {array_node.to_string(&mut string, 1);},
-                        &Node::Data(ref data_node) => {string.push_str(&format!("{:X} ==> {:?}", data_node.hash, data_node.value));}
+        while r < (H::BITS - CHILD_BITS) {
+            let pos = mut_hash.low_bits() & (bucket.len() - 1);
+            let level_bits = slot_bits(bucket);
+            mut_hash >>= level_bits;
+
+            loop {
+                match bucket[pos].get_ptr() {
+                    None => { return false; },
+                    Some(node_ptr) if atomic_markable::is_frozen(node_ptr) => {
+                        self.wait_for_shrink();
+                        return self.force_expand(key)
+                    },
+                    Some(node_ptr) => {
+                        if atomic_markable::is_marked_second(node_ptr) {
+                            bucket = match self.enter_bucket(&bucket[pos], node_ptr, &mut array_guard) {
+                                Some(next) => next,
+                                None => return self.force_expand(key)
+                            };
+                            break;
+                        }
+                        // Once the slot is marked, it can only be replaced by an ArrayNode
+                        let marked = if atomic_markable::is_marked(node_ptr) {
+                            Ok(node_ptr)
+                        } else {
+                            bucket[pos].compare_and_mark(node_ptr)
+                        };
+                        if marked.is_ok() {
+                            let new_ptr = self.expand_map(bucket, pos, r);
+                            self.manager.unprotect(0);
+                            return atomic_markable::is_marked_second(new_ptr);
+                        }
                     }
                 }
-            } else {
-                none_count += 1;
             }
+            r += level_bits;
         }
-        if none_count > 0 {
-            string.push_str(&format!("None x {}", none_count));
-        }
+        false
+    }
 
-        write!(f, "{}", string)
+    /// Obtain an iterator over values in the HashMap. No ordering is guaranteed.
+    pub fn iter(&self) -> Iter<'_, K, V, H> {
+        Iter { nodes: DataNodes::new(self.walk()) }
     }
-}
 
-pub struct Iter<'a, K: Send + 'a, V: Send + 'a> {
-    current_array: &'a Vec<AtomicMarkablePtr<Node<K, V>>>,
-    index: usize,
-    node_stack: Vec<&'a Vec<AtomicMarkablePtr<Node<K, V>>>>,
-    manager: &'a HPBRManager<Node<K, V>>
-}
+    /// Obtain an iterator over the keys in the HashMap, each protected by a DataGuard rather than cloned.
+    /// No ordering is guaranteed. HashSet keeps its values as the keys of a map, and iterates over them with this.
+    pub(crate) fn key_guards(&self) -> KeyGuards<'_, K, V, H> {
+        KeyGuards { nodes: DataNodes::new(self.walk()) }
+    }
 
-impl<'a, K: Send, V: Send> Iter<'a, K, V> {
-    fn new(start: &'a Vec<AtomicMarkablePtr<Node<K, V>>>, manager: &'a HPBRManager<Node<K, V>>) -> Self {
-        Self {
-            current_array: start,
-            index: 0,
-            node_stack: Vec::new(),
-            manager
-        }
+    /// The number of entries in the map. The count is updated separately from the tree, so while other
+    /// threads are inserting or removing it is only eventually consistent, but it is exact once they stop.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// assert_eq!(map.len(), 1);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
     }
-}
 
-impl<'a, K: Send, V: Send> Iterator for Iter<'a, K, V> {
-    type Item = DataGuard<'a, V, Node<K, V>>;
-    fn next(&mut self) -> Option<Self::Item> {
-        let index = self.index;
-        self.index += 1;
-        if index < self.current_array.len() {
-            // Check if data or array
-            match self.current_array[index].get_ptr() {
-                Some(mut node_ptr) => {
-                    // Protect with a HPHandle
-                    if atomic_markable::is_marked(node_ptr) {
-                        // Protect
-                        let mut hphandle = self.manager.protect_dynamic(atomic_markable::unmark(node_ptr));
-                        // need to loop here
-                        while Some(node_ptr) != self.current_array[index].get_ptr() {
-                            let new_node = self.current_array[index].get_ptr();
-                            match new_node {
-                                None => return self.next(),
-                                Some(new_ptr) => {
-                                    hphandle = self.manager.protect_dynamic(atomic_markable::unmark(atomic_markable::unmark_second(node_ptr)));
-                                    if atomic_markable::is_marked_second(new_ptr) {
-                                        let bucket = get_bucket(new_ptr);
-                                        self.node_stack.push(bucket);
-                                        return self.next()
-                                    }
-                                    node_ptr = new_ptr;
-                                }
-                            }
-                        }
-                        let data_node = get_data_node(atomic_markable::unmark(node_ptr));
-                        Some(DataGuard::new(&data_node.value.as_ref().unwrap(), hphandle))
-                    } else if atomic_markable::is_marked_second(node_ptr) {
-                        let bucket = get_bucket(node_ptr);
-                        self.node_stack.push(bucket);
-                        return self.next()
-                    } else {
-                        let mut hphandle = self.manager.protect_dynamic(node_ptr);
-                        while Some(node_ptr) != self.current_array[index].get_ptr() {
-                            let new_node = self.current_array[index].get_ptr();
-                            match new_node {
-                                None => return self.next(),
-                                Some(new_ptr) => {
-                                    hphandle = self.manager.protect_dynamic(atomic_markable::unmark(atomic_markable::unmark_second(node_ptr)));
-                                    if atomic_markable::is_marked_second(new_ptr) {
-                                        let bucket = get_bucket(new_ptr);
-                                        self.node_stack.push(bucket);
-                                        return self.next()
-                                    }
-                                    node_ptr = new_ptr;
-                                }
-                            }
-                        }
+    /// Check whether the map has no entries, with the same caveats as `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-                        let data_node = get_data_node(atomic_markable::unmark(node_ptr));
-                        Some(DataGuard::new(&data_node.value.as_ref().unwrap(), hphandle))
+    /// Remove every entry from the HashMap. The ArrayNodes are left in place, so the tree keeps its
+    /// shape and later inserts do not need to expand it again.
+    ///
+    /// The removed nodes are retired, so threads still reading them are safe. Entries inserted while
+    /// the map is being cleared may or may not be removed.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// map.clear();
+    /// assert_eq!(map.get_clone("hello"), None);
+    /// assert!(map.is_empty());
+    /// ```
+    pub fn clear(&self) {
+        let mut walk = self.walk();
+        while let Some((bucket, pos, r)) = walk.next_slot() {
+            let mut fail_count = 0;
+            while let Some(node_ptr) = bucket[pos].get_ptr() {
+                if atomic_markable::is_frozen(node_ptr) {
+                    // The ArrayNode is being collapsed, so clear the slot its child is moved to instead
+                    if walk.thaw() {
+                        continue;
                     }
-                },
-                None => {
-                    return self.next()
+                    break;
+                }
+                if atomic_markable::is_marked_second(node_ptr) {
+                    if walk.enter(node_ptr) {
+                        break;
+                    }
+                    continue;
+                }
+                // Slots at the bottom of the tree can never be expanded
+                if r < (H::BITS - CHILD_BITS) && (atomic_markable::is_marked(node_ptr) || fail_count > self.max_failures) {
+                    // Finish expanding the slot and clear the new ArrayNode instead
+                    bucket[pos].mark();
+                    self.expand_map(bucket, pos, r);
+                    continue;
                 }
+                match bucket[pos].compare_exchange(node_ptr, ptr::null_mut()) {
+                    Ok(_) => {
+                        self.retire_cleared(node_ptr);
+                        break;
+                    },
+                    Err(_) => fail_count += 1
+                }
+            }
+        }
+    }
+
+    /// Retire a data or collision node which has just been swapped out of the map, along with its entries.
+    fn retire_cleared(&self, node_ptr: *mut Node<K, V, H>) {
+        if let Node::Collision(ref collision_node) = unsafe { &*node_ptr } {
+            for &entry_ptr in &collision_node.entries {
+                self.manager.retire(entry_ptr, 0);
             }
+            self.len.fetch_sub(collision_node.entries.len(), Ordering::Relaxed);
         } else {
-            match self.node_stack.pop() {
-                Some(array) => {
-                    self.index = 0;
-                    self.current_array = array;
-                    return self.next()
-                },
-                None => None
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.manager.retire(node_ptr, 0);
+    }
+
+    /// Remove every entry for which the function returns false, keeping the rest. The removed entries are
+    /// retired, so threads still reading them are safe. Each entry is removed with the same CAS on its slot
+    /// as `remove`, so an entry inserted or updated in the same slot at the same time is never lost, but
+    /// entries inserted or updated while the map is being walked may or may not be checked.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// map.insert("world".to_owned(), 9);
+    /// map.retain(|_, &value| value > 8);
+    /// assert_eq!(map.keys().collect::<Vec<String>>(), vec!["world".to_owned()]);
+    /// ```
+    pub fn retain<F>(&self, f: F)
+    where F: Fn(&K, &V) -> bool
+    {
+        let rejected = |data_node: &DataNode<K, V, H>| match (data_node.key(), data_node.value.as_ref()) {
+            (Some(key), Some(value)) => !f(key, value),
+            _ => false
+        };
+        let mut walk = self.walk();
+        while let Some((bucket, pos, r)) = walk.next_slot() {
+            let mut fail_count = 0;
+            while let Some(node_ptr) = bucket[pos].get_ptr() {
+                if atomic_markable::is_frozen(node_ptr) {
+                    // The ArrayNode is being collapsed, so check the slot its child is moved to instead
+                    if walk.thaw() {
+                        continue;
+                    }
+                    break;
+                }
+                if atomic_markable::is_marked_second(node_ptr) {
+                    if walk.enter(node_ptr) {
+                        break;
+                    }
+                    continue;
+                }
+                // Slots at the bottom of the tree can never be expanded
+                if r < (H::BITS - CHILD_BITS) && (atomic_markable::is_marked(node_ptr) || fail_count > self.max_failures) {
+                    // Finish expanding the slot and walk the new ArrayNode instead
+                    bucket[pos].mark();
+                    self.expand_map(bucket, pos, r);
+                    continue;
+                }
+                self.manager.protect(atomic_markable::unmark(node_ptr), 0);
+                if bucket[pos].get_ptr() != Some(node_ptr) {
+                    fail_count += 1;
+                    continue;
+                }
+                // Remove one entry at a time, until every entry left in the slot is kept
+                let (entry_ptr, _handle) = match self.find_entry_where(&bucket[pos], node_ptr, rejected) {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => { break; },
+                    Err(()) => {
+                        fail_count += 1;
+                        continue;
+                    }
+                };
+                match self.try_remove(&bucket[pos], node_ptr, entry_ptr) {
+                    Ok(()) => { self.take_removed(node_ptr, entry_ptr); },
+                    Err(_) => fail_count += 1
+                }
             }
         }
+        self.manager.unprotect(0);
     }
 
-}
+    /// Iterate over clones of every key in the HashMap. No ordering is guaranteed. The keys are collected
+    /// when this is called, so keys inserted or removed while the map is being walked may or may not be included.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// assert_eq!(map.keys().collect::<Vec<String>>(), vec!["hello".to_owned()]);
+    /// ```
+    pub fn keys(&self) -> impl Iterator<Item = K>
+    where K: Clone
+    {
+        self.keys_matching(|_| true).into_iter()
+    }
 
-impl<K, V> Default for HashMap<K, V>
-where K: PartialEq + Hash + Send,
-      V: PartialEq + Send 
-{
-    fn default() -> Self {
-        HashMap::new()
+    /// Iterate over clones of every value in the HashMap. No ordering is guaranteed. The values are collected
+    /// when this is called, so values inserted or removed while the map is being walked may or may not be included.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// assert_eq!(map.values().sum::<u8>(), 8);
+    /// ```
+    pub fn values(&self) -> impl Iterator<Item = V>
+    where V: Clone
+    {
+        let mut values = Vec::new();
+        self.for_each_data_node(|data_node| {
+            if let Some(ref value) = data_node.value {
+                values.push(value.clone());
+            }
+        });
+        values.into_iter()
     }
-}
 
-pub enum Node<K: Send, V: Send> {
-    Data(DataNode<K, V>),
-    Array(ArrayNode<K, V>)
-}
+    /// Collect clones of every key in the HashMap for which the predicate returns true. No ordering
+    /// is guaranteed, and keys inserted or removed while the map is being walked may or may not be included.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("tenant1/hello".to_owned(), 8);
+    /// map.insert("tenant2/hello".to_owned(), 9);
+    /// assert_eq!(map.keys_matching(|key| key.starts_with("tenant1/")), vec!["tenant1/hello".to_owned()]);
+    /// ```
+    pub fn keys_matching<F>(&self, predicate: F) -> Vec<K>
+    where F: Fn(&K) -> bool,
+          K: Clone
+    {
+        let mut keys = Vec::new();
+        self.for_each_data_node(|data_node| {
+            if let Some(key) = data_node.key() {
+                if predicate(key) {
+                    keys.push(key.clone());
+                }
+            }
+        });
+        keys
+    }
 
-pub struct DataNode<K: Send, V: Send> {
-    value: Option<V>,
-    hash: u64,
-    _marker: PhantomData<K>
-}
+    /// Remove every entry whose hash starts with the given prefix and return them in a new map, for
+    /// migrating a range of hashes to another shard. The first `bits` bits of the hash which the map
+    /// consumes (the lowest bits, or the highest with `BitOrder::HighFirst`) are compared against the
+    /// lowest `bits` bits of `prefix`. The new map uses the same hasher and settings as this one, so
+    /// hashes can be compared between them.
+    ///
+    /// This is best-effort under concurrency: entries inserted while the map is being walked may be left
+    /// behind, and an entry which is updated or removed and reinserted during the split may end up in either map.
+    /// # Panics
+    /// This method will panic if `bits` is more than the size of the map's hashes: 64, or 128 for maps created
+    /// with `with_wide_hashes`.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// let shard = map.split_off_prefix(0, 0); // An empty prefix matches every entry
+    /// assert_eq!(shard.get_clone("hello"), Some(8));
+    /// assert!(!map.contains_key("hello"));
+    /// ```
+    pub fn split_off_prefix(&self, prefix: H, bits: usize) -> HashMap<K, V, S, H>
+    where K: Clone + PartialEq,
+          S: Clone
+    {
+        if bits > H::BITS {
+            panic!("A prefix can be at most {} bits, got {}!", H::BITS, bits)
+        }
+        let mask = H::low_mask(bits);
+        let mut keys = Vec::new();
+        self.for_each_data_node(|data_node| {
+            if let Some(key) = data_node.key() {
+                if data_node.hash & mask == prefix & mask {
+                    keys.push(key.clone());
+                }
+            }
+        });
 
-impl<K: Send, V: Send> DataNode<K, V> {
-    fn new(value: V, hash: u64) -> Self {
-        DataNode {
-            value: Some(value),
-            hash,
-            _marker: PhantomData
+        let shard = HashMapBuilder {
+            head_size: self.head_size,
+            bit_order: self.bit_order,
+            hasher: self.hasher.clone(),
+            wide_hasher: self.wide_hasher.clone(),
+            max_steps: self.max_steps,
+            max_failures: self.max_failures,
+            retire_capacity: 0,
+            hash_bits: PhantomData
+        }.build();
+        for key in keys {
+            if let Some(value) = self.remove(&key) {
+                // No other thread can see the shard yet, but a key which was removed and inserted again
+                // during the walk may have been collected twice, so the value removed last wins
+                shard.insert(key, value);
+            }
         }
+        shard
     }
-}
 
-impl<K: Send, V: Send> Default for DataNode<K, V> {
-    fn default() -> Self {
-        DataNode {
-            value: None,
-            hash: 0,
-            _marker: PhantomData
+    /// Walk the whole tree, calling the function on every DataNode which still holds a value. Each 
+    /// node is protected by a hazard pointer for the duration of the call.
+    fn for_each_data_node<F>(&self, mut func: F)
+    where F: FnMut(&DataNode<K, V, H>)
+    {
+        for (data_node, _handle) in DataNodes::new(self.walk()) {
+            func(data_node);
         }
     }
-}
 
-pub struct ArrayNode<K: Send, V: Send> {
-    array: Vec<AtomicMarkablePtr<Node<K, V>>>,
-    size: usize
-}
+    /// Count the data nodes found at each depth of the tree, where the head is depth 0. The count 
+    /// for depth `d` is at index `d` of the returned vector. This is intended for diagnosing how well 
+    /// the keys are spread out, and may be inaccurate while other threads are modifying the map.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// assert_eq!(map.depth_histogram(), vec![1]);
+    /// ```
+    pub fn depth_histogram(&self) -> Vec<usize> {
+        let mut histogram = Vec::new();
+        let mut walk = self.walk();
+        while walk.next_leaf().is_some() {
+            let depth = walk.depth();
+            if histogram.len() <= depth {
+                histogram.resize(depth + 1, 0);
+            }
+            histogram[depth] += 1;
+        }
+        histogram
+    }
 
-impl<K: Send, V: Send> ArrayNode<K, V> {
-    fn new(size: usize) -> Self {
-        let mut array = Vec::with_capacity(size);
-        for _ in 0..size {
-            array.push(AtomicMarkablePtr::default());
+    /// Summarise the shape of the tree: how deep the data nodes sit and how many ArrayNodes have been
+    /// created by expansion. Keys whose hashes share many bits end up deep in the tree, behind a chain of
+    /// ArrayNodes, so a large maximum depth points to a poor hash or a head which is too small. Like
+    /// `depth_histogram`, this may be inaccurate while other threads are modifying the map.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// let stats = map.depth_stats();
+    /// assert_eq!(stats.max_depth, 0);
+    /// assert_eq!(stats.array_nodes, 0);
+    /// ```
+    pub fn depth_stats(&self) -> DepthStats {
+        let mut max_depth = 0;
+        let mut total_depth = 0;
+        let mut data_nodes = 0;
+        let mut walk = self.walk();
+        while walk.next_leaf().is_some() {
+            let depth = walk.depth();
+            max_depth = max_depth.max(depth);
+            total_depth += depth;
+            data_nodes += 1;
         }
+        DepthStats {
+            max_depth,
+            average_depth: if data_nodes == 0 { 0.0 } else { total_depth as f64 / data_nodes as f64 },
+            array_nodes: walk.entered
+        }
+    }
 
-        ArrayNode {
-            array,
-            size
+    /// Collapse ArrayNodes left sparse by removals. An ArrayNode holding a single data or collision node
+    /// is replaced by that node in its parent, and an empty ArrayNode is removed entirely, working up
+    /// from the bottom of the tree so that whole chains collapse at once. This shortens the path to the
+    /// keys left in them.
+    ///
+    /// Other threads may use the map while it shrinks. Every slot of an ArrayNode is frozen before it is
+    /// collapsed, so that nothing more can be inserted into it, and the ArrayNode is then swapped for its
+    /// child in the parent slot with a CAS. An operation which finds a frozen slot waits for the collapse
+    /// to finish and starts again from the head, so operations on the keys below an ArrayNode block for
+    /// as long as it is being collapsed.
+    ///
+    /// Operations protect every ArrayNode they walk down into, so a collapsed ArrayNode is retired to the
+    /// manager like any other removed node. Its slots stay frozen, for threads which are still walking it.
+    /// Only one thread shrinks the map at a time.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// map.force_expand("hello");
+    /// map.shrink();
+    /// assert_eq!(map.depth_histogram(), vec![1]);
+    /// ```
+    pub fn shrink(&self) {
+        let _shrinking = self.shrink_lock.lock().unwrap();
+        for slot in &self.head {
+            self.shrink_slot(slot);
         }
     }
 
-    pub unsafe fn to_string(&self, start: &mut String, depth: usize)
-    where K: Debug,
-          V: Debug 
-    {
-        let mut none_count = 0;
-        start.push_str("\n");
-        for _ in 0..depth {
-            start.push_str("\t");
+    /// Collapse the ArrayNodes below a slot, then the ArrayNode in the slot itself if it is left with at
+    /// most one child which is not an ArrayNode. The depth of the tree is bounded, so this cannot overflow the stack.
+    fn shrink_slot(&self, slot: &AtomicMarkablePtr<Node<K, V, H>>) {
+        let node_ptr = match slot.get_ptr() {
+            Some(node_ptr) if atomic_markable::is_marked_second(node_ptr) => node_ptr,
+            _ => return
+        };
+        let bucket = get_bucket(node_ptr);
+        for child in bucket {
+            self.shrink_slot(child);
         }
-        start.push_str("ArrayNode: ");
-        for markable in &self.array {
-            if let Some(mut node_ptr) = markable.get_ptr() {
-                start.push_str("\n");
-                for _ in 0..depth {
-                    start.push_str("\t");
-                }
-                if none_count > 0 {
-                    start.push_str(&format!("None x {}\n", none_count));
-                    for _ in 0..depth {
-                        start.push_str("\t");
-                    }
-                    none_count = 0;
-                }
-                node_ptr = atomic_markable::unmark_second(atomic_markable::unmark(node_ptr));
-                match &*node_ptr {
-                    &Node::Array(ref array_node) => {
-                        array_node.to_string(start, depth + 1);
-                    },
-                    &Node::Data(ref data_node) => {
-                        start.push_str(&format!("{:X} ==> {:?}", data_node.hash, data_node.value));
+        // Leave busy ArrayNodes alone rather than freezing them
+        if bucket.iter().filter(|child| child.get_ptr().is_some()).count() > 1 {
+            return
+        }
+
+        self.shrinking.store(true, Ordering::SeqCst);
+        if let Some(lifted) = freeze_bucket(bucket) {
+            match slot.compare_exchange(node_ptr, lifted) {
+                Ok(_) => {
+                    // Frozen slots do not own their nodes, so this frees the ArrayNode without the node lifted out of it
+                    self.manager.retire(atomic_markable::unmark_second(node_ptr), 0);
+                },
+                Err(_) => {
+                    for child in bucket {
+                        child.unfreeze();
                     }
                 }
-            } else {
-                none_count += 1;
-            }
-        }
-        if none_count > 0 {
-            start.push_str("\n");
-            for _ in 0..depth {
-                start.push_str("\t");
             }
-            start.push_str(&format!("None x {}", none_count));
         }
+        self.shrinking.store(false, Ordering::SeqCst);
     }
-}
 
+    /// Wait for shrink to finish collapsing an ArrayNode, after finding one of its frozen slots. The
+    /// ArrayNode may have been taken out of the tree, so the caller has to start again from the head.
+    fn wait_for_shrink(&self) {
+        wait_while_shrinking(&self.shrinking);
+    }
 
-mod tests {
-    #![allow(unused_imports)]
-    extern crate im;
-    use self::im::Map;
+    /// Check whether nodes replaced by inserts, updates and removals are piling up unfreed.
+    pub fn has_reclaim_pressure(&self) -> bool {
+        self.manager.has_reclaim_pressure()
+    }
+}
 
-    use rand::{thread_rng, Rng};
+/// Spin until the shrinking flag of a map is cleared.
+fn wait_while_shrinking(shrinking: &AtomicBool) {
+    while shrinking.load(Ordering::Acquire) {
+        thread::yield_now();
+    }
+}
 
-    use super::HashMap;
-    use std::sync::Arc;
-    use std::thread;
-    use std::thread::JoinHandle;
-    use std::hash::Hash;
-    use std::fmt::Debug;
-    use super::super::super::super::testing::{LinearizabilityTester, LinearizabilityResult, ThreadLog};
+/// Check whether the number of steps taken is more than the budget, if there is one.
+fn over_budget(steps: usize, max_steps: Option<usize>) -> bool {
+    match max_steps {
+        Some(max) => steps > max,
+        None => false
+    }
+}
 
-    #[test]
-     
-    fn test_data_guard() {
-        let map: HashMap<u8, u8> = HashMap::new();
+/// The budget left after the number of steps taken, for a search which has to start again.
+fn remaining_budget(steps: usize, max_steps: Option<usize>) -> Option<usize> {
+    max_steps.map(|max| max.saturating_sub(steps))
+}
 
-        let _ = map.insert(23, 23);
-        match map.get(&23) {
-            Some(g) => {
-                assert_eq!(g.data(), &23);
-                assert_eq!(g.cloned(), 23);
-                println!("guard leaving scope");
-            },
-            None => {}
+/// Print each slot in an array on its own line at the given depth, for Debug.
+fn slots_to_string<K, V, H: HashBits>(slots: &Vec<AtomicMarkablePtr<Node<K, V, H>>>, string: &mut String, depth: usize, manager: &HPBRManager<Node<K, V, H>>)
+where K: Send + Debug,
+      V: Send + Debug
+{
+    let mut none_count = 0;
+    for slot in slots {
+        let mut node_string = "".to_owned();
+        if !slot_to_string(slot, &mut node_string, depth, manager) {
+            none_count += 1;
+            continue;
         }
-        println!("guard left scope");
-        let _ = map.insert(24, 24);
-        let _ = map.insert(25, 25);
+        string.push('\n');
+        for _ in 0..depth {
+            string.push('\t');
+        }
+        if none_count > 0 {
+            string.push_str(&format!("None x {}\n", none_count));
+            for _ in 0..depth {
+                string.push('\t');
+            }
+            none_count = 0;
+        }
+        string.push_str(&node_string);
     }
+    if none_count > 0 {
+        string.push('\n');
+        for _ in 0..depth {
+            string.push('\t');
+        }
+        string.push_str(&format!("None x {}", none_count));
+    }
+}
 
-    #[test]
-     
-    fn test_single_thread_semantics() {
-        let map : HashMap<u8, String> = HashMap::new();
-
-        for i in 0..240 {
-            match map.insert(i, format!("{}", i)) {
-                Ok(_) => {},
-                Err(_) => assert!(false)
+/// Print the node in a slot, protecting it while it is read. Returns false if the slot is empty.
+fn slot_to_string<K, V, H: HashBits>(slot: &AtomicMarkablePtr<Node<K, V, H>>, string: &mut String, depth: usize, manager: &HPBRManager<Node<K, V, H>>) -> bool
+where K: Send + Debug,
+      V: Send + Debug
+{
+    loop {
+        let node = slot.get_ptr();
+        let node_ptr = match node {
+            None => return false,
+            Some(node_ptr) if atomic_markable::is_frozen(node_ptr) => {
+                // The node in a frozen slot may already have been moved up the tree by shrink
+                string.push_str("Frozen");
+                return true
+            },
+            Some(node_ptr) => atomic_markable::unmark_second(atomic_markable::unmark(node_ptr))
+        };
+        let _handle = manager.protect_dynamic(node_ptr);
+        if slot.get_ptr() != node {
+            continue;
+        }
+        // Hazard pointer is safe here
+        match unsafe { &*node_ptr } {
+            Node::Array(array_node) => array_node.to_string(string, depth + 1, manager),
+            Node::Data(data_node) => data_node_to_string(data_node, string),
+            Node::Collision(collision_node) => {
+                let mut entries = Vec::new();
+                for &entry_ptr in &collision_node.entries {
+                    let _entry_handle = manager.protect_dynamic(entry_ptr);
+                    // Entries of a replaced collision node may already have been freed
+                    if slot.get_ptr() != node {
+                        break;
+                    }
+                    let mut entry_string = "".to_owned();
+                    data_node_to_string(get_data_node(entry_ptr), &mut entry_string);
+                    entries.push(entry_string);
+                }
+                if slot.get_ptr() != node {
+                    continue;
+                }
+                string.push_str(&format!("[{}]", entries.join(", ")));
             }
         }
-        
-        assert!(map.insert(9, "9".to_owned()).is_err());
+        return true
+    }
+}
 
-        assert_eq!(map.get(&3).unwrap().data(), &"3".to_owned());
-        assert_eq!(map.get(&250), None);
+fn data_node_to_string<K: Send + Debug, V: Send + Debug, H: HashBits>(data_node: &DataNode<K, V, H>, string: &mut String) {
+    match (data_node.key(), data_node.value.as_ref()) {
+        (Some(key), Some(value)) => string.push_str(&format!("{:?} ==> {:?}", key, value)),
+        // The value has been taken by a thread which removed the node
+        _ => string.push_str("<removed>")
+    }
+}
 
-        assert_eq!(map.update(&3, map.get(&3).unwrap().data(), format!("{}", 7)), Ok(()));
-        assert_eq!(map.update(&239, map.get(&239).unwrap().data(), format!("{}", 7)), Ok(()));
-        assert_eq!(map.get(&3).unwrap().data(), &"7".to_owned());
+/// The number of bits of the hash used to pick a slot in the given array. The head array may be any
+/// power of two in size, so this is not the same at every level of the tree.
+fn slot_bits<T>(bucket: &[T]) -> usize {
+    bucket.len().trailing_zeros() as usize
+}
 
-        println!("{:?}", map);
+fn get_bucket<'a, K: Send, V: Send, H: HashBits>(node_ptr: *mut Node<K, V, H>) -> &'a Vec<AtomicMarkablePtr<Node<K, V, H>>> {
+    unsafe {
+        match *atomic_markable::unmark_second(node_ptr) {
+            Node::Array(ref array_node) => &array_node.array,
+            _ => panic!("Unexpected data node!: {:b}", node_ptr as usize)
+        }
+    }
+}
 
-        println!("{:?}", map.get(&3));
-        assert_eq!(map.remove(&3, &"7".to_owned()), Some("7".to_owned()));
-        assert_eq!(map.remove(&250, &"2".to_owned()), None);
+fn get_data_node<'a, K: Send, V: Send, H: HashBits>(node_ptr: *mut Node<K, V, H>) -> &'a DataNode<K, V, H> {
+    unsafe {
+        match *atomic_markable::unmark(node_ptr) {
+            Node::Data(ref data_node) => data_node,
+            _ => panic!("Expected a data node!: {:b}", node_ptr as usize)
+        }
+    }
+}
 
-        assert_eq!(map.get(&3), None);
+/// Get the hash shared by the keys in a data or collision node.
+fn get_node_hash<K: Send, V: Send, H: HashBits>(node_ptr: *mut Node<K, V, H>) -> H {
+    unsafe {
+        match *atomic_markable::unmark(node_ptr) {
+            Node::Data(ref data_node) => data_node.hash,
+            Node::Collision(ref collision_node) => collision_node.hash,
+            Node::Array(_) => panic!("Unexpected array node!: {:b}", node_ptr as usize)
+        }
     }
+}
 
-    #[test]
-     
-    fn test_borrow_string_map() {
-        let map: HashMap<String, u16> = HashMap::new();
-        let _ = map.insert("hello".to_owned(), 8);
-        assert_eq!(map.get_clone("hello"), Some(8));
-        assert_eq!(map.get("hello").unwrap().data(), &8);
-        assert_eq!(map.remove("hello", &8), Some(8));
+/// Allocate the node which should replace a data or collision node once one of its entries has changed.
+/// This is null if there are no entries left, the data node itself if there is only one, and a new
+/// collision node otherwise.
+fn replacement_node<K: Send, V: Send, H: HashBits>(node_ptr: *mut Node<K, V, H>, old_entry: *mut Node<K, V, H>, new_entry: *mut Node<K, V, H>) -> *mut Node<K, V, H> {
+    let mut entries = unsafe { CollisionNode::replace_entry(node_ptr, old_entry, new_entry) };
+    match entries.len() {
+        0 => ptr::null_mut(),
+        1 => entries.pop().unwrap(),
+        _ => {
+            let hash = get_node_hash(node_ptr);
+            Box::into_raw(Box::new(Node::Collision(CollisionNode { entries, hash })))
+        }
+    }
+}
+
+/// Free a replacement node which could not be swapped into the map, leaving its entries alone.
+fn free_replacement<K: Send, V: Send, H: HashBits>(replacement: *mut Node<K, V, H>) {
+    unsafe {
+        if !replacement.is_null() {
+            if let Node::Collision(_) = *replacement {
+                drop(Box::from_raw(replacement));
+            }
+        }
+    }
+}
+
+/// Freeze every slot of an ArrayNode which is about to be collapsed, and return the one data or collision
+/// node in it, or null if it is empty. If the ArrayNode turns out to hold more than one child, or a child
+/// which is an ArrayNode or is being expanded, the slots are unfrozen again and None is returned.
+fn freeze_bucket<K: Send, V: Send, H: HashBits>(bucket: &[AtomicMarkablePtr<Node<K, V, H>>]) -> Option<*mut Node<K, V, H>> {
+    let mut lifted: *mut Node<K, V, H> = ptr::null_mut();
+    for (pos, child) in bucket.iter().enumerate() {
+        loop {
+            let child_ptr = child.get_ptr().unwrap_or(ptr::null_mut());
+            if atomic_markable::is_marked(child_ptr) || atomic_markable::is_marked_second(child_ptr)
+                || (!child_ptr.is_null() && !lifted.is_null())
+            {
+                for frozen in &bucket[..pos] {
+                    frozen.unfreeze();
+                }
+                return None
+            }
+            if child.compare_exchange(child_ptr, atomic_markable::freeze(child_ptr)).is_ok() {
+                if !child_ptr.is_null() {
+                    lifted = child_ptr;
+                }
+                break;
+            }
+        }
+    }
+    Some(lifted)
+}
+
+/// Free the node in a slot and every node below it, leaving the slot empty. Collision nodes do not own
+/// their entries, so those are freed here too. The depth of the tree is bounded, so this cannot overflow the stack.
+fn free_slot<K: Send, V: Send, H: HashBits>(slot: &AtomicMarkablePtr<Node<K, V, H>>) {
+    if let Some(node_ptr) = slot.get_ptr() {
+        slot.store(ptr::null_mut());
+        let node = unsafe { Box::from_raw(atomic_markable::unmark(atomic_markable::unmark_second(node_ptr))) };
+        match *node {
+            Node::Array(ref array_node) => {
+                for child in &array_node.array {
+                    free_slot(child);
+                }
+            },
+            Node::Collision(ref collision_node) => {
+                for &entry_ptr in &collision_node.entries {
+                    unsafe { drop(Box::from_raw(entry_ptr)); }
+                }
+            },
+            Node::Data(_) => {}
+        }
+    }
+}
+
+impl<K, V, S, H> Debug for HashMap<K, V, S, H>
+where K: Send + Debug,
+      V: Send + Debug,
+      H: HashBits
+{
+    /// Print the tree depth first, with one level of indentation per ArrayNode and each entry as `key ==> value`.
+    /// Runs of empty slots are printed as `None x count`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut string = "".to_owned();
+        slots_to_string(&self.head, &mut string, 0, &self.manager);
+        write!(f, "{}", string)
+    }
+}
+
+/// A depth first walk over every slot of the tree. Only the ArrayNodes on the path from the head to
+/// the current slot are protected, so a walk holds at most one hazard pointer for each level of the tree.
+struct Walk<'a, K: Send + 'a, V: Send + 'a, H: HashBits + 'a> {
+    manager: &'a HPBRManager<Node<K, V, H>>,
+    shrinking: &'a AtomicBool,
+    path: Vec<WalkFrame<'a, K, V, H>>,
+    // The number of ArrayNodes walked down into
+    entered: usize
+}
+
+/// An array on the path of a walk, with the position of the next slot to visit.
+struct WalkFrame<'a, K: Send + 'a, V: Send + 'a, H: HashBits + 'a> {
+    bucket: &'a Bucket<K, V, H>,
+    pos: usize,
+    // The number of bits of the hash used above this array
+    r: usize,
+    // The slot the ArrayNode was read from, the pointer read and the handle protecting it. None for the head
+    parent: Option<WalkedNode<'a, K, V, H>>
+}
+
+impl<'a, K: Send, V: Send, H: HashBits> Walk<'a, K, V, H> {
+    fn new(head: &'a Bucket<K, V, H>, manager: &'a HPBRManager<Node<K, V, H>>, shrinking: &'a AtomicBool) -> Self {
+        Walk {
+            manager,
+            shrinking,
+            path: vec![WalkFrame { bucket: head, pos: 0, r: 0, parent: None }],
+            entered: 0
+        }
+    }
+
+    /// Move on to the next slot, and return the array it is in, its position and the number of bits of the
+    /// hash used above it. Returns None once every slot has been visited.
+    fn next_slot(&mut self) -> Option<(&'a Bucket<K, V, H>, usize, usize)> {
+        loop {
+            let frame = self.path.last_mut()?;
+            if frame.pos < frame.bucket.len() {
+                frame.pos += 1;
+                return Some((frame.bucket, frame.pos - 1, frame.r))
+            }
+            self.path.pop();
+        }
+    }
+
+    /// The number of ArrayNodes above the current slot.
+    fn depth(&self) -> usize {
+        self.path.len() - 1
+    }
+
+    /// Walk down into the ArrayNode read from the current slot, before the rest of the slots are visited.
+    /// Returns false if the slot no longer holds the ArrayNode, and the caller should read it again.
+    fn enter(&mut self, array_ptr: *mut Node<K, V, H>) -> bool {
+        let (bucket, pos, r) = {
+            let frame = self.path.last().unwrap();
+            (frame.bucket, frame.pos - 1, frame.r)
+        };
+        let handle = self.manager.protect_dynamic(atomic_markable::unmark_second(array_ptr));
+        if bucket[pos].get_ptr() != Some(array_ptr) {
+            return false
+        }
+        self.path.push(WalkFrame {
+            bucket: get_bucket(array_ptr),
+            pos: 0,
+            r: r + slot_bits(bucket),
+            parent: Some((&bucket[pos], array_ptr, handle))
+        });
+        self.entered += 1;
+        true
+    }
+
+    /// Wait for shrink after finding the current slot frozen. Returns true if the ArrayNode holding the slot
+    /// is still in the tree, and the caller should read the slot again. Otherwise the walk leaves the
+    /// collapsed ArrayNode and returns false. Its child was moved up to the slot the ArrayNode was in, which
+    /// is visited again unless the child was already visited in the ArrayNode.
+    fn thaw(&mut self) -> bool {
+        wait_while_shrinking(self.shrinking);
+        let collapsed = {
+            let frame = self.path.last().unwrap();
+            match frame.parent {
+                Some((slot, array_ptr, _)) if slot.get_ptr() != Some(array_ptr) => {
+                    // The slots of a collapsed ArrayNode stay frozen, so the child can still be found
+                    let lifted = frame.bucket.iter().position(|child| {
+                        let child_ptr = child.get_ptr().unwrap_or(ptr::null_mut());
+                        !atomic_markable::unmark(atomic_markable::unfreeze(child_ptr)).is_null()
+                    });
+                    Some(lifted.is_none_or(|lifted| lifted >= frame.pos - 1))
+                },
+                _ => None
+            }
+        };
+        match collapsed {
+            None => true,
+            Some(revisit) => {
+                self.path.pop();
+                if revisit {
+                    self.path.last_mut().unwrap().pos -= 1;
+                }
+                false
+            }
+        }
+    }
+
+    /// Move on to the next slot holding a data or collision node, walking down into ArrayNodes on the
+    /// way, and return the node read from it. The node is not protected.
+    fn next_leaf(&mut self) -> Option<*mut Node<K, V, H>> {
+        while let Some((bucket, pos, _)) = self.next_slot() {
+            let slot = &bucket[pos];
+            while let Some(node_ptr) = slot.get_ptr() {
+                if atomic_markable::is_frozen(node_ptr) {
+                    if self.thaw() {
+                        continue;
+                    }
+                    break;
+                }
+                if atomic_markable::is_marked_second(node_ptr) {
+                    if self.enter(node_ptr) {
+                        break;
+                    }
+                    continue;
+                }
+                return Some(node_ptr)
+            }
+        }
+        None
+    }
+
+    /// The slot last returned by `next_slot`, or read by `next_leaf`.
+    fn current_slot(&self) -> &'a AtomicMarkablePtr<Node<K, V, H>> {
+        let frame = self.path.last().unwrap();
+        &frame.bucket[frame.pos - 1]
+    }
+
+    /// Like `next_leaf`, but the node is protected by the returned handle.
+    fn next_node(&mut self) -> Option<WalkedNode<'a, K, V, H>> {
+        loop {
+            let node_ptr = self.next_leaf()?;
+            let slot = self.current_slot();
+            let handle = self.manager.protect_dynamic(atomic_markable::unmark(node_ptr));
+            if slot.get_ptr() == Some(node_ptr) {
+                return Some((slot, node_ptr, handle))
+            }
+            // The node changed before it was protected, so visit the slot again
+            self.path.last_mut().unwrap().pos -= 1;
+        }
+    }
+}
+
+/// Every data node in the tree which holds a value, each with the handle protecting it. The entries of a
+/// collision node are protected together, and returned one at a time.
+struct DataNodes<'a, K: Send + 'a, V: Send + 'a, H: HashBits + 'a> {
+    walk: Walk<'a, K, V, H>,
+    pending: Vec<GuardedDataNode<'a, K, V, H>>
+}
+
+impl<'a, K: Send, V: Send, H: HashBits> DataNodes<'a, K, V, H> {
+    fn new(walk: Walk<'a, K, V, H>) -> Self {
+        DataNodes {
+            walk,
+            pending: Vec::new()
+        }
+    }
+}
+
+impl<'a, K: Send, V: Send, H: HashBits> Iterator for DataNodes<'a, K, V, H> {
+    type Item = GuardedDataNode<'a, K, V, H>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.pending.pop() {
+                return Some(entry)
+            }
+            let (slot, node_ptr, handle) = self.walk.next_node()?;
+            match unsafe { &*atomic_markable::unmark(node_ptr) } {
+                Node::Collision(ref collision_node) => {
+                    for &entry_ptr in &collision_node.entries {
+                        let entry_handle = self.walk.manager.protect_dynamic(entry_ptr);
+                        // Skip the rest of the entries if the collision node has been replaced
+                        if slot.get_ptr() != Some(node_ptr) {
+                            break;
+                        }
+                        let data_node = get_data_node(entry_ptr);
+                        if data_node.value.is_some() {
+                            self.pending.push((data_node, entry_handle));
+                        }
+                    }
+                },
+                Node::Data(ref data_node) => {
+                    if data_node.value.is_some() {
+                        return Some((data_node, handle))
+                    }
+                },
+                Node::Array(_) => panic!("Unexpected array node!: {:b}", node_ptr as usize)
+            }
+        }
+    }
+}
+
+/// An iterator over the values of a HashMap, returned by `HashMap::iter`. Entries inserted or removed
+/// while the map is being walked may or may not be included.
+pub struct Iter<'a, K: Send + 'a, V: Send + 'a, H: HashBits + 'a = u64> {
+    nodes: DataNodes<'a, K, V, H>
+}
+
+impl<'a, K: Send, V: Send, H: HashBits> Iterator for Iter<'a, K, V, H> {
+    type Item = DataGuard<'a, V, Node<K, V, H>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (data_node, handle) = self.nodes.next()?;
+            if let Some(ref value) = data_node.value {
+                return Some(DataGuard::new(value, handle))
+            }
+        }
+    }
+}
+
+/// An iterator over the keys of a HashMap, returned by `HashMap::key_guards`. Entries inserted or
+/// removed while the map is being walked may or may not be included.
+pub struct KeyGuards<'a, K: Send + 'a, V: Send + 'a, H: HashBits + 'a = u64> {
+    nodes: DataNodes<'a, K, V, H>
+}
+
+impl<'a, K: Send, V: Send, H: HashBits> Iterator for KeyGuards<'a, K, V, H> {
+    type Item = DataGuard<'a, K, Node<K, V, H>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (data_node, handle) = self.nodes.next()?;
+            if let Some(key) = data_node.key() {
+                return Some(DataGuard::new(key, handle))
+            }
+        }
+    }
+}
+
+/// A view of a single key in a HashMap, returned by `HashMap::entry`. Whether the entry is occupied
+/// or vacant is only a snapshot, since other threads may change the key at any time.
+pub enum Entry<'a, K: Send + 'a, V: Send + 'a, S: 'a = RandomState, H: HashBits + 'a = u64> {
+    /// The key was in the map.
+    Occupied(OccupiedEntry<'a, K, V, S, H>),
+    /// The key was not in the map.
+    Vacant(VacantEntry<'a, K, V, S, H>)
+}
+
+/// An entry for a key which was in the map.
+pub struct OccupiedEntry<'a, K: Send + 'a, V: Send + 'a, S: 'a = RandomState, H: HashBits + 'a = u64> {
+    map: &'a HashMap<K, V, S, H>,
+    key: K
+}
+
+/// An entry for a key which was not in the map.
+pub struct VacantEntry<'a, K: Send + 'a, V: Send + 'a, S: 'a = RandomState, H: HashBits + 'a = u64> {
+    map: &'a HashMap<K, V, S, H>,
+    key: K
+}
+
+impl<'a, K, V, S, H> Entry<'a, K, V, S, H>
+where K: Hash + Send + PartialEq,
+      V: Send,
+      S: BuildHasher,
+      H: HashBits
+{
+    /// The key this entry is for.
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => &entry.key,
+            Entry::Vacant(ref entry) => &entry.key
+        }
+    }
+
+    /// Insert the given value if the key is not in the map, and return a clone of the value the key
+    /// now has. See `HashMap::get_or_insert_with`.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// assert_eq!(map.entry("hello".to_owned()).or_insert(8), 8);
+    /// assert_eq!(map.entry("hello".to_owned()).or_insert(9), 8);
+    /// ```
+    pub fn or_insert(self, default: V) -> V
+    where V: Clone
+    {
+        self.or_insert_with(|| default)
+    }
+
+    /// Insert the value computed by `f` if the key is not in the map, and return a clone of the value the
+    /// key now has. The function is called at most once. See `HashMap::get_or_insert_with`.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// assert_eq!(map.entry("hello".to_owned()).or_insert_with(|| 8), 8);
+    /// ```
+    pub fn or_insert_with<F>(self, f: F) -> V
+    where V: Clone,
+          F: FnOnce() -> V
+    {
+        match self {
+            // An occupied key may have been removed since, so it is inserted the same way
+            Entry::Occupied(entry) => entry.map.get_or_insert_with(entry.key, f),
+            Entry::Vacant(entry) => entry.map.get_or_insert_with(entry.key, f)
+        }
+    }
+
+    /// Replace the value of an occupied key with `f` applied to it, in the same way as `HashMap::update_with`,
+    /// so `f` may be called more than once if other threads change the value at the same time. If the key
+    /// has been removed since the entry was made, the map is left unchanged and the entry becomes vacant.
+    /// A vacant entry is returned as it is, even if another thread has inserted the key since.
+    /// # Examples
+    /// ```
+    /// let map: HashMap<String, u8> = HashMap::new();
+    /// map.insert("hello".to_owned(), 8);
+    /// map.entry("hello".to_owned()).and_modify(|count| count + 1);
+    /// assert_eq!(map.get_clone("hello"), Some(9));
+    /// ```
+    pub fn and_modify<F>(self, f: F) -> Self
+    where F: Fn(&V) -> V
+    {
+        match self {
+            Entry::Occupied(entry) => {
+                if entry.map.update_with(&entry.key, f).is_some() {
+                    Entry::Occupied(entry)
+                } else {
+                    Entry::Vacant(VacantEntry { map: entry.map, key: entry.key })
+                }
+            },
+            Entry::Vacant(entry) => Entry::Vacant(entry)
+        }
+    }
+}
+
+impl<K, V, S> Default for HashMap<K, V, S>
+where K: PartialEq + Hash + Send,
+      V: PartialEq + Send,
+      S: BuildHasher + Default
+{
+    fn default() -> Self {
+        HashMap::with_hasher(S::default())
+    }
+}
+
+impl<K, V, S, H> PartialEq for HashMap<K, V, S, H>
+where K: Hash + Send + PartialEq,
+      V: Send + PartialEq,
+      S: BuildHasher,
+      H: HashBits
+{
+    /// Two maps are equal if they have the same number of entries, and every key in one maps to an equal value
+    /// in the other. Neither map is locked, so the comparison is only reliable while no other thread is
+    /// modifying them: entries added or removed during the walk may or may not be seen.
+    fn eq(&self, other: &Self) -> bool {
+        // Walking a map takes the same hazard pointer that looking up a key in it does
+        if ptr::eq(self, other) {
+            return true
+        }
+        if self.len() != other.len() {
+            return false
+        }
+        let mut equal = true;
+        self.for_each_data_node(|data_node| {
+            if let (Some(key), Some(value)) = (data_node.key(), data_node.value.as_ref()) {
+                equal = equal && match other.get(key) {
+                    Some(guard) => guard.data() == value,
+                    None => false
+                };
+            }
+        });
+        equal
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K, V, S, H> Serialize for HashMap<K, V, S, H>
+where K: Hash + Send + Serialize,
+      V: Send + Serialize,
+      S: BuildHasher,
+      H: HashBits
+{
+    /// Serialize the entries as a map, in no particular order. Each entry is written while it is
+    /// protected, so nothing is cloned, but entries inserted or removed while the map is being walked
+    /// may or may not be included. The number of entries is not given to the serializer up front,
+    /// because it can change during the walk.
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        let mut result = Ok(());
+        self.for_each_data_node(|data_node| {
+            if result.is_ok() {
+                if let (Some(key), Some(value)) = (data_node.key(), data_node.value.as_ref()) {
+                    result = map.serialize_entry(key, value);
+                }
+            }
+        });
+        result?;
+        map.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K, V, S> Deserialize<'de> for HashMap<K, V, S>
+where K: PartialEq + Hash + Send + Deserialize<'de>,
+      V: PartialEq + Send + Deserialize<'de>,
+      S: BuildHasher + Default
+{
+    /// Build a map by inserting each entry in turn, so a key which appears more than once keeps its last value.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(HashMapVisitor { _marker: PhantomData })
+    }
+}
+
+#[cfg(feature = "serde")]
+struct HashMapVisitor<K, V, S> {
+    _marker: PhantomData<fn() -> (K, V, S)>
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K, V, S> Visitor<'de> for HashMapVisitor<K, V, S>
+where K: PartialEq + Hash + Send + Deserialize<'de>,
+      V: PartialEq + Send + Deserialize<'de>,
+      S: BuildHasher + Default
+{
+    type Value = HashMap<K, V, S>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let map = HashMap::default();
+        while let Some((key, value)) = access.next_entry()? {
+            map.insert(key, value);
+        }
+        Ok(map)
+    }
+}
+
+impl<K: Send, V: Send, S, H: HashBits> Drop for HashMap<K, V, S, H> {
+    fn drop(&mut self) {
+        // Nodes which have been removed from the tree are freed when the manager is dropped
+        for slot in &self.head {
+            free_slot(slot);
+        }
+    }
+}
+
+// Slots use the lowest three bits of a node pointer as marks
+#[repr(align(8))]
+pub enum Node<K: Send, V: Send, H: HashBits> {
+    Data(DataNode<K, V, H>),
+    Array(ArrayNode<K, V, H>),
+    Collision(CollisionNode<K, V, H>)
+}
+
+/// A data node found in a data or collision node, along with the handle protecting it if it came from a
+/// collision node.
+type FoundEntry<'a, K, V, H> = (*mut Node<K, V, H>, Option<HPHandle<'a, Node<K, V, H>>>);
+
+/// An array of slots, either the head of the map or the array of an ArrayNode.
+type Bucket<K, V, H> = Vec<AtomicMarkablePtr<Node<K, V, H>>>;
+
+/// A node read from a slot during a walk, along with the slot and the handle protecting the node.
+type WalkedNode<'a, K, V, H> = (&'a AtomicMarkablePtr<Node<K, V, H>>, *mut Node<K, V, H>, HPHandle<'a, Node<K, V, H>>);
+
+/// A data node which holds a value, along with the handle protecting it.
+type GuardedDataNode<'a, K, V, H> = (&'a DataNode<K, V, H>, HPHandle<'a, Node<K, V, H>>);
+
+/// The slot holding a key's data or collision node, the node taken from it, and the key's data node along
+/// with the handle protecting it, as returned by `locate`.
+type LocatedEntry<'a, K, V, H> = (&'a AtomicMarkablePtr<Node<K, V, H>>, *mut Node<K, V, H>, *mut Node<K, V, H>, Option<HPHandle<'a, Node<K, V, H>>>);
+
+#[repr(align(8))]
+pub struct DataNode<K: Send, V: Send, H: HashBits> {
+    key: Option<SharedKey<K>>,
+    value: Option<V>,
+    hash: H
+}
+
+impl<K: Send, V: Send, H: HashBits> DataNode<K, V, H> {
+    fn new(key: K, value: V, hash: H) -> Self {
+        DataNode::sharing_key(SharedKey(Arc::new(key)), value, hash)
+    }
+
+    fn sharing_key(key: SharedKey<K>, value: V, hash: H) -> Self {
+        DataNode {
+            key: Some(key),
+            value: Some(value),
+            hash
+        }
+    }
+
+    fn key(&self) -> Option<&K> {
+        match self.key {
+            Some(ref key) => Some(&key.0),
+            None => None
+        }
+    }
+
+    /// Another reference to the key, for a node which will replace this one.
+    fn shared_key(&self) -> SharedKey<K> {
+        match self.key {
+            Some(ref key) => SharedKey(key.0.clone()),
+            None => panic!("Expected a data node with a key!")
+        }
+    }
+
+    /// Take the key and value back out of a node which never made it into the map, so nothing else can
+    /// be sharing its key.
+    fn into_parts(self) -> (K, V) {
+        match Arc::try_unwrap(self.key.expect("Expected a data node with a key!").0) {
+            Ok(key) => (key, self.value.expect("Expected a data node with a value!")),
+            Err(_) => panic!("The key of an unused node was shared!")
+        }
+    }
+
+    fn has_key<Q>(&self, key: &Q) -> bool
+    where K: Borrow<Q>,
+          Q: ?Sized + PartialEq
+    {
+        match self.key() {
+            Some(own_key) => own_key.borrow() == key,
+            None => false
+        }
+    }
+}
+
+/// A key shared between a data node and the nodes which replace it when its value is updated, so that
+/// updating a value never needs to clone the key.
+struct SharedKey<K>(Arc<K>);
+
+// Every thread which looks a key up in the map already reads it, so sharing it between nodes asks no more of K
+unsafe impl<K: Send> Send for SharedKey<K> {}
+
+impl<K: Send, V: Send, H: HashBits> Default for DataNode<K, V, H> {
+    fn default() -> Self {
+        DataNode {
+            key: None,
+            value: None,
+            hash: H::default()
+        }
+    }
+}
+
+/// A node holding the data nodes of several different keys which have the same hash. Collision nodes
+/// are never changed once they are in the map: adding or removing a key replaces the whole node. The
+/// data nodes are shared between a collision node and its replacement, so they are not freed with it.
+#[repr(align(8))]
+pub struct CollisionNode<K: Send, V: Send, H: HashBits> {
+    entries: Vec<*mut Node<K, V, H>>,
+    hash: H
+}
+
+unsafe impl<K: Send, V: Send, H: HashBits> Send for CollisionNode<K, V, H> {}
+unsafe impl<K: Send + Sync, V: Send + Sync, H: HashBits> Sync for CollisionNode<K, V, H> {}
+
+impl<K: Send, V: Send, H: HashBits> CollisionNode<K, V, H> {
+    /// Copy the entries of a data or collision node, replacing `old_entry` with `new_entry`. The old
+    /// entry is left out if there is no new entry, and the new entry is added on the end if there is no old one.
+    unsafe fn replace_entry(node_ptr: *mut Node<K, V, H>, old_entry: *mut Node<K, V, H>, new_entry: *mut Node<K, V, H>) -> Vec<*mut Node<K, V, H>> {
+        let mut entries = match *atomic_markable::unmark(node_ptr) {
+            Node::Data(_) => vec![atomic_markable::unmark(node_ptr)],
+            Node::Collision(ref collision_node) => collision_node.entries.clone(),
+            Node::Array(_) => panic!("Unexpected array node!")
+        };
+        match entries.iter().position(|&entry| ptr::eq(entry, old_entry)) {
+            Some(index) if new_entry.is_null() => { entries.remove(index); },
+            Some(index) => entries[index] = new_entry,
+            None => entries.push(new_entry)
+        }
+        entries
+    }
+}
+
+#[repr(align(8))]
+pub struct ArrayNode<K: Send, V: Send, H: HashBits> {
+    array: Vec<AtomicMarkablePtr<Node<K, V, H>>>,
+    size: usize
+}
+
+impl<K: Send, V: Send, H: HashBits> ArrayNode<K, V, H> {
+    fn new(size: usize) -> Self {
+        let mut array = Vec::with_capacity(size);
+        for _ in 0..size {
+            array.push(AtomicMarkablePtr::default());
+        }
+
+        ArrayNode {
+            array,
+            size
+        }
+    }
+
+    fn to_string(&self, start: &mut String, depth: usize, manager: &HPBRManager<Node<K, V, H>>)
+    where K: Debug,
+          V: Debug 
+    {
+        start.push_str("\n");
+        for _ in 0..depth {
+            start.push_str("\t");
+        }
+        start.push_str("ArrayNode: ");
+        slots_to_string(&self.array, start, depth, manager);
+    }
+}
+
+
+mod tests {
+    #![allow(unused_imports)]
+    extern crate im;
+    use self::im::Map;
+
+    use rand::{thread_rng, Rng};
+
+    use super::{HashMap, HashMapBuilder, BitOrder, InsertError, StepBudgetExceeded, DepthStats, Entry};
+    use super::HEAD_SIZE;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::thread::JoinHandle;
+    use std::hash::{Hash, Hasher, BuildHasher, BuildHasherDefault};
+    use std::collections::hash_map::{DefaultHasher, RandomState};
+    use std::fmt::Debug;
+    use super::super::super::super::testing::{LinearizabilityTester, LinearizabilityResult, ThreadLog};
+
+    #[test]
+     
+    fn test_data_guard() {
+        let map: HashMap<u8, u8> = HashMap::new();
+
+        let _ = map.insert_if_absent(23, 23);
+        match map.get(&23) {
+            Some(g) => {
+                assert_eq!(g.data(), &23);
+                assert_eq!(g.cloned(), 23);
+                println!("guard leaving scope");
+            },
+            None => {}
+        }
+        println!("guard left scope");
+        let _ = map.insert_if_absent(24, 24);
+        let _ = map.insert_if_absent(25, 25);
+    }
+
+    #[test]
+     
+    fn test_single_thread_semantics() {
+        let map : HashMap<u8, String> = HashMap::new();
+
+        for i in 0..240 {
+            match map.insert_if_absent(i, format!("{}", i)) {
+                Ok(_) => {},
+                Err(_) => assert!(false)
+            }
+        }
+        
+        assert!(map.insert_if_absent(9, "9".to_owned()).is_err());
+
+        assert_eq!(map.get(&3).unwrap().data(), &"3".to_owned());
+        assert_eq!(map.get(&250), None);
+
+        assert_eq!(map.update(&3, map.get(&3).unwrap().data(), format!("{}", 7)), Ok(()));
+        assert_eq!(map.update(&239, map.get(&239).unwrap().data(), format!("{}", 7)), Ok(()));
+        assert_eq!(map.get(&3).unwrap().data(), &"7".to_owned());
+
+        println!("{:?}", map);
+
+        println!("{:?}", map.get(&3));
+        assert_eq!(map.compare_and_remove(&3, &"7".to_owned()), Some("7".to_owned()));
+        assert_eq!(map.compare_and_remove(&250, &"2".to_owned()), None);
+        assert_eq!(map.compare_and_remove(&4, &"5".to_owned()), None);
+        assert_eq!(map.remove(&4), Some("4".to_owned()));
+        assert_eq!(map.remove(&4), None);
+
+        assert_eq!(map.get(&3), None);
+    }
+
+    #[test]
+     
+    fn test_borrow_string_map() {
+        let map: HashMap<String, u16> = HashMap::new();
+        let _ = map.insert_if_absent("hello".to_owned(), 8);
+        assert_eq!(map.get_clone("hello"), Some(8));
+        assert_eq!(map.get("hello").unwrap().data(), &8);
+        assert_eq!(map.compare_and_remove("hello", &8), Some(8));
+
+        let _ = map.insert_if_absent("rust".to_owned(), 24);
+        assert!(map.contains_key("rust"));
+        assert!(!map.contains_key("hello"));
+        assert_eq!(map.update_with("rust", |value| value + 1), Some(24));
+        assert_eq!(map.remove("rust"), Some(25));
+        assert_eq!(map.get_clone("rust"), None);
+    }
+
+    #[test]
+     
+    fn test_multithreaded_insert() {
+        let map: Arc<HashMap<u16, String>> = Arc::new(HashMap::new());
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+
+        for i in 0..10 {
+            let map_clone = map.clone();
+            wait_vec.push(thread::spawn(move || {
+                for j in 0..2000 {
+                    let val = format!("hello");
+                    //println!("inserting");
+                    match map_clone.insert_if_absent(j, val) {
+                        Ok(()) => {},
+                        Err((key, value)) => {
+                            let expected = map_clone.get(&key);
+                            match expected {
+                                Some(expected_value) => {
+                                    //println!("updating");
+                                    let _ = map_clone.update(&key, &expected_value.cloned(), value);
+                                },
+                                None => {}
+                            }
+                        }
+                    }
+                }
+            }));
+        }
+
+        for handle in wait_vec {
+            //println!("joined: {:?}", handle);
+            match handle.join() {
+                Ok(_) => {},
+                Err(_) => panic!("A thread panicked, test failed!")
+            }
+        }
+        println!("threads done");
+        //println!("{:?}", map.get(&1174));
+    }
+
+    #[test]
+    fn test_typical() {
+        let map: Arc<HashMap<u32, String>> = Arc::default();
+        let mut wait_vec: Vec<JoinHandle<()>> = Vec::new();
+        let num_threads = 16;
+
+        for _ in 0..num_threads / 2 {
+            let map_clone = map.clone();
+            wait_vec.push(thread::spawn(move || {
+                    // Every thread in this half inserts the same keys, so only one insert of each succeeds
+                    for i in 0..1000 {
+                        let _ = map_clone.insert_if_absent(i, "hello".to_owned());
+                    }
+                    //println!("done inserting");
+                    for i in 1000..2000 {
+                        map_clone.get(&i);
+                    }
+                    //println!("done normal get");
+                    for i in 0..7000 {
+                        map_clone.get_clone(&(i % 1000));
+                    }
+                    //println!("done clone get");
+                    for i in 0..200 {
+                        map_clone.compare_and_remove(&i, &"hello".to_owned());
+                    }
+                    //println!("done removing");
+                }));
+            }
+
+        for _ in 0..num_threads / 2 {
+            let map_clone = map.clone();
+            wait_vec.push(thread::spawn(move || {
+                for i in 1000..2000 {
+                    let _ = map_clone.insert_if_absent(i, "hello".to_owned());
+                }
+                //println!("done inserting");
+                for i in 0..1000 {
+                    if i > 300 && i < 800 {
+                        if let Some(guard) = map_clone.get(&i) {
+                            //assert_eq!(guard.data(), &format!(""));
+                        }
+                    }
+                }
+                //println!("done normal get");
+                for i in 0..7000 {
+                    map_clone.get_clone(&((i % 1000) + 1000));
+                }
+                //println!("done clone get");
+                for i in 1000..1200 {
+                    map_clone.compare_and_remove(&i, &"hello".to_owned());
+                }
+                //println!("done removing");
+            }));
+        }
+
+        for handle in wait_vec {
+            if let Err(_) = handle.join() {
+                panic!("Could not join thread!")
+            }
+        }
+    }
+
+    #[derive(Hash)]
+    #[derive(Copy)]
+    #[derive(Clone)]
+    #[derive(Eq)]
+    #[derive(PartialEq)]
+    #[derive(Debug)]
+    enum MapResult<K, V>
+    where K: Copy + Clone + Eq + Hash + Debug + Send,
+          V: Copy + Clone + Eq + Hash + Debug + Send
+    {
+        ArgWrap(K, V),
+        Insert(Result<(), (K, V)>),
+        Get(Option<V>),
+        Update(Result<(), V>),
+        Remove(Option<V>)
+    }
+
+    #[test]
+    fn test_linearizable() {
+        let map: HashMap<usize, usize> = HashMap::new();
+        let sequential: Map<usize, usize> = Map::new();
+
+        let mut linearizer: LinearizabilityTester<HashMap<usize, usize>, Map<usize, usize>, MapResult<usize, usize>>
+                = LinearizabilityTester::new(8, 100000, map, sequential);
+
+        fn conc_insert(map: &HashMap<usize, usize>, data: MapResult<usize, usize>)
+                -> Option<MapResult<usize, usize>>
+        {
+            if let MapResult::ArgWrap(key, val) = data {
+                Some(MapResult::Insert(map.insert_if_absent(key, val)))
+            } else {
+                panic!("Invalid argument")
+            }
+        }
+
+        fn conc_get(map: &HashMap<usize, usize>, data: MapResult<usize, usize>)
+                -> Option<MapResult<usize, usize>>
+        {
+            if let MapResult::ArgWrap(key, val) = data {
+                match map.get(&key) {
+                    Some(guard) => Some(MapResult::Get(Some(guard.cloned()))),
+                    None => Some(MapResult::Get(None))
+                }
+            } else {
+                panic!("Invalid argument")
+            }
+        }
+
+        fn conc_update(map: &HashMap<usize, usize>, data: MapResult<usize, usize>)
+                -> Option<MapResult<usize, usize>>
+        {
+            if let MapResult::ArgWrap(key, val) = data {
+                Some(MapResult::Update(map.update(&key, &val, val)))
+            } else {
+                panic!("Invalid argument")
+            }
+        }
+
+        fn conc_remove(map: &HashMap<usize, usize>, data: MapResult<usize, usize>)
+                -> Option<MapResult<usize, usize>>
+        {
+            if let MapResult::ArgWrap(key, val) = data {
+                Some(MapResult::Remove(map.compare_and_remove(&key, &val)))
+            } else {
+                panic!("Invalid argument")
+            }
+        }
+
+        fn seq_insert(map: &Map<usize, usize>, data: Option<MapResult<usize, usize>>)
+                -> (Map<usize, usize>, Option<MapResult<usize, usize>>)
+        {
+            if let MapResult::ArgWrap(key, val) = data.unwrap() {
+                if map.contains_key(&key) {
+                    (map.clone(), Some(MapResult::Insert(Err((key, val)))))
+                } else {
+                    (map.insert(key, val), Some(MapResult::Insert(Ok(()))))
+                }
+            } else {
+                panic!("Invalid argument")
+            }
+        }
+
+        fn seq_get(map: &Map<usize, usize>, data: Option<MapResult<usize, usize>>)
+                -> (Map<usize, usize>, Option<MapResult<usize, usize>>)
+        {
+            if let MapResult::ArgWrap(key, val) = data.unwrap() {
+                match map.get(&key) {
+                    Some(arc) => (map.clone(), Some(MapResult::Get(Some(*arc)))),
+                    None => (map.clone(), Some(MapResult::Get(None)))
+                }
+            } else {
+                panic!("Invalid argument")
+            }
+        }
+
+        fn seq_update(map: &Map<usize, usize>, data: Option<MapResult<usize, usize>>)
+                -> (Map<usize, usize>, Option<MapResult<usize, usize>>)
+        {
+            if let MapResult::ArgWrap(key, val) = data.unwrap() {
+                if let Some(value) = map.get(&key) {
+                    if *value == val {
+                        (map.insert(key, val), Some(MapResult::Update(Ok(()))))
+                    } else {
+                        (map.clone(), Some(MapResult::Update(Err(val))))
+                    }
+                } else {
+                    (map.clone(), Some(MapResult::Update(Err(val))))
+                }
+            } else {
+                panic!("Invalid argument")
+            }
+        }
+
+        fn seq_remove(map: &Map<usize, usize>, data: Option<MapResult<usize, usize>>)
+                -> (Map<usize, usize>, Option<MapResult<usize, usize>>)
+        {
+            if let MapResult::ArgWrap(key, val) = data.unwrap() {
+                if let Some(value) = map.get(&key) {
+                    if *value == val {
+                        (map.remove(&key), Some(MapResult::Remove(Some(val))))
+                    } else {
+                        (map.clone(), Some(MapResult::Remove(None)))
+                    }
+                } else {
+                    (map.clone(), Some(MapResult::Remove(None)))
+                }
+            } else {
+                panic!("Invalid argument")
+            }
+        }
+
+        fn worker(id: usize, log: &mut ThreadLog<HashMap<usize, usize>, Map<usize, usize>, MapResult<usize, usize>>) {
+            for _ in 0..1000 {
+                let rand = thread_rng().gen_range(0, 101);
+                let key = thread_rng().gen_range(0, 101);
+                let val = thread_rng().gen_range(0, 101);
+                if rand < 25 {
+                    log.log_val_result(id, conc_insert, MapResult::ArgWrap(key, val), format!("insert: {} -- {}", key, val), seq_insert);
+                } else if rand < 50 {
+                    log.log_val_result(id, conc_update, MapResult::ArgWrap(key, val), format!("update: {} -- {}", key, val), seq_update);
+                } else if rand < 75 {
+                    log.log_val_result(id, conc_get, MapResult::ArgWrap(key, val), format!("get: {} -- {}", key, val), seq_get);
+                } else {
+                    log.log_val_result(id, conc_remove, MapResult::ArgWrap(key, val), format!("remove: {} -- {}", key, val), seq_remove);
+                }
+            }
+        }
+
+        let result = linearizer.run(worker);
+
+        println!("{:?}", result);
+
+        match result {
+            LinearizabilityResult::Success => assert!(true),
+            _ => assert!(false)
+        }
     }
 
     #[test]
-     
-    fn test_multithreaded_insert() {
-        let map: Arc<HashMap<u16, String>> = Arc::new(HashMap::new());
-        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+    fn test_bit_order_high_first() {
+        let low_map: HashMap<u64, u64> = HashMap::with_bit_order(BitOrder::LowFirst);
+        let high_map: HashMap<u64, u64> = HashMap::with_bit_order(BitOrder::HighFirst);
 
-        for i in 0..10 {
+        // All of the entropy is in the top byte of the hash
+        for i in 0..256u64 {
+            let hash = i << 56;
+            assert!(low_map.insert_hashed(low_map.order_hash(hash), i, i, None).is_ok());
+            assert!(high_map.insert_hashed(high_map.order_hash(hash), i, i, None).is_ok());
+        }
+
+        let low_histogram = low_map.depth_histogram();
+        let high_histogram = high_map.depth_histogram();
+        assert_eq!(low_histogram.iter().sum::<usize>(), 256);
+        assert_eq!(high_histogram, vec![256]);
+        assert!(low_histogram.len() > high_histogram.len());
+    }
+
+    #[test]
+    fn test_depth_stats() {
+        let clustered: HashMap<u64, u64> = HashMap::new();
+        assert_eq!(clustered.depth_stats(), DepthStats { max_depth: 0, average_depth: 0.0, array_nodes: 0 });
+
+        // The hashes only differ above bit 40, so every key shares a path through the first levels of the tree
+        for i in 0..256u64 {
+            assert!(clustered.insert_hashed(i << 40, i, i, None).is_ok());
+        }
+        let stats = clustered.depth_stats();
+        assert!(stats.max_depth > 8);
+        assert!(stats.average_depth > 8.0);
+        assert!(stats.array_nodes > 8);
+        assert_eq!(clustered.depth_histogram().len(), stats.max_depth + 1);
+
+        // Every key gets its own slot in the head
+        let distributed: HashMap<u64, u64> = HashMap::new();
+        for i in 0..256u64 {
+            assert!(distributed.insert_hashed(i, i, i, None).is_ok());
+        }
+        assert_eq!(distributed.depth_stats(), DepthStats { max_depth: 0, average_depth: 0.0, array_nodes: 0 });
+    }
+
+    // Hashes a key to the eight bytes written at the given offset, ignoring the rest
+    #[derive(Clone)]
+    struct WindowState(usize);
+
+    struct WindowHasher {
+        offset: usize,
+        bytes: Vec<u8>
+    }
+
+    impl BuildHasher for WindowState {
+        type Hasher = WindowHasher;
+
+        fn build_hasher(&self) -> WindowHasher {
+            WindowHasher { offset: self.0, bytes: Vec::new() }
+        }
+    }
+
+    impl Hasher for WindowHasher {
+        fn write(&mut self, bytes: &[u8]) {
+            self.bytes.extend_from_slice(bytes);
+        }
+
+        fn finish(&self) -> u64 {
+            let mut window = [0u8; 8];
+            window.copy_from_slice(&self.bytes[self.offset..self.offset + 8]);
+            u64::from_le_bytes(window)
+        }
+    }
+
+    #[test]
+    fn test_wide_hashes() {
+        // Every key has the same lower 64 bits of hash, but the upper 64 bits tell them apart
+        let narrow: HashMap<(u64, u64), u64, WindowState> = HashMap::with_hasher(WindowState(0));
+        let wide: HashMap<(u64, u64), u64, WindowState, u128> = HashMap::with_wide_hasher(WindowState(0), WindowState(8));
+        for i in 0..100u64 {
+            assert!(narrow.insert_if_absent((7, i), i).is_ok());
+            assert!(wide.insert_if_absent((7, i), i).is_ok());
+        }
+
+        // The narrow map can only keep the keys together in a single collision node
+        assert_eq!(narrow.depth_histogram(), vec![1]);
+        let histogram = wide.depth_histogram();
+        assert_eq!(histogram.iter().sum::<usize>(), 100);
+        // The keys only separate once the tree has got past the first 64 bits
+        assert!(wide.depth_stats().max_depth > 14);
+
+        for i in 0..100u64 {
+            assert_eq!(narrow.get_clone(&(7, i)), Some(i));
+            assert_eq!(wide.get_clone(&(7, i)), Some(i));
+        }
+        assert_eq!(wide.remove(&(7, 50)), Some(50));
+        assert_eq!(wide.get_clone(&(7, 50)), None);
+        // A prefix can cover the whole 128-bit hash
+        let shard = wide.split_off_prefix(7 | (3 << 64), 128);
+        assert_eq!(shard.get_clone(&(7, 3)), Some(3));
+        assert_eq!(shard.len(), 1);
+        assert_eq!(wide.len(), 98);
+    }
+
+    #[test]
+    fn test_force_expand() {
+        let map: HashMap<u8, u8> = HashMap::new();
+        assert!(!map.force_expand(&1));
+
+        assert!(map.insert_if_absent(1, 10).is_ok());
+        assert!(!format!("{:?}", map).contains("ArrayNode"));
+
+        assert!(map.force_expand(&1));
+        assert!(format!("{:?}", map).contains("ArrayNode"));
+        assert_eq!(map.get_clone(&1), Some(10));
+        assert_eq!(map.get(&1).unwrap().cloned(), 10);
+        assert_eq!(map.depth_histogram(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_keys_matching() {
+        let map: HashMap<String, usize> = HashMap::new();
+        for i in 0..100 {
+            assert!(map.insert_if_absent(format!("tenant1/{}", i), i).is_ok());
+            assert!(map.insert_if_absent(format!("tenant2/{}", i), i).is_ok());
+        }
+        assert!(map.remove("tenant1/0").is_some());
+
+        let mut keys = map.keys_matching(|key| key.starts_with("tenant1/"));
+        keys.sort();
+        let mut expected: Vec<String> = (1..100).map(|i| format!("tenant1/{}", i)).collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        assert_eq!(map.keys_matching(|key| key.starts_with("tenant3/")), Vec::<String>::new());
+        assert_eq!(map.keys_matching(|_| true).len(), 199);
+    }
+
+    #[derive(Clone)]
+    #[derive(Debug)]
+    struct CaseInsensitive(String);
+
+    impl PartialEq for CaseInsensitive {
+        fn eq(&self, other: &CaseInsensitive) -> bool {
+            self.0.to_lowercase() == other.0.to_lowercase()
+        }
+    }
+
+    impl Hash for CaseInsensitive {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.0.to_lowercase().hash(state);
+        }
+    }
+
+    #[test]
+    fn test_get_entry() {
+        let map: HashMap<CaseInsensitive, u8> = HashMap::new();
+        assert!(map.insert_if_absent(CaseInsensitive("key".to_owned()), 8).is_ok());
+
+        match map.get_entry(&CaseInsensitive("KEY".to_owned())) {
+            Some((key, value)) => {
+                assert_eq!(key.0, "key".to_owned());
+                assert_eq!(value, 8);
+            },
+            None => panic!("Key not found")
+        }
+        assert!(map.get_entry(&CaseInsensitive("other".to_owned())).is_none());
+    }
+
+    #[test]
+    fn test_step_budget() {
+        let map: HashMap<u64, u64> = HashMap::with_max_steps(1);
+        // Both hashes go in the same slot of the head, so the second insertion has to expand it
+        assert!(map.insert_hashed(0, 0, 0, map.max_steps).is_ok());
+        assert_eq!(map.insert_hashed(256, 1, 1, map.max_steps), Err(InsertError::StepBudgetExceeded(1, 1)));
+        assert!(map.insert_hashed(256, 1, 1, None).is_ok());
+
+        let map: Arc<HashMap<u32, u32>> = Arc::new(HashMap::with_max_steps(2));
+        let mut wait_vec: Vec<JoinHandle<usize>> = Vec::new();
+        for id in 0..8 {
             let map_clone = map.clone();
             wait_vec.push(thread::spawn(move || {
-                for j in 0..2000 {
-                    let val = format!("hello");
-                    //println!("inserting");
-                    match map_clone.insert(j, val) {
+                let mut exceeded = 0;
+                for i in 0..500 {
+                    let key = id * 500 + i;
+                    match map_clone.insert_bounded(key, key) {
                         Ok(()) => {},
-                        Err((key, value)) => {
-                            let expected = map_clone.get(&key);
-                            match expected {
-                                Some(expected_value) => {
-                                    //println!("updating");
-                                    let _ = map_clone.update(&key, &expected_value.cloned(), value);
-                                },
-                                None => {}
-                            }
+                        Err(InsertError::StepBudgetExceeded(key, value)) => {
+                            exceeded += 1;
+                            assert!(map_clone.insert_if_absent(key, value).is_ok());
+                        },
+                        Err(InsertError::Exists(_, _)) => panic!("Key inserted twice")
+                    }
+                }
+                exceeded
+            }));
+        }
+
+        let mut exceeded = 0;
+        for handle in wait_vec {
+            exceeded += handle.join().unwrap();
+        }
+        assert!(exceeded > 0);
+
+        let mut get_exceeded = 0;
+        for key in 0..4000 {
+            assert_eq!(map.get_clone(&key), Some(key));
+            match map.get_bounded(&key) {
+                Ok(value) => assert_eq!(value, Some(key)),
+                Err(StepBudgetExceeded) => get_exceeded += 1
+            }
+        }
+        assert!(get_exceeded > 0);
+    }
+
+    #[test]
+    fn test_get_across_levels() {
+        let map: HashMap<u32, String> = HashMap::new();
+        for i in 0..5000 {
+            assert!(map.insert_if_absent(i, format!("{}", i)).is_ok());
+        }
+        // With this many keys, some must have been pushed below the head
+        assert!(map.depth_histogram().len() > 1);
+
+        for i in 0..5000 {
+            assert_eq!(map.get(&i).unwrap().data(), &format!("{}", i));
+            assert_eq!(map.get_clone(&i), Some(format!("{}", i)));
+        }
+        assert!(map.get(&5000).is_none());
+        assert_eq!(map.get_clone(&5000), None);
+    }
+
+    #[test]
+    fn test_remove_while_reinserting() {
+        let map: Arc<HashMap<u8, u32>> = Arc::new(HashMap::new());
+        assert!(map.insert_if_absent(1, 0).is_ok());
+
+        let remover = map.clone();
+        let remove_handle = thread::spawn(move || {
+            let mut removed = 0;
+            for _ in 0..10000 {
+                if remover.remove(&1).is_some() {
+                    removed += 1;
+                }
+            }
+            removed
+        });
+        let inserter = map.clone();
+        let insert_handle = thread::spawn(move || {
+            let mut inserted = 0;
+            for i in 0..10000 {
+                if inserter.insert_if_absent(1, i).is_ok() {
+                    inserted += 1;
+                }
+            }
+            inserted
+        });
+
+        let removed = remove_handle.join().unwrap();
+        let inserted = insert_handle.join().unwrap() + 1;
+        // Every value was either removed exactly once or is still in the map
+        let remaining = if map.get_clone(&1).is_some() { 1 } else { 0 };
+        assert_eq!(inserted, removed + remaining);
+    }
+
+    #[derive(Debug)]
+    #[derive(Clone)]
+    #[derive(PartialEq)]
+    struct Colliding(u32);
+
+    // Every key has the same hash, so they can only be told apart by comparing them
+    impl Hash for Colliding {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            0u32.hash(state);
+        }
+    }
+
+    #[test]
+    fn test_hash_collisions() {
+        let map: HashMap<Colliding, u32> = HashMap::new();
+        for i in 0..3 {
+            assert!(map.insert_if_absent(Colliding(i), i).is_ok());
+        }
+        assert_eq!(map.insert_if_absent(Colliding(1), 10), Err((Colliding(1), 10)));
+        for i in 0..3 {
+            assert_eq!(map.get_clone(&Colliding(i)), Some(i));
+        }
+        assert_eq!(map.get_clone(&Colliding(3)), None);
+        assert_eq!(map.iter().count(), 3);
+
+        assert!(map.update(&Colliding(1), &1, 11).is_ok());
+        assert_eq!(map.remove(&Colliding(0)), Some(0));
+        assert_eq!(map.get_clone(&Colliding(0)), None);
+        assert_eq!(map.get_clone(&Colliding(1)), Some(11));
+        assert_eq!(map.get_entry(&Colliding(2)), Some((Colliding(2), 2)));
+
+        let mut keys = map.keys_matching(|_| true);
+        keys.sort_by_key(|key| key.0);
+        assert_eq!(keys, vec![Colliding(1), Colliding(2)]);
+
+        assert_eq!(map.compare_and_remove(&Colliding(2), &3), None);
+        assert_eq!(map.compare_and_remove(&Colliding(2), &2), Some(2));
+        assert_eq!(map.remove(&Colliding(1)), Some(11));
+        assert_eq!(map.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_full_hash_collision_is_not_a_duplicate() {
+        // Both keys consume all 64 bits of the hash on the way down, whichever end it starts from
+        for &bit_order in &[BitOrder::LowFirst, BitOrder::HighFirst] {
+            let map: HashMap<Colliding, u32> = HashMap::with_bit_order(bit_order);
+            assert_eq!(map.insert_bounded(Colliding(1), 1), Ok(()));
+            assert_eq!(map.insert_bounded(Colliding(2), 2), Ok(()));
+            assert_eq!(map.insert_bounded(Colliding(2), 3), Err(InsertError::Exists(Colliding(2), 3)));
+            assert_eq!(map.get_clone(&Colliding(1)), Some(1));
+            assert_eq!(map.get_clone(&Colliding(2)), Some(2));
+            assert_eq!(map.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_hash_collisions() {
+        let map: Arc<HashMap<Colliding, u32>> = Arc::new(HashMap::new());
+        let mut wait_vec: Vec<JoinHandle<()>> = Vec::new();
+        for i in 0..4 {
+            let map_clone = map.clone();
+            wait_vec.push(thread::spawn(move || {
+                for j in 0..25 {
+                    let key = i * 25 + j;
+                    assert!(map_clone.insert_if_absent(Colliding(key), key).is_ok());
+                }
+                for j in 0..25 {
+                    let key = i * 25 + j;
+                    assert_eq!(map_clone.get_clone(&Colliding(key)), Some(key));
+                }
+                for j in 0..10 {
+                    let key = i * 25 + j;
+                    assert_eq!(map_clone.remove(&Colliding(key)), Some(key));
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
+        }
+
+        for i in 0..4 {
+            for j in 0..25 {
+                let key = i * 25 + j;
+                let expected = if j < 10 { None } else { Some(key) };
+                assert_eq!(map.get_clone(&Colliding(key)), expected);
+            }
+        }
+        assert_eq!(map.keys_matching(|_| true).len(), 60);
+    }
+
+    #[derive(Debug)]
+    struct DropCounter {
+        drops: Arc<AtomicUsize>
+    }
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_drop_frees_all_values() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        {
+            let map: HashMap<u32, DropCounter> = HashMap::new();
+            // Enough keys to fill out array nodes below the head
+            for i in 0..5000 {
+                assert!(map.insert_if_absent(i, DropCounter { drops: drops.clone() }).is_ok());
+            }
+            let colliding: HashMap<Colliding, DropCounter> = HashMap::new();
+            for i in 0..10 {
+                assert!(colliding.insert_if_absent(Colliding(i), DropCounter { drops: drops.clone() }).is_ok());
+            }
+
+            for i in 0..1000 {
+                assert!(map.remove(&i).is_some());
+            }
+            assert!(colliding.remove(&Colliding(3)).is_some());
+            assert_eq!(drops.load(Ordering::Relaxed), 1001);
+        }
+        assert_eq!(drops.load(Ordering::Relaxed), 5010);
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        for &head_size in &[1, 4, 16, 64, 256] {
+            let map: HashMap<u32, u32> = HashMap::with_capacity(head_size);
+            // More keys than head slots, so some slots must have been expanded
+            let num_keys = head_size as u32 * 4;
+            for i in 0..num_keys {
+                assert!(map.insert_if_absent(i, i).is_ok());
+            }
+            assert!(map.depth_histogram().len() > 1);
+            for i in 0..num_keys {
+                assert_eq!(map.get_clone(&i), Some(i));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "power of 2")]
+    fn test_with_capacity_not_power_of_two() {
+        let _map: HashMap<u32, u32> = HashMap::with_capacity(48);
+    }
+
+    #[test]
+    fn test_with_capacity_uses_every_bit() {
+        // A 256 slot head reads bits 0-7, so keys which first differ in bit 8 are split up one level down
+        let big: HashMap<u32, u32> = HashMap::with_capacity(256);
+        assert!(big.insert_hashed(0x005, 1, 1, None).is_ok());
+        assert!(big.insert_hashed(0x105, 2, 2, None).is_ok());
+        assert_eq!(big.depth_histogram(), vec![0, 2]);
+
+        // A 4 slot head reads bits 0-1, so keys which first differ in bit 2 are split up one level down
+        let small: HashMap<u32, u32> = HashMap::with_capacity(4);
+        assert!(small.insert_hashed(0x1, 1, 1, None).is_ok());
+        assert!(small.insert_hashed(0x5, 2, 2, None).is_ok());
+        assert_eq!(small.depth_histogram(), vec![0, 2]);
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let map: HashMap<String, u8> = HashMap::new();
+        assert!(!map.contains_key("hello"));
+        map.insert_if_absent("hello".to_owned(), 8).unwrap();
+        assert!(map.contains_key("hello"));
+        assert!(!map.contains_key("world"));
+        assert_eq!(map.remove("hello"), Some(8));
+        assert!(!map.contains_key("hello"));
+
+        let colliding: HashMap<Colliding, u32> = HashMap::new();
+        colliding.insert_if_absent(Colliding(1), 1).unwrap();
+        colliding.insert_if_absent(Colliding(2), 2).unwrap();
+        assert!(colliding.remove(&Colliding(1)).is_some());
+        assert!(!colliding.contains_key(&Colliding(1)));
+        assert!(colliding.contains_key(&Colliding(2)));
+    }
+
+    #[test]
+    fn test_split_off_prefix() {
+        let map: HashMap<u32, u32> = HashMap::new();
+        for i in 0..2000 {
+            map.insert_if_absent(i, i * 2).unwrap();
+        }
+
+        let shard = map.split_off_prefix(0b101, 3);
+        let mut moved = shard.keys_matching(|_| true);
+        let mut remaining = map.keys_matching(|_| true);
+        assert!(!moved.is_empty());
+        assert!(!remaining.is_empty());
+        for key in &moved {
+            assert_eq!(map.hash(key) & 0b111, 0b101);
+            assert_eq!(shard.get_clone(key), Some(key * 2));
+            assert!(!map.contains_key(key));
+        }
+        for key in &remaining {
+            assert!(map.hash(key) & 0b111 != 0b101);
+            assert!(!shard.contains_key(key));
+        }
+
+        moved.append(&mut remaining);
+        moved.sort();
+        assert_eq!(moved, (0..2000).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_len() {
+        let map: HashMap<u32, u32> = HashMap::new();
+        assert!(map.is_empty());
+        for i in 0..1000 {
+            map.insert_if_absent(i, i).unwrap();
+        }
+        assert_eq!(map.len(), 1000);
+        assert!(map.insert_if_absent(5, 5).is_err());
+        assert!(map.update(&5, &5, 6).is_ok());
+        assert_eq!(map.len(), 1000);
+        for i in 0..500 {
+            assert!(map.remove(&i).is_some());
+        }
+        assert!(map.remove(&0).is_none());
+        assert_eq!(map.len(), 500);
+
+        let colliding: HashMap<Colliding, u32> = HashMap::new();
+        for i in 0..5 {
+            colliding.insert_if_absent(Colliding(i), i).unwrap();
+        }
+        colliding.remove(&Colliding(2)).unwrap();
+        assert_eq!(colliding.len(), 4);
+    }
+
+    #[test]
+    fn test_len_concurrent() {
+        let map: Arc<HashMap<u32, u32>> = Arc::new(HashMap::new());
+        let mut wait_vec: Vec<JoinHandle<()>> = Vec::new();
+        for thread_no in 0..8 {
+            let map_clone = map.clone();
+            wait_vec.push(thread::spawn(move || {
+                for i in 0..1000 {
+                    map_clone.insert_if_absent(thread_no * 1000 + i, i).unwrap();
+                }
+                for i in 0..500 {
+                    map_clone.remove(&(thread_no * 1000 + i)).unwrap();
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
+        }
+        assert_eq!(map.len(), 4000);
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let map: Arc<HashMap<u32, usize>> = Arc::new(HashMap::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut wait_vec: Vec<JoinHandle<usize>> = Vec::new();
+        for thread_no in 0..16 {
+            let map_clone = map.clone();
+            let calls_clone = calls.clone();
+            wait_vec.push(thread::spawn(move || {
+                map_clone.get_or_insert_with(7, || {
+                    calls_clone.fetch_add(1, Ordering::Relaxed);
+                    thread_no
+                })
+            }));
+        }
+        let observed: Vec<usize> = wait_vec.into_iter().map(|handle| handle.join().unwrap()).collect();
+        let winner = map.get_clone(&7).unwrap();
+        assert!(observed.iter().all(|&value| value == winner));
+        assert!(calls.load(Ordering::Relaxed) >= 1);
+        assert_eq!(map.len(), 1);
+        // The key exists now, so the function is never called
+        assert_eq!(map.get_or_insert_with(7, || panic!("Should not be called")), winner);
+    }
+
+    #[test]
+    fn test_insert_replaces() {
+        let map: HashMap<String, u32> = HashMap::new();
+        assert_eq!(map.insert("hello".to_owned(), 1), None);
+        assert_eq!(map.insert("hello".to_owned(), 2), Some(1));
+        assert_eq!(map.insert("hello".to_owned(), 3), Some(2));
+        assert_eq!(map.get_clone("hello"), Some(3));
+        assert_eq!(map.len(), 1);
+
+        // Replacing one key of a collision node leaves the others alone
+        let colliding: HashMap<Colliding, u32> = HashMap::new();
+        assert_eq!(colliding.insert(Colliding(1), 1), None);
+        assert_eq!(colliding.insert(Colliding(2), 2), None);
+        assert_eq!(colliding.insert(Colliding(1), 10), Some(1));
+        assert_eq!(colliding.get_clone(&Colliding(1)), Some(10));
+        assert_eq!(colliding.get_clone(&Colliding(2)), Some(2));
+        assert_eq!(colliding.len(), 2);
+    }
+
+    #[test]
+    fn test_concurrent_insert_replaces() {
+        let map: Arc<HashMap<u32, u32>> = Arc::new(HashMap::new());
+        let mut wait_vec: Vec<JoinHandle<usize>> = Vec::new();
+        for _ in 0..8 {
+            let map_clone = map.clone();
+            wait_vec.push(thread::spawn(move || {
+                let mut replaced = 0;
+                for round in 0..100 {
+                    for key in 0..50 {
+                        if map_clone.insert(key, round).is_some() {
+                            replaced += 1;
                         }
                     }
                 }
+                replaced
+            }));
+        }
+        let replaced: usize = wait_vec.into_iter().map(|handle| handle.join().unwrap()).sum();
+        // Exactly one insert of each key found it missing
+        assert_eq!(replaced, 8 * 100 * 50 - 50);
+        assert_eq!(map.len(), 50);
+    }
+
+    #[test]
+    fn test_with_hasher_is_reproducible() {
+        // DefaultHasher::new() always starts from the same keys, unlike the hashers made by RandomState
+        let build_map = || {
+            let map: HashMap<u32, u32, BuildHasherDefault<DefaultHasher>> = HashMap::with_hasher(BuildHasherDefault::default());
+            for i in 0..1000 {
+                assert_eq!(map.insert(i, i), None);
+            }
+            map
+        };
+        let first = build_map();
+        let second = build_map();
+        assert_eq!(format!("{:?}", first), format!("{:?}", second));
+        for i in 0..1000 {
+            assert_eq!(first.get_clone(&i), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_update_with() {
+        let map: HashMap<String, u32> = HashMap::new();
+        assert_eq!(map.update_with("hello", |count| count + 1), None);
+        map.insert("hello".to_owned(), 8);
+        assert_eq!(map.update_with("hello", |count| count + 1), Some(8));
+        assert_eq!(map.get_clone("hello"), Some(9));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_update_non_clone_key() {
+        // Updating a value shares the key with the new node instead of cloning it
+        #[derive(Hash, PartialEq, Debug)]
+        struct Tenant(u32);
+
+        let map: HashMap<Tenant, u32> = HashMap::new();
+        map.insert(Tenant(1), 8);
+        assert_eq!(map.update(&Tenant(1), &8, 9), Ok(()));
+        assert_eq!(map.update_with(&Tenant(1), |count| count + 1), Some(9));
+        map.merge(Tenant(1), 5, |count, new| count + new);
+        assert_eq!(map.get_clone(&Tenant(1)), Some(15));
+        assert_eq!(map.remove(&Tenant(1)), Some(15));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_update_with_concurrent_increments() {
+        let map: Arc<HashMap<u32, u32>> = Arc::new(HashMap::new());
+        map.insert(1, 0);
+        let mut wait_vec: Vec<JoinHandle<()>> = Vec::new();
+        for _ in 0..16 {
+            let map_clone = map.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..1000 {
+                    assert!(map_clone.update_with(&1, |count| count + 1).is_some());
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
+        }
+        assert_eq!(map.get_clone(&1), Some(16000));
+    }
+
+    #[test]
+    fn test_merge() {
+        let map: HashMap<String, String> = HashMap::new();
+        map.merge("hello".to_owned(), "a".to_owned(), |old, new| format!("{}{}", old, new));
+        assert_eq!(map.get_clone("hello"), Some("a".to_owned()));
+        map.merge("hello".to_owned(), "b".to_owned(), |old, new| format!("{}{}", old, new));
+        assert_eq!(map.get_clone("hello"), Some("ab".to_owned()));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_merge_concurrent_counts() {
+        let map: Arc<HashMap<u32, u32>> = Arc::new(HashMap::new());
+        let mut wait_vec: Vec<JoinHandle<()>> = Vec::new();
+        for _ in 0..16 {
+            let map_clone = map.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..1000 {
+                    map_clone.merge(1, 1, |a, b| a + b);
+                }
             }));
         }
-
         for handle in wait_vec {
-            //println!("joined: {:?}", handle);
-            match handle.join() {
-                Ok(_) => {},
-                Err(_) => panic!("A thread panicked, test failed!")
-            }
+            handle.join().unwrap();
         }
-        println!("threads done");
-        //println!("{:?}", map.get(&1174));
+        assert_eq!(map.get_clone(&1), Some(16000));
+        assert_eq!(map.len(), 1);
     }
 
     #[test]
-    fn test_typical() {
-        let map: Arc<HashMap<u32, String>> = Arc::default();
-        let mut wait_vec: Vec<JoinHandle<()>> = Vec::new();
-        let num_threads = 16;
+    fn test_entry() {
+        let map: HashMap<String, u32> = HashMap::new();
+        match map.entry("hello".to_owned()) {
+            Entry::Vacant(_) => {},
+            Entry::Occupied(_) => panic!("The key should not be in the map yet!")
+        }
+        assert_eq!(map.entry("hello".to_owned()).or_insert(8), 8);
+        assert_eq!(map.entry("hello".to_owned()).or_insert(9), 8);
+        assert_eq!(map.get_clone("hello"), Some(8));
 
-        for _ in 0..num_threads / 2 {
-            let map_clone = map.clone();
-            wait_vec.push(thread::spawn(move || {
-                    for i in 0..1000 {
-                        map_clone.insert(i, format!("hello"));
-                    }
-                    //println!("done inserting");
-                    for i in 1000..2000 {
-                        map_clone.get(&i);
-                    }
-                    //println!("done normal get");
-                    for i in 0..7000 {
-                        map_clone.get_clone(&(i % 1000));
-                    }
-                    //println!("done clone get");
-                    for i in 0..200 {
-                        map_clone.remove(&i, &format!("hello"));
-                    }
-                    //println!("done removing");
-                }));
-            }
+        let entry = map.entry("hello".to_owned());
+        assert_eq!(entry.key(), "hello");
+        assert_eq!(entry.and_modify(|count| count + 1).or_insert(0), 9);
+        assert_eq!(map.entry("rust".to_owned()).and_modify(|count| count + 1).or_insert_with(|| 1), 1);
+        assert_eq!(map.get_clone("rust"), Some(1));
+        assert_eq!(map.len(), 2);
+    }
 
-        for _ in 0..num_threads / 2 {
+    #[test]
+    fn test_entry_removed_while_held() {
+        let map: HashMap<u32, u32> = HashMap::new();
+        map.insert(1, 10);
+        let entry = map.entry(1);
+        assert_eq!(map.remove(&1), Some(10));
+        // The key has gone, so there is nothing to modify and the default is inserted instead
+        assert_eq!(entry.and_modify(|value| value + 1).or_insert(20), 20);
+        assert_eq!(map.get_clone(&1), Some(20));
+    }
+
+    #[test]
+    fn test_eq() {
+        let forwards: HashMap<u32, String> = HashMap::new();
+        let backwards: HashMap<u32, String> = HashMap::new();
+        for i in 0..1000 {
+            forwards.insert(i, format!("{}", i));
+            backwards.insert(999 - i, format!("{}", 999 - i));
+        }
+        assert!(forwards == backwards);
+        assert!(forwards == forwards);
+
+        backwards.insert(500, "changed".to_owned());
+        assert!(forwards != backwards);
+        backwards.insert(500, "500".to_owned());
+        assert!(forwards == backwards);
+
+        backwards.insert(1000, "1000".to_owned());
+        assert!(forwards != backwards);
+        forwards.insert(1001, "1000".to_owned());
+        assert!(forwards != backwards);
+    }
+
+    #[test]
+    fn test_clear() {
+        let map: HashMap<u32, u32> = HashMap::new();
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+        map.clear();
+        assert_eq!(map.len(), 0);
+        for i in 0..1000 {
+            assert_eq!(map.get_clone(&i), None);
+        }
+        assert!(map.keys_matching(|_| true).is_empty());
+
+        // The map can be filled again after clearing
+        for i in 0..1000 {
+            assert_eq!(map.insert(i, i + 1), None);
+        }
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.get_clone(&999), Some(1000));
+
+        // Collision nodes are cleared along with all of their entries
+        let colliding: HashMap<Colliding, u32> = HashMap::new();
+        for i in 0..10 {
+            colliding.insert(Colliding(i), i);
+        }
+        colliding.clear();
+        assert_eq!(colliding.len(), 0);
+        assert_eq!(colliding.get_clone(&Colliding(3)), None);
+    }
+
+    #[test]
+    fn test_clear_with_readers() {
+        let map: Arc<HashMap<u32, String>> = Arc::new(HashMap::new());
+        for i in 0..1000 {
+            map.insert(i, format!("{}", i));
+        }
+        let mut wait_vec: Vec<JoinHandle<()>> = Vec::new();
+        for _ in 0..4 {
             let map_clone = map.clone();
             wait_vec.push(thread::spawn(move || {
-                for i in 1000..2000 {
-                    map_clone.insert(i, format!("hello"));
-                }
-                //println!("done inserting");
                 for i in 0..1000 {
-                    if i > 300 && i < 800 {
-                        if let Some(guard) = map_clone.get(&i) {
-                            //assert_eq!(guard.data(), &format!(""));
-                        }
+                    // Every key is either still there with its value, or cleared
+                    if let Some(value) = map_clone.get_clone(&i) {
+                        assert_eq!(value, format!("{}", i));
                     }
                 }
-                //println!("done normal get");
-                for i in 0..7000 {
-                    map_clone.get_clone(&((i % 1000) + 1000));
-                }
-                //println!("done clone get");
-                for i in 1000..1200 {
-                    map_clone.remove(&i, &format!("hello"));
-                }
-                //println!("done removing");
             }));
         }
-
+        map.clear();
         for handle in wait_vec {
-            if let Err(_) = handle.join() {
-                panic!("Could not join thread!")
-            }
+            handle.join().unwrap();
         }
-    }
-
-    #[derive(Hash)]
-    #[derive(Copy)]
-    #[derive(Clone)]
-    #[derive(Eq)]
-    #[derive(PartialEq)]
-    #[derive(Debug)]
-    enum MapResult<K, V>
-    where K: Copy + Clone + Eq + Hash + Debug + Send,
-          V: Copy + Clone + Eq + Hash + Debug + Send
-    {
-        ArgWrap(K, V),
-        Insert(Result<(), (K, V)>),
-        Get(Option<V>),
-        Update(Result<(), V>),
-        Remove(Option<V>)
+        assert_eq!(map.len(), 0);
+        assert!(map.is_empty());
     }
 
     #[test]
-    fn test_linearizable() {
-        let map: HashMap<usize, usize> = HashMap::new();
-        let sequential: Map<usize, usize> = Map::new();
-
-        let mut linearizer: LinearizabilityTester<HashMap<usize, usize>, Map<usize, usize>, MapResult<usize, usize>>
-                = LinearizabilityTester::new(8, 100000, map, sequential);
-
-        fn conc_insert(map: &HashMap<usize, usize>, data: MapResult<usize, usize>)
-                -> Option<MapResult<usize, usize>>
-        {
-            if let MapResult::ArgWrap(key, val) = data {
-                Some(MapResult::Insert(map.insert(key, val)))
+    fn test_retain() {
+        let map: HashMap<u32, u32> = HashMap::new();
+        for i in 0..100 {
+            map.insert(i, i * i);
+        }
+        map.retain(|&key, &value| {
+            assert_eq!(value, key * key);
+            key % 2 == 0
+        });
+        assert_eq!(map.len(), 50);
+        for i in 0..100 {
+            if i % 2 == 0 {
+                assert_eq!(map.get_clone(&i), Some(i * i));
             } else {
-                panic!("Invalid argument")
+                assert!(!map.contains_key(&i));
             }
         }
 
-        fn conc_get(map: &HashMap<usize, usize>, data: MapResult<usize, usize>)
-                -> Option<MapResult<usize, usize>>
-        {
-            if let MapResult::ArgWrap(key, val) = data {
-                match map.get(&key) {
-                    Some(guard) => Some(MapResult::Get(Some(guard.cloned()))),
-                    None => Some(MapResult::Get(None))
-                }
-            } else {
-                panic!("Invalid argument")
-            }
+        // Entries of a collision node are removed one by one, leaving the rest
+        let colliding: HashMap<Colliding, u32> = HashMap::new();
+        for i in 0..10 {
+            colliding.insert(Colliding(i), i);
         }
+        colliding.retain(|_, &value| value < 3);
+        assert_eq!(colliding.len(), 3);
+        assert_eq!(colliding.get_clone(&Colliding(2)), Some(2));
+        assert_eq!(colliding.get_clone(&Colliding(3)), None);
+    }
 
-        fn conc_update(map: &HashMap<usize, usize>, data: MapResult<usize, usize>)
-                -> Option<MapResult<usize, usize>>
-        {
-            if let MapResult::ArgWrap(key, val) = data {
-                Some(MapResult::Update(map.update(&key, &val, val)))
-            } else {
-                panic!("Invalid argument")
+    #[test]
+    fn test_retain_with_inserts() {
+        let map: Arc<HashMap<u32, u32>> = Arc::new(HashMap::new());
+        for i in 0..2000 {
+            map.insert(i, i);
+        }
+        let map_clone = map.clone();
+        // Odd keys inserted during the walk may or may not be removed, but even keys must all survive
+        let inserter = thread::spawn(move || {
+            for i in 2000..4000 {
+                map_clone.insert(i, i);
+            }
+        });
+        map.retain(|&key, _| key % 2 == 0);
+        inserter.join().unwrap();
+        for i in 0..4000 {
+            if i % 2 == 0 {
+                assert_eq!(map.get_clone(&i), Some(i));
+            } else if i < 2000 {
+                assert!(!map.contains_key(&i));
             }
         }
+    }
 
-        fn conc_remove(map: &HashMap<usize, usize>, data: MapResult<usize, usize>)
-                -> Option<MapResult<usize, usize>>
-        {
-            if let MapResult::ArgWrap(key, val) = data {
-                Some(MapResult::Remove(map.remove(&key, &val)))
-            } else {
-                panic!("Invalid argument")
-            }
+    #[test]
+    fn test_debug_prints_entries() {
+        let map: HashMap<String, u32> = HashMap::new();
+        map.insert("hello".to_owned(), 8);
+        map.insert("rust".to_owned(), 24);
+        let debug = format!("{:?}", map);
+        assert!(debug.contains("\"hello\" ==> 8"));
+        assert!(debug.contains("\"rust\" ==> 24"));
+
+        let colliding: HashMap<Colliding, u32> = HashMap::new();
+        colliding.insert(Colliding(1), 1);
+        colliding.insert(Colliding(2), 2);
+        let debug = format!("{:?}", colliding);
+        assert!(debug.contains("Colliding(1) ==> 1"));
+        assert!(debug.contains("Colliding(2) ==> 2"));
+    }
+
+    #[test]
+    fn test_shrink() {
+        let map: HashMap<u32, u32> = HashMap::new();
+        for i in 0..20 {
+            map.insert(i, i);
+        }
+        // Push key 0 a few levels down the tree
+        for _ in 0..3 {
+            assert!(map.force_expand(&0));
+        }
+        assert!(map.depth_histogram().len() > 1);
+        for i in 1..20 {
+            assert_eq!(map.remove(&i), Some(i));
         }
 
-        fn seq_insert(map: &Map<usize, usize>, data: Option<MapResult<usize, usize>>)
-                -> (Map<usize, usize>, Option<MapResult<usize, usize>>)
-        {
-            if let MapResult::ArgWrap(key, val) = data.unwrap() {
-                if map.contains_key(&key) {
-                    (map.clone(), Some(MapResult::Insert(Err((key, val)))))
-                } else {
-                    (map.insert(key, val), Some(MapResult::Insert(Ok(()))))
-                }
-            } else {
-                panic!("Invalid argument")
-            }
+        map.shrink();
+        assert_eq!(map.depth_histogram(), vec![1]);
+        assert!(!format!("{:?}", map).contains("ArrayNode"));
+        assert_eq!(map.get_clone(&0), Some(0));
+
+        // The map still works after shrinking
+        for i in 1..20 {
+            assert_eq!(map.insert(i, i), None);
+        }
+        for i in 0..20 {
+            assert_eq!(map.get_clone(&i), Some(i));
         }
+        assert_eq!(map.len(), 20);
+    }
 
-        fn seq_get(map: &Map<usize, usize>, data: Option<MapResult<usize, usize>>)
-                -> (Map<usize, usize>, Option<MapResult<usize, usize>>)
-        {
-            if let MapResult::ArgWrap(key, val) = data.unwrap() {
-                match map.get(&key) {
-                    Some(arc) => (map.clone(), Some(MapResult::Get(Some(*arc)))),
-                    None => (map.clone(), Some(MapResult::Get(None)))
+    #[test]
+    fn test_shrink_while_inserting() {
+        // Every key is pushed down into an ArrayNode of its own, which shrink then tries to collapse
+        let map: Arc<HashMap<u32, u32>> = Arc::new(HashMap::new());
+        let mut wait_vec: Vec<JoinHandle<()>> = Vec::new();
+        for thread_num in 0..4 {
+            let map_clone = map.clone();
+            wait_vec.push(thread::spawn(move || {
+                for i in 0..2000 {
+                    let key = thread_num * 2000 + i;
+                    assert_eq!(map_clone.insert(key, key), None);
+                    map_clone.force_expand(&key);
+                    // Look up an older key as well, which shrink may be moving
+                    let older = thread_num * 2000 + i / 2;
+                    assert_eq!(map_clone.get_clone(&older), Some(older));
                 }
-            } else {
-                panic!("Invalid argument")
+            }));
+        }
+        let map_clone = map.clone();
+        wait_vec.push(thread::spawn(move || {
+            for _ in 0..100 {
+                map_clone.shrink();
             }
+        }));
+        for handle in wait_vec {
+            handle.join().unwrap();
         }
 
-        fn seq_update(map: &Map<usize, usize>, data: Option<MapResult<usize, usize>>)
-                -> (Map<usize, usize>, Option<MapResult<usize, usize>>)
-        {
-            if let MapResult::ArgWrap(key, val) = data.unwrap() {
-                if let Some(value) = map.get(&key) {
-                    if *value == val {
-                        (map.insert(key, val), Some(MapResult::Update(Ok(()))))
-                    } else {
-                        (map.clone(), Some(MapResult::Update(Err(val))))
-                    }
-                } else {
-                    (map.clone(), Some(MapResult::Update(Err(val))))
-                }
-            } else {
-                panic!("Invalid argument")
-            }
+        for key in 0..8000 {
+            assert_eq!(map.get_clone(&key), Some(key));
+        }
+        assert_eq!(map.len(), 8000);
+        for key in 0..8000 {
+            assert_eq!(map.remove(&key), Some(key));
         }
+        map.shrink();
+        assert_eq!(map.depth_stats().array_nodes, 0);
+    }
 
-        fn seq_remove(map: &Map<usize, usize>, data: Option<MapResult<usize, usize>>)
-                -> (Map<usize, usize>, Option<MapResult<usize, usize>>)
-        {
-            if let MapResult::ArgWrap(key, val) = data.unwrap() {
-                if let Some(value) = map.get(&key) {
-                    if *value == val {
-                        (map.remove(&key), Some(MapResult::Remove(Some(val))))
-                    } else {
-                        (map.clone(), Some(MapResult::Remove(None)))
-                    }
-                } else {
-                    (map.clone(), Some(MapResult::Remove(None)))
-                }
-            } else {
-                panic!("Invalid argument")
-            }
+    #[test]
+    fn test_shrink_while_iterating() {
+        let map: HashMap<u32, u32> = HashMap::new();
+        for i in 0..20 {
+            map.insert(i, i);
+            map.force_expand(&i);
         }
+        // A live iterator does not keep shrink from collapsing the ArrayNodes it is walking
+        let expanded = map.depth_stats().array_nodes;
+        let mut iter = map.iter();
+        let mut seen: Vec<u32> = iter.by_ref().take(5).map(|guard| guard.cloned()).collect();
+        map.shrink();
+        assert!(map.depth_stats().array_nodes < expanded);
+        seen.extend(iter.map(|guard| guard.cloned()));
+        seen.sort();
+        assert_eq!(seen, (0..20).collect::<Vec<u32>>());
+    }
 
-        fn worker(id: usize, log: &mut ThreadLog<HashMap<usize, usize>, Map<usize, usize>, MapResult<usize, usize>>) {
-            for _ in 0..1000 {
-                let rand = thread_rng().gen_range(0, 101);
-                let key = thread_rng().gen_range(0, 101);
-                let val = thread_rng().gen_range(0, 101);
-                if rand < 25 {
-                    log.log_val_result(id, conc_insert, MapResult::ArgWrap(key, val), format!("insert: {} -- {}", key, val), seq_insert);
-                } else if rand < 50 {
-                    log.log_val_result(id, conc_update, MapResult::ArgWrap(key, val), format!("update: {} -- {}", key, val), seq_update);
-                } else if rand < 75 {
-                    log.log_val_result(id, conc_get, MapResult::ArgWrap(key, val), format!("get: {} -- {}", key, val), seq_get);
-                } else {
-                    log.log_val_result(id, conc_remove, MapResult::ArgWrap(key, val), format!("remove: {} -- {}", key, val), seq_remove);
+    #[test]
+    fn test_iter_while_shrinking() {
+        // Every key stays in the map, so each walk sees all of them exactly once while shrink moves them up
+        let map: Arc<HashMap<u32, u32>> = Arc::new(HashMap::new());
+        for key in 0..500 {
+            map.insert(key, key);
+        }
+        let mut wait_vec: Vec<JoinHandle<()>> = Vec::new();
+        let map_clone = map.clone();
+        wait_vec.push(thread::spawn(move || {
+            for _ in 0..20 {
+                for key in 0..500 {
+                    map_clone.force_expand(&key);
                 }
+                map_clone.shrink();
             }
+        }));
+        for _ in 0..2 {
+            let map_clone = map.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..20 {
+                    let mut seen: Vec<u32> = map_clone.iter().map(|guard| guard.cloned()).collect();
+                    seen.sort();
+                    assert_eq!(seen, (0..500).collect::<Vec<u32>>());
+                    assert_eq!(map_clone.depth_histogram().iter().sum::<usize>(), 500);
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
         }
+    }
 
-        let result = linearizer.run(worker);
+    #[test]
+    fn test_max_failures() {
+        // The second key shares the first one's hash, and its insert fails five times as if other threads
+        // kept changing the slot, so only a map which gives up sooner expands the slot
+        fn depth_after_failures(max_failures: u64) -> usize {
+            let map: HashMap<u32, u32> = HashMap::with_max_failures(max_failures);
+            map.insert_hashed(7, 1, 1, None).unwrap();
+            map.forced_failures.store(5, Ordering::Relaxed);
+            map.insert_hashed(7, 2, 2, None).unwrap();
+            assert_eq!(map.len(), 2);
+            map.depth_histogram().len()
+        }
 
-        println!("{:?}", result);
+        let eager_depth = depth_after_failures(2);
+        let patient_depth = depth_after_failures(50);
+        assert_eq!(patient_depth, 1);
+        assert!(eager_depth > patient_depth, "max_failures = 2 gave depth {}, max_failures = 50 gave depth {}", eager_depth, patient_depth);
+    }
 
-        match result {
-            LinearizabilityResult::Success => assert!(true),
-            _ => assert!(false)
+    #[test]
+    fn test_builder() {
+        let map: HashMap<u64, u64, RandomState, u128> = HashMapBuilder::new()
+            .capacity(16)
+            .bit_order(BitOrder::HighFirst)
+            .max_steps(32)
+            .max_failures(3)
+            .retire_capacity(64)
+            .wide_hashes()
+            .build();
+        assert_eq!(map.head.len(), 16);
+        assert_eq!(map.bit_order, BitOrder::HighFirst);
+        assert_eq!(map.max_steps, Some(32));
+        assert_eq!(map.max_failures, 3);
+        assert!(map.wide_hasher.is_some());
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+        for i in 0..100 {
+            assert_eq!(map.get_clone(&i), Some(i));
         }
+
+        let default: HashMap<u64, u64> = HashMapBuilder::default().build();
+        assert_eq!(default.head.len(), HEAD_SIZE);
+        assert!(default.wide_hasher.is_none());
     }
-}
 
+    #[test]
+    fn test_keys_and_values() {
+        let map: HashMap<u32, u32> = HashMap::new();
+        for i in 0..1000 {
+            map.insert(i, i * 2);
+        }
+        for i in 0..10 {
+            map.remove(&(i * 100));
+        }
+
+        let mut keys: Vec<u32> = map.keys().collect();
+        keys.sort();
+        let expected: Vec<u32> = (0..1000).filter(|i| i % 100 != 0).collect();
+        assert_eq!(keys, expected);
+
+        let mut values: Vec<u32> = map.values().collect();
+        values.sort();
+        assert_eq!(values, expected.iter().map(|i| i * 2).collect::<Vec<u32>>());
+    }
+}
@@ -1,5 +1,6 @@
 pub use self::data_guard::DataGuard;
-pub use self::hash_map::HashMap;
+pub use self::hash_map::{HashMap, HashMapBuilder, HashBits, BitOrder, InsertError, StepBudgetExceeded, DepthStats};
+pub use self::hash_map::{Entry, OccupiedEntry, VacantEntry};
 pub use self::hash_set::HashSet;
 
 mod hash_map;
@@ -0,0 +1,347 @@
+use memory::HPBRManager;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, AcqRel};
+use std::ptr;
+use super::utils::atomic_markable;
+use super::utils::read_mark::ReadMark;
+
+const PREV_HAZARD: usize = 0;
+const CURR_HAZARD: usize = 1;
+const NEXT_HAZARD: usize = 2;
+
+/// A lock-free singly linked list.
+///
+/// This list uses the same algorithm as the OrderedSet, the linked list described in [A Pragmatic
+/// Implementation of Non-Blocking Linked-Lists](https://link.springer.com/chapter/10.1007/3-540-45414-4_21)
+/// by Harris, with the changes from [High Performance Dynamic Lock-Free Hash Tables and List-Based Sets]
+/// (https://dl.acm.org/citation.cfm?id=564870) by Michael which make it safe to use with hazard pointers.
+/// Instead of being kept in sorted order, values can be added to either end of the list, and searches look
+/// for the first value matching a predicate.
+///
+/// A node is removed by marking its next pointer, which stops anything being linked after it. Any thread which
+/// comes across a marked node while searching the list unlinks it, and the thread which unlinks it retires it.
+pub struct LinkedList<T: Send> {
+    head: *mut Node<T>,
+    manager: HPBRManager<Node<T>>
+}
+
+unsafe impl<T: Send> Send for LinkedList<T> {}
+unsafe impl<T: Send> Sync for LinkedList<T> {}
+
+struct Node<T: Send> {
+    value: Option<T>,
+    next: AtomicPtr<Node<T>>,
+    // Searching threads can still be matching the value after the node is removed, so the remover
+    // waits for them before moving the value out
+    readers: ReadMark
+}
+
+impl<T: Send> LinkedList<T> {
+    /// Create a new, empty LinkedList.
+    /// # Examples
+    /// ```
+    /// let list: LinkedList<u32> = LinkedList::new();
+    /// ```
+    pub fn new() -> Self {
+        LinkedList {
+            head: Box::into_raw(Box::new(Node::new_head())),
+            manager: HPBRManager::new(100, 3)
+        }
+    }
+
+    /// Add a value to the front of the list.
+    /// # Examples
+    /// ```
+    /// let list: LinkedList<u32> = LinkedList::new();
+    /// list.push_front(1);
+    /// list.push_front(2);
+    /// assert_eq!(list.remove_first(|_| true), Some(2));
+    /// ```
+    pub fn push_front(&self, value: T) {
+        let node_ptr = Box::into_raw(Box::new(Node::new(value)));
+        // The head is never removed, so its next pointer is never marked
+        let mut first = Node::link(self.head).load(Acquire);
+        loop {
+            Node::link(node_ptr).store(first, Relaxed);
+            match Node::link(self.head).compare_exchange(first, node_ptr, AcqRel, Acquire) {
+                Ok(_) => { return; },
+                Err(current) => first = current
+            }
+        }
+    }
+
+    /// Add a value to the back of the list.
+    /// # Examples
+    /// ```
+    /// let list: LinkedList<u32> = LinkedList::new();
+    /// list.push_back(1);
+    /// list.push_back(2);
+    /// assert_eq!(list.remove_first(|_| true), Some(1));
+    /// ```
+    pub fn push_back(&self, value: T) {
+        let node_ptr = Box::into_raw(Box::new(Node::new(value)));
+        loop {
+            // Nothing matches, so the search runs to the end of the list
+            let (_, last, _) = self.find(|_| false);
+            // Fails if another node was linked after the last one, or the last one has been removed
+            if Node::link(last).compare_exchange(ptr::null_mut(), node_ptr, AcqRel, Acquire).is_ok() {
+                self.unprotect_all();
+                return
+            }
+        }
+    }
+
+    /// Remove the first value in the list which matches the given predicate, returning it, or None if
+    /// no value matches. Other threads may still be matching the removed value, so it is only moved out
+    /// once they have finished with it.
+    /// # Examples
+    /// ```
+    /// let list: LinkedList<u32> = LinkedList::new();
+    /// list.push_back(1);
+    /// list.push_back(2);
+    /// assert_eq!(list.remove_first(|&value| value % 2 == 0), Some(2));
+    /// assert_eq!(list.remove_first(|&value| value % 2 == 0), None);
+    /// ```
+    pub fn remove_first<F: Fn(&T) -> bool>(&self, pred: F) -> Option<T> {
+        loop {
+            let (found, prev, curr) = self.find(&pred);
+            if !found {
+                self.unprotect_all();
+                return None
+            }
+
+            // Whoever marks the node removes it
+            let next = Node::link(curr).load(Acquire);
+            if atomic_markable::is_marked(next) {
+                continue;
+            }
+            if Node::link(curr).compare_exchange(next, atomic_markable::mark(next), AcqRel, Acquire).is_err() {
+                continue;
+            }
+            // Marking the node makes this thread its only remover
+            let value = unsafe { Node::take_value(curr) };
+
+            // Try to unlink the node straight away, otherwise leave it to the next search which finds it
+            if Node::link(prev).compare_exchange(curr, next, AcqRel, Acquire).is_ok() {
+                self.manager.retire(curr, CURR_HAZARD);
+            } else {
+                self.find(&pred);
+            }
+            self.unprotect_all();
+            return value
+        }
+    }
+
+    /// Check whether unlinked nodes are piling up in the manager's free lists.
+    pub fn has_reclaim_pressure(&self) -> bool {
+        self.manager.has_reclaim_pressure()
+    }
+
+    /// Find the first node whose value matches the predicate, and the node before it, unlinking any removed
+    /// nodes on the way. Both nodes are left protected by hazard pointers. Returns false if no value matches,
+    /// in which case the first node is the last in the list and the second is null.
+    fn find<F: Fn(&T) -> bool>(&self, pred: F) -> (bool, *mut Node<T>, *mut Node<T>) {
+        'retry: loop {
+            // The head is never removed, so it does not need protecting
+            let mut prev = self.head;
+            let mut curr = Node::link(prev).load(Acquire);
+            loop {
+                if curr.is_null() {
+                    return (false, prev, curr)
+                }
+                self.manager.protect(curr, CURR_HAZARD);
+                // If prev has been removed or curr unlinked, curr could already be retired
+                if !ptr::eq(curr, Node::link(prev).load(Acquire)) {
+                    continue 'retry;
+                }
+
+                let next = Node::link(curr).load(Acquire);
+                self.manager.protect(atomic_markable::unmark(next), NEXT_HAZARD);
+                if !ptr::eq(next, Node::link(curr).load(Acquire)) {
+                    continue 'retry;
+                }
+
+                if atomic_markable::is_marked(next) {
+                    // curr has been removed, and its next pointer can no longer change, so unlink it
+                    let unmarked_next = atomic_markable::unmark(next);
+                    if Node::link(prev).compare_exchange(curr, unmarked_next, AcqRel, Acquire).is_err() {
+                        continue 'retry;
+                    }
+                    self.manager.retire(curr, CURR_HAZARD);
+                    curr = unmarked_next;
+                    continue;
+                }
+
+                // A value which has just been taken belongs to a removed node, so it no longer matches
+                if unsafe { Node::read_value(curr, &pred) } == Some(true) {
+                    return (true, prev, curr)
+                }
+                prev = curr;
+                self.manager.protect(prev, PREV_HAZARD);
+                curr = next;
+            }
+        }
+    }
+
+    fn unprotect_all(&self) {
+        self.manager.unprotect(PREV_HAZARD);
+        self.manager.unprotect(CURR_HAZARD);
+        self.manager.unprotect(NEXT_HAZARD);
+    }
+}
+
+impl<T: Send> Default for LinkedList<T> {
+    fn default() -> Self {
+        LinkedList::new()
+    }
+}
+
+impl<T: Send> Drop for LinkedList<T> {
+    fn drop(&mut self) {
+        // Retired nodes have been unlinked, so they will not be found here
+        let mut current = self.head;
+        while !current.is_null() {
+            unsafe {
+                let next = atomic_markable::unmark(Node::link(current).load(Relaxed));
+                drop(Box::from_raw(current));
+                current = next;
+            }
+        }
+    }
+}
+
+impl<T: Send> Node<T> {
+    fn new(value: T) -> Self {
+        Node {
+            value: Some(value),
+            next: AtomicPtr::default(),
+            readers: ReadMark::new()
+        }
+    }
+
+    fn new_head() -> Self {
+        Node {
+            value: None,
+            next: AtomicPtr::default(),
+            readers: ReadMark::new()
+        }
+    }
+
+    fn link<'a>(node: *mut Self) -> &'a AtomicPtr<Self> {
+        unsafe { &(*node).next }
+    }
+
+    /// Apply the function to the node's value, unless it has already been taken. Only the head node
+    /// starts without a value, and it is never looked at.
+    unsafe fn read_value<R, F: FnOnce(&T) -> R>(node: *mut Self, f: F) -> Option<R> {
+        let _reading = (*node).readers.read()?;
+        (*node).value.as_ref().map(f)
+    }
+
+    /// Move the value out of a node which has just been marked, once nobody is reading it.
+    unsafe fn take_value(node: *mut Self) -> Option<T> {
+        (*node).readers.take();
+        (*node).value.take()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::LinkedList;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_front_and_back_order() {
+        let list: LinkedList<u32> = LinkedList::new();
+        assert_eq!(list.remove_first(|_| true), None);
+        list.push_back(3);
+        list.push_front(2);
+        list.push_back(4);
+        list.push_front(1);
+        list.push_back(5);
+
+        assert_eq!(list.remove_first(|&value| value == 3), Some(3));
+        assert_eq!(list.remove_first(|&value| value == 3), None);
+        list.push_back(6);
+        let mut values = Vec::new();
+        while let Some(value) = list.remove_first(|_| true) {
+            values.push(value);
+        }
+        assert_eq!(values, vec![1, 2, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_remove_first_moves_value() {
+        // Neither Clone nor Copy, and counts how many times it is dropped
+        struct Counted<'a> {
+            value: u32,
+            drops: &'a AtomicUsize
+        }
+
+        impl<'a> Drop for Counted<'a> {
+            fn drop(&mut self) {
+                self.drops.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let drops = AtomicUsize::new(0);
+        {
+            let list: LinkedList<Counted> = LinkedList::new();
+            for value in 0..4 {
+                list.push_back(Counted { value, drops: &drops });
+            }
+            let removed = list.remove_first(|counted| counted.value == 2).unwrap();
+            assert_eq!(removed.value, 2);
+            assert_eq!(drops.load(Ordering::Relaxed), 0);
+            drop(removed);
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+            assert!(list.remove_first(|counted| counted.value == 2).is_none());
+        }
+        // The removed node's value was moved out, so it is not dropped again with the list
+        assert_eq!(drops.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_concurrent_remove_first() {
+        let list: Arc<LinkedList<usize>> = Arc::new(LinkedList::new());
+        for value in 0..4000 {
+            list.push_back(value);
+        }
+        // The number of times each value, including those added during the test, was removed
+        let removed: Arc<Vec<AtomicUsize>> = Arc::new((0..6000).map(|_| AtomicUsize::new(0)).collect());
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+
+        for i in 0..8 {
+            let list_clone = list.clone();
+            let removed_clone = removed.clone();
+            wait_vec.push(thread::spawn(move || {
+                // Half the threads remove even values and half odd ones, while all of them add more
+                for j in 0..250 {
+                    let value = list_clone.remove_first(|&value| value % 2 == i % 2).unwrap();
+                    removed_clone[value].fetch_add(1, Ordering::Relaxed);
+                    if j % 2 == 0 {
+                        list_clone.push_front(4000 + i * 250 + j);
+                    } else {
+                        list_clone.push_back(4000 + i * 250 + j);
+                    }
+                }
+            }));
+        }
+
+        for handle in wait_vec {
+            if handle.join().is_err() {
+                panic!("A thread panicked, test failed!")
+            }
+        }
+
+        // Every value was either removed exactly once or is still in the list
+        while let Some(value) = list.remove_first(|_| true) {
+            removed[value].fetch_add(1, Ordering::Relaxed);
+        }
+        for value in 0..6000 {
+            assert_eq!(removed[value].load(Ordering::Relaxed), 1);
+        }
+    }
+}
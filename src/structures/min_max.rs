@@ -0,0 +1,165 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::marker::PhantomData;
+
+/// A value which can be kept in an AtomicMin or AtomicMax. The value is stored in an AtomicUsize, so it
+/// has to fit in a usize, but values are always compared as themselves rather than as usizes.
+pub trait UsizeValue: Ord + Copy {
+    /// Pack the value into a usize.
+    fn into_usize(self) -> usize;
+
+    /// Unpack a value which was packed with `into_usize`.
+    fn from_usize(packed: usize) -> Self;
+}
+
+macro_rules! impl_usize_value {
+    ($($t:ty),*) => {
+        $(
+            impl UsizeValue for $t {
+                fn into_usize(self) -> usize {
+                    self as usize
+                }
+
+                fn from_usize(packed: usize) -> Self {
+                    packed as $t
+                }
+            }
+        )*
+    }
+}
+
+impl_usize_value!(u8, u16, u32, usize, i8, i16, i32, isize);
+#[cfg(target_pointer_width = "64")]
+impl_usize_value!(u64, i64);
+
+/// A register which keeps the smallest value it has been given, for example the fastest response time
+/// seen by a number of threads.
+///
+/// The value is kept in a single AtomicUsize. An update only writes to it if the new value is smaller,
+/// retrying with compare-and-swap if another thread changes it first, so updates which would not change
+/// the minimum only read it.
+/// # Examples
+/// ```
+/// let min: AtomicMin<u32> = AtomicMin::new(10);
+/// min.update(4);
+/// min.update(7);
+/// assert_eq!(min.get(), 4);
+/// ```
+pub struct AtomicMin<T: UsizeValue> {
+    value: AtomicUsize,
+    _marker: PhantomData<T>
+}
+
+/// A register which keeps the largest value it has been given, for example the longest queue seen by a
+/// number of threads. It works in the same way as an AtomicMin.
+/// # Examples
+/// ```
+/// let max: AtomicMax<i32> = AtomicMax::new(-10);
+/// max.update(4);
+/// max.update(-7);
+/// assert_eq!(max.get(), 4);
+/// ```
+pub struct AtomicMax<T: UsizeValue> {
+    value: AtomicUsize,
+    _marker: PhantomData<T>
+}
+
+impl<T: UsizeValue> AtomicMin<T> {
+    /// Create a new AtomicMin holding the given value.
+    pub fn new(initial: T) -> Self {
+        AtomicMin {
+            value: AtomicUsize::new(initial.into_usize()),
+            _marker: PhantomData
+        }
+    }
+
+    /// Replace the value with the given one if the given one is smaller.
+    pub fn update(&self, value: T) {
+        replace_while(&self.value, value, |new, current| new < current);
+    }
+
+    /// The smallest value the register has been given.
+    pub fn get(&self) -> T {
+        T::from_usize(self.value.load(Relaxed))
+    }
+}
+
+impl<T: UsizeValue> AtomicMax<T> {
+    /// Create a new AtomicMax holding the given value.
+    pub fn new(initial: T) -> Self {
+        AtomicMax {
+            value: AtomicUsize::new(initial.into_usize()),
+            _marker: PhantomData
+        }
+    }
+
+    /// Replace the value with the given one if the given one is larger.
+    pub fn update(&self, value: T) {
+        replace_while(&self.value, value, |new, current| new > current);
+    }
+
+    /// The largest value the register has been given.
+    pub fn get(&self) -> T {
+        T::from_usize(self.value.load(Relaxed))
+    }
+}
+
+/// Store the new value in the cell for as long as it beats the value already there.
+fn replace_while<T: UsizeValue, F: Fn(T, T) -> bool>(cell: &AtomicUsize, new: T, beats: F) {
+    let mut current = cell.load(Relaxed);
+    while beats(new, T::from_usize(current)) {
+        match cell.compare_exchange_weak(current, new.into_usize(), Relaxed, Relaxed) {
+            Ok(_) => { return; },
+            Err(actual) => current = actual
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+mod tests {
+    #![allow(unused_imports)]
+    use super::{AtomicMin, AtomicMax};
+    use rand::{thread_rng, Rng};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_single_threaded() {
+        let min: AtomicMin<i8> = AtomicMin::new(0);
+        let max: AtomicMax<i8> = AtomicMax::new(0);
+        for &value in [3, -128, 127, -5].iter() {
+            min.update(value);
+            max.update(value);
+        }
+        assert_eq!(min.get(), -128);
+        assert_eq!(max.get(), 127);
+    }
+
+    #[test]
+    fn test_many_threads() {
+        let min: Arc<AtomicMin<i32>> = Arc::new(AtomicMin::new(i32::MAX));
+        let max: Arc<AtomicMax<i32>> = Arc::new(AtomicMax::new(i32::MIN));
+        let mut wait_vec: Vec<thread::JoinHandle<Vec<i32>>> = Vec::new();
+        for _ in 0..8 {
+            let min_clone = min.clone();
+            let max_clone = max.clone();
+            wait_vec.push(thread::spawn(move || {
+                let mut submitted = Vec::new();
+                for _ in 0..10000 {
+                    let value = thread_rng().gen_range(-1000000, 1000000);
+                    min_clone.update(value);
+                    max_clone.update(value);
+                    submitted.push(value);
+                }
+                submitted
+            }));
+        }
+
+        let mut submitted: Vec<i32> = Vec::new();
+        for handle in wait_vec {
+            submitted.extend(handle.join().unwrap());
+        }
+        assert_eq!(min.get(), *submitted.iter().min().unwrap());
+        assert_eq!(max.get(), *submitted.iter().max().unwrap());
+    }
+}
@@ -6,15 +6,84 @@
 //!
 //! The structures in this crate can be used in a multi-threaded context by wrapping
 //! them inside an Arc, as they can all be modified with an immutable reference.
+//!
+//! Without the `std` feature, only the Stack, Queue, BoundedQueue, AtomicMin and AtomicMax are available.
 
 pub use self::stack::Stack;
-pub use self::queue::Queue; 
-pub use self::seg_queue::SegQueue;
-pub use self::hash::HashMap;
+pub use self::queue::{Queue, NodeHandle, Contended};
+#[cfg(feature = "std")]
+pub use self::blocking_queue::BlockingQueue;
+pub use self::bounded_queue::BoundedQueue;
+pub use self::min_max::{AtomicMin, AtomicMax, UsizeValue};
+#[cfg(feature = "std")]
+pub use self::bounded_stack::BoundedStack;
+#[cfg(feature = "std")]
+pub use self::spsc_queue::{SpscQueue, Producer, Consumer};
+#[cfg(feature = "std")]
+pub use self::seg_queue::{SegQueue, DequeueStrategy};
+#[cfg(feature = "std")]
+pub use self::hash::{HashMap, HashMapBuilder, HashBits};
+#[cfg(feature = "std")]
+pub use self::hash::BitOrder;
+#[cfg(feature = "std")]
+pub use self::hash::{InsertError, StepBudgetExceeded, DepthStats};
+#[cfg(feature = "std")]
+pub use self::hash::{Entry, OccupiedEntry, VacantEntry};
+#[cfg(feature = "std")]
 pub use self::hash::HashSet;
+#[cfg(feature = "std")]
+pub use self::ordered_map::OrderedMap;
+#[cfg(feature = "std")]
+pub use self::priority_queue::PriorityQueue;
+#[cfg(feature = "std")]
+pub use self::ordered_set::OrderedSet;
+#[cfg(feature = "std")]
+pub use self::linked_list::LinkedList;
+#[cfg(feature = "std")]
+pub use self::work_stealing_stack::{WorkStealingStack, Stealer};
+#[cfg(feature = "std")]
+pub use self::counter::Counter;
+#[cfg(feature = "std")]
+pub use self::pool::Pool;
+#[cfg(feature = "std")]
+pub use self::exchanger::Exchanger;
+#[cfg(feature = "std")]
+pub use self::rcu_cell::{RcuCell, RcuGuard};
+#[cfg(feature = "std")]
+pub use self::bag::Bag;
 
 mod stack;
 mod queue;
+#[cfg(feature = "std")]
+mod blocking_queue;
+mod bounded_queue;
+mod min_max;
+#[cfg(feature = "std")]
+mod bounded_stack;
+#[cfg(feature = "std")]
+mod spsc_queue;
+#[cfg(feature = "std")]
 mod seg_queue;
+#[cfg(feature = "std")]
 mod hash;
-mod utils;
\ No newline at end of file
+#[cfg(feature = "std")]
+mod ordered_map;
+#[cfg(feature = "std")]
+mod priority_queue;
+#[cfg(feature = "std")]
+mod ordered_set;
+#[cfg(feature = "std")]
+mod linked_list;
+#[cfg(feature = "std")]
+mod work_stealing_stack;
+#[cfg(feature = "std")]
+mod counter;
+#[cfg(feature = "std")]
+mod pool;
+#[cfg(feature = "std")]
+mod exchanger;
+#[cfg(feature = "std")]
+mod rcu_cell;
+#[cfg(feature = "std")]
+mod bag;
+pub(crate) mod utils;
\ No newline at end of file
@@ -0,0 +1,656 @@
+use memory::{HPBRManager, HPHandle};
+use std::sync::atomic::{AtomicPtr, AtomicUsize};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, AcqRel};
+use std::collections::Bound;
+use std::ops::RangeBounds;
+use std::borrow::Borrow;
+use std::ptr;
+use rand::{thread_rng, Rng};
+use super::utils::atomic_markable;
+
+const MAX_LEVEL: usize = 16;
+// Each level uses one hazard pointer for the predecessor and one for the successor,
+// and one more is used to protect the next node while moving along a level
+const NUM_HAZARDS: usize = MAX_LEVEL * 2 + 1;
+const NEXT_HAZARD: usize = MAX_LEVEL * 2;
+
+/// A lock-free ordered map based on a skip list.
+///
+/// This map is an implementation of the lock-free skip list described in [Practical lock-freedom]
+/// (https://www.cl.cam.ac.uk/techreports/UCAM-CL-TR-579.pdf), in the form presented in The Art of
+/// Multiprocessor Programming. Keys are kept in sorted order, so unlike the HashMap, the map can answer
+/// range queries.
+///
+/// Every node is part of the bottom level list, and is also part of a random number of the levels above it,
+/// up to a maximum of MAX_LEVEL. A node is removed by first marking its next pointers from the top level
+/// down, which stops anything else being linked after it. Marking the bottom level removes it from the map,
+/// after which any thread which comes across it will unlink it.
+///
+/// A node can only be retired once it has been unlinked from every level, and its inserter has stopped
+/// linking it into the levels above the bottom. Both the inserting and removing thread hold a claim on the node,
+/// and the node is retired by whichever one gives up its claim last.
+pub struct OrderedMap<K, V>
+where K: Send,
+      V: Send
+{
+    head: *mut Node<K, V>,
+    manager: HPBRManager<Node<K, V>>
+}
+
+unsafe impl<K: Send, V: Send> Send for OrderedMap<K, V> {}
+unsafe impl<K: Send, V: Send> Sync for OrderedMap<K, V> {}
+
+impl<K: Ord + Send, V: Send> OrderedMap<K, V> {
+    /// Create a new, empty OrderedMap.
+    /// # Examples
+    /// ```
+    /// let map: OrderedMap<u32, String> = OrderedMap::new();
+    /// ```
+    pub fn new() -> Self {
+        OrderedMap::with_retire_capacity(0)
+    }
+
+    /// Create a new, empty OrderedMap where each thread's list of unlinked nodes waiting to be freed
+    /// has room for `retire_capacity` nodes from the start. See `HPBRManager::with_retire_capacity`.
+    /// # Examples
+    /// ```
+    /// let map: OrderedMap<u32, String> = OrderedMap::with_retire_capacity(128);
+    /// ```
+    pub fn with_retire_capacity(retire_capacity: usize) -> Self {
+        OrderedMap {
+            head: Box::into_raw(Box::new(Node::new_head())),
+            manager: HPBRManager::with_retire_capacity(100, NUM_HAZARDS, retire_capacity)
+        }
+    }
+
+    /// Insert the given key and value into the map.
+    /// # Errors
+    /// If the key is already in the map, the key and value are returned in an Err.
+    /// # Examples
+    /// ```
+    /// let map: OrderedMap<u32, String> = OrderedMap::new();
+    /// assert!(map.insert(1, "hello".to_owned()).is_ok());
+    /// assert!(map.insert(1, "world".to_owned()).is_err());
+    /// ```
+    pub fn insert(&self, key: K, value: V) -> Result<(), (K, V)> {
+        let top_level = random_level();
+        let mut preds: [*mut Node<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        let mut succs: [*mut Node<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        let mut node = Box::new(Node::new(key, value, top_level));
+
+        let node_ptr = loop {
+            if self.find(node.key(), &mut preds, &mut succs) {
+                self.unprotect_all();
+                let node = *node;
+                return Err((node.key.unwrap(), node.value.unwrap()))
+            }
+            for (next, &succ) in node.next.iter().zip(succs.iter()).take(top_level) {
+                next.store(succ, Relaxed);
+            }
+            let node_ptr = Box::into_raw(node);
+            // Linking the bottom level is what adds the key to the map
+            match Node::link(preds[0], 0).compare_exchange(succs[0], node_ptr, AcqRel, Acquire) {
+                Ok(_) => break node_ptr,
+                Err(_) => { node = unsafe { Box::from_raw(node_ptr) }; }
+            }
+        };
+
+        // Link the rest of the levels, stopping if the node is removed in the meantime
+        'link: for level in 1..top_level {
+            loop {
+                let succ = succs[level];
+                let next = Node::link(node_ptr, level).load(Acquire);
+                if atomic_markable::is_marked(next) {
+                    break 'link;
+                }
+                if !ptr::eq(next, succ) && Node::link(node_ptr, level).compare_exchange(next, succ, AcqRel, Acquire).is_err() {
+                    break 'link;
+                }
+                if Node::link(preds[level], level).compare_exchange(succ, node_ptr, AcqRel, Acquire).is_ok() {
+                    break;
+                }
+                self.find(unsafe { (*node_ptr).key() }, &mut preds, &mut succs);
+            }
+        }
+
+        // If the node was removed while we were linking it, make sure it is unlinked from every level
+        if atomic_markable::is_marked(Node::link(node_ptr, 0).load(Acquire)) {
+            self.find(unsafe { (*node_ptr).key() }, &mut preds, &mut succs);
+        }
+        self.release(node_ptr);
+        self.unprotect_all();
+        Ok(())
+    }
+
+    /// Retrieve a clone of the value associated with the given key, or None if the key is not in the map.
+    /// # Examples
+    /// ```
+    /// let map: OrderedMap<u32, String> = OrderedMap::new();
+    /// map.insert(1, "hello".to_owned());
+    /// assert_eq!(map.get(&1), Some("hello".to_owned()));
+    /// assert_eq!(map.get(&2), None);
+    /// ```
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where K: Borrow<Q>,
+          Q: ?Sized + Ord,
+          V: Clone
+    {
+        let mut preds: [*mut Node<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        let mut succs: [*mut Node<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        let result = if self.find(key, &mut preds, &mut succs) {
+            // The node is protected by the bottom level's successor hazard pointer
+            unsafe { (*succs[0]).value.clone() }
+        } else {
+            None
+        };
+        self.unprotect_all();
+        result
+    }
+
+    /// Remove the given key from the map, returning a clone of its value, or None if the key is
+    /// not in the map.
+    /// # Examples
+    /// ```
+    /// let map: OrderedMap<u32, String> = OrderedMap::new();
+    /// map.insert(1, "hello".to_owned());
+    /// assert_eq!(map.remove(&1), Some("hello".to_owned()));
+    /// assert_eq!(map.remove(&1), None);
+    /// ```
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where K: Borrow<Q>,
+          Q: ?Sized + Ord,
+          V: Clone
+    {
+        let mut preds: [*mut Node<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        let mut succs: [*mut Node<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        if !self.find(key, &mut preds, &mut succs) {
+            self.unprotect_all();
+            return None
+        }
+
+        let node_ptr = succs[0];
+        if !self.mark_removed(node_ptr) {
+            self.unprotect_all();
+            return None
+        }
+
+        let value = unsafe { (*node_ptr).value.clone() };
+        // Unlink the node from every level before giving up our claim on it
+        self.find(key, &mut preds, &mut succs);
+        self.release(node_ptr);
+        self.unprotect_all();
+        value
+    }
+
+    /// Remove the entry with the smallest key from the map, returning clones of its key and value, or None
+    /// if the map is empty.
+    /// # Examples
+    /// ```
+    /// let map: OrderedMap<u32, String> = OrderedMap::new();
+    /// map.insert(2, "world".to_owned());
+    /// map.insert(1, "hello".to_owned());
+    /// assert_eq!(map.pop_first(), Some((1, "hello".to_owned())));
+    /// assert_eq!(map.pop_first(), Some((2, "world".to_owned())));
+    /// assert_eq!(map.pop_first(), None);
+    /// ```
+    pub fn pop_first(&self) -> Option<(K, V)>
+    where K: Clone,
+          V: Clone
+    {
+        let mut preds: [*mut Node<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        let mut succs: [*mut Node<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        loop {
+            let node_ptr = Node::link(self.head, 0).load(Acquire);
+            if node_ptr.is_null() {
+                self.unprotect_all();
+                return None
+            }
+            self.manager.protect(node_ptr, succ_hazard(0));
+            if !ptr::eq(node_ptr, Node::link(self.head, 0).load(Acquire)) {
+                continue;
+            }
+
+            // find moves the hazard pointers off the node, so it needs a key of its own
+            let key = unsafe { (*node_ptr).key().clone() };
+            if !self.mark_removed(node_ptr) {
+                // Someone else removed the first node, so unlink it before trying the next one
+                self.find(&key, &mut preds, &mut succs);
+                continue;
+            }
+
+            let value = unsafe { (*node_ptr).value.clone().unwrap() };
+            self.find(&key, &mut preds, &mut succs);
+            self.release(node_ptr);
+            self.unprotect_all();
+            return Some((key, value))
+        }
+    }
+
+    /// Obtain an iterator over clones of the keys and values in the given range of keys, in sorted order.
+    /// Keys which are inserted or removed during iteration may or may not be seen.
+    /// # Examples
+    /// ```
+    /// let map: OrderedMap<u32, u32> = OrderedMap::new();
+    /// for i in 0..10 {
+    ///     map.insert(i, i * 2);
+    /// }
+    /// let entries: Vec<(u32, u32)> = map.range(3..5).collect();
+    /// assert_eq!(entries, vec![(3, 6), (4, 8)]);
+    /// ```
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Range<'_, K, V>
+    where K: Clone,
+          V: Clone
+    {
+        Range::new(self, clone_bound(range.start_bound()), clone_bound(range.end_bound()))
+    }
+
+    /// Obtain an iterator over clones of every key and value in the map, in sorted order.
+    pub fn iter(&self) -> Range<'_, K, V>
+    where K: Clone,
+          V: Clone
+    {
+        self.range(..)
+    }
+
+    /// Check whether the manager reports a backlog of unlinked towers.
+    pub fn has_reclaim_pressure(&self) -> bool {
+        self.manager.has_reclaim_pressure()
+    }
+
+    /// Find the predecessor and successor of the key on every level, unlinking any removed nodes on the way.
+    /// Every predecessor and successor is left protected by a hazard pointer. Returns true if the bottom
+    /// level successor holds the key.
+    fn find<Q>(&self, key: &Q, preds: &mut [*mut Node<K, V>; MAX_LEVEL], succs: &mut [*mut Node<K, V>; MAX_LEVEL]) -> bool
+    where K: Borrow<Q>,
+          Q: ?Sized + Ord
+    {
+        'retry: loop {
+            let mut pred = self.head;
+            for level in (0..MAX_LEVEL).rev() {
+                let mut curr = Node::link(pred, level).load(Acquire);
+                if atomic_markable::is_marked(curr) {
+                    continue 'retry;
+                }
+                self.manager.protect(curr, succ_hazard(level));
+                if !ptr::eq(curr, Node::link(pred, level).load(Acquire)) {
+                    continue 'retry;
+                }
+
+                while !curr.is_null() {
+                    let next = Node::link(curr, level).load(Acquire);
+                    self.manager.protect(atomic_markable::unmark(next), NEXT_HAZARD);
+                    // Check that both links are still in place, otherwise next could already be freed
+                    if !ptr::eq(next, Node::link(curr, level).load(Acquire)) ||
+                       !ptr::eq(curr, Node::link(pred, level).load(Acquire))
+                    {
+                        continue 'retry;
+                    }
+
+                    if atomic_markable::is_marked(next) {
+                        // curr has been removed, so unlink it at this level
+                        let unmarked_next = atomic_markable::unmark(next);
+                        if Node::link(pred, level).compare_exchange(curr, unmarked_next, AcqRel, Acquire).is_err() {
+                            continue 'retry;
+                        }
+                        curr = unmarked_next;
+                        self.manager.protect(curr, succ_hazard(level));
+                    } else if unsafe { (*curr).key().borrow() } < key {
+                        pred = curr;
+                        self.manager.protect(pred, pred_hazard(level));
+                        curr = next;
+                        self.manager.protect(curr, succ_hazard(level));
+                    } else {
+                        break;
+                    }
+                }
+                preds[level] = pred;
+                succs[level] = curr;
+            }
+            return !succs[0].is_null() && unsafe { (*succs[0]).key().borrow() } == key
+        }
+    }
+
+    /// Mark every level of the node, upper levels first so nothing more can be linked after it. Returns
+    /// true if this thread marked the bottom level, which is what removes the node from the map.
+    fn mark_removed(&self, node_ptr: *mut Node<K, V>) -> bool {
+        let node = unsafe { &*node_ptr };
+        for level in (1..node.next.len()).rev() {
+            let mut next = node.next[level].load(Acquire);
+            while !atomic_markable::is_marked(next) {
+                match node.next[level].compare_exchange(next, atomic_markable::mark(next), AcqRel, Acquire) {
+                    Ok(_) => break,
+                    Err(current) => next = current
+                }
+            }
+        }
+
+        let mut next = node.next[0].load(Acquire);
+        loop {
+            if atomic_markable::is_marked(next) {
+                return false
+            }
+            match node.next[0].compare_exchange(next, atomic_markable::mark(next), AcqRel, Acquire) {
+                Ok(_) => return true,
+                Err(current) => next = current
+            }
+        }
+    }
+
+    /// Give up a claim on the node, retiring it if this was the last claim.
+    fn release(&self, node_ptr: *mut Node<K, V>) {
+        if unsafe { (*node_ptr).claims.fetch_sub(1, AcqRel) } == 1 {
+            self.manager.retire(node_ptr, NEXT_HAZARD);
+        }
+    }
+
+    fn unprotect_all(&self) {
+        for hazard in 0..NUM_HAZARDS {
+            self.manager.unprotect(hazard);
+        }
+    }
+}
+
+impl<K: Ord + Send, V: Send> Default for OrderedMap<K, V> {
+    fn default() -> Self {
+        OrderedMap::new()
+    }
+}
+
+impl<K: Send, V: Send> Drop for OrderedMap<K, V> {
+    fn drop(&mut self) {
+        // Retired nodes have been unlinked from the bottom level, so they will not be found here
+        let mut current = self.head;
+        while !current.is_null() {
+            unsafe {
+                let next = atomic_markable::unmark(Node::link(current, 0).load(Relaxed));
+                drop(Box::from_raw(current));
+                current = next;
+            }
+        }
+    }
+}
+
+/// An iterator over a range of keys in an OrderedMap, created by the `range` and `iter` methods.
+/// The node the iterator is currently at is protected by a HPHandle.
+pub struct Range<'a, K: Send + 'a, V: Send + 'a> {
+    map: &'a OrderedMap<K, V>,
+    current: *mut Node<K, V>,
+    handle: Option<HPHandle<'a, Node<K, V>>>,
+    lower: Bound<K>,
+    upper: Bound<K>
+}
+
+impl<'a, K: Ord + Clone + Send, V: Clone + Send> Range<'a, K, V> {
+    fn new(map: &'a OrderedMap<K, V>, lower: Bound<K>, upper: Bound<K>) -> Self {
+        let mut range = Range {
+            map,
+            current: map.head,
+            handle: None,
+            lower,
+            upper
+        };
+        range.seek();
+        range
+    }
+
+    /// Move to the last node before the lower bound.
+    fn seek(&mut self) {
+        let key = match self.lower {
+            Bound::Unbounded => {
+                self.current = self.map.head;
+                self.handle = None;
+                return
+            },
+            Bound::Included(ref key) | Bound::Excluded(ref key) => key.clone()
+        };
+        let mut preds: [*mut Node<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        let mut succs: [*mut Node<K, V>; MAX_LEVEL] = [ptr::null_mut(); MAX_LEVEL];
+        self.map.find(&key, &mut preds, &mut succs);
+        // The predecessor is still protected by find, so it is safe to protect it again
+        self.handle = Some(self.map.manager.protect_dynamic(preds[0]));
+        self.current = preds[0];
+        self.map.unprotect_all();
+    }
+
+    fn above_lower(&self, key: &K) -> bool {
+        match self.lower {
+            Bound::Unbounded => true,
+            Bound::Included(ref lower) => key >= lower,
+            Bound::Excluded(ref lower) => key > lower
+        }
+    }
+
+    fn below_upper(&self, key: &K) -> bool {
+        match self.upper {
+            Bound::Unbounded => true,
+            Bound::Included(ref upper) => key <= upper,
+            Bound::Excluded(ref upper) => key < upper
+        }
+    }
+}
+
+impl<'a, K: Ord + Clone + Send, V: Clone + Send> Iterator for Range<'a, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            let next = Node::link(self.current, 0).load(Acquire);
+            if atomic_markable::is_marked(next) {
+                // The current node has been removed, so its next pointer cannot be trusted
+                self.seek();
+                continue;
+            }
+            if next.is_null() {
+                self.handle = None;
+                return None
+            }
+            let handle = self.map.manager.protect_dynamic(next);
+            if !ptr::eq(next, Node::link(self.current, 0).load(Acquire)) {
+                continue;
+            }
+            self.current = next;
+            self.handle = Some(handle);
+
+            let node = unsafe { &*next };
+            if atomic_markable::is_marked(node.next[0].load(Acquire)) {
+                continue;
+            }
+            let key = node.key();
+            if !self.above_lower(key) {
+                continue;
+            }
+            if !self.below_upper(key) {
+                self.handle = None;
+                return None
+            }
+            self.lower = Bound::Excluded(key.clone());
+            return Some((key.clone(), node.value.clone().unwrap()))
+        }
+    }
+}
+
+pub struct Node<K: Send, V: Send> {
+    key: Option<K>,
+    value: Option<V>,
+    next: Vec<AtomicPtr<Node<K, V>>>,
+    claims: AtomicUsize
+}
+
+impl<K: Send, V: Send> Node<K, V> {
+    fn new(key: K, value: V, levels: usize) -> Self {
+        let mut next = Vec::with_capacity(levels);
+        for _ in 0..levels {
+            next.push(AtomicPtr::default());
+        }
+        Node {
+            key: Some(key),
+            value: Some(value),
+            next,
+            // One claim for the inserting thread, and one for the removing thread
+            claims: AtomicUsize::new(2)
+        }
+    }
+
+    fn new_head() -> Self {
+        let mut next = Vec::with_capacity(MAX_LEVEL);
+        for _ in 0..MAX_LEVEL {
+            next.push(AtomicPtr::default());
+        }
+        Node {
+            key: None,
+            value: None,
+            next,
+            claims: AtomicUsize::new(1)
+        }
+    }
+
+    /// Get the link to the next node on the given level.
+    fn link<'a>(node: *mut Self, level: usize) -> &'a AtomicPtr<Self> {
+        unsafe { &(&(*node).next)[level] }
+    }
+
+    /// Only the head node has no key, and it is never compared.
+    fn key(&self) -> &K {
+        self.key.as_ref().unwrap()
+    }
+}
+
+fn pred_hazard(level: usize) -> usize {
+    level * 2
+}
+
+fn succ_hazard(level: usize) -> usize {
+    level * 2 + 1
+}
+
+/// Pick the number of levels for a new node, where each extra level is half as likely as the last.
+fn random_level() -> usize {
+    let rand: u32 = thread_rng().gen();
+    let level = (rand.trailing_zeros() as usize) + 1;
+    if level > MAX_LEVEL { MAX_LEVEL } else { level }
+}
+
+fn clone_bound<K: Clone>(bound: Bound<&K>) -> Bound<K> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone()),
+        Bound::Unbounded => Bound::Unbounded
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use rand::{thread_rng, Rng};
+
+    use super::OrderedMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicIsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_single_threaded() {
+        let map: OrderedMap<u32, String> = OrderedMap::new();
+        for i in 0..100 {
+            assert!(map.insert(i, format!("{}", i)).is_ok());
+        }
+        assert!(map.insert(5, "5".to_owned()).is_err());
+
+        assert_eq!(map.get(&5), Some("5".to_owned()));
+        assert_eq!(map.get(&100), None);
+
+        assert_eq!(map.remove(&5), Some("5".to_owned()));
+        assert_eq!(map.remove(&5), None);
+        assert_eq!(map.get(&5), None);
+        assert!(map.insert(5, "five".to_owned()).is_ok());
+        assert_eq!(map.get(&5), Some("five".to_owned()));
+    }
+
+    #[test]
+    fn test_sorted_iteration() {
+        let map: OrderedMap<u32, u32> = OrderedMap::new();
+        let mut keys: Vec<u32> = (0..500).collect();
+        thread_rng().shuffle(&mut keys);
+        for &key in &keys {
+            assert!(map.insert(key, key * 2).is_ok());
+        }
+
+        let entries: Vec<(u32, u32)> = map.iter().collect();
+        let expected: Vec<(u32, u32)> = (0..500).map(|key| (key, key * 2)).collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_range() {
+        let map: OrderedMap<u32, u32> = OrderedMap::new();
+        for key in 0..100 {
+            assert!(map.insert(key * 2, key).is_ok());
+        }
+
+        let keys: Vec<u32> = map.range(10..20).map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![10, 12, 14, 16, 18]);
+        let keys: Vec<u32> = map.range(11..=20).map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![12, 14, 16, 18, 20]);
+        let keys: Vec<u32> = map.range(..5).map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![0, 2, 4]);
+        let keys: Vec<u32> = map.range(195..).map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![196, 198]);
+        assert_eq!(map.range(300..).count(), 0);
+    }
+
+    #[test]
+    fn test_pop_first() {
+        let map: OrderedMap<u32, u32> = OrderedMap::new();
+        assert_eq!(map.pop_first(), None);
+        for key in (0..100).rev() {
+            assert!(map.insert(key, key * 2).is_ok());
+        }
+        assert_eq!(map.remove(&0), Some(0));
+        for key in 1..100 {
+            assert_eq!(map.pop_first(), Some((key, key * 2)));
+        }
+        assert_eq!(map.pop_first(), None);
+        assert_eq!(map.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_insert_remove() {
+        let map: Arc<OrderedMap<usize, usize>> = Arc::new(OrderedMap::new());
+        // The number of successful inserts minus successful removes for each key
+        let counts: Arc<Vec<AtomicIsize>> = Arc::new((0..200).map(|_| AtomicIsize::new(0)).collect());
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+
+        for _ in 0..8 {
+            let map_clone = map.clone();
+            let counts_clone = counts.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..10000 {
+                    let key = thread_rng().gen_range(0, 200);
+                    if thread_rng().gen_range(0, 2) == 0 {
+                        if map_clone.insert(key, key).is_ok() {
+                            counts_clone[key].fetch_add(1, Ordering::Relaxed);
+                        }
+                    } else {
+                        if let Some(value) = map_clone.remove(&key) {
+                            assert_eq!(value, key);
+                            counts_clone[key].fetch_sub(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }));
+        }
+
+        for handle in wait_vec {
+            if handle.join().is_err() {
+                panic!("A thread panicked, test failed!")
+            }
+        }
+
+        let expected: Vec<usize> = (0..200).filter(|&key| {
+            let count = counts[key].load(Ordering::Relaxed);
+            assert!(count == 0 || count == 1);
+            count == 1
+        }).collect();
+        let keys: Vec<usize> = map.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, expected);
+    }
+}
@@ -0,0 +1,417 @@
+use memory::{HPBRManager, HPHandle};
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, AcqRel};
+use std::borrow::Borrow;
+use std::ptr;
+use super::utils::atomic_markable;
+
+const PREV_HAZARD: usize = 0;
+const CURR_HAZARD: usize = 1;
+const NEXT_HAZARD: usize = 2;
+
+/// A lock-free ordered set based on a sorted linked list.
+///
+/// This set is an implementation of the linked list described in [A Pragmatic Implementation of Non-Blocking
+/// Linked-Lists](https://link.springer.com/chapter/10.1007/3-540-45414-4_21) by Harris, with the changes from
+/// [High Performance Dynamic Lock-Free Hash Tables and List-Based Sets](https://dl.acm.org/citation.cfm?id=564870)
+/// by Michael which make it safe to use with hazard pointers. Values are kept in sorted order, so the set can be
+/// iterated over in order, at the cost of every operation taking time linear in the size of the set.
+///
+/// A node is removed by marking its next pointer, which stops anything being linked after it. Any thread which
+/// comes across a marked node while searching the list unlinks it, and the thread which unlinks it retires it.
+pub struct OrderedSet<T: Send> {
+    head: *mut Node<T>,
+    manager: HPBRManager<Node<T>>
+}
+
+unsafe impl<T: Send> Send for OrderedSet<T> {}
+unsafe impl<T: Send> Sync for OrderedSet<T> {}
+
+struct Node<T: Send> {
+    value: Option<T>,
+    next: AtomicPtr<Node<T>>
+}
+
+impl<T: Ord + Send> OrderedSet<T> {
+    /// Create a new, empty OrderedSet.
+    /// # Examples
+    /// ```
+    /// let set: OrderedSet<u32> = OrderedSet::new();
+    /// ```
+    pub fn new() -> Self {
+        OrderedSet {
+            head: Box::into_raw(Box::new(Node::new_head())),
+            manager: HPBRManager::new(100, 3)
+        }
+    }
+
+    /// Insert the given value into the set.
+    /// # Errors
+    /// If the value is already in the set, the value is returned in an Err.
+    /// # Examples
+    /// ```
+    /// let set: OrderedSet<u32> = OrderedSet::new();
+    /// assert_eq!(set.insert(8), Ok(()));
+    /// assert_eq!(set.insert(8), Err(8));
+    /// ```
+    pub fn insert(&self, value: T) -> Result<(), T> {
+        let mut node = Box::new(Node::new(value));
+        loop {
+            let (found, prev, curr) = self.find(node.value());
+            if found {
+                self.unprotect_all();
+                return Err(node.value.take().unwrap())
+            }
+            node.next.store(curr, Relaxed);
+            let node_ptr = Box::into_raw(node);
+            match Node::link(prev).compare_exchange(curr, node_ptr, AcqRel, Acquire) {
+                Ok(_) => {
+                    self.unprotect_all();
+                    return Ok(())
+                },
+                Err(_) => { node = unsafe { Box::from_raw(node_ptr) }; }
+            }
+        }
+    }
+
+    /// Check whether the given value is in the set.
+    /// # Examples
+    /// ```
+    /// let set: OrderedSet<u32> = OrderedSet::new();
+    /// set.insert(8);
+    /// assert!(set.contains(&8));
+    /// assert!(!set.contains(&9));
+    /// ```
+    pub fn contains<Q>(&self, value: &Q) -> bool
+    where T: Borrow<Q>,
+          Q: ?Sized + Ord
+    {
+        let (found, _, _) = self.find(value);
+        self.unprotect_all();
+        found
+    }
+
+    /// Remove the given value from the set, returning true if it was in the set.
+    /// # Examples
+    /// ```
+    /// let set: OrderedSet<u32> = OrderedSet::new();
+    /// set.insert(8);
+    /// assert!(set.remove(&8));
+    /// assert!(!set.remove(&8));
+    /// ```
+    pub fn remove<Q>(&self, value: &Q) -> bool
+    where T: Borrow<Q>,
+          Q: ?Sized + Ord
+    {
+        loop {
+            let (found, prev, curr) = self.find(value);
+            if !found {
+                self.unprotect_all();
+                return false
+            }
+
+            // Whoever marks the node removes it
+            let next = Node::link(curr).load(Acquire);
+            if atomic_markable::is_marked(next) {
+                continue;
+            }
+            if Node::link(curr).compare_exchange(next, atomic_markable::mark(next), AcqRel, Acquire).is_err() {
+                continue;
+            }
+
+            // Try to unlink the node straight away, otherwise leave it to the next search which finds it
+            if Node::link(prev).compare_exchange(curr, next, AcqRel, Acquire).is_ok() {
+                self.manager.retire(curr, CURR_HAZARD);
+            } else {
+                self.find(value);
+            }
+            self.unprotect_all();
+            return true
+        }
+    }
+
+    /// Obtain an iterator over clones of the values in the set, in sorted order. Values which are inserted
+    /// or removed during iteration may or may not be seen.
+    /// # Examples
+    /// ```
+    /// let set: OrderedSet<u32> = OrderedSet::new();
+    /// set.insert(2);
+    /// set.insert(1);
+    /// assert_eq!(set.iter().collect::<Vec<u32>>(), vec![1, 2]);
+    /// ```
+    pub fn iter(&self) -> Iter<'_, T>
+    where T: Clone
+    {
+        Iter {
+            set: self,
+            current: self.head,
+            handle: None,
+            last: None
+        }
+    }
+
+    /// Check whether the set's removed nodes are accumulating faster than they can be freed.
+    pub fn has_reclaim_pressure(&self) -> bool {
+        self.manager.has_reclaim_pressure()
+    }
+
+    /// Find the last node with a value less than the given one, and the node after it, unlinking any removed
+    /// nodes on the way. Both nodes are left protected by hazard pointers. Returns true if the second node
+    /// holds the value.
+    fn find<Q>(&self, value: &Q) -> (bool, *mut Node<T>, *mut Node<T>)
+    where T: Borrow<Q>,
+          Q: ?Sized + Ord
+    {
+        'retry: loop {
+            // The head is never removed, so it does not need protecting
+            let mut prev = self.head;
+            let mut curr = Node::link(prev).load(Acquire);
+            loop {
+                if curr.is_null() {
+                    return (false, prev, curr)
+                }
+                self.manager.protect(curr, CURR_HAZARD);
+                // If prev has been removed or curr unlinked, curr could already be retired
+                if !ptr::eq(curr, Node::link(prev).load(Acquire)) {
+                    continue 'retry;
+                }
+
+                let next = Node::link(curr).load(Acquire);
+                self.manager.protect(atomic_markable::unmark(next), NEXT_HAZARD);
+                if !ptr::eq(next, Node::link(curr).load(Acquire)) {
+                    continue 'retry;
+                }
+
+                if atomic_markable::is_marked(next) {
+                    // curr has been removed, and its next pointer can no longer change, so unlink it
+                    let unmarked_next = atomic_markable::unmark(next);
+                    if Node::link(prev).compare_exchange(curr, unmarked_next, AcqRel, Acquire).is_err() {
+                        continue 'retry;
+                    }
+                    self.manager.retire(curr, CURR_HAZARD);
+                    curr = unmarked_next;
+                    continue;
+                }
+
+                let curr_value: &Q = unsafe { (*curr).value().borrow() };
+                if curr_value >= value {
+                    return (curr_value == value, prev, curr)
+                }
+                prev = curr;
+                self.manager.protect(prev, PREV_HAZARD);
+                curr = next;
+            }
+        }
+    }
+
+    fn unprotect_all(&self) {
+        self.manager.unprotect(PREV_HAZARD);
+        self.manager.unprotect(CURR_HAZARD);
+        self.manager.unprotect(NEXT_HAZARD);
+    }
+}
+
+impl<T: Ord + Send> Default for OrderedSet<T> {
+    fn default() -> Self {
+        OrderedSet::new()
+    }
+}
+
+impl<T: Send> Drop for OrderedSet<T> {
+    fn drop(&mut self) {
+        // Retired nodes have been unlinked, so they will not be found here
+        let mut current = self.head;
+        while !current.is_null() {
+            unsafe {
+                let next = atomic_markable::unmark(Node::link(current).load(Relaxed));
+                drop(Box::from_raw(current));
+                current = next;
+            }
+        }
+    }
+}
+
+/// An iterator over the values in an OrderedSet, created by the `iter` method. The node the iterator
+/// is currently at is protected by a HPHandle.
+pub struct Iter<'a, T: Send + 'a> {
+    set: &'a OrderedSet<T>,
+    current: *mut Node<T>,
+    handle: Option<HPHandle<'a, Node<T>>>,
+    last: Option<T>
+}
+
+impl<'a, T: Ord + Clone + Send> Iter<'a, T> {
+    /// Move back to the last node before the last value returned.
+    fn seek(&mut self) {
+        let last = match self.last {
+            None => {
+                self.current = self.set.head;
+                self.handle = None;
+                return
+            },
+            Some(ref last) => last.clone()
+        };
+        let (_, prev, _) = self.set.find(&last);
+        // The predecessor is still protected by find, so it is safe to protect it again
+        self.handle = Some(self.set.manager.protect_dynamic(prev));
+        self.current = prev;
+        self.set.unprotect_all();
+    }
+}
+
+impl<'a, T: Ord + Clone + Send> Iterator for Iter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            let next = Node::link(self.current).load(Acquire);
+            if atomic_markable::is_marked(next) {
+                // The current node has been removed, so its next pointer cannot be trusted
+                self.seek();
+                continue;
+            }
+            if next.is_null() {
+                self.handle = None;
+                return None
+            }
+            let handle = self.set.manager.protect_dynamic(next);
+            if !ptr::eq(next, Node::link(self.current).load(Acquire)) {
+                continue;
+            }
+            self.current = next;
+            self.handle = Some(handle);
+
+            let node = unsafe { &*next };
+            if atomic_markable::is_marked(node.next.load(Acquire)) {
+                continue;
+            }
+            // After a seek the node holding the last value may be reached again
+            if let Some(ref last) = self.last {
+                if node.value() <= last {
+                    continue;
+                }
+            }
+            let value = node.value().clone();
+            self.last = Some(value.clone());
+            return Some(value)
+        }
+    }
+}
+
+impl<T: Send> Node<T> {
+    fn new(value: T) -> Self {
+        Node {
+            value: Some(value),
+            next: AtomicPtr::default()
+        }
+    }
+
+    fn new_head() -> Self {
+        Node {
+            value: None,
+            next: AtomicPtr::default()
+        }
+    }
+
+    fn link<'a>(node: *mut Self) -> &'a AtomicPtr<Self> {
+        unsafe { &(*node).next }
+    }
+
+    /// Only the head node has no value, and it is never compared.
+    fn value(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use rand::{thread_rng, Rng};
+
+    use super::OrderedSet;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicIsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_single_threaded() {
+        let set: OrderedSet<u32> = OrderedSet::new();
+        let mut values: Vec<u32> = (0..500).collect();
+        thread_rng().shuffle(&mut values);
+        for &value in &values {
+            assert_eq!(set.insert(value), Ok(()));
+        }
+        assert_eq!(set.insert(5), Err(5));
+
+        for &value in values.iter().filter(|&&value| value % 2 == 0) {
+            assert!(set.remove(&value));
+        }
+        assert!(!set.remove(&4));
+        for value in 0..500 {
+            assert_eq!(set.contains(&value), value % 2 == 1);
+        }
+
+        let remaining: Vec<u32> = set.iter().collect();
+        assert_eq!(remaining, (0..500).filter(|value| value % 2 == 1).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_borrowed_values() {
+        let set: OrderedSet<String> = OrderedSet::new();
+        assert!(set.insert("world".to_owned()).is_ok());
+        assert!(set.insert("hello".to_owned()).is_ok());
+        assert!(set.contains("hello"));
+        assert!(set.remove("world"));
+        assert_eq!(set.iter().collect::<Vec<String>>(), vec!["hello".to_owned()]);
+    }
+
+    #[test]
+    fn test_concurrent_insert_remove() {
+        let set: Arc<OrderedSet<usize>> = Arc::new(OrderedSet::new());
+        // The number of successful inserts minus successful removes for each value
+        let counts: Arc<Vec<AtomicIsize>> = Arc::new((0..200).map(|_| AtomicIsize::new(0)).collect());
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+
+        for _ in 0..8 {
+            let set_clone = set.clone();
+            let counts_clone = counts.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..10000 {
+                    let value = thread_rng().gen_range(0, 200);
+                    match thread_rng().gen_range(0, 3) {
+                        0 => {
+                            if set_clone.insert(value).is_ok() {
+                                counts_clone[value].fetch_add(1, Ordering::Relaxed);
+                            }
+                        },
+                        1 => {
+                            if set_clone.remove(&value) {
+                                counts_clone[value].fetch_sub(1, Ordering::Relaxed);
+                            }
+                        },
+                        _ => {
+                            // Iterating while others insert and remove still gives sorted values
+                            let values: Vec<usize> = set_clone.iter().take(20).collect();
+                            assert!(values.windows(2).all(|pair| pair[0] < pair[1]));
+                        }
+                    }
+                }
+            }));
+        }
+
+        for handle in wait_vec {
+            if handle.join().is_err() {
+                panic!("A thread panicked, test failed!")
+            }
+        }
+
+        let expected: Vec<usize> = (0..200).filter(|&value| {
+            let count = counts[value].load(Ordering::Relaxed);
+            assert!(count == 0 || count == 1);
+            count == 1
+        }).collect();
+        for value in 0..200 {
+            assert_eq!(set.contains(&value), expected.contains(&value));
+        }
+        assert_eq!(set.iter().collect::<Vec<usize>>(), expected);
+    }
+}
@@ -0,0 +1,151 @@
+use super::stack::Stack;
+
+/// A lock-free pool of reusable objects.
+///
+/// Objects which are expensive to create, such as large buffers, can be given back to the pool once
+/// a thread is done with them, and taken out again later by any thread instead of creating a new one.
+/// The free objects are kept on a Stack, so the most recently released object is the first to be
+/// reused, which is the one most likely to still be in the cache. The pool does not reset objects,
+/// so anything which should not be seen by the next user must be cleared before release.
+pub struct Pool<T: Send> {
+    free: Stack<T>
+}
+
+impl<T: Send> Pool<T> {
+    /// Create a new, empty Pool.
+    /// # Examples
+    /// ```
+    /// let pool: Pool<Vec<u8>> = Pool::new();
+    /// ```
+    pub fn new() -> Self {
+        Pool {
+            free: Stack::default()
+        }
+    }
+
+    /// Take a free object out of the pool, or return None if there are none.
+    /// # Examples
+    /// ```
+    /// let pool: Pool<Vec<u8>> = Pool::new();
+    /// assert_eq!(pool.acquire(), None);
+    /// pool.release(Vec::with_capacity(1024));
+    /// assert!(pool.acquire().is_some());
+    /// ```
+    pub fn acquire(&self) -> Option<T> {
+        self.free.pop()
+    }
+
+    /// Take a free object out of the pool, or create a new one with the given function if there are none.
+    /// # Examples
+    /// ```
+    /// let pool: Pool<Vec<u8>> = Pool::new();
+    /// let buffer = pool.acquire_or_else(|| Vec::with_capacity(1024));
+    /// ```
+    pub fn acquire_or_else<F: FnOnce() -> T>(&self, create: F) -> T {
+        match self.free.pop() {
+            Some(obj) => obj,
+            None => create()
+        }
+    }
+
+    /// Give an object back to the pool so that it can be reused.
+    /// # Examples
+    /// ```
+    /// let pool: Pool<Vec<u8>> = Pool::new();
+    /// let mut buffer = pool.acquire_or_else(|| Vec::with_capacity(1024));
+    /// buffer.clear();
+    /// pool.release(buffer);
+    /// ```
+    pub fn release(&self, obj: T) {
+        self.free.push(obj);
+    }
+
+    /// The number of free objects in the pool. See `Stack::len`.
+    pub fn len(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Check whether the pool has no free objects. See `Stack::is_empty`.
+    pub fn is_empty(&self) -> bool {
+        self.free.is_empty()
+    }
+}
+
+impl<T: Send> Default for Pool<T> {
+    fn default() -> Self {
+        Pool::new()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::Pool;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+    use std::thread;
+
+    struct Buffer {
+        id: usize,
+        in_use: Arc<AtomicBool>
+    }
+
+    #[test]
+    fn test_reuse() {
+        let pool: Pool<Buffer> = Pool::new();
+        let created = AtomicUsize::new(0);
+        let create = || Buffer {
+            id: created.fetch_add(1, Ordering::Relaxed),
+            in_use: Arc::new(AtomicBool::new(false))
+        };
+
+        let first = pool.acquire_or_else(&create);
+        let second = pool.acquire_or_else(&create);
+        assert_eq!((first.id, second.id), (0, 1));
+        pool.release(first);
+        assert_eq!(pool.len(), 1);
+
+        let reused = pool.acquire_or_else(&create);
+        assert_eq!(reused.id, 0);
+        assert!(pool.acquire().is_none());
+        pool.release(reused);
+        pool.release(second);
+        assert_eq!(pool.acquire().map(|buffer| buffer.id), Some(1));
+        assert_eq!(created.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_concurrent_acquire_release() {
+        const NUM_THREADS: usize = 16;
+        let pool: Arc<Pool<Buffer>> = Arc::new(Pool::new());
+        let created = Arc::new(AtomicUsize::new(0));
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+
+        for _ in 0..NUM_THREADS {
+            let pool_clone = pool.clone();
+            let created_clone = created.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..10000 {
+                    let buffer = pool_clone.acquire_or_else(|| Buffer {
+                        id: created_clone.fetch_add(1, Ordering::Relaxed),
+                        in_use: Arc::new(AtomicBool::new(false))
+                    });
+                    // No two threads ever hold the same object
+                    assert!(!buffer.in_use.swap(true, Ordering::AcqRel));
+                    buffer.in_use.store(false, Ordering::Release);
+                    pool_clone.release(buffer);
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
+        }
+
+        // Each thread holds at most one object at a time, so no more than that are ever created
+        let created = created.load(Ordering::Relaxed);
+        assert!(created <= NUM_THREADS);
+        assert_eq!(pool.len(), created);
+        let mut ids: Vec<usize> = (0..created).map(|_| pool.acquire().unwrap().id).collect();
+        ids.sort();
+        assert_eq!(ids, (0..created).collect::<Vec<usize>>());
+    }
+}
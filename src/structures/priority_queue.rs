@@ -0,0 +1,132 @@
+use super::ordered_map::OrderedMap;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+/// A lock-free priority queue based on a skip list.
+///
+/// The elements are kept in sorted order in an OrderedMap, so taking the smallest element is a matter
+/// of removing the first node of the skip list. Each element is stored alongside a sequence number,
+/// which lets equal elements be in the queue at the same time. Equal elements pushed by the same thread
+/// come out in the order they were pushed.
+pub struct PriorityQueue<T: Send> {
+    map: OrderedMap<(T, usize), ()>,
+    sequence: AtomicUsize
+}
+
+impl<T: Ord + Clone + Send> PriorityQueue<T> {
+    /// Create a new, empty PriorityQueue.
+    /// # Examples
+    /// ```
+    /// let queue: PriorityQueue<u32> = PriorityQueue::new();
+    /// ```
+    pub fn new() -> Self {
+        PriorityQueue {
+            map: OrderedMap::new(),
+            sequence: AtomicUsize::new(0)
+        }
+    }
+
+    /// Add an element to the queue.
+    /// # Examples
+    /// ```
+    /// let queue: PriorityQueue<u32> = PriorityQueue::new();
+    /// queue.push(8);
+    /// ```
+    pub fn push(&self, value: T) {
+        let sequence = self.sequence.fetch_add(1, Relaxed);
+        // The sequence number makes every key unique, so the insert cannot fail
+        let _ = self.map.insert((value, sequence), ());
+    }
+
+    /// Remove the smallest element from the queue, or return None if the queue is empty.
+    /// # Examples
+    /// ```
+    /// let queue: PriorityQueue<u32> = PriorityQueue::new();
+    /// queue.push(8);
+    /// queue.push(3);
+    /// assert_eq!(queue.pop_min(), Some(3));
+    /// assert_eq!(queue.pop_min(), Some(8));
+    /// assert_eq!(queue.pop_min(), None);
+    /// ```
+    pub fn pop_min(&self) -> Option<T> {
+        self.map.pop_first().map(|((value, _), ())| value)
+    }
+
+    /// Check whether the underlying map reports reclamation pressure.
+    pub fn has_reclaim_pressure(&self) -> bool {
+        self.map.has_reclaim_pressure()
+    }
+}
+
+impl<T: Ord + Clone + Send> Default for PriorityQueue<T> {
+    fn default() -> Self {
+        PriorityQueue::new()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use rand::{thread_rng, Rng};
+
+    use super::PriorityQueue;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_pop_min_sorted() {
+        let queue: PriorityQueue<u32> = PriorityQueue::new();
+        let mut values: Vec<u32> = (0..1000).collect();
+        thread_rng().shuffle(&mut values);
+        for &value in &values {
+            queue.push(value);
+        }
+        // Equal elements are all kept
+        queue.push(500);
+
+        let mut expected: Vec<u32> = (0..1000).collect();
+        expected.insert(500, 500);
+        let popped: Vec<u32> = (0..1001).map(|_| queue.pop_min().unwrap()).collect();
+        assert_eq!(popped, expected);
+        assert_eq!(queue.pop_min(), None);
+    }
+
+    #[test]
+    fn test_concurrent_push_pop() {
+        let queue: Arc<PriorityQueue<u32>> = Arc::new(PriorityQueue::new());
+        let mut wait_vec: Vec<thread::JoinHandle<Vec<u32>>> = Vec::new();
+
+        for id in 0..8 {
+            let queue_clone = queue.clone();
+            wait_vec.push(thread::spawn(move || {
+                let mut popped = Vec::new();
+                for i in 0..2000 {
+                    // Every thread pushes the same values, so many of them are equal
+                    queue_clone.push(i % 500);
+                    if (i + id) % 2 == 0 {
+                        if let Some(value) = queue_clone.pop_min() {
+                            popped.push(value);
+                        }
+                    }
+                }
+                popped
+            }));
+        }
+
+        let mut popped: Vec<u32> = Vec::new();
+        for handle in wait_vec {
+            popped.append(&mut handle.join().unwrap());
+        }
+        // Whatever is left comes out in order
+        let mut last = 0;
+        while let Some(value) = queue.pop_min() {
+            assert!(value >= last);
+            last = value;
+            popped.push(value);
+        }
+
+        popped.sort();
+        let mut expected: Vec<u32> = (0..8).flat_map(|_| (0..2000).map(|i| i % 500)).collect();
+        expected.sort();
+        assert_eq!(popped, expected);
+    }
+}
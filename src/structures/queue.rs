@@ -1,35 +1,96 @@
+#[cfg(feature = "std")]
 use memory::HPBRManager;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use memory::ReclamationStrategy;
+use sync::atomic::{AtomicPtr, AtomicUsize, AtomicBool, Ordering};
 use std::ptr;
-use std::thread;
-use std::time::Duration;
-use rand::{SmallRng, NewRng, Rng};
 use std::cell::UnsafeCell;
-use std::cmp;
+use super::utils::cache_padded::CachePadded;
+use super::utils::backoff::Backoff;
+use super::utils::read_mark::ReadMark;
+use std::boxed::Box;
+use std::vec::Vec;
+// loom's atomics cannot be created in a static, and the queue ids are never ordered against anything else
+use std::sync::atomic::AtomicUsize as StaticAtomicUsize;
 
-const MAX_BACKOFF: u32 = 2048;
+// Hands out a different id to each queue, so that a handle can be checked against the queue it came from
+static NEXT_QUEUE_ID: StaticAtomicUsize = StaticAtomicUsize::new(0);
 
-/// A lock-free Michael-Scott queue.
+/// A Michael-Scott queue.
 ///
 /// This queue is an implementation of that described in [Simple, Fast, and Practical
 /// Non-blocking and Blocking Concurrent Queue Algorithms](https://dl.acm.org/citation.cfm?id=248106). 
 /// It is implemented as a linked-list of nodes.
+///
+/// Enqueueing is lock-free, but dequeueing is not. `peek`, `contains` and `to_vec` read elements in
+/// place, so before moving an element out, the thread dequeueing it waits for any thread still reading
+/// it. A reader which stalls part way through holds up that dequeuer, though not the rest of the queue.
+///
+/// Elements added with `enqueue_handle` can also be removed from the middle of the queue through
+/// their `NodeHandle`. A removed element's node stays in the list until it reaches the front of the
+/// queue, where dequeue skips over it.
+///
+/// Dequeued nodes are freed through an HPBRManager unless the queue is created with `with_manager`,
+/// which takes any other ReclamationStrategy.
 #[derive(Debug)]
-pub struct Queue<T: Send> {
-    head: AtomicPtr<Node<T>>,
-    tail: AtomicPtr<Node<T>>,
-    manager: HPBRManager<Node<T>>,
-    rng: UnsafeCell<SmallRng>
+pub struct Queue<T: Send,
+                 #[cfg(feature = "std")] M: ReclamationStrategy<Node<T>> = HPBRManager<Node<T>>,
+                 #[cfg(not(feature = "std"))] M: ReclamationStrategy<Node<T>>> {
+    // Dequeuers work on the head and enqueuers on the tail, so they are kept on separate cache lines
+    head: CachePadded<AtomicPtr<Node<T>>>,
+    tail: CachePadded<AtomicPtr<Node<T>>>,
+    manager: M,
+    node_cap: Option<usize>,
+    len: AtomicUsize,
+    // Copied into every NodeHandle, since the queue itself may move
+    id: usize,
+    // How many enqueue attempts have lost to another thread, so that tests can account for every
+    // element enqueue_weak gives up on
+    #[cfg(test)]
+    lost_enqueues: AtomicUsize
 }
 
-unsafe impl<T: Send> Sync for Queue<T> {}
+unsafe impl<T: Send, M: ReclamationStrategy<Node<T>> + Sync> Sync for Queue<T, M> {}
 
+/// A node of a Queue. It is only public so that it can be named in the bounds of a Queue's
+/// reclamation strategy, and cannot be used outside the crate.
 #[derive(Debug)]
-struct Node<T: Send> {
+pub struct Node<T: Send> {
     next: AtomicPtr<Node<T>>,
-    value: Option<T>
+    value: UnsafeCell<Option<T>>,
+    cell: *mut HandleCell<T>,
+    // Threads walking the queue can still read the value after the node is dequeued, so the dequeuer
+    // waits for them before moving the value out
+    readers: ReadMark
 }
 
+unsafe impl<T: Send> Send for Node<T> {}
+unsafe impl<T: Send> Sync for Node<T> {}
+
+/// A handle to an element added with `enqueue_handle`, which can be used to remove that element
+/// from the queue with `remove_handle`, wherever it is in the queue.
+pub struct NodeHandle<T: Send> {
+    cell: *mut HandleCell<T>,
+    queue_id: usize
+}
+
+unsafe impl<T: Send> Send for NodeHandle<T> {}
+
+/// The error returned by `try_dequeue` when another thread changed the queue during the attempt.
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Contended;
+
+/// Holds the value of an element which has a handle. The cell is shared between the element's node and
+/// its handle, so that whichever of dequeue and remove_handle claims it first gets the value, and it
+/// is freed once both the node and the handle are gone.
+struct HandleCell<T: Send> {
+    value: UnsafeCell<Option<T>>,
+    claimed: AtomicBool,
+    refs: AtomicUsize,
+    readers: ReadMark
+}
+
+#[cfg(feature = "std")]
 impl<T: Send> Queue<T> {
     /// Create a new Queue.
     /// # Examples
@@ -37,22 +98,53 @@ impl<T: Send> Queue<T> {
     /// let queue: Queue<String> = Queue::new();
     /// ```
     pub fn new() -> Self {
+        Queue::with_manager(HPBRManager::new(100, 2))
+    }
+
+    /// Create a new Queue where `enqueue_weak` will give up once the queue holds roughly
+    /// `node_cap` elements, as counted by `len`. The cap does not affect `enqueue`.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<String> = Queue::with_node_cap(1);
+    /// assert!(queue.enqueue_weak("hello".to_owned()));
+    /// assert!(!queue.enqueue_weak("world".to_owned()));
+    /// ```
+    pub fn with_node_cap(node_cap: usize) -> Self {
+        let mut queue = Queue::new();
+        queue.node_cap = Some(node_cap);
+        queue
+    }
+
+    /// Create a new Queue where each thread's list of dequeued nodes waiting to be freed has room
+    /// for `retire_capacity` nodes from the start. See `HPBRManager::with_retire_capacity`.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<String> = Queue::with_retire_capacity(128);
+    /// ```
+    pub fn with_retire_capacity(retire_capacity: usize) -> Self {
+        Queue::with_manager(HPBRManager::with_retire_capacity(100, 2, retire_capacity))
+    }
+}
+
+impl<T: Send, M: ReclamationStrategy<Node<T>>> Queue<T, M> {
+    /// Create a new Queue which frees dequeued nodes through the given ReclamationStrategy. The
+    /// strategy must provide two hazard pointers per thread.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<String> = Queue::with_manager(HPBRManager::new(50, 2));
+    /// ```
+    pub fn with_manager(manager: M) -> Self {
         let dummy_node = Box::into_raw(Box::new(Node::new_dummy_node()));
         Queue {
-            head: AtomicPtr::new(dummy_node),
-            tail: AtomicPtr::new(dummy_node),
-            manager: HPBRManager::new(100, 2),
-            rng: UnsafeCell::new(SmallRng::new())
-        }
-    }
-    
-    fn backoff(&self, max_backoff: u32) -> u32 {
-        unsafe {
-            let rng = &mut *self.rng.get();
-            let backoff_time = rng.gen_range(0, max_backoff);
-            thread::sleep(Duration::new(0, backoff_time * 10));    
+            head: CachePadded::new(AtomicPtr::new(dummy_node)),
+            tail: CachePadded::new(AtomicPtr::new(dummy_node)),
+            manager,
+            node_cap: None,
+            len: AtomicUsize::new(0),
+            id: NEXT_QUEUE_ID.fetch_add(1, Ordering::Relaxed),
+            #[cfg(test)]
+            lost_enqueues: AtomicUsize::new(0)
         }
-        cmp::min(max_backoff * 2, MAX_BACKOFF)
     }
 
     /// Add a new element to the back of the queue.
@@ -62,22 +154,175 @@ impl<T: Send> Queue<T> {
     /// queue.enqueue("hello".to_owned());
     /// ```
     pub fn enqueue(&self, val: T) {
-        let mut backoff = 1;
-        let mut node = Box::new(Node::new(val));
+        self.enqueue_node(Box::new(Node::new(val)));
+    }
+
+    /// Add every element of the iterator to the back of the queue, in order. The elements are linked
+    /// together before they are added, so the whole batch is added with a single CAS and elements from
+    /// other threads are never interleaved with it.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<u8> = Queue::new();
+    /// queue.enqueue_all(vec![1, 2]);
+    /// assert_eq!(queue.dequeue(), Some(1));
+    /// assert_eq!(queue.dequeue(), Some(2));
+    /// ```
+    pub fn enqueue_all<I: IntoIterator<Item = T>>(&self, items: I) {
+        let nodes: Vec<Box<Node<T>>> = items.into_iter().map(|val| Box::new(Node::new(val))).collect();
+        let count = nodes.len();
+        let mut nodes = nodes.into_iter().rev();
+        let last = match nodes.next() {
+            Some(node) => Box::into_raw(node),
+            None => { return; }
+        };
+        let mut first = last;
+        for node in nodes {
+            node.next.store(first, Ordering::Relaxed);
+            first = Box::into_raw(node);
+        }
+
+        let mut backoff = Backoff::new();
+        while !self.try_enqueue_chain(first, last) {
+            backoff.spin();
+        }
+        self.len.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Attempt to link a chain of nodes onto the end of the queue, returning false if the tail moved.
+    fn try_enqueue_chain(&self, first: *mut Node<T>, last: *mut Node<T>) -> bool {
+        let tail = self.tail.load(Ordering::Acquire);
+        self.manager.protect(tail, 0);
+        if !ptr::eq(tail, self.tail.load(Ordering::Acquire)) {
+            return false
+        }
+        let next = unsafe { (*tail).next.load(Ordering::Acquire) };
+        if !next.is_null() {
+            let _ = self.tail.compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
+            return false
+        }
+
+        match unsafe { (*tail).next.compare_exchange(ptr::null_mut(), first, Ordering::Release, Ordering::Relaxed) } {
+            Ok(_) => {
+                // Other threads may already be moving the tail along the chain one node at a time
+                let _ = self.tail.compare_exchange(tail, last, Ordering::Release, Ordering::Relaxed);
+                true
+            },
+            Err(_) => false
+        }
+    }
+
+    /// Move every element of the other queue onto the back of this one, in order, consuming the other
+    /// queue. No other thread can be using the other queue, so its elements are already linked together
+    /// and are added with a single CAS, like `enqueue_all`.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<u8> = Queue::new();
+    /// let other: Queue<u8> = Queue::new();
+    /// queue.enqueue(1);
+    /// other.enqueue(2);
+    /// queue.append(other);
+    /// assert_eq!(queue.dequeue(), Some(1));
+    /// assert_eq!(queue.dequeue(), Some(2));
+    /// ```
+    pub fn append(&self, other: Queue<T, M>) {
+        let dummy = other.head.load(Ordering::Relaxed);
+        let first = unsafe { (*dummy).next.swap(ptr::null_mut(), Ordering::Relaxed) };
+        if first.is_null() {
+            return
+        }
+        // The other queue's tail may lag behind its last node
+        let mut last = first;
+        loop {
+            let next = unsafe { (*last).next.load(Ordering::Relaxed) };
+            if next.is_null() {
+                break;
+            }
+            last = next;
+        }
+        other.tail.store(dummy, Ordering::Relaxed);
+        let len = other.len.swap(0, Ordering::Relaxed);
+
+        let mut backoff = Backoff::new();
+        while !self.try_enqueue_chain(first, last) {
+            backoff.spin();
+        }
+        self.len.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Add a new element to the back of the queue, returning a handle which can be used to remove
+    /// the element later with `remove_handle`, even if it is not at the front of the queue.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<String> = Queue::new();
+    /// let handle = queue.enqueue_handle("hello".to_owned());
+    /// queue.enqueue("world".to_owned());
+    /// assert_eq!(queue.remove_handle(handle), Some("hello".to_owned()));
+    /// assert_eq!(queue.dequeue(), Some("world".to_owned()));
+    /// ```
+    pub fn enqueue_handle(&self, val: T) -> NodeHandle<T> {
+        let cell = Box::into_raw(Box::new(HandleCell::new(val)));
+        self.enqueue_node(Box::new(Node::new_with_cell(cell)));
+        NodeHandle { cell, queue_id: self.id }
+    }
+
+    /// Remove the element the handle was returned for, wherever it is in the queue. Returns None
+    /// if the element has already been dequeued.
+    /// # Panics
+    /// If the handle was returned by a different queue.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<String> = Queue::new();
+    /// let handle = queue.enqueue_handle("hello".to_owned());
+    /// assert_eq!(queue.dequeue(), Some("hello".to_owned()));
+    /// assert_eq!(queue.remove_handle(handle), None);
+    /// ```
+    pub fn remove_handle(&self, handle: NodeHandle<T>) -> Option<T> {
+        assert_eq!(handle.queue_id, self.id, "The handle belongs to a different queue");
+        // The node is skipped and reclaimed once it reaches the front of the queue
+        let data = unsafe { (*handle.cell).claim() };
+        if data.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        data
+    }
+
+    fn enqueue_node(&self, mut node: Box<Node<T>>) {
+        let mut backoff = Backoff::new();
         loop {
             node = match self.try_enqueue(node) {
                 Ok(_) => { return; },
                 Err(old_node) => old_node
             };
-            backoff = self.backoff(backoff);
+            backoff.spin();
         }
     }
 
+    /// Attempt to add a new element to the back of the queue without retrying. If the attempt
+    /// fails because of contention, or the queue has reached its node cap, the element is
+    /// dropped and false is returned. This is intended for workloads where losing some
+    /// elements under load is preferable to waiting.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<String> = Queue::new();
+    /// if queue.enqueue_weak("hello".to_owned()) {
+    ///     assert_eq!(queue.dequeue(), Some("hello".to_owned()));
+    /// }
+    /// ```
+    pub fn enqueue_weak(&self, val: T) -> bool {
+        if let Some(cap) = self.node_cap {
+            if self.len() >= cap {
+                return false
+            }
+        }
+        self.try_enqueue(Box::new(Node::new(val))).is_ok()
+    }
+
     fn try_enqueue(&self, val: Box<Node<T>>) -> Result<(), Box<Node<T>>> {
         let tail = self.tail.load(Ordering::Acquire);
         self.manager.protect(tail, 0);
         // Is the tail still consistent? Required for the hazard pointer to work
         if !ptr::eq(tail, self.tail.load(Ordering::Acquire)) {
+            self.record_lost_enqueue();
             return Err(val)
         }
         let next = unsafe { (*tail).next.load(Ordering::Acquire) };
@@ -86,6 +331,7 @@ impl<T: Send> Queue<T> {
         if !next.is_null() {
             // If it isn't, try to make next the end of the queue
             let _ = self.tail.compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
+            self.record_lost_enqueue();
             return Err(val)
         }
 
@@ -96,17 +342,29 @@ impl<T: Send> Queue<T> {
                 Ok(_) => {
                     // Success! Set our new node to the tail
                     let _ = self.tail.compare_exchange(tail, node_ptr, Ordering::Release, Ordering::Relaxed);
+                    self.len.fetch_add(1, Ordering::Relaxed);
                     return Ok(())
                 },
                 // Failure :( try again
                 Err(_) => {
+                    self.record_lost_enqueue();
                     return Err(Box::from_raw(node_ptr))
                 }
             }
         }
     }
 
-    /// Take an element from the front of the queue, or return None if the queue is empty.
+    /// Count an enqueue attempt which another thread got in ahead of.
+    #[cfg(test)]
+    fn record_lost_enqueue(&self) {
+        self.lost_enqueues.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(not(test))]
+    fn record_lost_enqueue(&self) {}
+
+    /// Take an element from the front of the queue, or return None if the queue is empty. Once the
+    /// element is claimed, this waits for any threads still reading it before moving it out.
     /// # Examples
     /// ```
     /// let queue: Queue<String> = Queue::new();
@@ -114,60 +372,351 @@ impl<T: Send> Queue<T> {
     /// assert_eq!(queue.dequeue(), Some("hello".to_owned()));
     /// ```
     pub fn dequeue(&self) -> Option<T> {
-        let mut backoff = 1;
+        let mut backoff = Backoff::new();
         loop {
             if let Ok(val) = self.try_dequeue() {
                 return val
             }
-            backoff = self.backoff(backoff);
+            backoff.spin();
+        }
+    }
+
+    /// Make a single attempt to take an element from the front of the queue without retrying or backing off.
+    /// Returns `Ok(None)` if the queue is empty, and `Err(Contended)` if another thread changed the front
+    /// of the queue during the attempt. Elements removed through their handle are still skipped over. A
+    /// successful attempt still waits for any threads reading the element it takes.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<String> = Queue::new();
+    /// assert_eq!(queue.try_dequeue(), Ok(None));
+    /// queue.enqueue("hello".to_owned());
+    /// match queue.try_dequeue() {
+    ///     Ok(val) => assert_eq!(val, Some("hello".to_owned())),
+    ///     Err(Contended) => { /* Do something else and try again later */ }
+    /// }
+    /// ```
+    pub fn try_dequeue(&self) -> Result<Option<T>, Contended> {
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            self.manager.protect(head, 0);
+            if !ptr::eq(head, self.head.load(Ordering::Acquire)) {
+                return Err(Contended)
+            }
+
+            let next = unsafe {(*head).next.load(Ordering::Acquire)};
+            self.manager.protect(next, 1);
+            if !ptr::eq(next, unsafe { (*head).next.load(Ordering::Acquire) }) {
+                return Err(Contended)
+            }
+
+            let tail = self.tail.load(Ordering::Acquire);
+            
+            if next.is_null() {
+                return Ok(None)
+            }
+
+            if ptr::eq(head, tail) {
+                let _ = self.tail.compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
+                return Err(Contended);
+            }
+
+            match self.head.compare_exchange(head, next, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => {
+                    // Only the thread which moved the head can take the value, and next is now the dummy node
+                    let data = unsafe { (*next).take_value() };
+                    self.manager.retire(head, 0);
+                    if data.is_none() {
+                        // The element was removed through its handle, so move on to the next one
+                        continue;
+                    }
+                    self.len.fetch_sub(1, Ordering::Relaxed);
+                    return Ok(data)
+                },
+                Err(_) => {
+                    return Err(Contended)
+                }
+            }
         }
     }
 
-    fn try_dequeue(&self) -> Result<Option<T>, ()> {
+    /// Take up to `max` elements from the front of the queue, in order, stopping early if the queue
+    /// empties. Runs of elements are taken with a single CAS on the head, so the cost of protecting the
+    /// front of the queue is shared by the whole batch.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<u8> = Queue::new();
+    /// queue.enqueue_all(vec![1, 2, 3]);
+    /// assert_eq!(queue.dequeue_n(2), vec![1, 2]);
+    /// assert_eq!(queue.dequeue_n(2), vec![3]);
+    /// ```
+    pub fn dequeue_n(&self, max: usize) -> Vec<T> {
+        let mut items = Vec::new();
+        let mut backoff = Backoff::new();
+        while items.len() < max {
+            match self.try_dequeue_run(max - items.len(), &mut items) {
+                Ok(true) => {},
+                Ok(false) => { break; },
+                Err(Contended) => backoff.spin()
+            }
+        }
+        items
+    }
+
+    /// Take roughly half of the elements in the queue, for handing work over to another thread. The elements
+    /// are taken from the front with `dequeue_n`, rather than cut off the back: threads enqueueing at the same
+    /// time link their nodes after the last node they saw, so a node detached from the back could have new
+    /// elements linked onto it and lost. Elements enqueued or dequeued while stealing make the split uneven.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<u8> = Queue::new();
+    /// queue.enqueue_all(vec![1, 2, 3, 4]);
+    /// assert_eq!(queue.steal_half(), vec![1, 2]);
+    /// assert_eq!(queue.len(), 2);
+    /// ```
+    pub fn steal_half(&self) -> Vec<T> {
+        // Round up, so that the last element in the queue can still be stolen
+        self.dequeue_n(self.len().div_ceil(2))
+    }
+
+    /// Take out the elements matching `filter`, in order, leaving the rest in the queue in their original
+    /// relative order. Every element in the queue is dequeued and those which do not match are enqueued again
+    /// at the back, so elements enqueued by other threads in the meantime end up in front of them, and
+    /// elements dequeued by other threads are never seen. This is best used while the queue is quiescent.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<u8> = Queue::new();
+    /// queue.enqueue_all(vec![1, 2, 3, 4]);
+    /// assert_eq!(queue.drain_filter(|val| val % 2 == 0), vec![2, 4]);
+    /// assert_eq!(queue.dequeue_n(2), vec![1, 3]);
+    /// ```
+    pub fn drain_filter<F: Fn(&T) -> bool>(&self, filter: F) -> Vec<T> {
+        // Only take the elements there are now, so elements put back are not looked at twice
+        let (drained, kept): (Vec<T>, Vec<T>) = self.dequeue_n(self.len()).into_iter().partition(|val| filter(val));
+        self.enqueue_all(kept);
+        drained
+    }
+
+    /// Attempt to take a run of up to `max` nodes from the front of the queue with a single CAS, adding
+    /// their elements to `items`. Returns false if the queue was empty.
+    fn try_dequeue_run(&self, max: usize, items: &mut Vec<T>) -> Result<bool, Contended> {
         let head = self.head.load(Ordering::Acquire);
         self.manager.protect(head, 0);
         if !ptr::eq(head, self.head.load(Ordering::Acquire)) {
-            return Err(())
+            return Err(Contended)
         }
 
-        let next = unsafe {(*head).next.load(Ordering::Acquire)};
-        self.manager.protect(next, 1);
-        if !ptr::eq(next, unsafe { (*head).next.load(Ordering::Acquire) }) {
-            return Err(())
+        // While the head has not moved nothing after it can have been dequeued, so each node is
+        // still linked once it has been protected and the head checked again
+        let mut last = head;
+        let mut count = 0;
+        while count < max {
+            let next = unsafe { (*last).next.load(Ordering::Acquire) };
+            if next.is_null() {
+                break;
+            }
+            self.manager.protect(next, 1);
+            if !ptr::eq(head, self.head.load(Ordering::Acquire)) {
+                return Err(Contended)
+            }
+            // The head must never pass the tail, so move a lagging tail along first
+            let tail = self.tail.load(Ordering::Acquire);
+            if ptr::eq(last, tail) {
+                let _ = self.tail.compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
+            }
+            last = next;
+            count += 1;
         }
 
-        let tail = self.tail.load(Ordering::Acquire);
-        
-        if next.is_null() {
-            return Ok(None)
+        if count == 0 {
+            return Ok(false)
+        }
+        if self.head.compare_exchange(head, last, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            return Err(Contended)
         }
 
-        if ptr::eq(head, tail) {
-            let _ = self.tail.compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
-            return Err(());
+        // Only the thread which moved the head can take the values, and last is now the dummy node
+        let mut taken = 0;
+        let mut current = head;
+        while !ptr::eq(current, last) {
+            let next = unsafe { (*current).next.load(Ordering::Acquire) };
+            // Elements removed through their handle have no value to take
+            if let Some(data) = unsafe { (*next).take_value() } {
+                items.push(data);
+                taken += 1;
+            }
+            self.manager.retire(current, 0);
+            current = next;
         }
+        self.len.fetch_sub(taken, Ordering::Relaxed);
+        Ok(true)
+    }
 
-        match self.head.compare_exchange(head, next, Ordering::AcqRel, Ordering::Acquire) {
-            Ok(_) => {
-                let node = unsafe { ptr::read(next) };
-                let data = node.value;
+    /// Clone the element at the front of the queue without removing it, or return None if the
+    /// queue is empty.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<String> = Queue::new();
+    /// queue.enqueue("hello".to_owned());
+    /// assert_eq!(queue.peek(), Some("hello".to_owned()));
+    /// assert_eq!(queue.dequeue(), Some("hello".to_owned()));
+    /// ```
+    pub fn peek(&self) -> Option<T>
+    where T: Clone
+    {
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            self.manager.protect(head, 0);
+            // The head may have been dequeued and retired before it was protected
+            if !ptr::eq(head, self.head.load(Ordering::Acquire)) {
+                continue;
+            }
+
+            let next = unsafe { (*head).next.load(Ordering::Acquire) };
+            self.manager.protect(next, 1);
+            if !ptr::eq(next, unsafe { (*head).next.load(Ordering::Acquire) }) {
+                continue;
+            }
+
+            if next.is_null() {
+                self.manager.unprotect(0);
+                self.manager.unprotect(1);
+                return None
+            }
+
+            if let Some(data) = unsafe { (*next).peek_value() } {
+                self.manager.unprotect(0);
+                self.manager.unprotect(1);
+                return Some(data)
+            }
+
+            // The front element was removed through its handle, so skip its node as dequeue would
+            let tail = self.tail.load(Ordering::Acquire);
+            if ptr::eq(head, tail) {
+                let _ = self.tail.compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
+                continue;
+            }
+            if self.head.compare_exchange(head, next, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                unsafe { (*next).take_value() };
                 self.manager.retire(head, 0);
-                return Ok(data)
-            },
-            Err(_) => {
-                return Err(())
             }
         }
     }
+
+    /// Check whether the queue holds an element equal to the given value. While other threads are
+    /// enqueueing or dequeueing the answer is only weakly consistent: an element added or removed during
+    /// the search may or may not be seen. Elements removed through their handle are never seen.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<u8> = Queue::new();
+    /// queue.enqueue(1);
+    /// queue.enqueue(2);
+    /// assert!(queue.contains(&2));
+    /// assert!(!queue.contains(&3));
+    /// ```
+    pub fn contains(&self, value: &T) -> bool
+    where T: PartialEq
+    {
+        loop {
+            if let Some(found) = self.try_walk(|node| node.holds(value)) {
+                return found
+            }
+        }
+    }
+
+    /// Clone every element in the queue, from front to back, without removing any. While other threads are
+    /// enqueueing or dequeueing this is only a weakly consistent snapshot: an element added or removed while
+    /// the queue is being walked may or may not be included. Elements removed through their handle are left out.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<u8> = Queue::new();
+    /// queue.enqueue(1);
+    /// queue.enqueue(2);
+    /// assert_eq!(queue.to_vec(), vec![1, 2]);
+    /// assert_eq!(queue.dequeue(), Some(1));
+    /// ```
+    pub fn to_vec(&self) -> Vec<T>
+    where T: Clone
+    {
+        loop {
+            let mut items = Vec::new();
+            let walked = self.try_walk(|node| {
+                if let Some(data) = node.peek_value() {
+                    items.push(data);
+                }
+                false
+            });
+            if walked.is_some() {
+                return items
+            }
+        }
+    }
+
+    // Visit the nodes after the head, from front to back, until `visit` returns true, and return whether
+    // it did. Hazard pointer 0 holds the head the walk started from, and hazard pointer 1 the node it has
+    // reached. While the head has not moved nothing can have been dequeued, so every node after it is still
+    // linked and was protected before it could be freed. Returns None as soon as the head moves, in which
+    // case the caller should start again from the new head.
+    fn try_walk<F>(&self, mut visit: F) -> Option<bool>
+    where F: FnMut(&Node<T>) -> bool
+    {
+        let head_guard = self.manager.guard(0);
+        let _node_guard = self.manager.guard(1);
+        let head = head_guard.protect(&self.head);
+        let mut current = head;
+        loop {
+            let next = unsafe { (*current).next.load(Ordering::Acquire) };
+            self.manager.protect(next, 1);
+            if !ptr::eq(head, self.head.load(Ordering::Acquire)) {
+                return None
+            }
+            if next.is_null() {
+                return Some(false)
+            }
+            if visit(unsafe { &*next }) {
+                return Some(true)
+            }
+            current = next;
+        }
+    }
+
+    /// The number of elements in the queue. The count is updated separately from the queue itself, so
+    /// while other threads are enqueueing or dequeueing it is only approximate, but it is exact once they
+    /// stop. Elements removed through their handle stop being counted as soon as they are removed.
+    /// # Examples
+    /// ```
+    /// let queue: Queue<String> = Queue::new();
+    /// queue.enqueue("hello".to_owned());
+    /// assert_eq!(queue.len(), 1);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Check whether the queue has no elements, with the same caveats as `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check whether the strategy reports a backlog of dequeued nodes.
+    pub fn has_reclaim_pressure(&self) -> bool {
+        self.manager.has_reclaim_pressure()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Send> Default for Queue<T> {
+    fn default() -> Self {
+        Queue::new()
+    }
 }
 
-impl<T: Send> Drop for Queue<T> {
+impl<T: Send, M: ReclamationStrategy<Node<T>>> Drop for Queue<T, M> {
     fn drop(&mut self) {
         let mut current = self.head.load(Ordering::Relaxed);
         while !current.is_null() {
             unsafe {
                 let next = (*current).next.load(Ordering::Relaxed);
-                Box::from_raw(current);
+                drop(Box::from_raw(current));
                 current = next;
             }
         }
@@ -178,14 +727,27 @@ impl<T: Send> Node<T> {
     fn new(value: T) -> Self {
         Node {
             next: AtomicPtr::default(),
-            value: Some(value)
+            value: UnsafeCell::new(Some(value)),
+            cell: ptr::null_mut(),
+            readers: ReadMark::new()
+        }
+    }
+
+    fn new_with_cell(cell: *mut HandleCell<T>) -> Self {
+        Node {
+            next: AtomicPtr::default(),
+            value: UnsafeCell::new(None),
+            cell,
+            readers: ReadMark::new()
         }
     }
 
     fn new_dummy_node() -> Self {
         Node {
             next: AtomicPtr::default(),
-            value: None
+            value: UnsafeCell::new(None),
+            cell: ptr::null_mut(),
+            readers: ReadMark::new()
         }
     }
 
@@ -193,21 +755,131 @@ impl<T: Send> Node<T> {
         let next_ptr = (*dest).next.load(Ordering::Acquire);
         let node = Node {
             next: AtomicPtr::new(next_ptr),
-            value: None
+            value: UnsafeCell::new(None),
+            cell: ptr::null_mut(),
+            readers: ReadMark::new()
         };
         ptr::replace(dest, node)
     }
+
+    /// Clone the value without taking it. Returns None if the node's element was removed through its handle,
+    /// or if the node has been dequeued and its value taken.
+    fn peek_value(&self) -> Option<T>
+    where T: Clone
+    {
+        if self.cell.is_null() {
+            let _reading = self.readers.read()?;
+            unsafe { (*self.value.get()).clone() }
+        } else {
+            unsafe { (*self.cell).peek() }
+        }
+    }
+
+    /// Check whether the node's value is equal to the given one. Always false once the node's element has
+    /// been removed through its handle, or its value taken.
+    fn holds(&self, value: &T) -> bool
+    where T: PartialEq
+    {
+        if self.cell.is_null() {
+            match self.readers.read() {
+                Some(_reading) => unsafe { (*self.value.get()).as_ref() == Some(value) },
+                None => false
+            }
+        } else {
+            unsafe { (*self.cell).holds(value) }
+        }
+    }
+
+    /// Take the value out of the node, once nobody is reading it. Returns None if the node's element was
+    /// removed through its handle. Only the thread which dequeued the node may take its value.
+    unsafe fn take_value(&self) -> Option<T> {
+        if self.cell.is_null() {
+            self.readers.take();
+            (*self.value.get()).take()
+        } else {
+            (*self.cell).claim()
+        }
+    }
+}
+
+impl<T: Send> Drop for Node<T> {
+    fn drop(&mut self) {
+        if !self.cell.is_null() {
+            unsafe { HandleCell::release(self.cell) };
+        }
+    }
 }
 
 impl<T: Send> Default for Node<T> {
     fn default() -> Self {
         Node {
             next: AtomicPtr::default(),
-            value: None
+            value: UnsafeCell::new(None),
+            cell: ptr::null_mut(),
+            readers: ReadMark::new()
         }
     }
 }
 
+impl<T: Send> HandleCell<T> {
+    fn new(value: T) -> Self {
+        HandleCell {
+            value: UnsafeCell::new(Some(value)),
+            claimed: AtomicBool::new(false),
+            // One reference for the node and one for the handle
+            refs: AtomicUsize::new(2),
+            readers: ReadMark::new()
+        }
+    }
+
+    /// Take the value if nobody else has claimed it yet, once nobody is reading it.
+    fn claim(&self) -> Option<T> {
+        match self.claimed.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => {
+                self.readers.take();
+                unsafe { (*self.value.get()).take() }
+            },
+            Err(_) => None
+        }
+    }
+
+    /// Clone the value if nobody has claimed it yet.
+    fn peek(&self) -> Option<T>
+    where T: Clone
+    {
+        let _reading = self.readers.read()?;
+        if self.claimed.load(Ordering::Acquire) {
+            None
+        } else {
+            unsafe { (*self.value.get()).clone() }
+        }
+    }
+
+    /// Check whether the value is equal to the given one, if nobody has claimed it yet.
+    fn holds(&self, value: &T) -> bool
+    where T: PartialEq
+    {
+        match self.readers.read() {
+            Some(_reading) => !self.claimed.load(Ordering::Acquire) && unsafe { (*self.value.get()).as_ref() } == Some(value),
+            None => false
+        }
+    }
+
+    /// Drop a reference to the cell, freeing it if it was the last one.
+    unsafe fn release(cell: *mut Self) {
+        if (*cell).refs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            drop(Box::from_raw(cell));
+        }
+    }
+}
+
+impl<T: Send> Drop for NodeHandle<T> {
+    fn drop(&mut self) {
+        unsafe { HandleCell::release(self.cell) };
+    }
+}
+
+#[cfg(feature = "std")]
 mod tests {
     #![allow(unused_imports)]
     extern crate im;
@@ -215,7 +887,7 @@ mod tests {
 
     use rand::{thread_rng, Rng};
 
-    use super::Queue;
+    use super::{Queue, Contended};
     use std::sync::Arc;
     use std::thread;
     use std::sync::atomic::Ordering;
@@ -230,7 +902,7 @@ mod tests {
         unsafe {
             println!("{:?}", *queue.head.load(Ordering::Relaxed));
             let head = (*queue.head.load(Ordering::Relaxed)).next.load(Ordering::Relaxed);
-            assert_eq!((*head).value, Some(8));
+            assert_eq!(*(*head).value.get(), Some(8));
         }
         queue.enqueue(7);
         assert_eq!(queue.dequeue(), Some(8));
@@ -246,6 +918,16 @@ mod tests {
         assert_eq!(queue.dequeue(), None);
     }
 
+    #[test]
+    fn test_head_and_tail_on_separate_cache_lines() {
+        let queue: Queue<u8> = Queue::new();
+        let head = &*queue.head as *const _ as usize;
+        let tail = &*queue.tail as *const _ as usize;
+        assert_eq!(head % 64, 0);
+        assert_eq!(tail % 64, 0);
+        assert!(head / 64 != tail / 64);
+    }
+
     #[test]
      
     fn test_queue_multithreaded() {
@@ -327,4 +1009,478 @@ mod tests {
             _ => assert!(false)
         }
     }
+
+    #[test]
+    fn test_enqueue_weak() {
+        const NUM_THREADS: usize = 8;
+        const PER_THREAD: usize = 1000;
+        // Set the cap above anything the threads can enqueue, so that every failure is a lost race
+        let queue: Arc<Queue<usize>> = Arc::new(Queue::with_node_cap(NUM_THREADS * PER_THREAD + 1));
+        let mut waitvec: Vec<thread::JoinHandle<Vec<usize>>> = Vec::new();
+
+        for id in 0..NUM_THREADS {
+            let queue_copy = queue.clone();
+            waitvec.push(thread::spawn(move || {
+                let mut enqueued = Vec::new();
+                for i in 0..PER_THREAD {
+                    let val = id * PER_THREAD + i;
+                    if queue_copy.enqueue_weak(val) {
+                        enqueued.push(val);
+                    }
+                }
+                enqueued
+            }));
+        }
+
+        let mut enqueued: Vec<usize> = Vec::new();
+        for handle in waitvec {
+            enqueued.append(&mut handle.join().unwrap());
+        }
+        assert_eq!(NUM_THREADS * PER_THREAD - enqueued.len(), queue.lost_enqueues.load(Ordering::Relaxed));
+
+        let mut dequeued: Vec<usize> = Vec::new();
+        while let Some(val) = queue.dequeue() {
+            dequeued.push(val);
+        }
+        enqueued.sort();
+        dequeued.sort();
+        assert_eq!(enqueued, dequeued);
+    }
+
+    #[test]
+    fn test_enqueue_weak_cap() {
+        let queue: Queue<u8> = Queue::with_node_cap(2);
+        assert!(queue.enqueue_weak(1));
+        assert!(queue.enqueue_weak(2));
+        assert!(!queue.enqueue_weak(3));
+        // Turning an element away at the cap is not a lost race
+        assert_eq!(queue.lost_enqueues.load(Ordering::Relaxed), 0);
+
+        // The cap no longer applies once there is room again
+        assert_eq!(queue.dequeue(), Some(1));
+        assert!(queue.enqueue_weak(3));
+        assert_eq!(queue.dequeue(), Some(2));
+        assert_eq!(queue.dequeue(), Some(3));
+        assert_eq!(queue.dequeue(), None);
+    }
+
+    #[test]
+    fn test_remove_handle() {
+        let queue: Queue<u8> = Queue::new();
+        queue.enqueue(1);
+        let first_handle = queue.enqueue_handle(2);
+        let middle_handle = queue.enqueue_handle(3);
+        queue.enqueue(4);
+        let last_handle = queue.enqueue_handle(5);
+
+        assert_eq!(queue.remove_handle(middle_handle), Some(3));
+        assert_eq!(queue.dequeue(), Some(1));
+        assert_eq!(queue.dequeue(), Some(2));
+        assert_eq!(queue.remove_handle(first_handle), None);
+        assert_eq!(queue.dequeue(), Some(4));
+        assert_eq!(queue.remove_handle(last_handle), Some(5));
+        assert_eq!(queue.dequeue(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "different queue")]
+    fn test_remove_handle_from_other_queue() {
+        let queue: Queue<u8> = Queue::new();
+        let other: Queue<u8> = Queue::new();
+        let handle = queue.enqueue_handle(1);
+        other.remove_handle(handle);
+    }
+
+    #[test]
+    fn test_remove_handle_after_moving_queue() {
+        // The handle still belongs to the queue once the queue has moved
+        let queue: Queue<u8> = Queue::new();
+        let handle = queue.enqueue_handle(1);
+        let queue = Arc::new(queue);
+        assert_eq!(queue.remove_handle(handle), Some(1));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_peek() {
+        let queue: Queue<u8> = Queue::new();
+        assert_eq!(queue.peek(), None);
+        queue.enqueue(1);
+        queue.enqueue(2);
+        queue.enqueue(3);
+        assert_eq!(queue.peek(), Some(1));
+        assert_eq!(queue.peek(), Some(1));
+        assert_eq!(queue.dequeue(), Some(1));
+        assert_eq!(queue.peek(), Some(2));
+
+        // A front element removed through its handle is skipped
+        let handle = queue.enqueue_handle(4);
+        queue.enqueue(5);
+        assert_eq!(queue.dequeue(), Some(2));
+        assert_eq!(queue.dequeue(), Some(3));
+        assert_eq!(queue.remove_handle(handle), Some(4));
+        assert_eq!(queue.peek(), Some(5));
+        assert_eq!(queue.dequeue(), Some(5));
+        assert_eq!(queue.peek(), None);
+    }
+
+    #[test]
+    fn test_peek_while_dequeueing() {
+        let queue: Arc<Queue<String>> = Arc::new(Queue::new());
+        let mut wait_vec: Vec<thread::JoinHandle<usize>> = Vec::new();
+        for i in 0..4 {
+            let queue_clone = queue.clone();
+            wait_vec.push(thread::spawn(move || {
+                let mut lost = 0;
+                for j in 0..2000 {
+                    if j % 2 == 0 {
+                        queue_clone.enqueue(format!("{}-{}", i, j));
+                        assert!(queue_clone.dequeue().is_some());
+                    } else {
+                        // Dequeuers and remove_handle race to claim the value while it is being peeked
+                        let handle = queue_clone.enqueue_handle(format!("{}-{}", i, j));
+                        if queue_clone.remove_handle(handle).is_none() {
+                            lost += 1;
+                        }
+                    }
+                }
+                lost
+            }));
+        }
+        for _ in 0..4 {
+            let queue_clone = queue.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..2000 {
+                    if let Some(front) = queue_clone.peek() {
+                        assert!(front.contains('-'));
+                    }
+                }
+                0
+            }));
+        }
+        let lost: usize = wait_vec.into_iter().map(|handle| handle.join().unwrap()).sum();
+        // Every handle lost to a dequeuer leaves that dequeuer's own value behind
+        assert_eq!(queue.len(), lost);
+    }
+
+    #[test]
+    fn test_contains() {
+        let queue: Queue<u8> = Queue::new();
+        assert!(!queue.contains(&1));
+        for i in 1..6 {
+            queue.enqueue(i);
+        }
+        for i in 1..6 {
+            assert!(queue.contains(&i));
+        }
+        assert!(!queue.contains(&0));
+        assert!(!queue.contains(&6));
+
+        assert_eq!(queue.dequeue(), Some(1));
+        assert!(!queue.contains(&1));
+
+        // An element removed through its handle is not found, even while its node is still linked
+        let handle = queue.enqueue_handle(7);
+        assert!(queue.contains(&7));
+        assert_eq!(queue.remove_handle(handle), Some(7));
+        assert!(!queue.contains(&7));
+        assert!(queue.contains(&5));
+    }
+
+    #[test]
+    fn test_contains_while_enqueueing_and_dequeueing() {
+        let queue: Arc<Queue<String>> = Arc::new(Queue::new());
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+        for i in 0..4 {
+            let queue_clone = queue.clone();
+            wait_vec.push(thread::spawn(move || {
+                for j in 0..2000 {
+                    queue_clone.enqueue(format!("{}-{}", i, j));
+                    assert!(queue_clone.dequeue().is_some());
+                }
+            }));
+        }
+        for _ in 0..4 {
+            let queue_clone = queue.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..500 {
+                    assert!(!queue_clone.contains(&"missing".to_owned()));
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
+        }
+        assert!(queue.is_empty());
+        assert!(!queue.contains(&"0-0".to_owned()));
+    }
+
+    #[test]
+    fn test_dequeue_n() {
+        let queue: Queue<u32> = Queue::new();
+        for i in 0..100 {
+            queue.enqueue(i);
+        }
+        assert_eq!(queue.dequeue_n(30), (0..30).collect::<Vec<u32>>());
+        assert_eq!(queue.dequeue_n(100), (30..100).collect::<Vec<u32>>());
+        assert_eq!(queue.dequeue_n(10), Vec::new());
+        assert!(queue.is_empty());
+
+        // Elements removed through their handle are skipped without counting towards the batch
+        queue.enqueue(1);
+        let handle = queue.enqueue_handle(2);
+        queue.enqueue(3);
+        assert_eq!(queue.remove_handle(handle), Some(2));
+        assert_eq!(queue.dequeue_n(2), vec![1, 3]);
+        assert_eq!(queue.dequeue(), None);
+    }
+
+    #[test]
+    fn test_steal_half() {
+        let queue: Queue<u32> = Queue::new();
+        assert_eq!(queue.steal_half(), Vec::new());
+        queue.enqueue_all(1..=10);
+        let stolen = queue.steal_half();
+        assert_eq!(stolen.len(), 5);
+        assert_eq!(queue.len(), 5);
+
+        let mut all = stolen.clone();
+        while let Some(value) = queue.dequeue() {
+            assert!(!stolen.contains(&value));
+            all.push(value);
+        }
+        all.sort();
+        assert_eq!(all, (1..=10).collect::<Vec<u32>>());
+
+        queue.enqueue(11);
+        assert_eq!(queue.steal_half(), vec![11]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_drain_filter() {
+        let queue: Queue<u32> = Queue::new();
+        queue.enqueue_all(1..=6);
+        assert_eq!(queue.drain_filter(|val| val % 2 == 0), vec![2, 4, 6]);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.dequeue_n(3), vec![1, 3, 5]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.drain_filter(|_| true), Vec::new());
+    }
+
+    #[test]
+    fn test_dequeue_n_multithreaded() {
+        let queue: Arc<Queue<u32>> = Arc::new(Queue::new());
+        let mut wait_vec: Vec<thread::JoinHandle<Vec<u32>>> = Vec::new();
+        for i in 0..4 {
+            let queue_clone = queue.clone();
+            wait_vec.push(thread::spawn(move || {
+                for j in 0..1000 {
+                    queue_clone.enqueue(i * 1000 + j);
+                }
+                let mut taken = Vec::new();
+                loop {
+                    let batch = queue_clone.dequeue_n(7);
+                    if batch.is_empty() {
+                        return taken
+                    }
+                    taken.extend(batch);
+                }
+            }));
+        }
+        let mut taken: Vec<u32> = Vec::new();
+        for handle in wait_vec {
+            taken.extend(handle.join().unwrap());
+        }
+        taken.extend(queue.dequeue_n(4000));
+        taken.sort();
+        assert_eq!(taken, (0..4000).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_append() {
+        let queue: Queue<u32> = Queue::new();
+        let other: Queue<u32> = Queue::new();
+        for i in 1..5 {
+            queue.enqueue(i);
+        }
+        for i in 5..10 {
+            other.enqueue(i);
+        }
+        queue.append(other);
+        assert_eq!(queue.len(), 9);
+        for i in 1..10 {
+            assert_eq!(queue.dequeue(), Some(i));
+        }
+        assert_eq!(queue.dequeue(), None);
+        assert!(queue.is_empty());
+
+        // Appending an empty queue leaves the queue as it was, and the queue can still be added to
+        queue.append(Queue::new());
+        assert!(queue.is_empty());
+        queue.enqueue(10);
+        assert_eq!(queue.dequeue(), Some(10));
+    }
+
+    #[test]
+    fn test_to_vec() {
+        let queue: Queue<u8> = Queue::new();
+        assert_eq!(queue.to_vec(), Vec::new());
+        for i in 1..6 {
+            queue.enqueue(i);
+        }
+        assert_eq!(queue.to_vec(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(queue.len(), 5);
+
+        // Dequeued elements and those removed through their handle are left out
+        assert_eq!(queue.dequeue(), Some(1));
+        let handle = queue.enqueue_handle(6);
+        queue.enqueue(7);
+        assert_eq!(queue.remove_handle(handle), Some(6));
+        assert_eq!(queue.to_vec(), vec![2, 3, 4, 5, 7]);
+    }
+
+    #[test]
+    fn test_to_vec_while_enqueueing_and_dequeueing() {
+        let queue: Arc<Queue<String>> = Arc::new(Queue::new());
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+        for i in 0..4 {
+            let queue_clone = queue.clone();
+            wait_vec.push(thread::spawn(move || {
+                for j in 0..2000 {
+                    queue_clone.enqueue(format!("{}-{}", i, j));
+                    if j % 2 == 0 {
+                        assert!(queue_clone.dequeue().is_some());
+                    }
+                }
+            }));
+        }
+        for _ in 0..4 {
+            let queue_clone = queue.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..200 {
+                    // Each thread's elements are still in the order it enqueued them
+                    let mut last = [None; 4];
+                    for item in queue_clone.to_vec() {
+                        let mut parts = item.split('-').map(|part| part.parse::<usize>().unwrap());
+                        let (i, j) = (parts.next().unwrap(), parts.next().unwrap());
+                        assert!(last[i] < Some(j));
+                        last[i] = Some(j);
+                    }
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
+        }
+        assert_eq!(queue.to_vec().len(), 4000);
+    }
+
+    #[test]
+    fn test_default() {
+        let queue: Queue<u8> = Default::default();
+        assert!(queue.is_empty());
+        queue.enqueue(1);
+        queue.enqueue(2);
+        assert_eq!(queue.dequeue(), Some(1));
+        assert_eq!(queue.dequeue(), Some(2));
+        assert_eq!(queue.dequeue(), None);
+    }
+
+    #[test]
+    fn test_len() {
+        let queue: Arc<Queue<u32>> = Arc::new(Queue::new());
+        assert!(queue.is_empty());
+        let handle = queue.enqueue_handle(1);
+        queue.enqueue(2);
+        assert_eq!(queue.len(), 2);
+        queue.remove_handle(handle);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.dequeue(), Some(2));
+        assert!(queue.is_empty());
+
+        let mut waitvec: Vec<thread::JoinHandle<()>> = Vec::new();
+        for _ in 0..4 {
+            let queue_copy = queue.clone();
+            waitvec.push(thread::spawn(move || {
+                for i in 0..5000 {
+                    queue_copy.enqueue(i);
+                }
+            }));
+            let queue_copy = queue.clone();
+            waitvec.push(thread::spawn(move || {
+                let mut dequeued = 0;
+                while dequeued < 5000 {
+                    if queue_copy.dequeue().is_some() {
+                        dequeued += 1;
+                    }
+                }
+            }));
+        }
+        for handle in waitvec {
+            handle.join().unwrap();
+        }
+        assert_eq!(queue.len(), 0);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_enqueue_all() {
+        let queue: Queue<u32> = Queue::new();
+        queue.enqueue(0);
+        queue.enqueue_all(1..1001);
+        queue.enqueue_all(Vec::new());
+        assert_eq!(queue.len(), 1001);
+        for i in 0..1001 {
+            assert_eq!(queue.dequeue(), Some(i));
+        }
+        assert_eq!(queue.dequeue(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_enqueue_all_multithreaded() {
+        let queue: Arc<Queue<u32>> = Arc::new(Queue::new());
+        let mut waitvec: Vec<thread::JoinHandle<()>> = Vec::new();
+        for id in 0..8 {
+            let queue_copy = queue.clone();
+            waitvec.push(thread::spawn(move || {
+                for batch in 0..100 {
+                    let start = id * 1000 + batch * 10;
+                    queue_copy.enqueue_all(start..start + 10);
+                }
+            }));
+        }
+        for handle in waitvec {
+            handle.join().unwrap();
+        }
+
+        // Each batch is added as a block, so the elements of every thread come out in order
+        let mut last_seen = [None; 8];
+        let mut count = 0;
+        while let Some(val) = queue.dequeue() {
+            let id = (val / 1000) as usize;
+            assert!(last_seen[id].is_none_or(|last| last < val));
+            last_seen[id] = Some(val);
+            count += 1;
+        }
+        assert_eq!(count, 8000);
+    }
+
+    #[test]
+    fn test_try_dequeue_contended() {
+        let queue: Queue<usize> = Queue::new();
+        assert_eq!(queue.try_dequeue(), Ok(None));
+        queue.enqueue(1);
+        assert_eq!(queue.try_dequeue(), Ok(Some(1)));
+
+        // Leave the tail behind, as an enqueuer which has linked its node but not yet moved the tail
+        // would. The attempt moves the tail along for it and reports the contention
+        queue.enqueue(2);
+        queue.tail.store(queue.head.load(Ordering::Relaxed), Ordering::Relaxed);
+        assert_eq!(queue.try_dequeue(), Err(Contended));
+        assert_eq!(queue.try_dequeue(), Ok(Some(2)));
+        assert_eq!(queue.try_dequeue(), Ok(None));
+    }
 }
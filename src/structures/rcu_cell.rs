@@ -0,0 +1,181 @@
+use memory::{HPBRManager, HPHandle};
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering::{Acquire, AcqRel, Relaxed};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::ptr;
+
+/// A shared value which many threads can read at once while it is occasionally replaced, in the style of
+/// read-copy-update.
+///
+/// The value is kept behind a single atomic pointer. Readers protect the current value with a hazard pointer
+/// and read it in place, without locking or cloning it. Writers swap in a whole new value and retire the old
+/// one to an HPBRManager, which frees it once no reader is still looking at it. This makes reads cheap and
+/// updates comparatively expensive, which suits configuration and other read-mostly state.
+/// # Examples
+/// ```
+/// let cell: RcuCell<String> = RcuCell::new("hello".to_owned());
+/// assert_eq!(*cell.read(), "hello");
+/// cell.update("world".to_owned());
+/// assert_eq!(*cell.read(), "world");
+/// ```
+pub struct RcuCell<T: Send> {
+    value: AtomicPtr<T>,
+    manager: HPBRManager<T>,
+    // Readers on other threads are handed references to the value
+    _marker: PhantomData<T>
+}
+
+/// A reference to the value of an RcuCell, obtained with `read`. The value it refers to is not freed until
+/// the guard goes out of scope, even if the cell is updated in the meantime.
+pub struct RcuGuard<'a, T: Send + 'a> {
+    value: *mut T,
+    _handle: HPHandle<'a, T>
+}
+
+impl<T: Send> RcuCell<T> {
+    /// Create a new RcuCell holding the given value.
+    /// # Examples
+    /// ```
+    /// let cell: RcuCell<u32> = RcuCell::new(8);
+    /// ```
+    pub fn new(value: T) -> Self {
+        RcuCell {
+            value: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            // Readers take dynamic hazard pointers so a thread can hold any number of guards. The one
+            // fixed hazard pointer is only there to be cleared when a writer retires the old value.
+            manager: HPBRManager::new(100, 1),
+            _marker: PhantomData
+        }
+    }
+
+    /// Get a reference to the current value. Updates made while the guard is held are not seen through it.
+    /// # Examples
+    /// ```
+    /// let cell: RcuCell<u32> = RcuCell::new(8);
+    /// let guard = cell.read();
+    /// cell.update(9);
+    /// assert_eq!(*guard, 8);
+    /// ```
+    pub fn read(&self) -> RcuGuard<'_, T> {
+        loop {
+            let value = self.value.load(Acquire);
+            let handle = self.manager.protect_dynamic(value);
+            // The value may have been replaced and retired before it was protected
+            if ptr::eq(value, self.value.load(Acquire)) {
+                return RcuGuard {
+                    value,
+                    _handle: handle
+                }
+            }
+        }
+    }
+
+    /// Replace the value with a new one. The old value is dropped once no guard refers to it.
+    /// # Examples
+    /// ```
+    /// let cell: RcuCell<u32> = RcuCell::new(8);
+    /// cell.update(9);
+    /// assert_eq!(*cell.read(), 9);
+    /// ```
+    pub fn update(&self, value: T) {
+        let old = self.value.swap(Box::into_raw(Box::new(value)), AcqRel);
+        self.manager.retire(old, 0);
+    }
+
+    /// Check whether readers are holding on to replaced values long enough for them to pile up.
+    pub fn has_reclaim_pressure(&self) -> bool {
+        self.manager.has_reclaim_pressure()
+    }
+}
+
+impl<T: Send + Default> Default for RcuCell<T> {
+    fn default() -> Self {
+        RcuCell::new(T::default())
+    }
+}
+
+impl<T: Send> Drop for RcuCell<T> {
+    // Replaced values are freed by the manager when it is dropped
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.value.load(Relaxed))) };
+    }
+}
+
+impl<'a, T: Send> Deref for RcuGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.value }
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::RcuCell;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    const ALIVE: u64 = 0x600D_600D_600D_600D;
+    const FREED: u64 = 0xDEAD_DEAD_DEAD_DEAD;
+
+    // Overwrites its sentinel when dropped, so a reader holding a freed value would see it
+    struct Checked {
+        sentinel: u64,
+        version: usize
+    }
+
+    impl Drop for Checked {
+        fn drop(&mut self) {
+            self.sentinel = FREED;
+        }
+    }
+
+    #[test]
+    fn test_guard_outlives_update() {
+        let cell: RcuCell<Checked> = RcuCell::new(Checked { sentinel: ALIVE, version: 0 });
+        let first = cell.read();
+        for version in 1..500 {
+            cell.update(Checked { sentinel: ALIVE, version });
+        }
+        assert_eq!(first.sentinel, ALIVE);
+        assert_eq!(first.version, 0);
+        assert_eq!(cell.read().version, 499);
+    }
+
+    #[test]
+    fn test_readers_never_see_freed_values() {
+        let cell: Arc<RcuCell<Checked>> = Arc::new(RcuCell::new(Checked { sentinel: ALIVE, version: 0 }));
+        let done = Arc::new(AtomicBool::new(false));
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+
+        for _ in 0..8 {
+            let cell_clone = cell.clone();
+            let done_clone = done.clone();
+            wait_vec.push(thread::spawn(move || {
+                let mut last_version = 0;
+                while !done_clone.load(Ordering::Relaxed) {
+                    let guard = cell_clone.read();
+                    assert_eq!(guard.sentinel, ALIVE);
+                    // A single writer only moves the version forwards
+                    assert!(guard.version >= last_version);
+                    last_version = guard.version;
+                    thread::yield_now();
+                    assert_eq!(guard.sentinel, ALIVE);
+                }
+            }));
+        }
+
+        for version in 1..10000 {
+            cell.update(Checked { sentinel: ALIVE, version });
+        }
+        done.store(true, Ordering::Relaxed);
+        for handle in wait_vec {
+            if handle.join().is_err() {
+                panic!("A thread panicked, test failed!")
+            }
+        }
+        assert_eq!(cell.read().version, 9999);
+    }
+}
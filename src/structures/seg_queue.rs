@@ -1,11 +1,34 @@
-use memory::HPBRManager;
-use std::sync::atomic::{AtomicPtr};
+use memory::{HPBRManager, ReclamationStrategy};
+use std::sync::atomic::{AtomicPtr, AtomicUsize};
 use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
 use std::ptr;
-use std::cell::UnsafeCell;
+use std::cell::Cell;
 use super::utils::atomic_markable::AtomicMarkablePtr;
 use super::utils::atomic_markable;
-use rand::{Rng, SmallRng, NewRng};
+use super::utils::cache_padded::CachePadded;
+use super::utils::backoff::Backoff;
+use std::cmp;
+use std::time::{Duration, Instant};
+
+// Hands out a different seed to each thread's random number generator
+static NEXT_SEED: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    static RNG_STATE: Cell<u64> = Cell::new((NEXT_SEED.fetch_add(1, Relaxed) as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1);
+}
+
+/// A cheap random number from this thread's xorshift64* generator, used to pick where in a segment
+/// to start looking. Each thread has its own generator, so no synchronisation is needed.
+fn random() -> usize {
+    RNG_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        (x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as usize
+    })
+}
 
 /// A lock-free k-FIFO segmented queue.
 ///
@@ -19,16 +42,45 @@ use rand::{Rng, SmallRng, NewRng};
 /// Once a node is full, a new one is enqueued. Once a node is empty, it is dequeued and freed.
 /// 
 /// If relaxed consistency is undesirable, do not set `k` to 1. Instead, use the Queue structure
-/// from the `rustcurrent` library as it is far better optimised for that scenario.
-pub struct SegQueue<T: Send> {
-    head:AtomicPtr<Segment<T>>,
-    tail: AtomicPtr<Segment<T>>,
-    manager: HPBRManager<Segment<T>>,
-    rng: UnsafeCell<SmallRng>,
-    k: usize
+/// from the `rustcurrent` library as it is far better optimised for that scenario. A queue with `k`
+/// set to 1 is still a strict FIFO queue, and skips choosing a random slot since there is only one.
+///
+/// By default a dequeue looks through the slots of the front segment starting from a random one. A
+/// queue created with `with_dequeue_strategy` can instead probe a few random slots first and then
+/// fall back to a scan starting from the last slot which was successfully dequeued from.
+///
+/// Empty segments are freed through an HPBRManager unless the queue is created with `with_manager`,
+/// which takes any other ReclamationStrategy.
+pub struct SegQueue<T: Send, M: ReclamationStrategy<Segment<T>> = HPBRManager<Segment<T>>> {
+    // Dequeuers work on the head and enqueuers on the tail, so they are kept on separate cache lines
+    head: CachePadded<AtomicPtr<Segment<T>>>,
+    tail: CachePadded<AtomicPtr<Segment<T>>>,
+    manager: M,
+    k: usize,
+    strategy: DequeueStrategy,
+    // Only used by ProbeThenScan, which writes it on every dequeue, so it gets its own cache line
+    last_index: CachePadded<AtomicUsize>,
+    // How many enqueues have taken the fast path, so that tests can check it is used
+    #[cfg(test)]
+    fast_path_count: AtomicUsize,
+    // How many slots dequeues have looked at in total, so that tests can compare strategies
+    #[cfg(test)]
+    scanned_count: AtomicUsize
 }
 
-unsafe impl<T: Send> Sync for SegQueue<T> {}
+/// How a SegQueue chooses the order in which to look through the slots of the front segment on dequeue.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum DequeueStrategy {
+    /// Look through every slot in order, starting from a random one.
+    Random,
+    /// Probe the given number of consecutive slots starting from a random one, then look through the 
+    /// rest in order starting from the slot of the last successful dequeue. No slot is looked at twice,
+    /// so an attempt never looks at more than `k` slots.
+    ProbeThenScan(usize)
+}
 
 impl<T: Send> SegQueue<T> {
     /// Create a new SegQueue with a given node size. The node size must be
@@ -38,16 +90,57 @@ impl<T: Send> SegQueue<T> {
     /// let queue: SegQueue<u8> = SegQueue::new(8);
     /// ```
     pub fn new(k: usize) -> Self {
+        SegQueue::with_dequeue_strategy(k, DequeueStrategy::Random)
+    }
+
+    /// Create a new SegQueue with a given node size, which looks through the front segment
+    /// in the order given by the strategy when dequeueing. The node size must be a power of 2.
+    /// # Examples
+    /// ```
+    /// let queue: SegQueue<u8> = SegQueue::with_dequeue_strategy(8, DequeueStrategy::ProbeThenScan(2));
+    /// ```
+    pub fn with_dequeue_strategy(k: usize, strategy: DequeueStrategy) -> Self {
+        let mut queue = SegQueue::with_manager(k, HPBRManager::new(100, 2));
+        queue.strategy = strategy;
+        queue
+    }
+
+    /// Create a new SegQueue where each thread's list of empty segments waiting to be freed has room
+    /// for `retire_capacity` segments from the start. See `HPBRManager::with_retire_capacity`.
+    /// The node size must be a power of 2.
+    /// # Examples
+    /// ```
+    /// let queue: SegQueue<u8> = SegQueue::with_retire_capacity(8, 128);
+    /// ```
+    pub fn with_retire_capacity(k: usize, retire_capacity: usize) -> Self {
+        SegQueue::with_manager(k, HPBRManager::with_retire_capacity(100, 2, retire_capacity))
+    }
+}
+
+impl<T: Send, M: ReclamationStrategy<Segment<T>>> SegQueue<T, M> {
+    /// Create a new SegQueue with a given node size, which frees empty segments through the given
+    /// ReclamationStrategy. The strategy must provide two hazard pointers per thread. The node size
+    /// must be a power of 2.
+    /// # Examples
+    /// ```
+    /// let queue: SegQueue<u8> = SegQueue::with_manager(8, HPBRManager::new(50, 2));
+    /// ```
+    pub fn with_manager(k: usize, manager: M) -> Self {
         if (k & !(k - 1)) != k {
             panic!("k must be a non-zero power of 2!")
         }
         let init_node = Box::into_raw(Box::new(Segment::new(k)));
         SegQueue {
-            head: AtomicPtr::new(init_node),
-            tail: AtomicPtr::new(init_node),
-            manager: HPBRManager::new(100, 2),
-            rng: UnsafeCell::new(SmallRng::new()),
-            k
+            head: CachePadded::new(AtomicPtr::new(init_node)),
+            tail: CachePadded::new(AtomicPtr::new(init_node)),
+            manager,
+            k,
+            strategy: DequeueStrategy::Random,
+            last_index: CachePadded::new(AtomicUsize::new(0)),
+            #[cfg(test)]
+            fast_path_count: AtomicUsize::new(0),
+            #[cfg(test)]
+            scanned_count: AtomicUsize::new(0)
         }
     }
 
@@ -58,7 +151,10 @@ impl<T: Send> SegQueue<T> {
     /// queue.enqueue(8);
     /// ``` 
     pub fn enqueue(&self, data: T) {
-        let mut data_box = Box::new(data);
+        let mut data_box = match self.try_enqueue_empty(Box::new(data)) {
+            Ok(()) => { return; },
+            Err(val) => val
+        };
         loop {
             data_box = match self.try_enqueue(data_box) {
                 Ok(()) => { return; },
@@ -67,6 +163,31 @@ impl<T: Send> SegQueue<T> {
         }
     }
 
+    /// Fast path for enqueueing onto an empty queue. If the queue only has one segment, the data is
+    /// placed directly in its first slot without shuffling. Returns the data if the slot has already been
+    /// used or another thread got there first.
+    fn try_enqueue_empty(&self, data: Box<T>) -> Result<(), Box<T>> {
+        let tail = self.tail.load(Acquire);
+        self.manager.protect(tail, 0);
+        if !ptr::eq(tail, self.tail.load(Acquire)) || !ptr::eq(tail, self.head.load(Acquire))
+            || unsafe { !(*tail).next.load(Acquire).is_null() } {
+            self.manager.unprotect(0);
+            return Err(data)
+        }
+
+        let item_ptr = Box::into_raw(data);
+        let result = match Segment::get_cells_from_ptr(tail)[0].compare_exchange(ptr::null_mut(), item_ptr) {
+            Ok(_) => {
+                #[cfg(test)]
+                self.fast_path_count.fetch_add(1, Relaxed);
+                Ok(())
+            },
+            Err(_) => Err(unsafe { Box::from_raw(item_ptr) })
+        };
+        self.manager.unprotect(0);
+        result
+    }
+
     fn try_enqueue(&self, mut data: Box<T>) -> Result<(), Box<T>> {
         let tail = self.tail.load(Acquire);
         self.manager.protect(tail, 0);
@@ -76,8 +197,8 @@ impl<T: Send> SegQueue<T> {
             return Err(data)
         }
 
-        let rand: usize = unsafe { (*self.rng.get()).gen() };
-        let permutation_start = rand & (self.k - 1);
+        // With a single slot there is nothing to choose between, so skip the random start
+        let permutation_start = if self.k == 1 { 0 } else { random() & (self.k - 1) };
         let permutation = OrderGenerator::new(permutation_start, self.k);
 
         for index in permutation.iter() {
@@ -115,6 +236,30 @@ impl<T: Send> SegQueue<T> {
         }
     }
 
+    /// Dequeue a piece of data, waiting up to the given timeout for one to be enqueued if the queue is
+    /// empty, and returning None if none was. The queue is polled with an increasing backoff, which yields
+    /// to other threads once the queue has been empty for a while rather than spinning.
+    /// # Examples
+    /// ```
+    /// let queue: SegQueue<u8> = SegQueue::new(8);
+    /// assert_eq!(queue.dequeue_timeout(Duration::from_millis(1)), None);
+    /// queue.enqueue(8);
+    /// assert_eq!(queue.dequeue_timeout(Duration::from_millis(1)), Some(8));
+    /// ```
+    pub fn dequeue_timeout(&self, timeout: Duration) -> Option<T> {
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Backoff::new();
+        loop {
+            if let Ok(Some(val)) = self.try_dequeue() {
+                return Some(val)
+            }
+            if Instant::now() >= deadline {
+                return None
+            }
+            backoff.snooze();
+        }
+    }
+
     fn try_dequeue(&self) -> Result<Option<T>, ()> {
         let head = self.head.load(Acquire);
         self.manager.protect(head, 0);
@@ -122,41 +267,81 @@ impl<T: Send> SegQueue<T> {
             return Err(())
         }
 
-        let rand: usize = unsafe { (*self.rng.get()).gen() };
-        let permutation_start = rand & (self.k - 1);
+        let mut has_empty = false;
+        let mut in_use = false;
+        if self.k == 1 {
+            // The queue is a strict FIFO, so there is only one slot to look at
+            if let Some(data) = self.try_take(head, 0, &mut has_empty, &mut in_use) {
+                return Ok(Some(data))
+            }
+            if in_use {
+                return Err(())
+            }
+            return self.finish_empty_scan(head, has_empty)
+        }
+
+        let permutation_start = random() & (self.k - 1);
         let permutation = OrderGenerator::new(permutation_start, self.k);
 
-        let mut has_empty = false;
-        for index in permutation.iter() {
-            let cell = &Segment::get_cells_from_ptr(head)[index];
-            match cell.get_ptr() {
-                Some(item_ptr) => {
-                    if !atomic_markable::is_marked(item_ptr) {
-                        // Try to mark it as deleted
-                        match cell.compare_and_mark(item_ptr) {
-                            Ok(_) => { 
-                                // We got it, read
-                                // Need to now treat this as unitialised memory
-                                let data = unsafe { ptr::read(item_ptr) };
-                                unsafe { Box::from_raw(item_ptr) } ; 
-                                return Ok(Some(data)) 
-                            },
-                            Err(_) => {
-                                // We didn't get it
-                            }
-                        }
+        let mut scanned = 0;
+        match self.strategy {
+            DequeueStrategy::Random => {
+                for index in permutation.iter() {
+                    scanned += 1;
+                    if let Some(data) = self.try_take(head, index, &mut has_empty, &mut in_use) {
+                        self.record_scan(scanned);
+                        return Ok(Some(data))
+                    }
+                }
+            },
+            DequeueStrategy::ProbeThenScan(probes) => {
+                let probes = cmp::min(probes, self.k);
+                for index in permutation.iter().take(probes) {
+                    scanned += 1;
+                    if let Some(data) = self.try_take(head, index, &mut has_empty, &mut in_use) {
+                        self.last_index.store(index, Relaxed);
+                        self.record_scan(scanned);
+                        return Ok(Some(data))
+                    }
+                }
+                let scan = OrderGenerator::new(self.last_index.load(Relaxed) & (self.k - 1), self.k);
+                for index in scan.iter() {
+                    // Skip the slots which have already been probed
+                    if (index.wrapping_sub(permutation_start) & (self.k - 1)) < probes {
+                        continue;
+                    }
+                    scanned += 1;
+                    if let Some(data) = self.try_take(head, index, &mut has_empty, &mut in_use) {
+                        self.last_index.store(index, Relaxed);
+                        self.record_scan(scanned);
+                        return Ok(Some(data))
                     }
-                },
-                None => {
-                    has_empty = true;
                 }
             }
         }
+        self.record_scan(scanned);
 
-        // How do we tell if the queue is empty?
-        if ptr::eq(head, self.tail.load(Acquire)) || has_empty {
-            // Must be the last node, because there are empty slots
-            // If we reach the end and there are empty spots, we return None
+        // An element is being peeked at, so the segment is neither empty nor finished with
+        if in_use {
+            return Err(())
+        }
+
+        self.finish_empty_scan(head, has_empty)
+    }
+
+    /// Decide what to do after finding nothing to take in the front segment.
+    fn finish_empty_scan(&self, head: *mut Segment<T>, has_empty: bool) -> Result<Option<T>, ()> {
+        // Must be the last node, because there are empty slots
+        // If we reach the end and there are empty spots, we return None
+        if has_empty {
+            return Ok(None)
+        }
+
+        if ptr::eq(head, self.tail.load(Acquire)) {
+            // Every slot of the only segment has been used, so nothing can be enqueued there again.
+            // Swap it for a fresh segment, so that the next enqueue can take the fast path.
+            self.advance_tail(head);
+            self.advance_head(head);
             return Ok(None)
         }
 
@@ -165,6 +350,45 @@ impl<T: Send> SegQueue<T> {
         Err(())
     }
 
+    /// Try to take the element in the given slot of the segment. If the slot has never been filled,
+    /// `has_empty` is set, and if its element is being peeked at, `in_use` is set.
+    fn try_take(&self, segment: *mut Segment<T>, index: usize, has_empty: &mut bool, in_use: &mut bool) -> Option<T> {
+        let cell = &Segment::get_cells_from_ptr(segment)[index];
+        match cell.get_ptr() {
+            Some(item_ptr) => {
+                if atomic_markable::is_marked_second(item_ptr) {
+                    *in_use = true;
+                } else if !atomic_markable::is_marked(item_ptr) {
+                    // Try to mark it as deleted
+                    match cell.compare_and_mark(item_ptr) {
+                        Ok(_) => { 
+                            // We got it, so take the data out of its box and free the box. The marked
+                            // slot is skipped when the segment is dropped, so this is the only owner
+                            let data = unsafe { *Box::from_raw(item_ptr) };
+                            return Some(data)
+                        },
+                        Err(_) => {
+                            // We didn't get it
+                        }
+                    }
+                }
+            },
+            None => {
+                *has_empty = true;
+            }
+        }
+        None
+    }
+
+    /// Count the slots a dequeue attempt has looked at.
+    #[cfg(test)]
+    fn record_scan(&self, scanned: usize) {
+        self.scanned_count.fetch_add(scanned, Relaxed);
+    }
+
+    #[cfg(not(test))]
+    fn record_scan(&self, _scanned: usize) {}
+
     fn advance_tail(&self, tail_old: *mut Segment<T>) {
         if ptr::eq(tail_old, self.tail.load(Acquire)) {
             let next = unsafe { (*tail_old).next.load(Acquire)}; 
@@ -179,7 +403,7 @@ impl<T: Send> SegQueue<T> {
                                 Err(_) => {}
                             }
                         },
-                        Err(_) => { Box::from_raw(new_seg_ptr); }
+                        Err(_) => { drop(Box::from_raw(new_seg_ptr)); }
                     }
                 }
             } else {
@@ -214,22 +438,175 @@ impl<T: Send> SegQueue<T> {
         }
         
     }
+
+    /// Free the segments at the front of the queue whose elements have all been dequeued. A dequeue only moves
+    /// on from a drained segment when it next finds nothing to take there, so until then the segment stays in
+    /// the queue. Dequeues only ever take elements from the front segment, so no segment behind it can be
+    /// drained. The last segment is always kept, even if it is drained, since enqueuers may still be using it.
+    /// # Examples
+    /// ```
+    /// let queue: SegQueue<u8> = SegQueue::new(2);
+    /// queue.enqueue(1);
+    /// queue.enqueue(2);
+    /// queue.enqueue(3);
+    /// queue.dequeue();
+    /// queue.dequeue();
+    /// queue.compact();
+    /// assert_eq!(queue.dequeue(), Some(3));
+    /// ```
+    pub fn compact(&self) {
+        loop {
+            let head = self.head.load(Acquire);
+            self.manager.protect(head, 0);
+            if !ptr::eq(head, self.head.load(Acquire)) {
+                continue;
+            }
+
+            // A slot which has never been filled, or is being peeked at, keeps the segment in use
+            let drained = Segment::get_cells_from_ptr(head).iter().all(|cell| match cell.get_ptr() {
+                Some(item_ptr) => atomic_markable::is_marked(item_ptr),
+                None => false
+            });
+            if !drained || unsafe { (*head).next.load(Acquire).is_null() } {
+                self.manager.unprotect(0);
+                return
+            }
+            self.advance_head(head);
+        }
+    }
+
+    /// Clone one of the elements at the front of the queue without removing it, or return None if the
+    /// queue is empty. Like dequeue, this looks through the slots of the front segment starting from a
+    /// random one, so the element may be any of the first `k`, and a following dequeue may return a
+    /// different one. While the element is being cloned its slot is held, so that dequeues pass over it. A
+    /// dequeue which finds nothing else to take in the front segment waits until the clone is finished, so
+    /// cloning should be quick. The slot is given back even if the clone panics.
+    /// # Examples
+    /// ```
+    /// let queue: SegQueue<u8> = SegQueue::new(8);
+    /// queue.enqueue(8);
+    /// assert_eq!(queue.peek(), Some(8));
+    /// assert_eq!(queue.dequeue(), Some(8));
+    /// ```
+    pub fn peek(&self) -> Option<T>
+    where T: Clone
+    {
+        loop {
+            let head = self.head.load(Acquire);
+            self.manager.protect(head, 0);
+            // The head may have been retired before it was protected
+            if !ptr::eq(head, self.head.load(Acquire)) {
+                continue;
+            }
+
+            let permutation_start = if self.k == 1 { 0 } else { random() & (self.k - 1) };
+            let mut has_empty = false;
+            let mut in_use = false;
+            for index in OrderGenerator::new(permutation_start, self.k).iter() {
+                let cell = &Segment::get_cells_from_ptr(head)[index];
+                match cell.get_ptr() {
+                    None => has_empty = true,
+                    Some(item_ptr) => {
+                        if atomic_markable::is_marked(item_ptr) {
+                            continue;
+                        }
+                        if atomic_markable::is_marked_second(item_ptr) {
+                            in_use = true;
+                            continue;
+                        }
+                        // Hold the slot so that no dequeue can take and free the element while it is cloned
+                        if cell.compare_exchange(item_ptr, atomic_markable::mark_second(item_ptr)).is_ok() {
+                            let held = HeldSlot { cell, item_ptr };
+                            let data = unsafe { (*item_ptr).clone() };
+                            drop(held);
+                            self.manager.unprotect(0);
+                            return Some(data)
+                        }
+                        in_use = true;
+                    }
+                }
+            }
+
+            if in_use {
+                continue;
+            }
+            if ptr::eq(head, self.tail.load(Acquire)) || has_empty {
+                self.manager.unprotect(0);
+                return None
+            }
+            // Every element of the front segment has been taken, so move on as dequeue would
+            self.advance_head(head);
+        }
+    }
+
+    /// The number of elements in the queue, found by walking the segments from head to tail and counting
+    /// the slots which hold an element. Other threads may enqueue and dequeue during the walk, so while
+    /// they are running the length is only approximate, but it is exact once they stop. If the front
+    /// segment is removed during the walk, it starts again from the new front.
+    /// # Examples
+    /// ```
+    /// let queue: SegQueue<u8> = SegQueue::new(2);
+    /// queue.enqueue(1);
+    /// queue.enqueue(2);
+    /// queue.enqueue(3);
+    /// assert_eq!(queue.len(), 3);
+    /// ```
+    pub fn len(&self) -> usize {
+        'walk: loop {
+            let head = self.head.load(Acquire);
+            self.manager.protect(head, 0);
+            if !ptr::eq(head, self.head.load(Acquire)) {
+                continue;
+            }
+
+            // While the protected head is still the head no segment can have been retired, so every
+            // segment reached from it is safe to read once protected
+            let mut len = 0;
+            let mut current = head;
+            while !current.is_null() {
+                len += Segment::get_cells_from_ptr(current).iter().filter(|cell| match cell.get_ptr() {
+                    Some(item_ptr) => !atomic_markable::is_marked(item_ptr),
+                    None => false
+                }).count();
+                current = unsafe { (*current).next.load(Acquire) };
+                self.manager.protect(current, 1);
+                if !ptr::eq(head, self.head.load(Acquire)) {
+                    continue 'walk;
+                }
+            }
+            self.manager.unprotect(0);
+            self.manager.unprotect(1);
+            return len
+        }
+    }
+
+    /// Check whether the queue has no elements, with the same caveats as `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check whether drained segments are backing up in the strategy's free lists.
+    pub fn has_reclaim_pressure(&self) -> bool {
+        self.manager.has_reclaim_pressure()
+    }
 }
 
-impl<T: Send> Drop for SegQueue<T> {
+impl<T: Send, M: ReclamationStrategy<Segment<T>>> Drop for SegQueue<T, M> {
     fn drop(&mut self) {
         let mut current = self.head.load(Relaxed);
         while !current.is_null() {
             unsafe {
                 let next = (*current).next.load(Relaxed);
-                Box::from_raw(current);
+                drop(Box::from_raw(current));
                 current = next;
             }
         } 
     }
 }
 
-struct Segment<T: Send> {
+/// A segment of a SegQueue. It is only public so that it can be named in the bounds of a SegQueue's
+/// reclamation strategy, and cannot be used outside the crate.
+pub struct Segment<T: Send> {
     cells: Vec<AtomicMarkablePtr<T>>,
     next: AtomicPtr<Segment<T>>
 }
@@ -251,6 +628,19 @@ impl<T: Send> Segment<T> {
     }
 }
 
+/// A slot held by `peek`, which is given back when the hold is dropped, even while unwinding from a
+/// panicking clone, so that dequeues never wait on it forever.
+struct HeldSlot<'a, T: Send + 'a> {
+    cell: &'a AtomicMarkablePtr<T>,
+    item_ptr: *mut T
+}
+
+impl<'a, T: Send> Drop for HeldSlot<'a, T> {
+    fn drop(&mut self) {
+        self.cell.store(self.item_ptr);
+    }
+}
+
 struct OrderGenerator {
     start: usize,
     size: usize
@@ -302,9 +692,13 @@ mod tests {
     use self::im::Vector;
 
     use rand::{thread_rng, Rng};
-    use super::{SegQueue, OrderGenerator};
+    use super::{SegQueue, DequeueStrategy, OrderGenerator};
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::thread;
+    use std::time::Duration;
+    use std::collections::VecDeque;
+    use std::panic::{self, AssertUnwindSafe};
     
     use super::super::super::testing::{LinearizabilityTester, LinearizabilityResult, ThreadLog}; 
 
@@ -318,6 +712,333 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_empty_fast_path() {
+        let queue: SegQueue<u16> = SegQueue::new(4);
+
+        queue.enqueue(1);
+        assert_eq!(queue.fast_path_count.load(Ordering::Relaxed), 1);
+        // The first slot is taken, so these must take the normal path
+        for i in 2..5 {
+            queue.enqueue(i);
+        }
+        assert_eq!(queue.fast_path_count.load(Ordering::Relaxed), 1);
+
+        let mut values = Vec::new();
+        while let Some(val) = queue.dequeue() {
+            values.push(val);
+        }
+        values.sort();
+        assert_eq!(values, vec![1, 2, 3, 4]);
+
+        // Every slot of the segment has been used, so it has been swapped for a fresh one, and enqueueing
+        // into the freshly emptied queue should use the fast path again
+        for round in 0..10 {
+            for i in 0..4 {
+                queue.enqueue(i);
+            }
+            assert_eq!(queue.fast_path_count.load(Ordering::Relaxed), 2 + round);
+            let mut values = Vec::new();
+            while let Some(val) = queue.dequeue() {
+                values.push(val);
+            }
+            values.sort();
+            assert_eq!(values, vec![0, 1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn test_probe_then_scan_looks_at_fewer_slots() {
+        // Draining full segments one by one leaves long runs of taken slots behind. Starting every scan
+        // from a random slot keeps running into them, while scanning on from the last dequeue does not.
+        let random: SegQueue<u32> = SegQueue::with_dequeue_strategy(64, DequeueStrategy::Random);
+        let probing: SegQueue<u32> = SegQueue::with_dequeue_strategy(64, DequeueStrategy::ProbeThenScan(2));
+        for queue in &[&random, &probing] {
+            for i in 0..64 * 20 {
+                queue.enqueue(i);
+            }
+            let mut values = Vec::new();
+            while let Some(val) = queue.dequeue() {
+                values.push(val);
+            }
+            values.sort();
+            assert_eq!(values, (0..64 * 20).collect::<Vec<u32>>());
+        }
+
+        let random_scanned = random.scanned_count.load(Ordering::Relaxed);
+        let probing_scanned = probing.scanned_count.load(Ordering::Relaxed);
+        assert!(probing_scanned < random_scanned, "{} >= {}", probing_scanned, random_scanned);
+    }
+
+    #[test]
+    fn test_len() {
+        let queue: SegQueue<u16> = SegQueue::new(4);
+        assert_eq!(queue.len(), 0);
+        assert!(queue.is_empty());
+
+        // Spread over four segments, the last of them partly filled
+        for i in 0..14 {
+            queue.enqueue(i);
+            assert_eq!(queue.len(), i as usize + 1);
+        }
+
+        // Any of the first k elements may come out first, but each dequeue takes exactly one
+        for taken in 1..15 {
+            assert!(queue.dequeue().is_some());
+            assert_eq!(queue.len(), 14 - taken);
+        }
+        assert!(queue.is_empty());
+        assert_eq!(queue.dequeue(), None);
+    }
+
+    fn segment_count<T: Send>(queue: &SegQueue<T>) -> usize {
+        let mut count = 0;
+        let mut current = queue.head.load(Ordering::Acquire);
+        while !current.is_null() {
+            count += 1;
+            current = unsafe { (*current).next.load(Ordering::Acquire) };
+        }
+        count
+    }
+
+    #[test]
+    fn test_compact() {
+        let queue: SegQueue<u16> = SegQueue::new(4);
+        for i in 0..40 {
+            queue.enqueue(i);
+        }
+        assert_eq!(segment_count(&queue), 10);
+
+        // The ninth segment is drained by the last of these, but nothing has moved past it yet
+        let mut values: Vec<u16> = (0..36).map(|_| queue.dequeue().unwrap()).collect();
+        assert_eq!(segment_count(&queue), 2);
+        queue.compact();
+        assert_eq!(segment_count(&queue), 1);
+        queue.compact();
+        assert_eq!(segment_count(&queue), 1);
+
+        assert_eq!(queue.len(), 4);
+        while let Some(val) = queue.dequeue() {
+            values.push(val);
+        }
+        values.sort();
+        assert_eq!(values, (0..40).collect::<Vec<u16>>());
+
+        // The last segment is kept even once it is drained
+        queue.compact();
+        assert_eq!(segment_count(&queue), 1);
+        queue.enqueue(40);
+        assert_eq!(queue.dequeue(), Some(40));
+    }
+
+    #[test]
+    fn test_k_fifo_order() {
+        // Single threaded, every element dequeued must be one of the k oldest still in the queue
+        for &k in &[1, 2, 4, 8] {
+            let queue: SegQueue<u32> = SegQueue::new(k);
+            let mut model: VecDeque<u32> = VecDeque::new();
+            let mut next = 0;
+            for _ in 0..5000 {
+                if thread_rng().gen_range(0, 3) < 2 {
+                    queue.enqueue(next);
+                    model.push_back(next);
+                    next += 1;
+                } else {
+                    match queue.dequeue() {
+                        Some(val) => {
+                            let position = model.iter().position(|&queued| queued == val).unwrap();
+                            assert!(position < k, "dequeued {} from position {} with k = {}", val, position, k);
+                            model.remove(position);
+                        },
+                        None => assert!(model.is_empty())
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_k_one_strict_fifo() {
+        let queue: SegQueue<u32> = SegQueue::new(1);
+        let mut expected = 0;
+        for i in 0..1000 {
+            queue.enqueue(i);
+            if i % 3 == 0 {
+                assert_eq!(queue.dequeue(), Some(expected));
+                expected += 1;
+            }
+        }
+        while let Some(val) = queue.dequeue() {
+            assert_eq!(val, expected);
+            expected += 1;
+        }
+        assert_eq!(expected, 1000);
+
+        // Each producer's elements come out in the order it enqueued them
+        let queue: Arc<SegQueue<(usize, usize)>> = Arc::new(SegQueue::new(1));
+        let mut producers: Vec<thread::JoinHandle<()>> = Vec::new();
+        for id in 0..4 {
+            let queue_copy = queue.clone();
+            producers.push(thread::spawn(move || {
+                for i in 0..2000 {
+                    queue_copy.enqueue((id, i));
+                }
+            }));
+        }
+        let mut next = [0; 4];
+        let mut taken = 0;
+        while taken < 8000 {
+            match queue.dequeue() {
+                Some((id, i)) => {
+                    assert_eq!(i, next[id]);
+                    next[id] += 1;
+                    taken += 1;
+                },
+                None => thread::yield_now()
+            }
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert_eq!(queue.dequeue(), None);
+    }
+
+    #[test]
+    fn test_elements_dropped_once() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+        struct DropCounter(u32);
+
+        impl Drop for DropCounter {
+            fn drop(&mut self) {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let queue: SegQueue<DropCounter> = SegQueue::new(4);
+        for i in 0..100 {
+            queue.enqueue(DropCounter(i));
+        }
+        // Empty the first few segments so that they are retired, and leave the rest in the queue
+        for _ in 0..30 {
+            let taken = queue.dequeue().unwrap();
+            assert!(taken.0 < 100);
+        }
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 30);
+
+        drop(queue);
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 100);
+    }
+
+    #[test]
+    fn test_dequeue_timeout() {
+        let queue: Arc<SegQueue<u8>> = Arc::new(SegQueue::new(4));
+        let consumer_queue = queue.clone();
+        let consumer = thread::spawn(move || consumer_queue.dequeue_timeout(Duration::from_millis(20)));
+        assert_eq!(consumer.join().unwrap(), None);
+
+        // An element enqueued before the deadline is picked up
+        let consumer_queue = queue.clone();
+        let consumer = thread::spawn(move || consumer_queue.dequeue_timeout(Duration::from_secs(10)));
+        thread::sleep(Duration::from_millis(10));
+        queue.enqueue(8);
+        assert_eq!(consumer.join().unwrap(), Some(8));
+        assert_eq!(queue.dequeue_timeout(Duration::from_millis(0)), None);
+    }
+
+    #[test]
+    fn test_peek() {
+        let queue: SegQueue<u32> = SegQueue::new(4);
+        assert_eq!(queue.peek(), None);
+        for i in 0..10 {
+            queue.enqueue(i);
+        }
+
+        // Any of the first k elements may be seen, and it stays in the queue
+        for _ in 0..20 {
+            let peeked = queue.peek().unwrap();
+            assert!(peeked < 4);
+            assert_eq!(queue.len(), 10);
+        }
+
+        let mut dequeued = Vec::new();
+        while let Some(val) = queue.dequeue() {
+            dequeued.push(val);
+        }
+        dequeued.sort();
+        assert_eq!(dequeued, (0..10).collect::<Vec<u32>>());
+        assert_eq!(queue.peek(), None);
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct PanicsOnClone(u8);
+
+    impl Clone for PanicsOnClone {
+        fn clone(&self) -> Self {
+            panic!("cloned")
+        }
+    }
+
+    #[test]
+    fn test_peek_gives_slot_back_on_panic() {
+        let queue: SegQueue<PanicsOnClone> = SegQueue::new(1);
+        queue.enqueue(PanicsOnClone(1));
+        let peeked = panic::catch_unwind(AssertUnwindSafe(|| queue.peek()));
+        assert!(peeked.is_err());
+        // The held slot was given back, so the dequeue does not wait for it forever
+        assert_eq!(queue.dequeue(), Some(PanicsOnClone(1)));
+        assert_eq!(queue.dequeue(), None);
+    }
+
+    #[test]
+    fn test_peek_while_dequeueing() {
+        let queue: Arc<SegQueue<String>> = Arc::new(SegQueue::new(4));
+        for i in 0..20000 {
+            queue.enqueue(i.to_string());
+        }
+
+        let mut wait_vec: Vec<thread::JoinHandle<Vec<String>>> = Vec::new();
+        for _ in 0..2 {
+            let queue_copy = queue.clone();
+            wait_vec.push(thread::spawn(move || {
+                let mut dequeued = Vec::new();
+                while let Some(val) = queue_copy.dequeue() {
+                    dequeued.push(val);
+                }
+                dequeued
+            }));
+            let queue_copy = queue.clone();
+            wait_vec.push(thread::spawn(move || {
+                // Peeked elements must still be intact, even if they are dequeued straight after
+                while let Some(val) = queue_copy.peek() {
+                    assert!(val.parse::<u32>().unwrap() < 20000);
+                }
+                Vec::new()
+            }));
+        }
+
+        let mut dequeued: Vec<u32> = wait_vec.into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .map(|val| val.parse().unwrap())
+            .collect();
+        dequeued.sort();
+        assert_eq!(dequeued, (0..20000).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_probe_then_scan_single_threaded() {
+        let queue: SegQueue<u16> = SegQueue::with_dequeue_strategy(4, DequeueStrategy::ProbeThenScan(2));
+        for i in 0..50 {
+            queue.enqueue(i);
+        }
+        let mut values = Vec::new();
+        while let Some(val) = queue.dequeue() {
+            values.push(val);
+        }
+        values.sort();
+        assert_eq!(values, (0..50).collect::<Vec<u16>>());
+    }
+
     #[test]
     fn test_with_contention() {
         let mut queue: Arc<SegQueue<u16>> = Arc::new(SegQueue::new(32));
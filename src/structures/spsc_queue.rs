@@ -0,0 +1,194 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use super::utils::cache_padded::CachePadded;
+
+/// A lock-free single-producer single-consumer queue.
+///
+/// The queue is a fixed-capacity ring buffer, which is only used through the Producer and Consumer
+/// returned by `new`. Since only one thread ever writes to each end, the ends are moved with plain
+/// stores rather than CAS, and each end is kept on its own cache line so that the producer and
+/// consumer do not slow each other down.
+pub struct SpscQueue<T: Send> {
+    buffer: Vec<UnsafeCell<Option<T>>>,
+    mask: usize,
+    // The next position to read from, only written by the consumer
+    head: CachePadded<AtomicUsize>,
+    // The next position to write to, only written by the producer
+    tail: CachePadded<AtomicUsize>
+}
+
+/// The sending half of an SpscQueue. The Producer can be sent to another thread, but not shared
+/// between threads.
+pub struct Producer<T: Send> {
+    inner: Arc<SpscQueue<T>>,
+    // Stops the producer from being shared between threads
+    _marker: PhantomData<*mut ()>
+}
+
+unsafe impl<T: Send> Send for Producer<T> {}
+
+/// The receiving half of an SpscQueue. The Consumer can be sent to another thread, but not shared
+/// between threads.
+pub struct Consumer<T: Send> {
+    inner: Arc<SpscQueue<T>>,
+    // Stops the consumer from being shared between threads
+    _marker: PhantomData<*mut ()>
+}
+
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+unsafe impl<T: Send> Sync for SpscQueue<T> {}
+
+impl<T: Send> SpscQueue<T> {
+    /// Create a new SpscQueue which can hold at least `capacity` elements, returning its two halves.
+    /// The capacity is rounded up to the next power of 2, and must not be zero.
+    /// # Examples
+    /// ```
+    /// let (producer, consumer) = SpscQueue::new(8);
+    /// producer.push(8);
+    /// assert_eq!(consumer.pop(), Some(8));
+    /// ```
+    // Returning the halves rather than the queue is what keeps each end with a single thread
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(capacity: usize) -> (Producer<T>, Consumer<T>) {
+        if capacity == 0 {
+            panic!("capacity must be non-zero!")
+        }
+        let capacity = capacity.next_power_of_two();
+        let inner = Arc::new(SpscQueue {
+            buffer: (0..capacity).map(|_| UnsafeCell::new(None)).collect(),
+            mask: capacity - 1,
+            head: CachePadded::new(AtomicUsize::new(0)),
+            tail: CachePadded::new(AtomicUsize::new(0))
+        });
+        let producer = Producer {
+            inner: inner.clone(),
+            _marker: PhantomData
+        };
+        let consumer = Consumer {
+            inner,
+            _marker: PhantomData
+        };
+        (producer, consumer)
+    }
+}
+
+impl<T: Send> Producer<T> {
+    /// Add a new element to the back of the queue.
+    /// # Errors
+    /// If the queue is full, the element is returned in an Err.
+    /// # Examples
+    /// ```
+    /// let (producer, consumer) = SpscQueue::new(1);
+    /// assert_eq!(producer.push(8), Ok(()));
+    /// assert_eq!(producer.push(9), Err(9));
+    /// ```
+    pub fn push(&self, val: T) -> Result<(), T> {
+        let inner = &*self.inner;
+        let tail = inner.tail.load(Relaxed);
+        // Pairs with the consumer's release, so the slot has been emptied before it is reused
+        if tail.wrapping_sub(inner.head.load(Acquire)) == inner.buffer.len() {
+            return Err(val)
+        }
+        unsafe { *inner.buffer[tail & inner.mask].get() = Some(val) };
+        inner.tail.store(tail.wrapping_add(1), Release);
+        Ok(())
+    }
+
+    /// The number of elements the queue can hold.
+    pub fn capacity(&self) -> usize {
+        self.inner.buffer.len()
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    /// Take an element from the front of the queue, or return None if the queue is empty.
+    /// # Examples
+    /// ```
+    /// let (producer, consumer) = SpscQueue::new(8);
+    /// assert_eq!(consumer.pop(), None);
+    /// producer.push(8);
+    /// assert_eq!(consumer.pop(), Some(8));
+    /// ```
+    pub fn pop(&self) -> Option<T> {
+        let inner = &*self.inner;
+        let head = inner.head.load(Relaxed);
+        // Pairs with the producer's release, so the element has been written before it is read
+        if head == inner.tail.load(Acquire) {
+            return None
+        }
+        let val = unsafe { (*inner.buffer[head & inner.mask].get()).take() };
+        inner.head.store(head.wrapping_add(1), Release);
+        val
+    }
+
+    /// The number of elements the queue can hold.
+    pub fn capacity(&self) -> usize {
+        self.inner.buffer.len()
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::{SpscQueue, Producer, Consumer};
+    use std::thread;
+
+    #[test]
+    fn test_single_threaded() {
+        let (producer, consumer) = SpscQueue::new(5);
+        assert_eq!(producer.capacity(), 8);
+        // Go round the ring a few times
+        for round in 0..3 {
+            for i in 0..8 {
+                assert_eq!(producer.push(round * 8 + i), Ok(()));
+            }
+            assert_eq!(producer.push(100), Err(100));
+            for i in 0..8 {
+                assert_eq!(consumer.pop(), Some(round * 8 + i));
+            }
+            assert_eq!(consumer.pop(), None);
+        }
+    }
+
+    #[test]
+    fn test_fifo_across_threads() {
+        const NUM_ITEMS: usize = 1000000;
+        let (producer, consumer) = SpscQueue::new(1024);
+
+        let producer_thread = thread::spawn(move || {
+            for i in 0..NUM_ITEMS {
+                let mut val = i;
+                while let Err(returned) = producer.push(val) {
+                    val = returned;
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0;
+        while expected < NUM_ITEMS {
+            match consumer.pop() {
+                Some(val) => {
+                    assert_eq!(val, expected);
+                    expected += 1;
+                },
+                None => thread::yield_now()
+            }
+        }
+        producer_thread.join().unwrap();
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn test_drops_remaining_items() {
+        let (producer, consumer): (Producer<String>, Consumer<String>) = SpscQueue::new(4);
+        producer.push("hello".to_owned()).unwrap();
+        producer.push("world".to_owned()).unwrap();
+        assert_eq!(consumer.pop(), Some("hello".to_owned()));
+        drop(consumer);
+        drop(producer);
+    }
+}
@@ -1,12 +1,33 @@
-use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering::{Relaxed, Release, Acquire}};
+use sync::atomic::{AtomicPtr, AtomicUsize, Ordering::{Relaxed, Release, Acquire}};
 use std::ptr;
+#[cfg(feature = "std")]
 use super::HashMap;
+#[cfg(feature = "std")]
 use std::{thread, thread::ThreadId};
+#[cfg(feature = "std")]
 use std::time::Duration;
+#[cfg(feature = "std")]
 use std::cell::UnsafeCell;
+#[cfg(feature = "std")]
 use rand::{Rng, SmallRng, NewRng};
+#[cfg(feature = "std")]
 use memory::HPBRManager;
+use memory::ReclamationStrategy;
+use super::utils::backoff::Backoff;
+use super::utils::read_mark::ReadMark;
+#[cfg(feature = "std")]
+use super::utils::cache_padded::CachePadded;
+#[cfg(feature = "std")]
 use std::mem;
+#[cfg(feature = "std")]
+use std::iter::FromIterator;
+use std::boxed::Box;
+use std::vec::Vec;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+// The number of collision slots in the elimination layer, unless the stack is created with another
+const COLLISION_SIZE: usize = 5;
 
 /// A lock-free stack with optional elimination backoff.
 ///
@@ -28,20 +49,31 @@ use std::mem;
 ///     });
 /// }
 /// ```
-
-pub struct Stack<T: Send> {
+///
+/// Popped nodes are freed through an HPBRManager unless the stack is created with `with_manager`, which
+/// takes any other ReclamationStrategy.
+pub struct Stack<T: Send,
+                 #[cfg(feature = "std")] M: ReclamationStrategy<Node<T>> = HPBRManager<Node<T>>,
+                 #[cfg(not(feature = "std"))] M: ReclamationStrategy<Node<T>>> {
     head: AtomicPtr<Node<T>>,
-    elimination: EliminationLayer<T>,
-    manager: HPBRManager<Node<T>>,
-    elimination_on: bool
+    // Only built if the stack was created with the elimination layer turned on
+    #[cfg(feature = "std")]
+    elimination: Option<EliminationLayer<T>>,
+    manager: M,
+    len: AtomicUsize
 }
 
-
-struct Node<T: Send> {
+/// A node of a Stack. It is only public so that it can be named in the bounds of a Stack's
+/// reclamation strategy, and cannot be used outside the crate.
+pub struct Node<T: Send> {
     data: Option<T>,
-    next: AtomicPtr<Node<T>>
+    next: AtomicPtr<Node<T>>,
+    // Threads which protected the node can still read its data after it is popped, so the popper waits
+    // for them before moving the data out
+    readers: ReadMark
 }
 
+#[cfg(feature = "std")]
 impl<T: Send> Stack<T> {
     /// Create a new stack, with or without elimination layer.
     /// # Examples
@@ -49,20 +81,55 @@ impl<T: Send> Stack<T> {
     /// let stack: Stack<u8> = Stack::new(true);
     /// ```
     pub fn new(elimination_on: bool) -> Stack<T> {
-        Stack {
-            head: AtomicPtr::default(),
-            elimination: EliminationLayer::new(5),
-            manager: HPBRManager::new(200, 1),
-            elimination_on
-        }
+        Stack::with_manager(elimination_on, HPBRManager::new(200, 2))
+    }
+
+    /// Create a new stack where each thread's list of popped nodes waiting to be freed has room
+    /// for `retire_capacity` nodes from the start. See `HPBRManager::with_retire_capacity`.
+    /// # Examples
+    /// ```
+    /// let stack: Stack<u8> = Stack::with_retire_capacity(true, 256);
+    /// ```
+    pub fn with_retire_capacity(elimination_on: bool, retire_capacity: usize) -> Self {
+        let manager = HPBRManager::with_retire_capacity(200, 2, retire_capacity);
+        Stack::with_parts(elimination_on, manager, COLLISION_SIZE, retire_capacity)
     }
 
     pub fn new_with_collision_size(elimination_on: bool, collision_size: usize) -> Self {
-        Self {
+        Stack::with_parts(elimination_on, HPBRManager::new(200, 2), collision_size, 0)
+    }
+}
+
+impl<T: Send, M: ReclamationStrategy<Node<T>>> Stack<T, M> {
+    /// Create a new stack, with or without elimination layer, which frees popped nodes through the
+    /// given ReclamationStrategy. The strategy must provide two hazard pointers per thread. Pushes take
+    /// their nodes from the strategy's `allocate` where it can, so a manager created with
+    /// `HPBRManager::with_recycling` lets the stack reuse the nodes it pops.
+    /// # Panics
+    /// The elimination layer needs std, so without the `std` feature this method will panic if
+    /// `elimination_on` is true.
+    /// # Examples
+    /// ```
+    /// let stack: Stack<u8> = Stack::with_manager(true, HPBRManager::new(50, 2));
+    /// ```
+    pub fn with_manager(elimination_on: bool, manager: M) -> Self {
+        if cfg!(not(feature = "std")) && elimination_on {
+            panic!("The elimination layer is not available without std!")
+        }
+        Stack::with_parts(elimination_on, manager, COLLISION_SIZE, 0)
+    }
+
+    /// Create a new stack whose elimination layer has `collision_size` slots, and frees its thread
+    /// records with room for `retire_capacity` of them per thread. Without std there is no
+    /// elimination layer, so both are ignored.
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    fn with_parts(elimination_on: bool, manager: M, collision_size: usize, retire_capacity: usize) -> Self {
+        Stack {
             head: AtomicPtr::default(),
-            elimination: EliminationLayer::new(collision_size),
-            manager: HPBRManager::new(200, 1),
-            elimination_on
+            #[cfg(feature = "std")]
+            elimination: if elimination_on { Some(EliminationLayer::new(collision_size, retire_capacity)) } else { None },
+            manager,
+            len: AtomicUsize::new(0)
         }
     }
 
@@ -74,30 +141,47 @@ impl<T: Send> Stack<T> {
     /// stack.push("hello".to_owned());
     /// ```
     pub fn push(&self, val: T) {
-        let mut node_ptr = Box::into_raw(Box::new(Node::new(val)));
+        let mut node_ptr = Box::into_raw(self.alloc_node(val));
+        #[cfg(feature = "std")]
         let mut thread_info_ptr: *mut ThreadInfo<T> = ptr::null_mut();
+        let mut backoff = Backoff::new();
         loop {
             node_ptr = match self.try_push(node_ptr) {
                 Ok(_) => {
-                    if !thread_info_ptr.is_null() {
-                        // Make sure this doesn't need to be done with the memory manager
-                        unsafe { Box::from_raw(thread_info_ptr) };
-                    } 
+                    #[cfg(feature = "std")]
+                    {
+                        if !thread_info_ptr.is_null() {
+                            // Make sure this doesn't need to be done with the memory manager
+                            unsafe { drop(Box::from_raw(thread_info_ptr)) };
+                        }
+                    }
                     return; 
                 }
                 Err(old_node) => old_node
             };
-            if thread_info_ptr.is_null() {
-                thread_info_ptr = Box::into_raw(Box::new(ThreadInfo::new(Some(node_ptr), OpType::Push)));
-            }
-            if self.elimination_on {
-                match self.elimination.try_eliminate(thread_info_ptr, OpType::Push) {
-                    Ok(_) => {
+            #[cfg(feature = "std")]
+            {
+                if thread_info_ptr.is_null() {
+                    thread_info_ptr = Box::into_raw(Box::new(ThreadInfo::new(Some(node_ptr), OpType::Push)));
+                }
+                if let Some(ref elimination) = self.elimination {
+                    if elimination.try_eliminate(thread_info_ptr, OpType::Push).is_ok() {
                         return
-                    },
-                    Err(_) => {}
+                    }
                 }
             }
+            backoff.snooze();
+        }
+    }
+
+    // Reuse a spare node from the manager if it has one
+    fn alloc_node(&self, val: T) -> Box<Node<T>> {
+        match self.manager.allocate() {
+            Some(mut spare) => {
+                *spare = Node::new(val);
+                spare
+            },
+            None => Box::new(Node::new(val))
         }
     }
 
@@ -106,7 +190,10 @@ impl<T: Send> Stack<T> {
         unsafe { (*node_ptr).next = AtomicPtr::new(old_head) };
 
         match self.head.compare_exchange(old_head, node_ptr, Release, Relaxed) {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                self.len.fetch_add(1, Relaxed);
+                Ok(())
+            },
             Err(_) => {
                 Err(node_ptr)
             }
@@ -122,66 +209,307 @@ impl<T: Send> Stack<T> {
     /// assert_eq!(stack.pop(), "hello".to_owned()); 
     /// ```
     pub fn pop(&self) -> Option<T> {
+        #[cfg(feature = "std")]
         let mut thread_info_ptr: *mut ThreadInfo<T> = ptr::null_mut();
+        let mut backoff = Backoff::new();
         loop {
             if let Ok(val) = self.try_pop() {
-                if !thread_info_ptr.is_null() {
-                    unsafe { Box::from_raw(thread_info_ptr) };
+                #[cfg(feature = "std")]
+                {
+                    if !thread_info_ptr.is_null() {
+                        unsafe { drop(Box::from_raw(thread_info_ptr)) };
+                    }
                 }
                 return val
             }
-            if thread_info_ptr.is_null() {
-                thread_info_ptr = Box::into_raw(Box::new(ThreadInfo::new(None, OpType::Pop)));
-            }
-            if self.elimination_on {
-                if let Ok(val) = self.elimination.try_eliminate(thread_info_ptr, OpType::Pop) {
-                    unsafe { Box::from_raw(thread_info_ptr) };
-                    return val
+            #[cfg(feature = "std")]
+            {
+                if thread_info_ptr.is_null() {
+                    thread_info_ptr = Box::into_raw(Box::new(ThreadInfo::new(None, OpType::Pop)));
+                }
+                if let Some(ref elimination) = self.elimination {
+                    if let Ok(val) = elimination.try_eliminate(thread_info_ptr, OpType::Pop) {
+                        unsafe { drop(Box::from_raw(thread_info_ptr)) };
+                        return val
+                    }
                 }
             }
+            backoff.snooze();
         }
     }
 
     fn try_pop(&self) -> Result<Option<T>, ()> {
-        let old_head = self.head.load(Acquire);
+        let guard = self.manager.guard(0);
+        let old_head = guard.protect(&self.head);
         if old_head.is_null() {
             return Ok(None)
         }
         unsafe {
-            self.manager.protect(old_head, 0);
-            if !ptr::eq(old_head, self.head.load(Acquire)) {
-                return Err(())
-            }
             let new_head = (*old_head).next.load(Acquire);
             match self.head.compare_exchange_weak(old_head, new_head, Release, Relaxed) {
                 Err(_) => Err(()),
                 Ok(old_head) => {
-                    let old_head_val = ptr::replace(old_head, Node::default());
-                    let data = old_head_val.data;
-                    self.manager.retire(old_head, 0);
+                    self.len.fetch_sub(1, Relaxed);
+                    // Only the data is moved out: other threads may still be reading the node's next pointer
+                    let data = Node::take_data(old_head);
+                    guard.retire(old_head);
                     Ok(data)
                 }
             }
         }
     }
+
+    /// Return a clone of the data at the top of the stack without removing it, or None if
+    /// the stack is empty. A thread popping the item waits for the clone to finish before moving it out.
+    /// # Examples
+    /// ```
+    /// let stack: Stack<String> = Stack::new(true);
+    /// stack.push("hello".to_owned());
+    /// assert_eq!(stack.peek(), Some("hello".to_owned()));
+    /// assert_eq!(stack.pop(), Some("hello".to_owned()));
+    /// ```
+    pub fn peek(&self) -> Option<T>
+    where T: Clone
+    {
+        loop {
+            let guard = self.manager.guard(0);
+            let head = guard.protect(&self.head);
+            if head.is_null() {
+                return None
+            }
+            // A pop may have taken the data after we protected the node, in which case the head has moved
+            if let Some(data) = unsafe { Node::read_data(head, |data| data.clone()) } {
+                return Some(data)
+            }
+        }
+    }
+
+    /// Check whether the stack holds an item equal to the given value. While other threads are pushing or
+    /// popping the answer is only weakly consistent: an item pushed or popped during the search may or may
+    /// not be seen. Items exchanged through the elimination layer never reach the stack, so they are never seen.
+    /// # Examples
+    /// ```
+    /// let stack: Stack<u8> = Stack::new(true);
+    /// stack.push(1);
+    /// stack.push(2);
+    /// assert!(stack.contains(&1));
+    /// assert!(!stack.contains(&3));
+    /// ```
+    pub fn contains(&self, value: &T) -> bool
+    where T: PartialEq
+    {
+        loop {
+            if let Some(found) = self.try_walk(|data| data == value) {
+                return found
+            }
+        }
+    }
+
+    /// Replace the data at the top of the stack with the result of applying the given function to it,
+    /// without popping it. Returns false if the stack is empty. The new data is put on top in a new node,
+    /// which only replaces the old one if the top has not changed in the meantime, otherwise the function
+    /// is applied again to the new top, so it may be called more than once. A thread popping the old top waits
+    /// while the function is reading it, so the function should be quick.
+    /// # Examples
+    /// ```
+    /// let stack: Stack<u8> = Stack::new(true);
+    /// assert!(!stack.modify_top(|top| top + 1));
+    /// stack.push(1);
+    /// assert!(stack.modify_top(|top| top + 1));
+    /// assert_eq!(stack.pop(), Some(2));
+    /// ```
+    pub fn modify_top<F: Fn(&T) -> T>(&self, f: F) -> bool {
+        let mut backoff = Backoff::new();
+        loop {
+            let guard = self.manager.guard(0);
+            let old_head = guard.protect(&self.head);
+            if old_head.is_null() {
+                return false
+            }
+            unsafe {
+                // A pop may have taken the data after we protected the node, in which case the head has moved
+                let new_val = match Node::read_data(old_head, &f) {
+                    Some(new_val) => new_val,
+                    None => {
+                        backoff.snooze();
+                        continue;
+                    }
+                };
+                let new_head = Box::into_raw(self.alloc_node(new_val));
+                (*new_head).next.store((*old_head).next.load(Acquire), Relaxed);
+                match self.head.compare_exchange(old_head, new_head, Release, Relaxed) {
+                    Ok(_) => {
+                        guard.retire(old_head);
+                        return true
+                    },
+                    Err(_) => {
+                        drop(Box::from_raw(new_head));
+                    }
+                }
+            }
+            backoff.snooze();
+        }
+    }
+
+    /// Replace the data at the top of the stack with the given data, returning the data it replaced, as if it
+    /// had been popped and the new data pushed in a single step. If the stack is empty the data is pushed and
+    /// None is returned.
+    /// # Examples
+    /// ```
+    /// let stack: Stack<u8> = Stack::new(true);
+    /// assert_eq!(stack.replace_top(1), None);
+    /// assert_eq!(stack.replace_top(2), Some(1));
+    /// assert_eq!(stack.pop(), Some(2));
+    /// assert_eq!(stack.pop(), None);
+    /// ```
+    pub fn replace_top(&self, val: T) -> Option<T> {
+        let new_head = Box::into_raw(self.alloc_node(val));
+        let mut backoff = Backoff::new();
+        loop {
+            let guard = self.manager.guard(0);
+            let old_head = guard.protect(&self.head);
+            unsafe {
+                if old_head.is_null() {
+                    (*new_head).next.store(ptr::null_mut(), Relaxed);
+                    if self.head.compare_exchange(old_head, new_head, Release, Relaxed).is_ok() {
+                        self.len.fetch_add(1, Relaxed);
+                        return None
+                    }
+                } else {
+                    (*new_head).next.store((*old_head).next.load(Acquire), Relaxed);
+                    if self.head.compare_exchange(old_head, new_head, Release, Relaxed).is_ok() {
+                        let data = Node::take_data(old_head);
+                        guard.retire(old_head);
+                        return data
+                    }
+                }
+            }
+            backoff.snooze();
+        }
+    }
+
+    /// Detach every item on the stack at once, leaving it empty, and return an iterator over the
+    /// detached items from the top down. Items pushed after the stack is detached stay on the stack.
+    /// Any items which have not been yielded when the iterator is dropped are dropped with it.
+    /// # Examples
+    /// ```
+    /// let stack: Stack<u8> = Stack::new(true);
+    /// stack.push(1);
+    /// stack.push(2);
+    /// assert_eq!(stack.drain().collect::<Vec<u8>>(), vec![2, 1]);
+    /// assert_eq!(stack.pop(), None);
+    /// ```
+    pub fn drain(&self) -> Drain<'_, T, M> {
+        Drain {
+            current: self.head.swap(ptr::null_mut(), Acquire),
+            stack: self
+        }
+    }
+
+    /// Clone every item on the stack from the top down, without removing any, and return an iterator over the
+    /// clones. The clones are a snapshot of the stack at some point during the call: if other threads push or
+    /// pop while the stack is being walked, the walk starts again from the new top, so under constant changes
+    /// it may take several attempts. Items pushed or popped after the call are not reflected in the iterator.
+    /// # Examples
+    /// ```
+    /// let stack: Stack<u8> = Stack::new(true);
+    /// stack.push(1);
+    /// stack.push(2);
+    /// assert_eq!(stack.iter().collect::<Vec<u8>>(), vec![2, 1]);
+    /// assert_eq!(stack.len(), 2);
+    /// ```
+    pub fn iter(&self) -> impl Iterator<Item = T>
+    where T: Clone
+    {
+        self.snapshot().into_iter()
+    }
+
+    /// Consume the stack, returning its items from the top down. No other thread can be using the stack,
+    /// so the nodes are freed directly rather than through the ReclamationStrategy.
+    /// # Examples
+    /// ```
+    /// let stack: Stack<u8> = Stack::new(true);
+    /// stack.push(1);
+    /// stack.push(2);
+    /// assert_eq!(stack.into_vec(), vec![2, 1]);
+    /// ```
+    pub fn into_vec(self) -> Vec<T> {
+        self.into_iter().collect()
+    }
+
+    /// The number of items on the stack. The count is updated separately from the stack itself, so while
+    /// other threads are pushing or popping it is only approximate, but it is exact once they stop. Items
+    /// exchanged through the elimination layer never reach the stack, so they are never counted.
+    /// # Examples
+    /// ```
+    /// let stack: Stack<u8> = Stack::new(true);
+    /// stack.push(8);
+    /// assert_eq!(stack.len(), 1);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.len.load(Relaxed)
+    }
+
+    /// Check whether the stack has no items, with the same caveats as `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check whether popped nodes or elimination records are being retired faster than they are freed.
+    pub fn has_reclaim_pressure(&self) -> bool {
+        #[cfg(feature = "std")]
+        {
+            if let Some(ref elimination) = self.elimination {
+                if elimination.manager.has_reclaim_pressure() {
+                    return true
+                }
+            }
+        }
+        self.manager.has_reclaim_pressure()
+    }
+
+    // Visit the items from the top down until `visit` returns true, and return whether it did. Hazard
+    // pointer 0 holds the top node the walk started from, and hazard pointer 1 the node it has reached.
+    // While the head is still that top node, nothing can have been popped, so every node below it is still
+    // linked and was protected before it could be freed. Returns None as soon as the head moves, in which
+    // case the caller should start again from the new top.
+    fn try_walk<F>(&self, mut visit: F) -> Option<bool>
+    where F: FnMut(&T) -> bool
+    {
+        let top_guard = self.manager.guard(0);
+        let _node_guard = self.manager.guard(1);
+        let top = top_guard.protect(&self.head);
+        let mut current = top;
+        while !current.is_null() {
+            unsafe {
+                if Node::read_data(current, &mut visit) == Some(true) {
+                    return Some(true)
+                }
+                let next = (*current).next.load(Acquire);
+                self.manager.protect(next, 1);
+                if !ptr::eq(self.head.load(Acquire), top) {
+                    return None
+                }
+                current = next;
+            }
+        }
+        Some(false)
+    }
 }
 
+#[cfg(feature = "std")]
 fn get_id() -> usize {
     unsafe { mem::transmute::<ThreadId, u64>(thread::current().id()) as usize } 
 }
 
+#[cfg(feature = "std")]
 impl<T: Send> Default for Stack<T> {
     fn default() -> Self {
-        Self {
-            head: AtomicPtr::default(),
-            elimination: EliminationLayer::new(5),
-            manager: HPBRManager::new(200, 1),
-            elimination_on: false
-        }
+        Stack::new(false)
     }
 }
 
-impl<T: Send> Drop for Stack<T> {
+impl<T: Send, M: ReclamationStrategy<Node<T>>> Drop for Stack<T, M> {
     // We can assume that when drop is called, the program holds no more references to the stack
     // This means we can walk the stack, freeing all the data within
     fn drop(&mut self) {
@@ -189,55 +517,222 @@ impl<T: Send> Drop for Stack<T> {
         while !ptr::eq(current, ptr::null()) {
             unsafe {
                 let next = (*current).next.load(Relaxed);
-                Box::from_raw(current);
+                drop(Box::from_raw(current));
                 current = next;
             }
         }
     }
 }
 
+impl<T: Send + Clone, M: ReclamationStrategy<Node<T>>> Stack<T, M> {
+    // Clone every item from the top down, starting again whenever the head moves
+    fn snapshot(&self) -> Vec<T> {
+        loop {
+            let mut items = Vec::new();
+            let walked = self.try_walk(|data| {
+                items.push(data.clone());
+                false
+            });
+            if walked.is_some() {
+                return items
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: Send + Clone + Serialize, M: ReclamationStrategy<Node<T>>> Serialize for Stack<T, M> {
+    /// Serialize the items as a sequence from the top down, so the item which would be popped first
+    /// comes first. The sequence is a snapshot of the stack at some point during the call: if other
+    /// threads push or pop while the stack is being walked, the walk starts again from the new top.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.snapshot())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: Send + Deserialize<'de>> Deserialize<'de> for Stack<T> {
+    /// Build a stack without elimination from a sequence listed from the top down, as `serialize`
+    /// writes it, so the first item ends up on top.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let items: Vec<T> = Vec::deserialize(deserializer)?;
+        Ok(items.into_iter().rev().collect())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Send> FromIterator<T> for Stack<T> {
+    /// Build a stack without elimination by pushing each item in order, so the last item ends up on top.
+    /// # Examples
+    /// ```
+    /// let stack: Stack<u8> = vec![1, 2, 3].into_iter().collect();
+    /// assert_eq!(stack.pop(), Some(3));
+    /// ```
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut stack = Stack::default();
+        stack.extend(iter);
+        stack
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Send> From<Vec<T>> for Stack<T> {
+    /// Build a stack without elimination by pushing each item in order, so the last item ends up on top.
+    /// # Examples
+    /// ```
+    /// let stack = Stack::from(vec![1, 2, 3]);
+    /// assert_eq!(stack.pop(), Some(3));
+    /// ```
+    fn from(items: Vec<T>) -> Self {
+        items.into_iter().collect()
+    }
+}
+
+impl<T: Send, M: ReclamationStrategy<Node<T>>> Extend<T> for Stack<T, M> {
+    /// Push each item in order, so the last item ends up on top.
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for item in iter {
+            self.push(item);
+        }
+    }
+}
+
+impl<T: Send, M: ReclamationStrategy<Node<T>>> IntoIterator for Stack<T, M> {
+    type Item = T;
+    type IntoIter = IntoIter<T, M>;
+
+    /// Consume the stack, yielding its items from the top down.
+    /// # Examples
+    /// ```
+    /// let stack: Stack<u8> = Stack::new(true);
+    /// stack.push(1);
+    /// stack.push(2);
+    /// assert_eq!(stack.into_iter().collect::<Vec<u8>>(), vec![2, 1]);
+    /// ```
+    fn into_iter(self) -> IntoIter<T, M> {
+        IntoIter {
+            stack: self
+        }
+    }
+}
+
+/// An iterator which pops every item off a Stack it owns. No other thread can be using the stack,
+/// so the nodes are unlinked and freed directly rather than through the ReclamationStrategy.
+pub struct IntoIter<T: Send,
+                    #[cfg(feature = "std")] M: ReclamationStrategy<Node<T>> = HPBRManager<Node<T>>,
+                    #[cfg(not(feature = "std"))] M: ReclamationStrategy<Node<T>>> {
+    stack: Stack<T, M>
+}
+
+impl<T: Send, M: ReclamationStrategy<Node<T>>> Iterator for IntoIter<T, M> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let head = self.stack.head.load(Relaxed);
+        if head.is_null() {
+            return None
+        }
+        let node = unsafe { *Box::from_raw(head) };
+        self.stack.head.store(node.next.load(Relaxed), Relaxed);
+        self.stack.len.fetch_sub(1, Relaxed);
+        node.data
+    }
+}
+
+/// An iterator over the items detached from a Stack by `drain`. Other threads may still be looking at
+/// the detached nodes, so each one is retired through the stack's ReclamationStrategy once its item is taken.
+pub struct Drain<'a, T: Send + 'a,
+                 #[cfg(feature = "std")] M: ReclamationStrategy<Node<T>> + 'a = HPBRManager<Node<T>>,
+                 #[cfg(not(feature = "std"))] M: ReclamationStrategy<Node<T>> + 'a> {
+    current: *mut Node<T>,
+    stack: &'a Stack<T, M>
+}
+
+impl<'a, T: Send, M: ReclamationStrategy<Node<T>>> Iterator for Drain<'a, T, M> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.current.is_null() {
+            return None
+        }
+        let node_ptr = self.current;
+        unsafe {
+            self.current = (*node_ptr).next.load(Acquire);
+            // The node cannot be overwritten as a whole: a thread which protected it before the stack was
+            // detached may still be loading its next pointer, or reading its data
+            let data = Node::take_data(node_ptr);
+            self.stack.manager.retire(node_ptr, 0);
+            self.stack.len.fetch_sub(1, Relaxed);
+            data
+        }
+    }
+}
+
+impl<'a, T: Send, M: ReclamationStrategy<Node<T>>> Drop for Drain<'a, T, M> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+    }
+}
+
 impl<T: Send> Node<T> {
     fn new_as_pointer(val: T) -> *mut Self {
-        Box::into_raw(Box::new(Node {
-            data: Some(val),
-            next: AtomicPtr::default()
-        }))
+        Box::into_raw(Box::new(Node::new(val)))
     }
 
     fn new(val: T) -> Self {
         Node {
             data: Some(val),
-            next: AtomicPtr::default()
+            next: AtomicPtr::default(),
+            readers: ReadMark::new()
         }
     }
+
+    // Apply the function to the node's data, unless it has already been taken
+    unsafe fn read_data<R, F: FnOnce(&T) -> R>(node: *mut Self, f: F) -> Option<R> {
+        let _reading = (*node).readers.read()?;
+        (*node).data.as_ref().map(f)
+    }
+
+    // Move the data out of a node which has just been unlinked, once nobody is reading it
+    unsafe fn take_data(node: *mut Self) -> Option<T> {
+        (*node).readers.take();
+        (*node).data.take()
+    }
 }
 
 impl<T: Send> Default for Node<T> {
     fn default() -> Self {
         Node {
             data: None,
-            next: AtomicPtr::default()
+            next: AtomicPtr::default(),
+            readers: ReadMark::new()
         }
     }
 } 
 
+#[cfg(feature = "std")]
 struct EliminationLayer<T: Send> {
     location: HashMap<usize, AtomicPtr<ThreadInfo<T>>>,
-    collision: Vec<AtomicUsize>,
+    // Every eliminating thread writes to a collision slot, so each one has its own cache line
+    collision: Vec<CachePadded<AtomicUsize>>,
     rng: UnsafeCell<SmallRng>,
     manager: HPBRManager<ThreadInfo<T>>
 }
 
+#[cfg(feature = "std")]
 unsafe impl<T: Send> Sync for EliminationLayer<T> {}
 
+#[cfg(feature = "std")]
 struct ThreadInfo<T: Send> {
     id: usize,
     op: OpType,
     node: Option<*mut Node<T>>
 }
 
+#[cfg(feature = "std")]
 unsafe impl<T: Send> Send for ThreadInfo<T> {} 
 
+#[cfg(feature = "std")]
 #[derive(Copy)]
 #[derive(Clone)]
 enum OpType {
@@ -245,18 +740,19 @@ enum OpType {
     Pop
 }
 
+#[cfg(feature = "std")]
 // Segfault is on the nodes, not the thread info. How to manage this?
 impl<T: Send> EliminationLayer<T> {
-    fn new(collision_size: usize) -> Self {
+    fn new(collision_size: usize, retire_capacity: usize) -> Self {
         let mut collision = Vec::with_capacity(collision_size);
         for _ in 0..collision_size {
-            collision.push(AtomicUsize::new(usize::max_value()))
+            collision.push(CachePadded::new(AtomicUsize::new(usize::MAX)))
         }
         Self {
             location: HashMap::new(),
             collision,
             rng: UnsafeCell::new(SmallRng::new()),
-            manager: HPBRManager::new(100, 2)
+            manager: HPBRManager::with_retire_capacity(100, 2, retire_capacity)
         }
     }
 
@@ -265,7 +761,7 @@ impl<T: Send> EliminationLayer<T> {
 
         match self.location.get(&me_id) {
             None => {
-                match self.location.insert(me_id, AtomicPtr::new(me_info_ptr)) {
+                match self.location.insert_if_absent(me_id, AtomicPtr::new(me_info_ptr)) {
                     Ok(()) => {},
                     Err(_) => {
                         return Err(())
@@ -346,9 +842,9 @@ impl<T: Send> EliminationLayer<T> {
                 match them_atomic.compare_exchange(them_ptr, ptr::null_mut(), Release, Relaxed) {
                     Ok(_) => {
                         let mut owned_info = unsafe { ptr::read(them_ptr) };
-                        let mut node_ptr = mem::replace(&mut owned_info.node, None).unwrap();
+                        let node_ptr = owned_info.node.take().unwrap();
                         let node = unsafe { ptr::replace(node_ptr, Node::default()) };
-                        unsafe { Box::from_raw(node_ptr) };
+                        unsafe { drop(Box::from_raw(node_ptr)) };
                         self.manager.retire(them_ptr, 0);
                         me_atomic.store(ptr::null_mut(), Release);
                         return Ok(node.data)
@@ -367,9 +863,9 @@ impl<T: Send> EliminationLayer<T> {
             OpType::Push => { return Ok(None) },
             OpType::Pop => {
                 let mut owned_info = unsafe { ptr::read(new_info_ptr) };
-                let mut node_ptr = mem::replace(&mut owned_info.node, None).unwrap();
+                let node_ptr = owned_info.node.take().unwrap();
                 let node = unsafe { ptr::replace(node_ptr, Node::default()) };
-                unsafe { Box::from_raw(node_ptr) };
+                unsafe { drop(Box::from_raw(node_ptr)) };
                 self.location.get(&get_id()).unwrap().data().store(ptr::null_mut(), Release);
                 self.manager.retire(new_info_ptr, 0);
                 return Ok(node.data)
@@ -378,6 +874,7 @@ impl<T: Send> EliminationLayer<T> {
     } 
 }
 
+#[cfg(feature = "std")]
 fn is_complimentary<T: Send>(them_id: usize, them_ptr: *mut ThreadInfo<T>, 
                              them_atomic: &AtomicPtr<ThreadInfo<T>>, me_op: OpType) -> bool 
 {
@@ -398,17 +895,19 @@ fn is_complimentary<T: Send>(them_id: usize, them_ptr: *mut ThreadInfo<T>,
     false
 }
 
+#[cfg(feature = "std")]
 impl<T: Send> Drop for EliminationLayer<T> {
     fn drop(&mut self) {
         for guard in self.location.iter() {
             let ptr = guard.data().load(Relaxed);
             if !ptr.is_null() {
-                unsafe { Box::from_raw(ptr) };
+                unsafe { drop(Box::from_raw(ptr)) };
             }
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl<T: Send> ThreadInfo<T> {
     fn new(node: Option<*mut Node<T>>, op: OpType) -> Self {
         Self {
@@ -419,6 +918,7 @@ impl<T: Send> ThreadInfo<T> {
     }
 }
 
+#[cfg(feature = "std")]
 mod tests {
     #![allow(unused_imports)]
     extern crate im;
@@ -427,6 +927,8 @@ mod tests {
     use rand::{thread_rng, Rng};
 
     use super::Stack;
+    use memory::HPBRManager;
+    use std::iter::FromIterator;
     use super::get_id;
     use super::super::super::testing::linearizability_tester::{LinearizabilityTester, LinearizabilityResult, ThreadLog};
 
@@ -466,6 +968,359 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_peek() {
+        let stack: Stack<u8> = Stack::new(true);
+        assert_eq!(stack.peek(), None);
+
+        stack.push(1);
+        stack.push(2);
+        stack.push(3);
+        for _ in 0..3 {
+            assert_eq!(stack.peek(), Some(3));
+        }
+
+        assert_eq!(stack.pop(), Some(3));
+        assert_eq!(stack.peek(), Some(2));
+        assert_eq!(stack.pop(), Some(2));
+        assert_eq!(stack.pop(), Some(1));
+        assert_eq!(stack.peek(), None);
+    }
+
+    #[test]
+    fn test_peek_while_popping() {
+        let stack: Arc<Stack<String>> = Arc::new(Stack::new(false));
+        stack.push("base".to_owned());
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+        for i in 0..4 {
+            let stack_clone = stack.clone();
+            wait_vec.push(thread::spawn(move || {
+                for j in 0..2000 {
+                    stack_clone.push(format!("{}-{}", i, j));
+                    assert!(stack_clone.pop().is_some());
+                }
+            }));
+        }
+        for _ in 0..4 {
+            let stack_clone = stack.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..2000 {
+                    let top = stack_clone.peek().unwrap();
+                    assert!(top == "base" || top.contains('-'));
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
+        }
+        assert_eq!(stack.pop(), Some("base".to_owned()));
+    }
+
+    #[test]
+    fn test_iter() {
+        let stack: Stack<u8> = Stack::new(true);
+        assert_eq!(stack.iter().next(), None);
+        for i in 1..4 {
+            stack.push(i);
+        }
+        assert_eq!(stack.iter().collect::<Vec<u8>>(), vec![3, 2, 1]);
+        assert_eq!(stack.len(), 3);
+        assert_eq!(stack.pop(), Some(3));
+        assert_eq!(stack.iter().collect::<Vec<u8>>(), vec![2, 1]);
+    }
+
+    #[test]
+    fn test_iter_while_pushing_and_popping() {
+        let stack: Arc<Stack<String>> = Arc::new(Stack::new(false));
+        stack.push("base".to_owned());
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+        for i in 0..4 {
+            let stack_clone = stack.clone();
+            wait_vec.push(thread::spawn(move || {
+                for j in 0..2000 {
+                    stack_clone.push(format!("{}-{}", i, j));
+                    assert!(stack_clone.pop().is_some());
+                }
+            }));
+        }
+        for _ in 0..4 {
+            let stack_clone = stack.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..500 {
+                    let items: Vec<String> = stack_clone.iter().collect();
+                    assert_eq!(items.last().map(|item| item.as_str()), Some("base"));
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
+        }
+        assert_eq!(stack.iter().collect::<Vec<String>>(), vec!["base".to_owned()]);
+    }
+
+    #[test]
+    fn test_contains() {
+        let stack: Stack<u8> = Stack::new(true);
+        assert!(!stack.contains(&1));
+        for i in 1..6 {
+            stack.push(i);
+        }
+        for i in 1..6 {
+            assert!(stack.contains(&i));
+        }
+        assert!(!stack.contains(&0));
+        assert!(!stack.contains(&6));
+
+        assert_eq!(stack.pop(), Some(5));
+        assert!(!stack.contains(&5));
+        assert!(stack.contains(&4));
+    }
+
+    #[test]
+    fn test_contains_while_pushing_and_popping() {
+        let stack: Arc<Stack<String>> = Arc::new(Stack::new(false));
+        stack.push("base".to_owned());
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+        for i in 0..4 {
+            let stack_clone = stack.clone();
+            wait_vec.push(thread::spawn(move || {
+                for j in 0..2000 {
+                    stack_clone.push(format!("{}-{}", i, j));
+                    assert!(stack_clone.pop().is_some());
+                }
+            }));
+        }
+        for _ in 0..4 {
+            let stack_clone = stack.clone();
+            wait_vec.push(thread::spawn(move || {
+                // Every thread pushes before it pops, so the bottom item is never popped
+                for _ in 0..500 {
+                    assert!(stack_clone.contains(&"base".to_owned()));
+                    assert!(!stack_clone.contains(&"missing".to_owned()));
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
+        }
+        assert_eq!(stack.pop(), Some("base".to_owned()));
+    }
+
+    #[test]
+    fn test_vec_round_trip() {
+        let stack = Stack::from(vec![1, 2, 3, 4]);
+        assert_eq!(stack.len(), 4);
+        assert_eq!(stack.peek(), Some(4));
+        assert_eq!(stack.into_vec(), vec![4, 3, 2, 1]);
+
+        let stack: Stack<u8> = Stack::from(Vec::new());
+        assert_eq!(stack.into_vec(), Vec::new());
+    }
+
+    #[test]
+    fn test_modify_top() {
+        let stack: Arc<Stack<u32>> = Arc::new(Stack::new(false));
+        stack.push(100);
+        stack.push(0);
+        let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+        for _ in 0..8 {
+            let stack_clone = stack.clone();
+            wait_vec.push(thread::spawn(move || {
+                for _ in 0..1000 {
+                    assert!(stack_clone.modify_top(|top| top + 1));
+                }
+            }));
+        }
+        for handle in wait_vec {
+            handle.join().unwrap();
+        }
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack.pop(), Some(8000));
+        assert_eq!(stack.pop(), Some(100));
+        assert!(!stack.modify_top(|top| top + 1));
+    }
+
+    #[test]
+    fn test_default() {
+        let stack: Stack<u8> = Default::default();
+        assert!(stack.is_empty());
+        stack.push(1);
+        stack.push(2);
+        assert_eq!(stack.pop(), Some(2));
+        assert_eq!(stack.pop(), Some(1));
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_replace_top() {
+        let stack: Arc<Stack<u32>> = Arc::new(Stack::new(false));
+        assert_eq!(stack.replace_top(1), None);
+        assert_eq!(stack.replace_top(2), Some(1));
+        assert_eq!(stack.len(), 1);
+        stack.push(0);
+        stack.push(3);
+
+        let mut wait_vec: Vec<thread::JoinHandle<Vec<u32>>> = Vec::new();
+        for i in 0..8 {
+            let stack_clone = stack.clone();
+            wait_vec.push(thread::spawn(move || {
+                (0..1000).map(|j| stack_clone.replace_top(4 + i * 1000 + j).unwrap()).collect()
+            }));
+        }
+        let mut replaced: Vec<u32> = Vec::new();
+        for handle in wait_vec {
+            replaced.extend(handle.join().unwrap());
+        }
+
+        // Every value was replaced exactly once except the one left on top, and the rest of the stack is untouched
+        replaced.push(stack.pop().unwrap());
+        replaced.sort();
+        assert_eq!(replaced, (3..8004).collect::<Vec<u32>>());
+        assert_eq!(stack.pop(), Some(0));
+        assert_eq!(stack.pop(), Some(2));
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_len() {
+        let stack: Stack<u32> = Stack::new(true);
+        assert!(stack.is_empty());
+        for i in 0..1000 {
+            stack.push(i);
+        }
+        assert_eq!(stack.len(), 1000);
+        while stack.pop().is_some() {}
+        assert_eq!(stack.len(), 0);
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let stack: Stack<u8> = Stack::new(true);
+        stack.push(1);
+        stack.push(2);
+        stack.push(3);
+        assert_eq!(stack.into_iter().collect::<Vec<u8>>(), vec![3, 2, 1]);
+
+        // Items left in a partly consumed iterator are freed with it
+        let stack: Stack<String> = Stack::new(false);
+        for i in 0..10 {
+            stack.push(format!("{}", i));
+        }
+        let mut iter = stack.into_iter();
+        assert_eq!(iter.next(), Some("9".to_owned()));
+        assert_eq!(iter.next(), Some("8".to_owned()));
+    }
+
+    #[test]
+    fn test_drain_while_pushing() {
+        let stack: Arc<Stack<u32>> = Arc::new(Stack::new(false));
+        let mut pushers = Vec::new();
+        for id in 0..4 {
+            let stack_clone = stack.clone();
+            pushers.push(thread::spawn(move || {
+                for i in 0..2000 {
+                    stack_clone.push(id * 2000 + i);
+                }
+            }));
+        }
+
+        let mut seen = Vec::new();
+        for _ in 0..100 {
+            seen.extend(stack.drain());
+            thread::yield_now();
+        }
+        for pusher in pushers {
+            pusher.join().unwrap();
+        }
+        // Whatever was pushed after the last drain is still on the stack
+        while let Some(item) = stack.pop() {
+            seen.push(item);
+        }
+        seen.sort();
+        assert_eq!(seen, (0..8000).collect::<Vec<u32>>());
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn test_drain_while_peeking() {
+        let stack: Arc<Stack<String>> = Arc::new(Stack::new(false));
+        let mut peekers = Vec::new();
+        for _ in 0..4 {
+            let stack_clone = stack.clone();
+            peekers.push(thread::spawn(move || {
+                for _ in 0..2000 {
+                    if let Some(top) = stack_clone.peek() {
+                        assert!(top.starts_with("item"));
+                    }
+                }
+            }));
+        }
+
+        for round in 0..200 {
+            for i in 0..10 {
+                stack.push(format!("item {} {}", round, i));
+            }
+            assert_eq!(stack.drain().count(), 10);
+        }
+        for peeker in peekers {
+            peeker.join().unwrap();
+        }
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn test_drain_dropped_early() {
+        let stack: Stack<String> = Stack::new(false);
+        for i in 0..10 {
+            stack.push(format!("{}", i));
+        }
+        {
+            let mut drain = stack.drain();
+            assert_eq!(drain.next(), Some("9".to_owned()));
+        }
+        // The rest of the detached items were dropped with the iterator
+        assert!(stack.is_empty());
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_high_contention() {
+        // Enough threads to keep losing CAS races, so that push and pop back off
+        let stack: Arc<Stack<u32>> = Arc::new(Stack::new(false));
+        let mut wait_vec = Vec::new();
+        for id in 0..32 {
+            let stack_clone = stack.clone();
+            wait_vec.push(thread::spawn(move || {
+                let mut popped = 0;
+                for i in 0..1000 {
+                    stack_clone.push(id * 1000 + i);
+                    if stack_clone.pop().is_some() {
+                        popped += 1;
+                    }
+                }
+                popped
+            }));
+        }
+        let popped: usize = wait_vec.into_iter().map(|handle| handle.join().unwrap()).sum();
+        assert_eq!(popped, 32 * 1000);
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn test_from_iter_and_extend() {
+        let mut stack: Stack<u8> = vec![1, 2, 3].into_iter().collect();
+        assert_eq!(stack.len(), 3);
+        stack.extend(vec![4, 5]);
+        assert_eq!(stack.into_iter().collect::<Vec<u8>>(), vec![5, 4, 3, 2, 1]);
+
+        let stack: Stack<u8> = Stack::from_iter(vec![1, 2, 3]);
+        assert_eq!(stack.pop(), Some(3));
+        assert_eq!(stack.pop(), Some(2));
+        assert_eq!(stack.pop(), Some(1));
+        assert_eq!(stack.pop(), None);
+    }
+
     fn test_pop_single_threaded() {
         let stack : Stack<Foo> = Stack::new(true);
 
@@ -532,6 +1387,68 @@ mod tests {
         assert_eq!(None, stack.pop());
     }
 
+    #[test]
+    fn test_elimination_hands_over_every_value() {
+        let stack: Arc<Stack<u32>> = Arc::new(Stack::new_with_collision_size(true, 4));
+        let mut pushers = Vec::new();
+        let mut poppers = Vec::new();
+        for id in 0..8 {
+            let stack_copy = stack.clone();
+            pushers.push(thread::spawn(move || {
+                for i in 0..2000 {
+                    stack_copy.push(id * 2000 + i);
+                }
+            }));
+            let stack_copy = stack.clone();
+            poppers.push(thread::spawn(move || {
+                let mut popped = Vec::new();
+                while popped.len() < 2000 {
+                    if let Some(val) = stack_copy.pop() {
+                        popped.push(val);
+                    }
+                }
+                popped
+            }));
+        }
+        for pusher in pushers {
+            pusher.join().unwrap();
+        }
+        let mut popped: Vec<u32> = poppers.into_iter().flat_map(|popper| popper.join().unwrap()).collect();
+        // Values exchanged through the elimination layer must arrive exactly once, like those from the stack
+        popped.sort();
+        assert_eq!(popped, (0..16000).collect::<Vec<u32>>());
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_recycled_nodes() {
+        let stack: Arc<Stack<String>> = Arc::new(Stack::with_manager(false, HPBRManager::with_recycling(50, 2)));
+        let mut wait_vec: Vec<thread::JoinHandle<Vec<String>>> = Vec::new();
+        for id in 0..4 {
+            let stack_copy = stack.clone();
+            wait_vec.push(thread::spawn(move || {
+                let mut popped = Vec::new();
+                for i in 0..5000 {
+                    stack_copy.push(format!("{}-{}", id, i));
+                    if let Some(val) = stack_copy.pop() {
+                        popped.push(val);
+                    }
+                }
+                popped
+            }));
+        }
+        let mut popped: Vec<String> = wait_vec.into_iter().flat_map(|handle| handle.join().unwrap()).collect();
+        while let Some(val) = stack.pop() {
+            popped.push(val);
+        }
+
+        // Reused nodes must not bring back old values
+        popped.sort();
+        let mut expected: Vec<String> = (0..4).flat_map(|id| (0..5000).map(move |i| format!("{}-{}", id, i))).collect();
+        expected.sort();
+        assert_eq!(popped, expected);
+    }
+
     #[test]
     fn test_linearizable() {
         let stack: Stack<usize> = Stack::new(true);
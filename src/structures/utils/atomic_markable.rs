@@ -1,5 +1,6 @@
 use std::sync::atomic::AtomicPtr;
 use std::sync::atomic::Ordering::{Acquire, Release, Relaxed};
+use std::boxed::Box;
 
 pub fn is_marked<T>(ptr: *mut T) -> bool {
     let ptr_usize = ptr as usize;
@@ -73,7 +74,7 @@ impl<T: Send> Drop for AtomicMarkablePtr<T> {
     fn drop(&mut self) {
         let ptr = self.ptr.load(Relaxed);
         if !is_marked(ptr) && !ptr.is_null() {
-            unsafe { Box::from_raw(ptr) };
+            unsafe { drop(Box::from_raw(ptr)) };
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,146 @@
+use std::hint;
+#[cfg(feature = "std")]
+use std::thread;
+
+const SPIN_LIMIT: u32 = 6;
+const YIELD_LIMIT: u32 = 10;
+
+/// Exponential backoff for retry loops which keep losing a CAS to other threads.
+///
+/// Each call to `spin` or `snooze` waits for twice as long as the last one, until the spin count
+/// reaches `2^SPIN_LIMIT`. After that `spin` keeps spinning for that long, while `snooze` yields the
+/// thread instead, since whoever it is waiting for may need the processor. Without std there is no
+/// thread to yield, so `snooze` keeps spinning too. A new Backoff costs nothing until it is first used,
+/// so the uncontended path is not slowed down.
+///
+/// Use `spin` when retrying after losing a CAS to another thread, which will have made progress, and
+/// `snooze` when waiting for another thread to do something. Once `is_completed` returns true, the
+/// wait has gone on long enough that it may be worth blocking instead.
+/// # Examples
+/// ```
+/// let stack: Stack<u8> = Stack::new(false);
+/// let mut backoff = Backoff::new();
+/// let popped = loop {
+///     if let Some(val) = stack.pop() {
+///         break Some(val)
+///     }
+///     if backoff.is_completed() {
+///         break None
+///     }
+///     backoff.snooze();
+/// };
+/// ```
+#[derive(Debug)]
+pub struct Backoff {
+    step: u32
+}
+
+impl Backoff {
+    /// Create a new Backoff, which starts with the shortest wait.
+    pub fn new() -> Self {
+        Backoff {
+            step: 0
+        }
+    }
+
+    /// Start again from the shortest wait, for example after the loop has made progress.
+    pub fn reset(&mut self) {
+        self.step = 0;
+    }
+
+    /// Spin for a little longer than last time before the next attempt, without yielding.
+    pub fn spin(&mut self) {
+        for _ in 0..(1 << self.step.min(SPIN_LIMIT)) {
+            hint::spin_loop();
+        }
+        if self.step <= SPIN_LIMIT {
+            self.step += 1;
+        }
+    }
+
+    /// Wait a little longer than last time before the next attempt, yielding the thread once spinning
+    /// has gone on for long enough.
+    pub fn snooze(&mut self) {
+        if self.step <= SPIN_LIMIT {
+            for _ in 0..(1 << self.step) {
+                hint::spin_loop();
+            }
+        } else {
+            #[cfg(feature = "std")]
+            thread::yield_now();
+            #[cfg(not(feature = "std"))]
+            for _ in 0..(1 << SPIN_LIMIT) {
+                hint::spin_loop();
+            }
+        }
+        if self.step <= YIELD_LIMIT {
+            self.step += 1;
+        }
+    }
+
+    /// Check whether `snooze` has backed off as far as it will go.
+    pub fn is_completed(&self) -> bool {
+        self.step > YIELD_LIMIT
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new()
+    }
+}
+
+#[cfg(feature = "std")]
+mod tests {
+    #![allow(unused_imports)]
+    use super::Backoff;
+    use structures::Stack;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_backoff_completes() {
+        let mut backoff = Backoff::new();
+        for _ in 0..20 {
+            backoff.spin();
+        }
+        // Spinning alone never gives up on the loop
+        assert!(!backoff.is_completed());
+        let mut snoozes = 0;
+        while !backoff.is_completed() {
+            backoff.snooze();
+            snoozes += 1;
+        }
+        assert!(snoozes > 0);
+        backoff.reset();
+        assert!(!backoff.is_completed());
+    }
+
+    #[test]
+    fn test_contended_retry_loop() {
+        // Every thread spins on try_pop-style retries of its own, backing off between attempts
+        let stack: Arc<Stack<usize>> = Arc::new(Stack::new(false));
+        let mut wait_vec: Vec<thread::JoinHandle<usize>> = Vec::new();
+        for id in 0..8 {
+            let stack_clone = stack.clone();
+            wait_vec.push(thread::spawn(move || {
+                let mut popped = 0;
+                for i in 0..1000 {
+                    stack_clone.push(id * 1000 + i);
+                    let mut backoff = Backoff::new();
+                    loop {
+                        if stack_clone.pop().is_some() {
+                            popped += 1;
+                            break
+                        }
+                        backoff.snooze();
+                    }
+                }
+                popped
+            }));
+        }
+        let popped: usize = wait_vec.into_iter().map(|handle| handle.join().unwrap()).sum();
+        assert_eq!(popped, 8000);
+        assert_eq!(stack.pop(), None);
+    }
+}
@@ -0,0 +1,71 @@
+use std::ops::{Deref, DerefMut};
+
+/// Aligns a value to the start of its own cache line, so that writes to it do not invalidate the
+/// cache lines of values next to it which are used by other threads.
+///
+/// The value is reached through `Deref` and `DerefMut`, so a padded atomic can be used just like the
+/// atomic itself.
+/// # Examples
+/// ```
+/// let counter = CachePadded::new(AtomicUsize::new(0));
+/// counter.fetch_add(1, Ordering::Relaxed);
+/// assert_eq!(counter.load(Ordering::Relaxed), 1);
+/// ```
+#[derive(Debug)]
+#[repr(align(64))]
+pub struct CachePadded<T> {
+    value: T
+}
+
+impl<T> CachePadded<T> {
+    /// Pad the given value out to a cache line.
+    pub fn new(value: T) -> Self {
+        CachePadded {
+            value
+        }
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+#[cfg(feature = "std")]
+mod tests {
+    #![allow(unused_imports)]
+    use super::CachePadded;
+    use std::mem;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_padded_to_cache_line() {
+        assert!(mem::size_of::<CachePadded<u8>>() >= 64);
+        assert_eq!(mem::align_of::<CachePadded<u8>>(), 64);
+        let pair = [CachePadded::new(AtomicUsize::new(0)), CachePadded::new(AtomicUsize::new(0))];
+        let first = &*pair[0] as *const AtomicUsize as usize;
+        let second = &*pair[1] as *const AtomicUsize as usize;
+        assert!(second - first >= 64);
+    }
+
+    #[test]
+    fn test_deref() {
+        let mut padded = CachePadded::new(vec![1, 2]);
+        assert_eq!(padded.len(), 2);
+        padded.push(3);
+        assert_eq!(*padded, vec![1, 2, 3]);
+
+        let counter = CachePadded::new(AtomicUsize::new(0));
+        counter.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(counter.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1 +1,4 @@
-pub mod atomic_markable;
\ No newline at end of file
+pub mod atomic_markable;
+pub mod backoff;
+pub mod cache_padded;
+pub mod read_mark;
\ No newline at end of file
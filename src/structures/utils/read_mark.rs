@@ -0,0 +1,68 @@
+use sync::atomic::AtomicUsize;
+use sync::atomic::Ordering::{Acquire, Release, Relaxed};
+use super::backoff::Backoff;
+
+// The lowest bit is set once the value has been taken, and the rest count the readers
+const TAKEN: usize = 1;
+const READER: usize = 2;
+
+/// Keeps track of the threads reading a value which another thread may move out of shared memory,
+/// such as the data of a node which can still be reached after it has been popped.
+///
+/// Readers register with `read` and stay registered until the returned guard is dropped. The thread
+/// moving the value out calls `take` first, which stops new readers from registering and waits for
+/// the registered ones to finish, so nobody is still reading the value when it is moved.
+#[derive(Debug)]
+pub struct ReadMark {
+    state: AtomicUsize
+}
+
+/// A reader registered with a ReadMark, which leaves when it goes out of scope, even while unwinding.
+pub struct ReadGuard<'a> {
+    mark: &'a ReadMark
+}
+
+impl ReadMark {
+    pub fn new() -> Self {
+        ReadMark {
+            state: AtomicUsize::new(0)
+        }
+    }
+
+    /// Register as a reader, or return None if the value has already been taken.
+    pub fn read(&self) -> Option<ReadGuard<'_>> {
+        let mut state = self.state.load(Relaxed);
+        loop {
+            if state & TAKEN != 0 {
+                return None
+            }
+            match self.state.compare_exchange_weak(state, state + READER, Acquire, Relaxed) {
+                Ok(_) => return Some(ReadGuard { mark: self }),
+                Err(actual) => state = actual
+            }
+        }
+    }
+
+    /// Stop any more readers from registering, and wait until the registered ones have left. The caller
+    /// must be the only thread taking the value.
+    pub fn take(&self) {
+        let mut backoff = Backoff::new();
+        let mut state = self.state.fetch_or(TAKEN, Acquire);
+        while state != 0 {
+            backoff.snooze();
+            state = self.state.load(Acquire) & !TAKEN;
+        }
+    }
+}
+
+impl Default for ReadMark {
+    fn default() -> Self {
+        ReadMark::new()
+    }
+}
+
+impl<'a> Drop for ReadGuard<'a> {
+    fn drop(&mut self) {
+        self.mark.state.fetch_sub(READER, Release);
+    }
+}
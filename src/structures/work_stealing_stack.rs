@@ -0,0 +1,368 @@
+use memory::HPBRManager;
+use std::sync::atomic::{AtomicPtr, AtomicIsize, fence};
+use std::sync::atomic::Ordering::{Acquire, Release, Relaxed, SeqCst};
+use std::sync::Arc;
+use std::marker::PhantomData;
+use std::ptr;
+
+const INITIAL_CAPACITY: usize = 32;
+
+/// A lock-free work-stealing deque, to be used as the task stack of one worker in a scheduler.
+///
+/// This is an implementation of the deque described in [Dynamic Circular Work-Stealing Deque]
+/// (https://dl.acm.org/citation.cfm?id=1073974) by Chase and Lev. The owner of the deque pushes and pops
+/// tasks at the bottom, so it sees its own tasks in LIFO order, while other threads steal from the top
+/// through a `Stealer`, taking the oldest tasks first.
+///
+/// Only the owner can push and pop, so the deque itself can be sent to another thread but not shared.
+/// Stealers can be cloned and shared freely. The tasks are kept in a circular buffer which the owner
+/// grows when it is full. Thieves protect the buffer they are reading from with the HPBRManager, so that
+/// an old buffer is only freed once no thief is still looking at it.
+pub struct WorkStealingStack<V: Send> {
+    inner: Arc<Inner<V>>,
+    // Stops the owner's half from being shared between threads
+    _marker: PhantomData<*mut ()>
+}
+
+unsafe impl<V: Send> Send for WorkStealingStack<V> {}
+
+/// The thieves' half of a WorkStealingStack, which takes tasks from the top of the deque.
+pub struct Stealer<V: Send> {
+    inner: Arc<Inner<V>>
+}
+
+unsafe impl<V: Send> Send for Stealer<V> {}
+unsafe impl<V: Send> Sync for Stealer<V> {}
+
+struct Inner<V: Send> {
+    top: AtomicIsize,
+    bottom: AtomicIsize,
+    buffer: AtomicPtr<Buffer<V>>,
+    manager: HPBRManager<Buffer<V>>
+}
+
+/// A circular array of boxed tasks. The tasks are not freed with the buffer, since a grown buffer
+/// shares them with the one it replaced.
+struct Buffer<V: Send> {
+    slots: Vec<AtomicPtr<V>>,
+    mask: usize
+}
+
+impl<V: Send> WorkStealingStack<V> {
+    /// Create a new, empty WorkStealingStack.
+    /// # Examples
+    /// ```
+    /// let stack: WorkStealingStack<u8> = WorkStealingStack::new();
+    /// ```
+    pub fn new() -> Self {
+        WorkStealingStack {
+            inner: Arc::new(Inner {
+                top: AtomicIsize::new(0),
+                bottom: AtomicIsize::new(0),
+                buffer: AtomicPtr::new(Box::into_raw(Box::new(Buffer::new(INITIAL_CAPACITY)))),
+                manager: HPBRManager::new(10, 1)
+            }),
+            _marker: PhantomData
+        }
+    }
+
+    /// Create a Stealer for this deque, which other threads can use to steal tasks.
+    /// # Examples
+    /// ```
+    /// let stack: WorkStealingStack<u8> = WorkStealingStack::new();
+    /// let stealer = stack.stealer();
+    /// stack.push(8);
+    /// assert_eq!(stealer.steal(), Some(8));
+    /// ```
+    pub fn stealer(&self) -> Stealer<V> {
+        Stealer {
+            inner: self.inner.clone()
+        }
+    }
+
+    /// Push a task onto the bottom of the deque, growing it if it is full.
+    /// # Examples
+    /// ```
+    /// let stack: WorkStealingStack<u8> = WorkStealingStack::new();
+    /// stack.push(8);
+    /// ```
+    pub fn push(&self, val: V) {
+        let inner = &*self.inner;
+        let bottom = inner.bottom.load(Relaxed);
+        let top = inner.top.load(Acquire);
+        let mut buffer = inner.buffer.load(Relaxed);
+        if bottom - top >= unsafe { (*buffer).capacity() } as isize {
+            buffer = self.grow(buffer, bottom, top);
+        }
+        unsafe { (*buffer).put(bottom, Box::into_raw(Box::new(val))) };
+        fence(Release);
+        inner.bottom.store(bottom + 1, Relaxed);
+    }
+
+    /// Pop the most recently pushed task from the bottom of the deque, or return None if it is empty.
+    /// # Examples
+    /// ```
+    /// let stack: WorkStealingStack<u8> = WorkStealingStack::new();
+    /// stack.push(8);
+    /// stack.push(9);
+    /// assert_eq!(stack.pop(), Some(9));
+    /// ```
+    pub fn pop(&self) -> Option<V> {
+        let inner = &*self.inner;
+        let bottom = inner.bottom.load(Relaxed) - 1;
+        let buffer = inner.buffer.load(Relaxed);
+        inner.bottom.store(bottom, Relaxed);
+        fence(SeqCst);
+        let top = inner.top.load(Relaxed);
+
+        if top > bottom {
+            // The deque was already empty
+            inner.bottom.store(bottom + 1, Relaxed);
+            return None
+        }
+
+        let task_ptr = unsafe { (*buffer).get(bottom) };
+        if top == bottom {
+            // This is the last task, so race the thieves for it
+            let won = inner.top.compare_exchange(top, top + 1, SeqCst, Relaxed).is_ok();
+            inner.bottom.store(bottom + 1, Relaxed);
+            if !won {
+                return None
+            }
+        }
+        Some(unsafe { *Box::from_raw(task_ptr) })
+    }
+
+    /// Copy the live tasks into a buffer twice the size, and retire the old buffer.
+    fn grow(&self, old_buffer: *mut Buffer<V>, bottom: isize, top: isize) -> *mut Buffer<V> {
+        let inner = &*self.inner;
+        let new_buffer = unsafe { Buffer::new((*old_buffer).capacity() * 2) };
+        for index in top..bottom {
+            unsafe { new_buffer.put(index, (*old_buffer).get(index)) };
+        }
+        let new_buffer_ptr = Box::into_raw(Box::new(new_buffer));
+        inner.buffer.store(new_buffer_ptr, Release);
+        // The owner never protects anything, so this only clears an unused hazard pointer
+        inner.manager.retire(old_buffer, 0);
+        new_buffer_ptr
+    }
+}
+
+impl<V: Send> Default for WorkStealingStack<V> {
+    fn default() -> Self {
+        WorkStealingStack::new()
+    }
+}
+
+impl<V: Send> Stealer<V> {
+    /// Steal the oldest task from the top of the deque, or return None if it is empty.
+    /// # Examples
+    /// ```
+    /// let stack: WorkStealingStack<u8> = WorkStealingStack::new();
+    /// let stealer = stack.stealer();
+    /// stack.push(8);
+    /// stack.push(9);
+    /// assert_eq!(stealer.steal(), Some(8));
+    /// ```
+    pub fn steal(&self) -> Option<V> {
+        loop {
+            if let Ok(val) = self.try_steal() {
+                return val
+            }
+        }
+    }
+
+    fn try_steal(&self) -> Result<Option<V>, ()> {
+        let inner = &*self.inner;
+        let top = inner.top.load(Acquire);
+        fence(SeqCst);
+        let bottom = inner.bottom.load(Acquire);
+        if top >= bottom {
+            return Ok(None)
+        }
+
+        let buffer = inner.buffer.load(Acquire);
+        inner.manager.protect(buffer, 0);
+        if !ptr::eq(buffer, inner.buffer.load(Acquire)) {
+            return Err(())
+        }
+        let task_ptr = unsafe { (*buffer).get(top) };
+        let result = inner.top.compare_exchange(top, top + 1, SeqCst, Relaxed);
+        inner.manager.unprotect(0);
+        match result {
+            // Only the thread which moved the top past the task can take it
+            Ok(_) => Ok(Some(unsafe { *Box::from_raw(task_ptr) })),
+            Err(_) => Err(())
+        }
+    }
+}
+
+impl<V: Send> Clone for Stealer<V> {
+    fn clone(&self) -> Self {
+        Stealer {
+            inner: self.inner.clone()
+        }
+    }
+}
+
+impl<V: Send> Drop for Inner<V> {
+    // The owner and every stealer are gone, so whatever is left between the top and bottom can be freed
+    fn drop(&mut self) {
+        let buffer = self.buffer.load(Relaxed);
+        unsafe {
+            for index in self.top.load(Relaxed)..self.bottom.load(Relaxed) {
+                drop(Box::from_raw((*buffer).get(index)));
+            }
+            drop(Box::from_raw(buffer));
+        }
+    }
+}
+
+impl<V: Send> Buffer<V> {
+    fn new(capacity: usize) -> Self {
+        let mut slots = Vec::with_capacity(capacity);
+        for _ in 0..capacity {
+            slots.push(AtomicPtr::default());
+        }
+        Buffer {
+            slots,
+            mask: capacity - 1
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn get(&self, index: isize) -> *mut V {
+        self.slots[index as usize & self.mask].load(Relaxed)
+    }
+
+    fn put(&self, index: isize, task_ptr: *mut V) {
+        self.slots[index as usize & self.mask].store(task_ptr, Relaxed);
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use super::WorkStealingStack;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_single_threaded() {
+        let stack: WorkStealingStack<u32> = WorkStealingStack::new();
+        let stealer = stack.stealer();
+        // Enough to grow the buffer a few times
+        for i in 0..200 {
+            stack.push(i);
+        }
+        assert_eq!(stealer.steal(), Some(0));
+        assert_eq!(stealer.steal(), Some(1));
+        assert_eq!(stack.pop(), Some(199));
+        assert_eq!(stack.pop(), Some(198));
+        for i in 2..198 {
+            assert_eq!(stealer.steal(), Some(i));
+        }
+        assert_eq!(stack.pop(), None);
+        assert_eq!(stealer.steal(), None);
+    }
+
+    #[test]
+    fn test_every_task_run_once() {
+        const NUM_TASKS: usize = 100000;
+        let stack: WorkStealingStack<usize> = WorkStealingStack::new();
+        let executed: Arc<Vec<AtomicUsize>> = Arc::new((0..NUM_TASKS).map(|_| AtomicUsize::new(0)).collect());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let mut thieves = Vec::new();
+        for _ in 0..4 {
+            let stealer = stack.stealer();
+            let executed = executed.clone();
+            let done = done.clone();
+            thieves.push(thread::spawn(move || {
+                loop {
+                    match stealer.steal() {
+                        Some(task) => { executed[task].fetch_add(1, Ordering::Relaxed); },
+                        None => {
+                            if done.load(Ordering::Acquire) {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }));
+        }
+
+        // The owner runs a task for every few it pushes, so it competes with the thieves at the bottom
+        for task in 0..NUM_TASKS {
+            stack.push(task);
+            if task % 3 == 0 {
+                if let Some(task) = stack.pop() {
+                    executed[task].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        while let Some(task) = stack.pop() {
+            executed[task].fetch_add(1, Ordering::Relaxed);
+        }
+        done.store(true, Ordering::Release);
+        for thief in thieves {
+            thief.join().unwrap();
+        }
+
+        for count in executed.iter() {
+            assert_eq!(count.load(Ordering::Relaxed), 1);
+        }
+    }
+
+    #[test]
+    fn test_owned_tasks_taken_once() {
+        // Boxed tasks would be freed twice if a pop and a steal both took the same one
+        let stack: WorkStealingStack<Box<usize>> = WorkStealingStack::new();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let mut thieves = Vec::new();
+        for _ in 0..8 {
+            let stealer = stack.stealer();
+            let done = done.clone();
+            thieves.push(thread::spawn(move || {
+                let mut stolen = Vec::new();
+                loop {
+                    match stealer.steal() {
+                        Some(task) => stolen.push(*task),
+                        None => {
+                            if done.load(Ordering::Acquire) {
+                                return stolen
+                            }
+                        }
+                    }
+                }
+            }));
+        }
+
+        // Push in bursts from an empty deque, so the buffer is grown while thieves are reading it
+        let mut taken = Vec::new();
+        for burst in 0..100 {
+            for task in burst * 500..(burst + 1) * 500 {
+                stack.push(Box::new(task));
+            }
+            for _ in 0..250 {
+                if let Some(task) = stack.pop() {
+                    taken.push(*task);
+                }
+            }
+        }
+        while let Some(task) = stack.pop() {
+            taken.push(*task);
+        }
+        done.store(true, Ordering::Release);
+        for thief in thieves {
+            taken.append(&mut thief.join().unwrap());
+        }
+
+        taken.sort();
+        assert_eq!(taken, (0..50000).collect::<Vec<usize>>());
+    }
+}
@@ -55,7 +55,9 @@
 //! ```
 
 pub use self::linearizability_tester::{LinearizabilityTester, LinearizabilityResult, ThreadLog};
+pub use self::replay::{Recorder, RecordLog, Recording, ReplayResult, Operation, StackOp, QueueOp};
 
 pub mod linearizability_tester;
+pub mod replay;
 mod time_stamped;
 mod automaton;
\ No newline at end of file
@@ -0,0 +1,368 @@
+use std::sync::Arc;
+use std::time::Instant;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+
+use rayon;
+
+use structures::{Stack, Queue};
+
+/// An operation that can be performed on a concurrent object of type `C`. The recorder performs
+/// the operation concurrently and remembers its output, and the replayer performs it again
+/// single-threaded to check that it gives the same output.
+pub trait Operation<C>: Clone + Debug {
+    type Output: PartialEq + Clone + Debug;
+
+    /// Perform the operation on the given object and return its result.
+    fn perform(&self, target: &C) -> Self::Output;
+}
+
+/// The operations which can be recorded on a `Stack`.
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum StackOp<T> {
+    Push(T),
+    Pop
+}
+
+impl<T: Send + Clone + PartialEq + Debug> Operation<Stack<T>> for StackOp<T> {
+    type Output = Option<T>;
+
+    fn perform(&self, stack: &Stack<T>) -> Option<T> {
+        match *self {
+            StackOp::Push(ref val) => {
+                stack.push(val.clone());
+                None
+            },
+            StackOp::Pop => stack.pop()
+        }
+    }
+}
+
+/// The operations which can be recorded on a `Queue`.
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum QueueOp<T> {
+    Enqueue(T),
+    Dequeue
+}
+
+impl<T: Send + Clone + PartialEq + Debug> Operation<Queue<T>> for QueueOp<T> {
+    type Output = Option<T>;
+
+    fn perform(&self, queue: &Queue<T>) -> Option<T> {
+        match *self {
+            QueueOp::Enqueue(ref val) => {
+                queue.enqueue(val.clone());
+                None
+            },
+            QueueOp::Dequeue => queue.dequeue()
+        }
+    }
+}
+
+/// Records the operations performed on a concurrent object by a number of threads, so that
+/// they can later be replayed single-threaded. This is intended for debugging heisenbugs: a
+/// failing concurrent run can be captured and then examined deterministically.
+pub struct Recorder<C: Sync + Send, O: Operation<C>> {
+    num_threads: usize,
+    concurrent: Arc<C>,
+    _marker: PhantomData<O>
+}
+
+impl<C, O> Recorder<C, O>
+where C: Sync + Send,
+      O: Operation<C> + Send,
+      O::Output: Send
+{
+    /// Create a new Recorder which will run the given number of threads against the concurrent object.
+    pub fn new(num_threads: usize, concurrent: C) -> Self {
+        Self {
+            num_threads,
+            concurrent: Arc::new(concurrent),
+            _marker: PhantomData
+        }
+    }
+
+    /// Run the worker function on each thread and collect everything the workers recorded.
+    pub fn run(&mut self, worker: fn(usize, &mut RecordLog<C, O>) -> ()) -> Recording<C, O> {
+        let mut logs: Vec<RecordLog<C, O>> = (0..self.num_threads)
+            .map(|id| RecordLog::new(id, self.concurrent.clone()))
+            .collect();
+
+        rayon::scope(|s| {
+            for log in logs.iter_mut() {
+                s.spawn(move |_| {
+                    let id = log.id;
+                    worker(id, log);
+                });
+            }
+        });
+
+        Recording::merge(logs)
+    }
+
+    /// Access the concurrent object the operations were recorded against.
+    pub fn concurrent(&self) -> &C {
+        &self.concurrent
+    }
+}
+
+/// The log of the operations performed by one thread. The worker function should use this
+/// to call methods on the concurrent object.
+pub struct RecordLog<C, O: Operation<C>> {
+    id: usize,
+    concurrent: Arc<C>,
+    entries: Vec<Recorded<O, O::Output>>
+}
+
+impl<C, O: Operation<C>> RecordLog<C, O> {
+    fn new(id: usize, concurrent: Arc<C>) -> Self {
+        Self {
+            id,
+            concurrent,
+            entries: Vec::new()
+        }
+    }
+
+    /// Perform the operation on the concurrent object, remembering its arguments, its result
+    /// and when it was invoked and returned.
+    pub fn record(&mut self, op: O) -> O::Output {
+        let invoke = Instant::now();
+        let result = op.perform(&*self.concurrent);
+        let ret = Instant::now();
+        self.entries.push(Recorded {
+            thread: self.id,
+            op,
+            result: result.clone(),
+            invoke,
+            ret
+        });
+        result
+    }
+}
+
+/// A single recorded operation.
+#[derive(Debug)]
+pub struct Recorded<O, R> {
+    pub thread: usize,
+    pub op: O,
+    pub result: R,
+    invoke: Instant,
+    ret: Instant
+}
+
+/// The merged logs of every thread in a recorded run, ordered by invocation time.
+pub struct Recording<C, O: Operation<C>> {
+    entries: Vec<Recorded<O, O::Output>>,
+    _marker: PhantomData<C>
+}
+
+/// The result of replaying a Recording. If the recording is consistent, the order in which
+/// the operations were linearized is returned as indices into the recording's entries.
+#[derive(Debug)]
+pub enum ReplayResult {
+    Consistent(Vec<usize>),
+    Diverged,
+    TimedOut
+}
+
+impl<C, O: Operation<C>> Recording<C, O> {
+    fn merge(logs: Vec<RecordLog<C, O>>) -> Self {
+        let mut entries = Vec::new();
+        for mut log in logs {
+            entries.append(&mut log.entries);
+        }
+        entries.sort_by_key(|entry| entry.invoke);
+
+        Self {
+            entries,
+            _marker: PhantomData
+        }
+    }
+
+    /// The recorded operations, ordered by invocation time.
+    pub fn entries(&self) -> &[Recorded<O, O::Output>] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Replay the recorded operations single-threaded on fresh objects created by `fresh`, searching for
+    /// an order which respects the real-time order of the recorded operations and in which every
+    /// operation returns the same result it did when recorded. At most `iterations` operations will
+    /// be attempted before giving up.
+    pub fn replay<F: Fn() -> C>(&self, fresh: F, iterations: usize) -> ReplayResult {
+        let num_entries = self.entries.len();
+        let mut order: Vec<usize> = Vec::with_capacity(num_entries);
+        let mut chosen = vec![false; num_entries];
+        // The index to resume searching for candidates from at each depth, used when backtracking
+        let mut resume: Vec<usize> = vec![0];
+        let mut target = fresh();
+        let mut attempts = 0;
+
+        while order.len() < num_entries {
+            let depth = order.len();
+            // An operation can only go next if it was invoked before every remaining operation returned
+            let earliest_return = self.entries.iter()
+                .enumerate()
+                .filter(|&(i, _)| !chosen[i])
+                .map(|(_, entry)| entry.ret)
+                .min()
+                .unwrap();
+
+            let mut next = None;
+            let mut dirty = false;
+            for (i, entry) in self.entries.iter().enumerate().skip(resume[depth]) {
+                if chosen[i] {
+                    continue;
+                }
+                if entry.invoke > earliest_return {
+                    break;
+                }
+                attempts += 1;
+                if attempts > iterations {
+                    return ReplayResult::TimedOut
+                }
+                if dirty {
+                    target = self.rebuild(&order, &fresh);
+                }
+                dirty = true;
+                if entry.op.perform(&target) == entry.result {
+                    next = Some(i);
+                    break;
+                }
+            }
+
+            match next {
+                Some(i) => {
+                    resume[depth] = i + 1;
+                    chosen[i] = true;
+                    order.push(i);
+                    resume.push(0);
+                },
+                None => {
+                    resume.pop();
+                    match order.pop() {
+                        None => return ReplayResult::Diverged,
+                        Some(last) => chosen[last] = false
+                    }
+                    target = self.rebuild(&order, &fresh);
+                }
+            }
+        }
+
+        ReplayResult::Consistent(order)
+    }
+
+    /// Create a fresh object and perform the recorded operations on it in the given order,
+    /// ignoring their results.
+    pub fn rebuild<F: Fn() -> C>(&self, order: &[usize], fresh: F) -> C {
+        let target = fresh();
+        for &i in order {
+            self.entries[i].op.perform(&target);
+        }
+        target
+    }
+}
+
+mod tests {
+    #![allow(unused_imports)]
+    use rand::{thread_rng, Rng};
+
+    use super::{Recorder, RecordLog, ReplayResult, StackOp, QueueOp};
+    use structures::{Stack, Queue};
+
+    #[test]
+    fn test_stack_replay() {
+        let mut recorder: Recorder<Stack<usize>, StackOp<usize>> = Recorder::new(4, Stack::new(false));
+
+        fn worker(id: usize, log: &mut RecordLog<Stack<usize>, StackOp<usize>>) {
+            for i in 0..100 {
+                if thread_rng().gen_range(0, 101) < 50 {
+                    log.record(StackOp::Push(id * 1000 + i));
+                } else {
+                    log.record(StackOp::Pop);
+                }
+            }
+        }
+
+        let recording = recorder.run(worker);
+        assert_eq!(recording.len(), 400);
+
+        match recording.replay(|| Stack::new(false), 1000000) {
+            ReplayResult::Consistent(order) => {
+                let replayed = recording.rebuild(&order, || Stack::new(false));
+                loop {
+                    let expected = recorder.concurrent().pop();
+                    assert_eq!(replayed.pop(), expected);
+                    if expected.is_none() {
+                        break;
+                    }
+                }
+            },
+            result => panic!("Replay failed: {:?}", result)
+        }
+    }
+
+    #[test]
+    fn test_queue_replay() {
+        let mut recorder: Recorder<Queue<usize>, QueueOp<usize>> = Recorder::new(4, Queue::new());
+
+        fn worker(id: usize, log: &mut RecordLog<Queue<usize>, QueueOp<usize>>) {
+            for i in 0..100 {
+                if thread_rng().gen_range(0, 101) < 50 {
+                    log.record(QueueOp::Enqueue(id * 1000 + i));
+                } else {
+                    log.record(QueueOp::Dequeue);
+                }
+            }
+        }
+
+        let recording = recorder.run(worker);
+
+        match recording.replay(Queue::new, 1000000) {
+            ReplayResult::Consistent(order) => {
+                assert_eq!(order.len(), recording.len());
+                let replayed = recording.rebuild(&order, Queue::new);
+                loop {
+                    let expected = recorder.concurrent().dequeue();
+                    assert_eq!(replayed.dequeue(), expected);
+                    if expected.is_none() {
+                        break;
+                    }
+                }
+            },
+            result => panic!("Replay failed: {:?}", result)
+        }
+    }
+
+    #[test]
+    fn test_replay_detects_divergence() {
+        let mut recorder: Recorder<Stack<usize>, StackOp<usize>> = Recorder::new(1, Stack::new(false));
+
+        fn worker(_id: usize, log: &mut RecordLog<Stack<usize>, StackOp<usize>>) {
+            log.record(StackOp::Pop);
+            log.record(StackOp::Push(1));
+        }
+
+        let recording = recorder.run(worker);
+
+        // The recorded pop found an empty stack, which cannot happen if the stack starts with an element
+        let fresh = || {
+            let stack = Stack::new(false);
+            stack.push(7);
+            stack
+        };
+        match recording.replay(fresh, 1000) {
+            ReplayResult::Diverged => {},
+            result => panic!("Expected divergence, got {:?}", result)
+        }
+    }
+}
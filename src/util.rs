@@ -0,0 +1,4 @@
+//! General utilities for writing concurrent code, which the structures in this crate use themselves.
+
+pub use structures::utils::backoff::Backoff;
+pub use structures::utils::cache_padded::CachePadded;
@@ -0,0 +1,71 @@
+// Run with `cargo test --features loom --test loom`. The Stack and Queue are built on loom's atomics,
+// and every interleaving of the small programs below is checked. The HPBRManager relies on real
+// thread-local storage, which loom's threads do not have, so the structures are given a strategy
+// which never frees anything.
+#![cfg(feature = "loom")]
+extern crate loom;
+extern crate rustcurrent;
+
+use rustcurrent::memory::ReclamationStrategy;
+use rustcurrent::structures::{Stack, Queue};
+
+use loom::sync::Arc;
+use loom::thread;
+
+/// A strategy which never frees anything, so that no interleaving can free a node too early.
+struct Leak;
+
+impl<T: Send> ReclamationStrategy<T> for Leak {
+    fn protect(&self, _record: *mut T, _hazard_num: usize) {}
+
+    fn unprotect(&self, _hazard_num: usize) {}
+
+    fn retire(&self, _record: *mut T, _hazard_num: usize) {}
+}
+
+#[test]
+fn stack_push_pop() {
+    loom::model(|| {
+        let stack = Arc::new(Stack::with_manager(false, Leak));
+        let stack_clone = stack.clone();
+        let handle = thread::spawn(move || {
+            stack_clone.push(1);
+            stack_clone.pop()
+        });
+        stack.push(2);
+        let mine = stack.pop();
+        let theirs = handle.join().unwrap();
+
+        // Each thread pushes before it pops, so neither pop can find the stack empty, and no item
+        // can be popped twice
+        let mut popped = vec![mine.unwrap(), theirs.unwrap()];
+        popped.sort();
+        assert_eq!(popped, vec![1, 2]);
+        assert_eq!(stack.pop(), None);
+        assert_eq!(stack.len(), 0);
+    });
+}
+
+#[test]
+fn queue_enqueue_dequeue() {
+    loom::model(|| {
+        let queue = Arc::new(Queue::with_manager(Leak));
+        let queue_clone = queue.clone();
+        let handle = thread::spawn(move || {
+            queue_clone.enqueue(1);
+            queue_clone.enqueue(2);
+        });
+        let first = queue.dequeue();
+        let second = queue.dequeue();
+        handle.join().unwrap();
+
+        // Whatever was dequeued while the other thread was enqueueing came out in order, and
+        // the rest is still in the queue, also in order
+        let mut dequeued: Vec<u32> = first.into_iter().chain(second).collect();
+        while let Some(val) = queue.dequeue() {
+            dequeued.push(val);
+        }
+        assert_eq!(dequeued, vec![1, 2]);
+        assert!(queue.is_empty());
+    });
+}
@@ -0,0 +1,172 @@
+#![cfg(feature = "std")]
+extern crate proptest;
+extern crate rustcurrent;
+
+use proptest::prelude::*;
+use rustcurrent::structures::{Stack, Queue, HashMap};
+
+use std::collections;
+use std::collections::VecDeque;
+use std::collections::hash_map::Entry;
+
+// Keys and values are drawn from small ranges so that operations collide often and
+// failing cases shrink towards a handful of distinct keys.
+const KEY_RANGE: u8 = 16;
+const VALUE_RANGE: u8 = 8;
+
+#[derive(Clone)]
+#[derive(Debug)]
+enum StackOp {
+    Push(u8),
+    Pop
+}
+
+fn stack_op() -> impl Strategy<Value = StackOp> {
+    prop_oneof![
+        (0..VALUE_RANGE).prop_map(StackOp::Push),
+        Just(StackOp::Pop)
+    ]
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+enum QueueOp {
+    Enqueue(u8),
+    Dequeue
+}
+
+fn queue_op() -> impl Strategy<Value = QueueOp> {
+    prop_oneof![
+        (0..VALUE_RANGE).prop_map(QueueOp::Enqueue),
+        Just(QueueOp::Dequeue)
+    ]
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+enum MapOp {
+    Insert(u8, u8),
+    InsertIfAbsent(u8, u8),
+    Get(u8),
+    Update(u8, u8, u8),
+    Remove(u8),
+    CompareAndRemove(u8, u8)
+}
+
+fn map_op() -> impl Strategy<Value = MapOp> {
+    prop_oneof![
+        (0..KEY_RANGE, 0..VALUE_RANGE).prop_map(|(k, v)| MapOp::Insert(k, v)),
+        (0..KEY_RANGE, 0..VALUE_RANGE).prop_map(|(k, v)| MapOp::InsertIfAbsent(k, v)),
+        (0..KEY_RANGE).prop_map(MapOp::Get),
+        (0..KEY_RANGE, 0..VALUE_RANGE, 0..VALUE_RANGE).prop_map(|(k, e, v)| MapOp::Update(k, e, v)),
+        (0..KEY_RANGE).prop_map(MapOp::Remove),
+        (0..KEY_RANGE, 0..VALUE_RANGE).prop_map(|(k, e)| MapOp::CompareAndRemove(k, e))
+    ]
+}
+
+/// Every entry in the map, sorted by key, for comparison against the model.
+fn map_snapshot(map: &HashMap<u8, u8>) -> Vec<(u8, u8)> {
+    let mut entries: Vec<(u8, u8)> = map.keys_matching(|_| true)
+        .into_iter()
+        .map(|key| (key, map.get_clone(&key).unwrap()))
+        .collect();
+    entries.sort();
+    entries
+}
+
+fn model_snapshot(model: &collections::HashMap<u8, u8>) -> Vec<(u8, u8)> {
+    let mut entries: Vec<(u8, u8)> = model.iter().map(|(&k, &v)| (k, v)).collect();
+    entries.sort();
+    entries
+}
+
+proptest! {
+    #[test]
+    fn stack_matches_vec(ops in prop::collection::vec(stack_op(), 0..200)) {
+        let stack = Stack::new(false);
+        let mut model = Vec::new();
+
+        for op in ops {
+            match op {
+                StackOp::Push(val) => {
+                    stack.push(val);
+                    model.push(val);
+                },
+                StackOp::Pop => prop_assert_eq!(stack.pop(), model.pop())
+            }
+            prop_assert_eq!(stack.len(), model.len());
+        }
+
+        while let Some(expected) = model.pop() {
+            prop_assert_eq!(stack.pop(), Some(expected));
+        }
+        prop_assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn queue_matches_vec_deque(ops in prop::collection::vec(queue_op(), 0..200)) {
+        let queue = Queue::new();
+        let mut model = VecDeque::new();
+
+        for op in ops {
+            match op {
+                QueueOp::Enqueue(val) => {
+                    queue.enqueue(val);
+                    model.push_back(val);
+                },
+                QueueOp::Dequeue => prop_assert_eq!(queue.dequeue(), model.pop_front())
+            }
+            prop_assert_eq!(queue.len(), model.len());
+        }
+
+        while let Some(expected) = model.pop_front() {
+            prop_assert_eq!(queue.dequeue(), Some(expected));
+        }
+        prop_assert_eq!(queue.dequeue(), None);
+    }
+
+    #[test]
+    fn hash_map_matches_std(ops in prop::collection::vec(map_op(), 0..200)) {
+        let map: HashMap<u8, u8> = HashMap::new();
+        let mut model: collections::HashMap<u8, u8> = collections::HashMap::new();
+
+        for op in ops {
+            match op {
+                MapOp::Insert(key, val) => prop_assert_eq!(map.insert(key, val), model.insert(key, val)),
+                MapOp::InsertIfAbsent(key, val) => {
+                    let expected = match model.entry(key) {
+                        Entry::Occupied(_) => Err((key, val)),
+                        Entry::Vacant(slot) => {
+                            slot.insert(val);
+                            Ok(())
+                        }
+                    };
+                    prop_assert_eq!(map.insert_if_absent(key, val), expected);
+                },
+                MapOp::Get(key) => prop_assert_eq!(map.get_clone(&key), model.get(&key).cloned()),
+                MapOp::Update(key, expected, val) => {
+                    let result = if model.get(&key) == Some(&expected) {
+                        model.insert(key, val);
+                        Ok(())
+                    } else {
+                        Err(val)
+                    };
+                    prop_assert_eq!(map.update(&key, &expected, val), result);
+                },
+                MapOp::Remove(key) => prop_assert_eq!(map.remove(&key), model.remove(&key)),
+                MapOp::CompareAndRemove(key, expected) => {
+                    let result = if model.get(&key) == Some(&expected) {
+                        model.remove(&key)
+                    } else {
+                        None
+                    };
+                    prop_assert_eq!(map.compare_and_remove(&key, &expected), result);
+                }
+            }
+
+            // Every operation runs on this thread, so the map is quiescent between them
+            prop_assert_eq!(map_snapshot(&map), model_snapshot(&model));
+            prop_assert_eq!(map.len(), model.len());
+        }
+    }
+}
@@ -0,0 +1,89 @@
+// Run with `cargo test --no-default-features`. The library is built without std, so
+// the structures have to be given a ReclamationStrategy which does not need it.
+#![cfg(not(feature = "std"))]
+extern crate rustcurrent;
+
+use rustcurrent::memory::ReclamationStrategy;
+use rustcurrent::structures::{Stack, Queue, BoundedQueue, AtomicMin, AtomicMax};
+
+use std::cell::RefCell;
+
+/// A single-threaded strategy which frees every retired record when it is dropped.
+struct Deferred<T> {
+    retired: RefCell<Vec<*mut T>>
+}
+
+impl<T> Deferred<T> {
+    fn new() -> Self {
+        Deferred { retired: RefCell::new(Vec::new()) }
+    }
+}
+
+impl<T: Send> ReclamationStrategy<T> for Deferred<T> {
+    fn protect(&self, _record: *mut T, _hazard_num: usize) {}
+
+    fn unprotect(&self, _hazard_num: usize) {}
+
+    fn retire(&self, record: *mut T, _hazard_num: usize) {
+        self.retired.borrow_mut().push(record);
+    }
+}
+
+impl<T> Drop for Deferred<T> {
+    fn drop(&mut self) {
+        for &record in self.retired.borrow().iter() {
+            unsafe { drop(Box::from_raw(record)) };
+        }
+    }
+}
+
+#[test]
+fn stack_without_std() {
+    let stack = Stack::with_manager(false, Deferred::new());
+    for i in 0..100 {
+        stack.push(i);
+    }
+    assert_eq!(stack.len(), 100);
+    for i in (0..100).rev() {
+        assert_eq!(stack.pop(), Some(i));
+    }
+    assert_eq!(stack.pop(), None);
+}
+
+#[test]
+#[should_panic]
+fn stack_elimination_needs_std() {
+    let _stack: Stack<u32, _> = Stack::with_manager(true, Deferred::new());
+}
+
+#[test]
+fn queue_without_std() {
+    let queue = Queue::with_manager(Deferred::new());
+    for i in 0..100 {
+        queue.enqueue(i);
+    }
+    for i in 0..100 {
+        assert_eq!(queue.dequeue(), Some(i));
+    }
+    assert_eq!(queue.dequeue(), None);
+}
+
+#[test]
+fn bounded_queue_without_std() {
+    let queue: BoundedQueue<u32> = BoundedQueue::new(4);
+    assert_eq!(queue.try_enqueue(1), Ok(()));
+    assert_eq!(queue.try_dequeue(), Some(1));
+    assert_eq!(queue.try_dequeue(), None);
+}
+
+#[test]
+fn min_max_without_std() {
+    let min: AtomicMin<i32> = AtomicMin::new(0);
+    let max: AtomicMax<i32> = AtomicMax::new(0);
+    for &value in [5, -3, 8, -1].iter() {
+        min.update(value);
+        max.update(value);
+    }
+    assert_eq!(min.get(), -3);
+    assert_eq!(max.get(), 8);
+}
\ No newline at end of file
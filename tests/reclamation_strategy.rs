@@ -0,0 +1,73 @@
+#![cfg(feature = "std")]
+extern crate rustcurrent;
+
+use rustcurrent::memory::ReclamationStrategy;
+use rustcurrent::structures::{Stack, Queue, SegQueue};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A strategy which never frees anything, and only counts what it is given.
+struct Leak {
+    retired: Arc<AtomicUsize>
+}
+
+impl Leak {
+    fn new() -> (Self, Arc<AtomicUsize>) {
+        let retired = Arc::new(AtomicUsize::new(0));
+        (Leak { retired: retired.clone() }, retired)
+    }
+}
+
+impl<T: Send> ReclamationStrategy<T> for Leak {
+    fn protect(&self, _record: *mut T, _hazard_num: usize) {}
+
+    fn unprotect(&self, _hazard_num: usize) {}
+
+    fn retire(&self, _record: *mut T, _hazard_num: usize) {
+        self.retired.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn stack_with_leaking_strategy() {
+    let (leak, retired) = Leak::new();
+    let stack = Stack::with_manager(false, leak);
+    for i in 0..100 {
+        stack.push(i);
+    }
+    for i in (0..100).rev() {
+        assert_eq!(stack.pop(), Some(i));
+    }
+    assert_eq!(stack.pop(), None);
+    assert_eq!(retired.load(Ordering::Relaxed), 100);
+}
+
+#[test]
+fn queue_with_leaking_strategy() {
+    let (leak, retired) = Leak::new();
+    let queue = Queue::with_manager(leak);
+    for i in 0..100 {
+        queue.enqueue(i);
+    }
+    for i in 0..100 {
+        assert_eq!(queue.dequeue(), Some(i));
+    }
+    assert_eq!(queue.dequeue(), None);
+    assert!(!queue.has_reclaim_pressure());
+    assert_eq!(retired.load(Ordering::Relaxed), 100);
+}
+
+#[test]
+fn seg_queue_with_leaking_strategy() {
+    let (leak, retired) = Leak::new();
+    let queue = SegQueue::with_manager(4, leak);
+    for i in 0..100 {
+        queue.enqueue(i);
+    }
+    let mut dequeued: Vec<u32> = (0..100).map(|_| queue.dequeue().unwrap()).collect();
+    dequeued.sort();
+    assert_eq!(dequeued, (0..100).collect::<Vec<u32>>());
+    assert_eq!(queue.dequeue(), None);
+    assert!(retired.load(Ordering::Relaxed) > 0);
+}
@@ -0,0 +1,81 @@
+#![cfg(feature = "serde")]
+extern crate rustcurrent;
+extern crate serde_json;
+
+use rustcurrent::structures::{Stack, HashMap};
+
+use std::collections::HashMap as StdHashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+#[test]
+fn stack_round_trip() {
+    let stack: Stack<String> = Stack::new(false);
+    for word in &["bottom", "middle", "top"] {
+        stack.push(word.to_string());
+    }
+
+    let json = serde_json::to_string(&stack).unwrap();
+    assert_eq!(json, r#"["top","middle","bottom"]"#);
+
+    let copy: Stack<String> = serde_json::from_str(&json).unwrap();
+    assert_eq!(serde_json::to_string(&copy).unwrap(), json);
+    assert_eq!(copy.pop(), Some("top".to_owned()));
+    assert_eq!(copy.pop(), Some("middle".to_owned()));
+    assert_eq!(copy.pop(), Some("bottom".to_owned()));
+    assert_eq!(copy.pop(), None);
+}
+
+#[test]
+fn stack_serialize_while_contended() {
+    let stack: Arc<Stack<u32>> = Arc::new(Stack::new(false));
+    for i in 0..100 {
+        stack.push(i);
+    }
+    let done = Arc::new(AtomicBool::new(false));
+    let mut wait_vec: Vec<thread::JoinHandle<()>> = Vec::new();
+    for _ in 0..4 {
+        let stack_clone = stack.clone();
+        let done_clone = done.clone();
+        wait_vec.push(thread::spawn(move || {
+            while !done_clone.load(Ordering::Relaxed) {
+                // The bottom 100 items are never popped
+                stack_clone.push(1000);
+                stack_clone.pop();
+                thread::yield_now();
+            }
+        }));
+    }
+
+    for _ in 0..50 {
+        let items: Vec<u32> = serde_json::from_str(&serde_json::to_string(&*stack).unwrap()).unwrap();
+        let bottom = items.len() - 100;
+        assert!(items[..bottom].iter().all(|&item| item == 1000));
+        assert_eq!(items[bottom..].to_vec(), (0..100).rev().collect::<Vec<u32>>());
+    }
+    done.store(true, Ordering::Relaxed);
+    for handle in wait_vec {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn hash_map_round_trip() {
+    let map: HashMap<String, u32> = HashMap::new();
+    for i in 0..50 {
+        map.insert(format!("key{}", i), i);
+    }
+
+    let json = serde_json::to_string(&map).unwrap();
+    let copy: HashMap<String, u32> = serde_json::from_str(&json).unwrap();
+    assert_eq!(copy.len(), 50);
+    for i in 0..50 {
+        assert_eq!(copy.get_clone(&format!("key{}", i)), Some(i));
+    }
+
+    // Every entry is written exactly once
+    let entries: StdHashMap<String, u32> = serde_json::from_str(&json).unwrap();
+    assert_eq!(entries.len(), 50);
+    assert_eq!(entries["key7"], 7);
+}